@@ -0,0 +1,89 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+#[macro_use]
+extern crate criterion;
+extern crate formdata;
+
+use std::io::{self, Cursor};
+use criterion::{Criterion, Throughput};
+use formdata::{ParseConfig, WriteConfig};
+use formdata::bench::{self, LargeFileBody};
+
+const BOUNDARY: &str = "----WebKitFormBoundary7MA4YWxkTrZu0gW";
+
+fn many_fields(c: &mut Criterion) {
+    let body = bench::many_fields_body(BOUNDARY, 1000);
+    let headers = bench::headers(BOUNDARY);
+    let config = ParseConfig::default();
+    let mut group = c.benchmark_group("many_fields");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    group.bench_function("1000", |b| b.iter(|| {
+        formdata::read_formdata_with_config(&mut Cursor::new(&body), &headers, &config).unwrap()
+    }));
+    group.finish();
+}
+
+fn text_file(c: &mut Criterion) {
+    let body = bench::text_body(BOUNDARY, 8 * 1024 * 1024);
+    let headers = bench::headers(BOUNDARY);
+    let config = ParseConfig::default();
+    let mut group = c.benchmark_group("text_file");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    group.bench_function("8MiB", |b| b.iter(|| {
+        formdata::read_formdata_with_config(&mut Cursor::new(&body), &headers, &config).unwrap()
+    }));
+    group.finish();
+}
+
+fn large_file(c: &mut Criterion) {
+    const SIZE: u64 = 1024 * 1024 * 1024;
+    let headers = bench::headers(BOUNDARY);
+    let config = ParseConfig::default();
+    let mut group = c.benchmark_group("large_file");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE));
+    group.bench_function("1GiB", |b| b.iter(|| {
+        let mut body = LargeFileBody::new(BOUNDARY, SIZE);
+        formdata::read_formdata_with_config(&mut body, &headers, &config).unwrap()
+    }));
+    group.finish();
+}
+
+fn pathological(c: &mut Criterion) {
+    let body = bench::pathological_body(BOUNDARY, 8 * 1024 * 1024);
+    let headers = bench::headers(BOUNDARY);
+    let config = ParseConfig::default();
+    let mut group = c.benchmark_group("pathological");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    group.bench_function("boundary_prefixes", |b| b.iter(|| {
+        formdata::read_formdata_with_config(&mut Cursor::new(&body), &headers, &config).unwrap()
+    }));
+    group.finish();
+}
+
+// Writing a form holding a single large file, reading the file (the default) or through a
+// memory map (with the `mmap` feature).
+fn write_large_file(c: &mut Criterion) {
+    const SIZE: u64 = 256 * 1024 * 1024;
+    let headers = bench::headers(BOUNDARY);
+    let mut body = LargeFileBody::new(BOUNDARY, SIZE);
+    let formdata = formdata::read_formdata(&mut body, &headers).unwrap();
+    let boundary = BOUNDARY.as_bytes().to_vec();
+    let mut group = c.benchmark_group("write_large_file");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE));
+    for &(name, mmap) in &[("read", false), ("mmap", true)] {
+        let config = WriteConfig { mmap, ..WriteConfig::default() };
+        group.bench_function(name, |b| b.iter(|| {
+            formdata::write_formdata_with_config(&mut io::sink(), &boundary, &formdata, &config)
+                .unwrap()
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, many_fields, text_file, large_file, pathological, write_large_file);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::sync::Arc;
+use hyper::header::{Headers, ContentLength};
+use error::Error;
+use file_part;
+use shared::Shared;
+
+/// A part that was rejected while parsing, as reported to an `AuditSink`.  Only what the
+/// client declared about the part is included, never its content.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RejectedPart {
+    /// The part's name, if it was known.
+    pub name: Option<String>,
+    /// The part's filename, without any directory components, if it had one.
+    pub filename: Option<String>,
+    /// The part's Content-Length, if it declared one.
+    pub declared_size: Option<u64>,
+    /// Why it was rejected, as an `Error::code()`.
+    pub reason: &'static str,
+}
+
+/// Receives a report of each part rejected while parsing: one that broke a limit such as
+/// `ParseConfig::max_files` or `ParseConfig::max_body_size`, that `ParseConfig::mime_policy`
+/// did not allow, or whose headers were invalid.  Give one to `ParseConfig::audit` (wrapped
+/// in an `Auditor`) to monitor for abuse without handling each error yourself.
+///
+/// The parse fails as it would have anyway; the sink is called just before.
+pub trait AuditSink: Send + Sync {
+    fn rejected(&self, part: &RejectedPart);
+}
+
+/// A shareable handle to an `AuditSink`, for `ParseConfig::audit`.  Cloning an `Auditor`
+/// gives another handle to the same sink.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Auditor {
+    sink: Shared<dyn AuditSink>,
+}
+
+impl Auditor {
+    pub fn new<S: AuditSink + 'static>(sink: S) -> Auditor {
+        Auditor { sink: Shared(Arc::new(sink)) }
+    }
+
+    // Report a part with `headers` (if they were read) and `name` (if known) as rejected
+    // with `error`.
+    pub(crate) fn report(&self, name: Option<&str>, headers: Option<&Headers>, error: &Error) {
+        self.sink.rejected(&RejectedPart {
+            name: name.map(|name| name.to_owned()),
+            filename: headers.and_then(file_part::headers_filename),
+            declared_size: headers.and_then(|h| h.get::<ContentLength>()).map(|cl| cl.0),
+            reason: error.code(),
+        });
+    }
+}
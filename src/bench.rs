@@ -0,0 +1,180 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Helpers for measuring parsing throughput, used by the benchmarks in `benches/` and
+//! available for measuring the parser in your own environment.
+//!
+//! The benchmark suite covers three shapes of body.  With a release build on a single core
+//! of an ordinary x86-64 machine, with temporary files on a local SSD, we aim for at least:
+//!
+//! | Body                                           | Target     |
+//! |------------------------------------------------|------------|
+//! | 1000 small fields (`many_fields_body`)         | 50 MB/s    |
+//! | a single large file (`LargeFileBody`)          | 200 MB/s   |
+//! | boundary-prefix content (`pathological_body`)  | 100 MB/s   |
+//!
+//! A change that pushes any of these below target is a regression.  Run the suite with
+//! `cargo bench`.
+
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+use hyper::header::{Headers, ContentType};
+use mime::{Mime, TopLevel, SubLevel, Attr, Value};
+use config::ParseConfig;
+use error::Error;
+use headers::RequestHeaders;
+use reader::read_formdata_with_config;
+
+/// The result of a `throughput()` measurement.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Throughput {
+    /// The number of body bytes parsed
+    pub bytes: u64,
+    /// How long parsing took
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    /// Bytes parsed per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.bytes as f64 / secs } else { 0.0 }
+    }
+}
+
+/// Parse the whole of `stream` with `config` and report how quickly it went.  The parsed
+/// `FormData` (and so any temporary files) is discarded.
+pub fn throughput<S: Read, H: RequestHeaders>(stream: &mut S, headers: &H,
+                                              config: &ParseConfig)
+                                              -> Result<Throughput, Error>
+{
+    let mut counter = CountingReader { inner: stream, count: 0 };
+    let start = Instant::now();
+    let formdata = read_formdata_with_config(&mut counter, headers, config)?;
+    let elapsed = start.elapsed();
+    drop(formdata);
+    Ok(Throughput { bytes: counter.count, elapsed })
+}
+
+/// Request headers announcing a `multipart/form-data` body delimited by `boundary`.
+pub fn headers(boundary: &str) -> Headers {
+    let mut headers = Headers::new();
+    headers.set(ContentType(Mime(TopLevel::Multipart, SubLevel::FormData,
+                                 vec![(Attr::Boundary, Value::Ext(boundary.to_owned()))])));
+    headers
+}
+
+/// A body of `count` small text fields.
+pub fn many_fields_body(boundary: &str, count: usize) -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    for i in 0..count {
+        body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"field{}\"\r\n\r\n\
+                             value number {}\r\n", boundary, i, i).into_bytes());
+    }
+    body.extend(format!("--{}--\r\n", boundary).into_bytes());
+    body
+}
+
+/// A body holding a single text file of `size` bytes, in short CRLF-terminated lines, so
+/// that the boundary search meets a carriage return every few dozen bytes.
+pub fn text_body(boundary: &str, size: usize) -> Vec<u8> {
+    let line = b"The quick brown fox jumps over the lazy dog.\r\n";
+    let mut body = file_header(boundary);
+    body.extend(line.iter().cycle().take(size));
+    body.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+    body
+}
+
+/// A body holding a single file of `size` bytes, content consisting of nothing but
+/// near-misses of the delimiter (`\r\n--` followed by all but the last byte of the
+/// boundary), which defeats any skip-ahead in the boundary search.
+pub fn pathological_body(boundary: &str, size: usize) -> Vec<u8> {
+    let mut near_miss: Vec<u8> = b"\r\n--".to_vec();
+    near_miss.extend_from_slice(&boundary.as_bytes()[..boundary.len() - 1]);
+    let mut body = file_header(boundary);
+    body.extend(near_miss.iter().cycle().take(size));
+    body.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+    body
+}
+
+/// A body holding a single file of `size` bytes, generated as it is read so that even
+/// very large bodies need not be held in memory.
+pub struct LargeFileBody {
+    header: Vec<u8>,
+    remaining: u64,
+    trailer: Vec<u8>,
+    pos: usize,
+}
+
+impl LargeFileBody {
+    pub fn new(boundary: &str, size: u64) -> LargeFileBody {
+        LargeFileBody {
+            header: file_header(boundary),
+            remaining: size,
+            trailer: format!("\r\n--{}--\r\n", boundary).into_bytes(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for LargeFileBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.header.len() {
+            let n = (&self.header[self.pos..]).read(buf)?;
+            self.pos += n;
+            return Ok(n);
+        }
+        if self.remaining > 0 {
+            let n = (buf.len() as u64).min(self.remaining) as usize;
+            for (i, b) in buf[..n].iter_mut().enumerate() {
+                *b = b'a' + (i % 26) as u8;
+            }
+            self.remaining -= n as u64;
+            return Ok(n);
+        }
+        let start = self.pos - self.header.len();
+        let n = (&self.trailer[start.min(self.trailer.len())..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn file_header(boundary: &str) -> Vec<u8> {
+    format!("--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"data.bin\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n", boundary).into_bytes()
+}
+
+struct CountingReader<'a, S: Read + 'a> {
+    inner: &'a mut S,
+    count: u64,
+}
+
+impl<'a, S: Read> Read for CountingReader<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::{throughput, headers, many_fields_body, pathological_body, LargeFileBody};
+    use config::ParseConfig;
+
+    #[test]
+    fn generated_bodies_parse() {
+        let config = ParseConfig::default();
+        let body = many_fields_body("boundary", 10);
+        let t = throughput(&mut Cursor::new(&body), &headers("boundary"), &config).unwrap();
+        assert_eq!(t.bytes, body.len() as u64);
+
+        let body = pathological_body("boundary", 1000);
+        assert!(throughput(&mut Cursor::new(body), &headers("boundary"), &config).is_ok());
+
+        let mut body = LargeFileBody::new("boundary", 100_000);
+        let t = throughput(&mut body, &headers("boundary"), &config).unwrap();
+        assert!(t.bytes > 100_000);
+    }
+}
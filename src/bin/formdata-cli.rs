@@ -0,0 +1,144 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Decode a captured `multipart/form-data` body into a directory of parts, or encode such a
+//! directory back into a body.
+//!
+//! ```text
+//! formdata-cli decode <body-file> <boundary> <out-dir>
+//! formdata-cli encode <manifest> <boundary> <body-file>
+//! ```
+//!
+//! Decoding writes each part's content to its own file in `<out-dir>`, and lists the parts
+//! in `<out-dir>/manifest`, one per line, with tab-separated columns:
+//!
+//! ```text
+//! field   <name>  <content file>
+//! file    <name>  <content file>  <filename>  <content-type>
+//! ```
+//!
+//! Tabs, newlines and backslashes within a column are backslash-escaped.  Content file
+//! paths are relative to the manifest.  Encoding reads such a manifest.
+
+extern crate formdata;
+extern crate hyper;
+extern crate mime;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::process;
+use hyper::header::{Headers, ContentType, ContentDisposition, DispositionParam,
+                    DispositionType, Charset};
+use mime::{Mime, TopLevel, SubLevel, Attr, Value};
+use formdata::{FormData, FilePart, Error, WriteConfig};
+
+const USAGE: &str = "usage: formdata-cli decode <body-file> <boundary> <out-dir>\n       \
+                     formdata-cli encode <manifest> <boundary> <body-file>";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(|a| &**a).collect::<Vec<&str>>()[..] {
+        ["decode", body, boundary, out_dir] =>
+            decode(Path::new(body), boundary, Path::new(out_dir)),
+        ["encode", manifest, boundary, body] =>
+            encode(Path::new(manifest), boundary, Path::new(body)),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("formdata-cli: {}", e);
+        process::exit(1);
+    }
+}
+
+fn decode(body: &Path, boundary: &str, out_dir: &Path) -> Result<(), Error> {
+    let mut headers = Headers::new();
+    headers.set(ContentType(Mime(TopLevel::Multipart, SubLevel::FormData,
+                                 vec![(Attr::Boundary, Value::Ext(boundary.to_owned()))])));
+    let formdata = formdata::read_formdata(&mut BufReader::new(File::open(body)?), &headers)?;
+
+    fs::create_dir_all(out_dir)?;
+    let mut manifest = File::create(out_dir.join("manifest"))?;
+    for (i, (name, value)) in formdata.fields.iter().enumerate() {
+        let content = format!("field-{}", i);
+        fs::write(out_dir.join(&content), value.as_bytes())?;
+        writeln!(manifest, "field\t{}\t{}", escape(name), content)?;
+    }
+    let files = formdata.large_fields.iter().map(|f| (f, false))
+        .chain(formdata.files.iter().map(|f| (f, true)));
+    for (i, ((name, file), is_file)) in files.enumerate() {
+        let content = format!("file-{}", i);
+        fs::copy(&file.path, out_dir.join(&content))?;
+        if is_file {
+            writeln!(manifest, "file\t{}\t{}\t{}\t{}", escape(name), content,
+                     escape(&file.raw_filename()?.unwrap_or_default()),
+                     file.content_type().map(|ct| ct.to_string()).unwrap_or_default())?;
+        } else {
+            writeln!(manifest, "field\t{}\t{}", escape(name), content)?;
+        }
+    }
+    Ok(())
+}
+
+fn encode(manifest: &Path, boundary: &str, body: &Path) -> Result<(), Error> {
+    let dir = manifest.parent().unwrap_or_else(|| Path::new("."));
+    let mut formdata = FormData::new();
+    for line in BufReader::new(File::open(manifest)?).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let columns: Vec<String> = line.split('\t').map(unescape).collect();
+        match columns.iter().map(|c| &**c).collect::<Vec<&str>>()[..] {
+            ["field", name, content] => {
+                let value = String::from_utf8(fs::read(dir.join(content))?)?;
+                formdata.fields.push((name.to_owned().into(), value.into()));
+            },
+            ["file", name, content, filename, content_type] => {
+                let mut headers = Headers::new();
+                headers.set(ContentDisposition {
+                    disposition: DispositionType::Ext("form-data".to_owned()),
+                    parameters: vec![DispositionParam::Filename(
+                        Charset::Ext("UTF-8".to_owned()), None, filename.as_bytes().to_vec())],
+                });
+                if let Ok(mime) = content_type.parse::<Mime>() {
+                    headers.set(ContentType(mime));
+                }
+                formdata.files.push((name.to_owned(), FilePart::new(headers, &dir.join(content))));
+            },
+            _ => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData,
+                                                     format!("bad manifest line: {}", line)))),
+        }
+    }
+    let mut out = File::create(body)?;
+    // The manifest's filenames, not those of the content files
+    let config = WriteConfig { part_filenames: true, ..WriteConfig::default() };
+    formdata::write_formdata_with_config(&mut out, boundary.as_bytes(), &formdata, &config)?;
+    Ok(())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
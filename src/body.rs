@@ -0,0 +1,230 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Take, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use config::WriteConfig;
+use encode;
+use error::Error;
+use file_part::open_range;
+use form_data::FormData;
+
+// A piece of the body: bytes held in memory, or a range of a file, read when needed.
+#[derive(Clone, Debug)]
+enum Segment {
+    Bytes(Vec<u8>),
+    File { path: PathBuf, range: Range<u64> },
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match *self {
+            Segment::Bytes(ref bytes) => bytes.len() as u64,
+            Segment::File { ref range, .. } => range.end - range.start,
+        }
+    }
+}
+
+// Gathers the output of `encode::write_multipart` as segments, taking file contents by
+// reference.
+#[derive(Default)]
+struct Segments(Vec<Segment>);
+
+impl Write for Segments {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.last_mut() {
+            Some(Segment::Bytes(bytes)) => bytes.extend_from_slice(buf),
+            _ => self.0.push(Segment::Bytes(buf.to_vec())),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The `multipart/form-data` body of a `FormData`, as a reader that generates it on demand
+/// and can be rewound to retry a failed upload, with `reset()` or `Seek`.
+///
+/// The parts are encoded when the `FormBody` is created, except for file contents, which
+/// are read from disk as the body is read.  Their sizes are taken at creation, so the files
+/// must not change until the body has been sent.  A file that has shrunk fails the read
+/// with `io::ErrorKind::UnexpectedEof`.  `WriteConfig::rate_limiter` is not applied.
+#[derive(Debug)]
+pub struct FormBody {
+    segments: Vec<Segment>,
+    // Where in the body the next read starts
+    pos: u64,
+    // The segment being read and where in the body it starts, both at or before `pos`
+    index: usize,
+    start: u64,
+    // The file being read, if `index` is a file segment that has been read from
+    file: Option<Take<File>>,
+    // The `FormData` given to `FormData::into_reader()`, kept so that its temporary files
+    // outlive the body
+    owned: Option<FormData>,
+}
+
+impl FormBody {
+    /// The body of `formdata`, delimited by `boundary`, as `write_formdata()` would write
+    /// it.
+    pub fn new(formdata: &FormData, boundary: &[u8]) -> Result<FormBody, Error> {
+        FormBody::with_config(formdata, boundary, &WriteConfig::default())
+    }
+
+    /// The body of `formdata`, delimited by `boundary`, as directed by `config`.
+    pub fn with_config(formdata: &FormData, boundary: &[u8], config: &WriteConfig)
+                       -> Result<FormBody, Error>
+    {
+        let (nodes, ranges) = formdata.nodes(config, false)?;
+        encode::check_nodes(boundary, &nodes)?;
+        let mut segments = Segments::default();
+        encode::write_multipart(
+            &mut segments, boundary, &nodes, &ranges,
+            &mut |s: &mut Segments, path: &Path, range: Option<&Range<u64>>| {
+                let range = match range {
+                    Some(range) => range.clone(),
+                    None => 0..fs::metadata(path)?.len(),
+                };
+                let len = range.end - range.start;
+                s.0.push(Segment::File { path: path.to_owned(), range });
+                Ok(len)
+            })?;
+        Ok(FormBody { segments: segments.0, pos: 0, index: 0, start: 0, file: None,
+                      owned: None })
+    }
+
+    /// The size of the body in bytes, for the Content-Length of the request.
+    pub fn content_length(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.len()).sum()
+    }
+
+    /// Go back to the start of the body, to send it again.
+    pub fn reset(&mut self) {
+        self.pos = 0;
+        self.rewind_segments();
+    }
+
+    fn rewind_segments(&mut self) {
+        self.index = 0;
+        self.start = 0;
+        self.file = None;
+    }
+}
+
+impl FormData {
+    /// The body of this `FormData`, delimited by `boundary`, as a reader that generates it
+    /// on demand, for client APIs that take the body as a `Read`.  See `FormBody`.
+    pub fn into_reader(self, boundary: &[u8]) -> Result<FormBody, Error> {
+        let mut body = FormBody::new(&self, boundary)?;
+        body.owned = Some(self);
+        Ok(body)
+    }
+
+    /// The body of this `FormData`, delimited by `boundary`, as an iterator of chunks of
+    /// `chunk_size` bytes (the last may be shorter), generated as they are asked for.  For
+    /// integration layers that send a body as a stream of chunks.
+    pub fn into_chunks(self, boundary: &[u8], chunk_size: usize) -> Result<BodyChunks, Error> {
+        Ok(BodyChunks { body: self.into_reader(boundary)?, chunk_size: chunk_size.max(1),
+                        done: false })
+    }
+}
+
+/// An iterator over the body of a `FormData` in chunks.  See `FormData::into_chunks()`.
+/// It ends after the first error.
+#[derive(Debug)]
+pub struct BodyChunks {
+    body: FormBody,
+    chunk_size: usize,
+    done: bool,
+}
+
+impl BodyChunks {
+    /// The size of the whole body in bytes.
+    pub fn content_length(&self) -> u64 {
+        self.body.content_length()
+    }
+}
+
+impl Iterator for BodyChunks {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        if self.done {
+            return None;
+        }
+        let mut chunk = Vec::with_capacity(self.chunk_size);
+        match (&mut self.body).take(self.chunk_size as u64).read_to_end(&mut chunk) {
+            Ok(_) if chunk.len() < self.chunk_size => self.done = true,
+            Ok(_) => {},
+            Err(e) => {
+                self.done = true;
+                return Some(Err(From::from(e)));
+            },
+        }
+        if chunk.is_empty() { None } else { Some(Ok(chunk)) }
+    }
+}
+
+impl Read for FormBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while let Some(segment) = self.segments.get(self.index) {
+            let len = segment.len();
+            let within = self.pos - self.start;
+            if within >= len {
+                self.index += 1;
+                self.start += len;
+                self.file = None;
+                continue;
+            }
+            let n = match *segment {
+                Segment::Bytes(ref bytes) => {
+                    let n = cmp::min(buf.len() as u64, len - within) as usize;
+                    buf[..n].copy_from_slice(&bytes[within as usize..within as usize + n]);
+                    n
+                },
+                Segment::File { ref path, ref range } => {
+                    if self.file.is_none() {
+                        let rest = range.start + within..range.end;
+                        self.file = Some(open_range(path, Some(&rest))?);
+                    }
+                    let n = self.file.as_mut().unwrap().read(buf)?;
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                  "a file shrank while being sent"));
+                    }
+                    n
+                },
+            };
+            self.pos += n as u64;
+            return Ok(n);
+        }
+        Ok(0)
+    }
+}
+
+impl Seek for FormBody {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.content_length().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                self.rewind_segments();
+                Ok(pos)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       "seek to before the start of the body")),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use textnonce::TextNonce;
+use error::Error;
+use parser;
+use random::RandomSource;
+
+const ALPHANUMERIC: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+const HEX: &[u8] = b"0123456789abcdef";
+const DIGITS: &[u8] = b"0123456789";
+
+/// The form of a generated boundary.  Some servers only accept bodies whose boundary looks
+/// like one sent by a well-known client; these match them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BoundaryStyle {
+    /// As from `generate_boundary()`: 68 random characters.
+    Default,
+    /// As sent by Chrome, Safari and Edge: `----WebKitFormBoundary` and 16 letters and
+    /// digits.
+    WebKit,
+    /// As sent by Firefox: 27 dashes and 28 digits.
+    Firefox,
+    /// As sent by curl: 24 dashes and 16 hex digits.
+    Curl,
+    /// As sent by OkHttp: a random UUID.
+    OkHttp,
+    /// As sent by Python `requests`: 32 hex digits.
+    PythonRequests,
+    /// The given prefix, followed by 24 letters and digits.  The prefix may be at most 46
+    /// characters of those allowed in a boundary.
+    Prefixed(String),
+}
+
+impl BoundaryStyle {
+    /// Generate a boundary of this style.  Fails with `Error::InvalidBoundary` only for a
+    /// `Prefixed` style whose prefix is too long or has characters a boundary cannot.
+    pub fn generate(&self) -> Result<Vec<u8>, Error> {
+        self.generate_from(None)
+    }
+
+    /// Like `generate()`, drawing the random characters from `random`.  A `Default` boundary
+    /// is then 68 letters and digits.
+    pub fn generate_with(&self, random: &RandomSource) -> Result<Vec<u8>, Error> {
+        self.generate_from(Some(random))
+    }
+
+    fn generate_from(&self, random: Option<&RandomSource>) -> Result<Vec<u8>, Error> {
+        let with_random = |prefix: &str, alphabet: &[u8], n: usize| {
+            with_random(prefix, alphabet, n, random)
+        };
+        let boundary = match *self {
+            BoundaryStyle::Default if random.is_none() =>
+                return Ok(::mime_multipart::generate_boundary()),
+            BoundaryStyle::Default => with_random("", ALPHANUMERIC, 68),
+            BoundaryStyle::WebKit => with_random("----WebKitFormBoundary", ALPHANUMERIC, 16),
+            BoundaryStyle::Firefox => with_random(&"-".repeat(27), DIGITS, 28),
+            BoundaryStyle::Curl => with_random(&"-".repeat(24), HEX, 16),
+            BoundaryStyle::OkHttp => {
+                let hex = with_random("", HEX, 32);
+                let mut uuid = Vec::with_capacity(36);
+                for (i, &b) in hex.iter().enumerate() {
+                    if i == 8 || i == 12 || i == 16 || i == 20 {
+                        uuid.push(b'-');
+                    }
+                    uuid.push(b);
+                }
+                // A version 4, variant 1 UUID
+                uuid[14] = b'4';
+                uuid[19] = HEX[8 + (uuid[19] as usize % 4)];
+                uuid
+            },
+            BoundaryStyle::PythonRequests => with_random("", HEX, 32),
+            BoundaryStyle::Prefixed(ref prefix) => with_random(prefix, ALPHANUMERIC, 24),
+        };
+        if !parser::is_valid_boundary(&boundary) {
+            return Err(Error::InvalidBoundary);
+        }
+        Ok(boundary)
+    }
+}
+
+// `prefix` followed by `n` characters chosen at random (from `random`, if given) from
+// `alphabet`.
+fn with_random(prefix: &str, alphabet: &[u8], n: usize, random: Option<&RandomSource>)
+               -> Vec<u8>
+{
+    let bytes = match random {
+        Some(random) => random.bytes(n),
+        None => random_bytes(n),
+    };
+    let mut boundary = prefix.as_bytes().to_vec();
+    boundary.extend(bytes.iter().map(|&b| alphabet[b as usize % alphabet.len()]));
+    boundary
+}
+
+// `n` random bytes, taken from a nonce after its 12 bytes of time.
+pub(crate) fn random_bytes(n: usize) -> Vec<u8> {
+    let nonce = TextNonce::sized((n + 12).div_ceil(3) * 4).unwrap().into_string();
+    let raw = STANDARD.decode(nonce).unwrap();
+    raw[12..12 + n].to_vec()
+}
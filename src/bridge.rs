@@ -0,0 +1,111 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Bridging between a body that arrives elsewhere (e.g. from an async framework) and the
+//! synchronous parser running on a worker thread.
+//!
+//! ```no_run
+//! # extern crate hyper;
+//! # extern crate formdata;
+//! # use hyper::header::Headers;
+//! # use std::io::Write;
+//! # fn main() {
+//! # let headers = Headers::new();
+//! let (mut writer, mut reader) = formdata::bridge::channel_reader(16);
+//! let worker = std::thread::spawn(move || formdata::read_formdata(&mut reader, &headers));
+//! // ... for each chunk of the body as it arrives:
+//! # let chunk = b"";
+//! writer.write_all(chunk).unwrap();
+//! // ... then signal the end of the body:
+//! drop(writer);
+//! let formdata = worker.join().unwrap();
+//! # }
+//! ```
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+/// Create a connected `ChannelWriter` and `ChannelReader`.  At most `capacity` writes may be
+/// buffered before the writer blocks, waiting for the reader to catch up.
+pub fn channel_reader(capacity: usize) -> (ChannelWriter, ChannelReader) {
+    let (sender, receiver) = sync_channel(capacity);
+    (ChannelWriter { sender },
+     ChannelReader { receiver, chunk: Vec::new(), pos: 0 })
+}
+
+/// The writing half of a `channel_reader()`.  Dropping it signals the end of the stream.
+pub struct ChannelWriter {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.sender.send(buf.to_owned()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                         "The channel reader has been dropped")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The reading half of a `channel_reader()`.  Reads block until data is written, and
+/// report end of stream once the `ChannelWriter` has been dropped.
+pub struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = (&self.chunk[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::thread;
+    use super::channel_reader;
+
+    #[test]
+    fn bridged_chunks_arrive_in_order() {
+        let (mut writer, mut reader) = channel_reader(2);
+        let worker = thread::spawn(move || {
+            let mut body = String::new();
+            reader.read_to_string(&mut body).unwrap();
+            body
+        });
+        for chunk in &["--abc\r\n", "Content-Disposition: form-data; name=\"a\"\r\n", "\r\n"] {
+            writer.write_all(chunk.as_bytes()).unwrap();
+        }
+        drop(writer);
+        assert_eq!(worker.join().unwrap(),
+                   "--abc\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n");
+    }
+
+    #[test]
+    fn writer_fails_once_reader_is_gone() {
+        let (mut writer, reader) = channel_reader(1);
+        drop(reader);
+        assert!(writer.write_all(b"data").is_err());
+    }
+}
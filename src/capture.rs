@@ -0,0 +1,70 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use httparse;
+use hyper::header::Headers;
+use config::ParseConfig;
+use error::Error;
+use form_data::FormData;
+use parser::find;
+use reader::read_formdata_with_config;
+
+// Captured requests carry all the browser's headers, not just those of a part.
+const MAX_REQUEST_HEADERS: usize = 100;
+
+/// How the body of a captured request is encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyEncoding {
+    /// The body bytes exactly as sent.
+    Raw,
+    /// Base64, as browser developer tools use in HAR files for bodies that are not text.
+    /// Whitespace is ignored.
+    Base64,
+}
+
+/// Parse a captured request, such as one exported from browser developer tools or pulled
+/// out of a packet capture, as a `FormData`.  `head` holds the request headers, with or
+/// without the request line (`POST /upload HTTP/1.1`) and the blank line that ends them.
+pub fn read_captured(head: &[u8], body: &[u8], encoding: BodyEncoding, config: &ParseConfig)
+                     -> Result<FormData, Error>
+{
+    let headers = parse_captured_head(head)?;
+    let body = match encoding {
+        BodyEncoding::Raw => body.to_vec(),
+        BodyEncoding::Base64 => {
+            let compact: Vec<u8> = body.iter().cloned()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect();
+            STANDARD.decode(&compact).map_err(|e| Error::Decoding(e.to_string().into()))?
+        },
+    };
+    read_formdata_with_config(&mut &body[..], &headers, config)
+}
+
+pub(crate) fn parse_captured_head(head: &[u8]) -> Result<Headers, Error> {
+    let mut head = head.to_vec();
+    while head.last().is_some_and(|b| b.is_ascii_whitespace()) {
+        head.pop();
+    }
+    head.extend_from_slice(b"\r\n\r\n");
+
+    let mut header_memory = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+    let first_line = &head[..head.iter().position(|&b| b == b'\n').unwrap_or(0)];
+    let has_request_line = find(first_line, b" HTTP/").is_some();
+    let raw_headers: &[httparse::Header] = if !has_request_line {
+        match httparse::parse_headers(&head, &mut header_memory)? {
+            httparse::Status::Complete((_, raw_headers)) => raw_headers,
+            httparse::Status::Partial => return Err(Error::PartialHeaders),
+        }
+    } else {
+        let mut request = httparse::Request::new(&mut header_memory);
+        if request.parse(&head)?.is_partial() {
+            return Err(Error::PartialHeaders);
+        }
+        let count = request.headers.len();
+        &header_memory[..count]
+    };
+    Ok(Headers::from_raw(raw_headers)?)
+}
@@ -0,0 +1,751 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::env;
+use std::ffi::OsString;
+use std::ops::BitOr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use unicode_normalization::UnicodeNormalization;
+use audit::Auditor;
+use dedup::Deduplicator;
+use error::Error;
+use durability::DurabilityPolicy;
+use file_part::long_path;
+use default_headers::DefaultHeaders;
+use filter::{NameMapper, PartFilters};
+use policy::MimePolicy;
+use quarantine::Quarantine;
+use random::RandomSource;
+use pool::BufferPool;
+use record::BodyRecorder;
+use spill::SpillOver;
+use tenant::Tenant;
+use throttle::RateLimiter;
+
+/// What to do with a text field whose value grows beyond
+/// `ParseConfig::field_memory_limit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldOverflow {
+    /// Move the value into a temporary file, and report the field in
+    /// `FormData::large_fields` instead of `FormData::fields`.
+    Spill,
+    /// Keep only the first `field_memory_limit` bytes of the value (backing off to a UTF-8
+    /// character boundary), discarding the rest.
+    Truncate,
+}
+
+/// What to name a file part whose Content-Disposition has a filename but no `name`, or an
+/// empty one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnnamedFiles {
+    /// Fail with `Error::NoName` if the name is missing; keep an empty name as it is.
+    Error,
+    /// Name the part `file_0`, `file_1` and so on, after its position among the files.
+    Generate,
+    /// Name the part after its filename, without any directory components.  If the filename
+    /// cannot be decoded, generate a name as for `Generate`.
+    Filename,
+}
+
+/// What to do with NUL and other control characters in text field values, part names and
+/// filenames, which many C libraries and databases mishandle.  Tabs and line breaks are
+/// allowed in values.  See `ParseConfig::control_chars`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlChars {
+    /// Keep them.
+    Allow,
+    /// Remove them, noting the part in `FormData::warnings` as
+    /// `FieldWarning::ControlCharsStripped`.
+    Strip,
+    /// Fail with `Error::ControlCharacters`.
+    Reject,
+}
+
+/// What to do with a file declared as an image or as `application/octet-stream` (or with no
+/// Content-Type) whose content begins like an HTML document or script, which a browser may
+/// render if the file is later served back.  See `ParseConfig::markup_guard`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkupGuard {
+    /// Keep the file, noting it in `FormData::warnings` as `FieldWarning::MarkupInFile`.
+    Warn,
+    /// Fail with `Error::FileNotAllowed`.
+    Reject,
+}
+
+/// A way of decoding a text field's value, for `ParseConfig::field_decoders`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldDecoder {
+    /// The value is base64, optionally as a `data:` URL such as `FileReader.readAsDataURL()`
+    /// produces.  It is decoded into a temporary file as it arrives, and the field is
+    /// reported in `FormData::files`, with the data URL's media type (if any) as its
+    /// Content-Type.
+    Base64ToFile,
+}
+
+/// What `ParseConfig::raw_parts` keeps of each part as it was received.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawParts {
+    /// Only the byte range each part occupied within the body, for when the body can be
+    /// read again (e.g. it was spooled to a file).
+    Ranges,
+    /// The range, and a copy of the part's bytes.
+    Bytes,
+}
+
+/// A set of client quirks to tolerate, for use with `ParseConfig::quirks()`.  Combine
+/// presets with `|`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks(u32);
+
+impl Quirks {
+    /// Internet Explorer 11 sends the full client-side path as the filename
+    /// (`C:\Users\me\photo.png`), and does not escape quotes within it.
+    pub const IE11: Quirks = Quirks(1);
+    /// Some Android WebView versions terminate lines with a bare LF rather than CRLF, and
+    /// mix the two within a single body.
+    pub const ANDROID_WEBVIEW: Quirks = Quirks(1 << 1);
+    /// Older curl versions do not escape quotes or semicolons within quoted
+    /// Content-Disposition values.
+    pub const CURL: Quirks = Quirks(1 << 2);
+
+    /// No quirks.
+    pub fn empty() -> Quirks {
+        Quirks(0)
+    }
+
+    /// Whether every quirk in `other` is also in `self`.
+    pub fn contains(&self, other: Quirks) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Quirks {
+    type Output = Quirks;
+
+    fn bitor(self, other: Quirks) -> Quirks {
+        Quirks(self.0 | other.0)
+    }
+}
+
+/// Settings that control how `multipart/form-data` is read.
+///
+/// `ParseConfig::default()` matches the behaviour of `read_formdata()`, which sets no
+/// limits.  A server will usually want `ParseConfig::builder()` instead.  A config is
+/// `Send` and `Sync`, so one can serve every thread.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseConfig {
+    /// Directory in which uploaded files are stored.  If `None`, the system temporary
+    /// directory is used.
+    pub temp_dir: Option<PathBuf>,
+    /// Where to move a file whose temporary directory fills up while it is being received,
+    /// along with what had been written of it, rather than failing.  Without it (the
+    /// default), or once every place it offers is full too, parsing fails with
+    /// `Error::StorageFull`.  Not applied to files written with `write_queue`.
+    pub spill_over: Option<SpillOver>,
+    /// Hold uploads in a quarantine directory, and optionally scan them, until the whole
+    /// body has been parsed, so that the upload directory only ever receives the files of
+    /// requests that succeeded.  A partial `FormData` returned with
+    /// `Error::EofWithPartial` (see `keep_partial`) is left in quarantine, unscanned.  Not
+    /// applied by `read_formdata_in_place()` to files left within the body.  `None` (the
+    /// default) writes uploads straight to the upload directory.
+    pub quarantine: Option<Quarantine>,
+    /// Leave the files of a failed parse on disk, for inspection, rather than deleting
+    /// them.  By default (`false`) every file created while parsing, including one that
+    /// was only partly written, is deleted before the error is returned, whatever the
+    /// error and whether or not the file's `FilePart` is still held somewhere.  Files
+    /// returned with `Error::EofWithPartial` (see `keep_partial`) are not affected.
+    pub keep_files_on_error: bool,
+    /// Where the random names of temporary files come from.  `None` (the default) uses the
+    /// operating system's generator; see `RandomSource`.
+    pub random: Option<RandomSource>,
+    /// The tenant this request is parsed for.  Its uploads are stored in its own
+    /// subdirectory of the temporary directory (created if need be), and count against its
+    /// quota.  Defaults to `None`.
+    pub tenant: Option<Tenant>,
+    /// The largest header section a single part may have, in bytes.  Defaults to 8 KiB.
+    pub max_header_size: usize,
+    /// The most bytes of a text field's value to hold in memory.  If `None` (the default),
+    /// values are held in memory regardless of size.
+    pub field_memory_limit: Option<usize>,
+    /// What to do with a field that exceeds `field_memory_limit`.  Defaults to `Spill`.
+    pub field_overflow: FieldOverflow,
+    /// The largest body to accept, in bytes.  Checked against the declared Content-Length
+    /// before reading (see `precheck()`), and against the bytes actually read.  `None` (the
+    /// default) means no limit.
+    pub max_body_size: Option<u64>,
+    /// The largest file to accept, in bytes, failing with `Error::FileTooLarge` at the first
+    /// file that grows beyond it.  Fields spilled to disk are not counted.  `None` (the
+    /// default) means no limit.
+    pub max_file_size: Option<u64>,
+    /// The longest to spend parsing a body, by the clock, failing with
+    /// `Error::ParseTimeout` once it is exceeded.  This catches a client that trickles the
+    /// body in slowly, even though each read returns promptly.  It is checked between
+    /// reads, so a read that blocks is not interrupted; give the stream its own read
+    /// timeout for that.  `None` (the default) means no limit.
+    pub max_duration: Option<Duration>,
+    /// The most parts (fields and files, including those nested within a `multipart/mixed`
+    /// group) to accept.  `None` means no limit.
+    pub max_parts: Option<usize>,
+    /// The most files to accept.  `None` means no limit.
+    pub max_files: Option<usize>,
+    /// How many levels of nested multipart to read.  At 0, nested multiparts are skipped.
+    /// At 1 (the default), the parts of a `multipart/mixed` group are taken as parts of the
+    /// form under the group's name.  Groups nested deeper than that are gathered into
+    /// `FormData::groups`, and those deeper than `max_nesting` are skipped.  A nested
+    /// multipart that reuses an enclosing boundary fails with `Error::RepeatedBoundary`.
+    pub max_nesting: usize,
+    /// Which files to accept, by type and extension.  `None` (the default) accepts any.
+    pub mime_policy: Option<MimePolicy>,
+    /// Look for HTML or script at the start of files declared as something else.  `None`
+    /// (the default) does not look.
+    pub markup_guard: Option<MarkupGuard>,
+    /// Deduplicate uploaded files by content.  `None` (the default) keeps every file.
+    pub dedup: Option<Deduplicator>,
+    /// Filters to rename, rewrite or drop parts as they are read.  `None` (the default)
+    /// keeps every part as it is.
+    pub part_filters: Option<PartFilters>,
+    /// Where to report parts rejected while parsing.  `None` (the default) reports nothing.
+    pub audit: Option<Auditor>,
+    /// When `max_parts` or `max_files` is exceeded, stop reading at once instead of first
+    /// reading (and discarding) the remainder of the body.  Stopping at once leaves the
+    /// stream part way through the body, so the connection cannot be reused.  The remainder
+    /// is only read as far as `max_body_size` allows, and until `max_duration` runs out.
+    pub abort_on_limit: bool,
+    /// Normalize part names and filenames to Unicode Normalization Form C, so that a name
+    /// matches regardless of whether the client sent composed or decomposed characters.
+    /// Defaults to `false`.
+    pub normalize_names: bool,
+    /// Lowercase part names and filenames.  Defaults to `false`.
+    pub fold_case: bool,
+    /// Rewrite or drop part names (after `normalize_names` and `fold_case` are applied),
+    /// before any `part_filters` see them.  A dropped part still counts towards
+    /// `max_parts`.  `None` (the default) keeps names as they are.
+    pub name_mapper: Option<NameMapper>,
+    /// How to name file parts sent without a name.  Defaults to `UnnamedFiles::Error`.
+    pub unnamed_files: UnnamedFiles,
+    /// Accept top-level parts with an `attachment` or `file` disposition, as some API
+    /// clients send in place of `form-data`, as files.  Such a part without a name is named
+    /// after its filename, unless `unnamed_files` is `Generate`.  Defaults to `false`.
+    pub file_dispositions: bool,
+    /// Accept parts sent without a `Content-Disposition`, as in `multipart/mixed` bodies,
+    /// naming each after its `Content-ID`, or else `part_N` for the Nth part.  Defaults to
+    /// `false`; `read_formdata_response()` turns it on.
+    pub anonymous_parts: bool,
+    /// Trim leading and trailing whitespace from text field values.  Each field affected is
+    /// noted in `FormData::warnings`, as are those of the next two settings.  Fields spilled
+    /// to disk are left alone.  Defaults to `false`.
+    pub trim_values: bool,
+    /// Replace CRLF line endings in text field values with LF.  Defaults to `false`.
+    pub normalize_newlines: bool,
+    /// Leave out text fields whose value is empty (after trimming, if `trim_values` is
+    /// set).  Defaults to `false`.
+    pub drop_empty_fields: bool,
+    /// What to do with control characters in text field values (before trimming), part
+    /// names and filenames.  Fields spilled to disk are left alone.  Defaults to
+    /// `ControlChars::Allow`.
+    pub control_chars: ControlChars,
+    /// Text fields to decode, by name (after `normalize_names`, `fold_case` and
+    /// `name_mapper` are applied), rather than hold in memory.  Defaults to none.
+    pub field_decoders: Vec<(String, FieldDecoder)>,
+    /// Parse parts whose Content-Type is `application/json` (or ends in `+json`) into
+    /// `FormData::json_parts`.  A part that is not valid JSON fails the parse with
+    /// `Error::InvalidFieldValue`.  Defaults to `false`.
+    #[cfg(feature = "serde_json")]
+    pub parse_json: bool,
+    /// The encoding in which to decode part header values that are not valid UTF-8, given
+    /// as a WHATWG encoding label such as `"iso-8859-1"`.  Older clients send filenames as
+    /// raw Latin-1 bytes.  If `None` (the default), such headers are left undecoded and
+    /// will generally fail to parse.
+    pub header_fallback_encoding: Option<String>,
+    /// Reduce client-supplied filenames to their final path component, so that
+    /// `C:\Users\me\photo.png` becomes `photo.png`.  Defaults to `false`.
+    pub strip_filename_paths: bool,
+    /// Accept a bare LF wherever CRLF is expected, even if the sender mixes the two.
+    /// Defaults to `false`, in which case the line terminator used after the first boundary
+    /// is expected throughout.
+    pub lenient_line_endings: bool,
+    /// Accept a body that ends after its last boundary without the `--` terminator, as some
+    /// proxies send.  `ParseStats::terminated` tells whether it was there.  Defaults to
+    /// `false`, in which case such a body fails with `Error::MissingTerminator`.
+    pub lenient_termination: bool,
+    /// Tolerate unescaped quotes and semicolons within quoted Content-Disposition values.
+    /// A quoted value then ends only at a quote that is followed by the end of the header
+    /// or by another parameter.  Defaults to `false`.
+    pub lenient_disposition: bool,
+    /// Refuse a request whose Content-Type has a parameter other than `boundary` and
+    /// `charset`, has any parameter twice, or is sent in more than one header, with
+    /// `Error::InvalidContentType`, as a gateway screening requests may require.  Checked
+    /// by `read_formdata_with_config()`, `read_formdata_in_place()` and `precheck()`.
+    /// Defaults to `false`.
+    pub strict_content_type: bool,
+    /// The name of a part header, such as `"Idempotency-Key"`, by which a client marks each
+    /// part so that one sent again, after a network error, is recognised.  A part whose key
+    /// was already seen, earlier in the body or (with an `UploadSession`) in an earlier
+    /// request, is skipped and listed in `FormData::skipped` rather than stored twice.  The
+    /// keys received are listed in `FormData::idempotency_keys`.  Defaults to `None`.
+    pub idempotency_header: Option<String>,
+    /// Write files to disk on a dedicated thread, fed through a queue of this many pieces
+    /// of content, so that disk writes overlap with reading the body.  Requires the
+    /// `threaded` feature; without it, or if `None` (the default), files are written on
+    /// the calling thread.
+    pub write_queue: Option<usize>,
+    /// Preallocate disk space for each file, using the part's own Content-Length if it has
+    /// one and otherwise the remainder of the request's Content-Length, trimming any excess
+    /// once the file is complete.  This requires Linux and the `fallocate` feature.  Also
+    /// reserve memory for each text field with a Content-Length of its own, up to 64 KiB
+    /// (or `field_memory_limit`, if lower), so that its value is not grown a piece at a
+    /// time.  Defaults to `false`.
+    pub preallocate: bool,
+    /// The permission bits of the temporary files that uploads are stored in, on Unix.
+    /// Each file's own directory gets the matching execute bits.  Defaults to `0o600`, so
+    /// that other local users cannot read uploads.
+    pub file_mode: u32,
+    /// Create each temporary file unnamed (`O_TMPFILE`), and link it into its directory
+    /// only once the part is complete, so that no other process can open an upload in
+    /// flight and an aborted one leaves nothing behind.  Requires Linux and the `tmpfile`
+    /// feature, and a filesystem that supports it; otherwise, or with `write_queue`, files
+    /// are named from the start.  Defaults to `false`.
+    pub unnamed_temp_files: bool,
+    /// How durable to make each file (including a spilled field) once its part is
+    /// complete.  Defaults to `DurabilityPolicy::None`.
+    pub durability: DurabilityPolicy,
+    /// A pool to take the read buffer and the parser's working buffer from, rather than
+    /// allocating them for each request.
+    pub buffer_pool: Option<BufferPool>,
+    /// Record how long parsing takes, in `ParseStats::elapsed`, and how long each part
+    /// takes, in `ParseStats::part_timings`.  Defaults to `false`.
+    pub timing: bool,
+    /// Limit how fast the body is read.
+    pub rate_limiter: Option<RateLimiter>,
+    /// If the body ends before the closing boundary, fail with `Error::EofWithPartial`,
+    /// carrying the parts received so far, instead of `Error::Eof`.  The file that was being
+    /// received, if any, is included with what had arrived of it.  Defaults to `false`.
+    pub keep_partial: bool,
+    /// Keep each part exactly as it was received, headers included, in
+    /// `FormData::raw_parts`, e.g. for verifying a signature over it.  `None` (the default)
+    /// keeps nothing.
+    pub raw_parts: Option<RawParts>,
+    /// Copy the body, exactly as it is read, to a recorder.  Failing to write to it fails
+    /// the parse with `Error::Io`.  `None` (the default) records nothing.
+    pub record_body: Option<BodyRecorder>,
+}
+
+impl ParseConfig {
+    pub fn new() -> ParseConfig {
+        Default::default()
+    }
+
+    /// Start building a config with limits suited to a server; see `ParseConfigBuilder`.
+    pub fn builder() -> ParseConfigBuilder {
+        ParseConfigBuilder::new()
+    }
+
+    /// Apply the `normalize_names` and `fold_case` settings to `name`.  Use this on names
+    /// you look up in the resulting `FormData`, so they match what was parsed.
+    pub fn normalize_name(&self, name: &str) -> String {
+        let name: String = if self.normalize_names { name.nfc().collect() } else { name.to_owned() };
+        if self.fold_case { name.to_lowercase() } else { name }
+    }
+
+    /// The directory in which uploaded files are stored: `temp_dir` or the system temporary
+    /// directory, or the tenant's subdirectory of that.  On Windows, a path too long for
+    /// the usual APIs is given in its `\\?\` form.
+    pub fn upload_dir(&self) -> PathBuf {
+        let dir = self.temp_dir.clone().unwrap_or_else(env::temp_dir);
+        long_path(match self.tenant {
+            Some(ref tenant) => tenant.temp_dir(&dir),
+            None => dir,
+        })
+    }
+
+    // Like `normalize_name()`, but reuses `name` when there is nothing to do.
+    pub(crate) fn normalize_owned(&self, name: String) -> String {
+        if self.normalize_names || self.fold_case { self.normalize_name(&name) } else { name }
+    }
+
+    /// Enable the settings needed to tolerate the given client quirks, e.g.
+    /// `ParseConfig::new().quirks(Quirks::IE11 | Quirks::CURL)`.
+    pub fn quirks(mut self, quirks: Quirks) -> ParseConfig {
+        if quirks.contains(Quirks::IE11) {
+            self.strip_filename_paths = true;
+            self.lenient_disposition = true;
+        }
+        if quirks.contains(Quirks::ANDROID_WEBVIEW) {
+            self.lenient_line_endings = true;
+        }
+        if quirks.contains(Quirks::CURL) {
+            self.lenient_disposition = true;
+        }
+        self
+    }
+}
+
+impl Default for ParseConfig {
+    fn default() -> ParseConfig {
+        ParseConfig {
+            temp_dir: None,
+            spill_over: None,
+            quarantine: None,
+            keep_files_on_error: false,
+            random: None,
+            tenant: None,
+            max_header_size: 8 * 1024,
+            field_memory_limit: None,
+            field_overflow: FieldOverflow::Spill,
+            max_body_size: None,
+            max_file_size: None,
+            max_duration: None,
+            max_parts: None,
+            max_files: None,
+            max_nesting: 1,
+            mime_policy: None,
+            markup_guard: None,
+            dedup: None,
+            part_filters: None,
+            audit: None,
+            abort_on_limit: false,
+            normalize_names: false,
+            fold_case: false,
+            name_mapper: None,
+            unnamed_files: UnnamedFiles::Error,
+            file_dispositions: false,
+            anonymous_parts: false,
+            trim_values: false,
+            normalize_newlines: false,
+            drop_empty_fields: false,
+            control_chars: ControlChars::Allow,
+            field_decoders: Vec::new(),
+            #[cfg(feature = "serde_json")]
+            parse_json: false,
+            header_fallback_encoding: None,
+            strip_filename_paths: false,
+            lenient_line_endings: false,
+            lenient_termination: false,
+            lenient_disposition: false,
+            strict_content_type: false,
+            idempotency_header: None,
+            write_queue: None,
+            preallocate: false,
+            file_mode: 0o600,
+            unnamed_temp_files: false,
+            durability: DurabilityPolicy::None,
+            buffer_pool: None,
+            timing: false,
+            rate_limiter: None,
+            keep_partial: false,
+            raw_parts: None,
+            record_body: None,
+        }
+    }
+}
+
+/// Builds a `ParseConfig` once, at startup, to be shared by every request.  It starts from
+/// `ParseConfig::default()` with these limits:
+///
+/// | Setting              | Default                        |
+/// |----------------------|--------------------------------|
+/// | `field_memory_limit` | 2 MiB (larger fields spill)    |
+/// | `max_file_size`      | 50 MiB                         |
+/// | `max_parts`          | 100                            |
+///
+/// ```
+/// let config = formdata::ParseConfig::builder()
+///     .max_file_size(Some(10 * 1024 * 1024))
+///     .max_parts(Some(20))
+///     .build();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ParseConfigBuilder {
+    config: ParseConfig,
+}
+
+impl ParseConfigBuilder {
+    pub const DEFAULT_FIELD_MEMORY_LIMIT: usize = 2 * 1024 * 1024;
+    pub const DEFAULT_MAX_FILE_SIZE: u64 = 50 * 1024 * 1024;
+    pub const DEFAULT_MAX_PARTS: usize = 100;
+
+    pub fn new() -> ParseConfigBuilder {
+        ParseConfigBuilder {
+            config: ParseConfig {
+                field_memory_limit: Some(Self::DEFAULT_FIELD_MEMORY_LIMIT),
+                max_file_size: Some(Self::DEFAULT_MAX_FILE_SIZE),
+                max_parts: Some(Self::DEFAULT_MAX_PARTS),
+                ..ParseConfig::default()
+            },
+        }
+    }
+
+    /// See `ParseConfig::temp_dir`.
+    pub fn temp_dir<P: Into<PathBuf>>(mut self, dir: P) -> ParseConfigBuilder {
+        self.config.temp_dir = Some(dir.into());
+        self
+    }
+
+    /// See `ParseConfig::max_header_size`.
+    pub fn max_header_size(mut self, size: usize) -> ParseConfigBuilder {
+        self.config.max_header_size = size;
+        self
+    }
+
+    /// See `ParseConfig::field_memory_limit`.
+    pub fn field_memory_limit(mut self, limit: Option<usize>) -> ParseConfigBuilder {
+        self.config.field_memory_limit = limit;
+        self
+    }
+
+    /// See `ParseConfig::max_body_size`.
+    pub fn max_body_size(mut self, size: Option<u64>) -> ParseConfigBuilder {
+        self.config.max_body_size = size;
+        self
+    }
+
+    /// See `ParseConfig::max_file_size`.
+    pub fn max_file_size(mut self, size: Option<u64>) -> ParseConfigBuilder {
+        self.config.max_file_size = size;
+        self
+    }
+
+    /// See `ParseConfig::max_duration`.
+    pub fn max_duration(mut self, duration: Option<Duration>) -> ParseConfigBuilder {
+        self.config.max_duration = duration;
+        self
+    }
+
+    /// See `ParseConfig::max_parts`.
+    pub fn max_parts(mut self, parts: Option<usize>) -> ParseConfigBuilder {
+        self.config.max_parts = parts;
+        self
+    }
+
+    /// See `ParseConfig::max_files`.
+    pub fn max_files(mut self, files: Option<usize>) -> ParseConfigBuilder {
+        self.config.max_files = files;
+        self
+    }
+
+    /// See `ParseConfig::max_nesting`.
+    pub fn max_nesting(mut self, depth: usize) -> ParseConfigBuilder {
+        self.config.max_nesting = depth;
+        self
+    }
+
+    /// See `ParseConfig::quirks()`.
+    pub fn quirks(mut self, quirks: Quirks) -> ParseConfigBuilder {
+        self.config = self.config.quirks(quirks);
+        self
+    }
+
+    /// Override settings from the environment, so a deployment can tune them without a code
+    /// change.  Each variable that is set replaces the setting:
+    ///
+    /// | Variable                       | Setting              |
+    /// |--------------------------------|----------------------|
+    /// | `FORMDATA_TEMP_DIR`            | `temp_dir`           |
+    /// | `FORMDATA_MAX_HEADER_SIZE`     | `max_header_size`    |
+    /// | `FORMDATA_FIELD_MEMORY_LIMIT`  | `field_memory_limit` |
+    /// | `FORMDATA_MAX_BODY_SIZE`       | `max_body_size`      |
+    /// | `FORMDATA_MAX_FILE_SIZE`       | `max_file_size`      |
+    /// | `FORMDATA_MAX_DURATION_SECS`   | `max_duration`       |
+    /// | `FORMDATA_MAX_PARTS`           | `max_parts`          |
+    /// | `FORMDATA_MAX_FILES`           | `max_files`          |
+    /// | `FORMDATA_MAX_NESTING`         | `max_nesting`        |
+    ///
+    /// Sizes are in bytes, optionally followed by `K`, `M` or `G` (in units of 1024), and
+    /// `none` removes a limit.  A value that cannot be read fails with
+    /// `Error::InvalidConfig`, naming the variable.
+    pub fn apply_env(mut self) -> Result<ParseConfigBuilder, Error> {
+        let config = &mut self.config;
+        if let Some(dir) = env::var_os("FORMDATA_TEMP_DIR") {
+            config.temp_dir = Some(PathBuf::from(dir));
+        }
+        if let Some(size) = env_value("FORMDATA_MAX_HEADER_SIZE", parse_size)? {
+            let size = size.ok_or_else(|| invalid("FORMDATA_MAX_HEADER_SIZE"))?;
+            config.max_header_size = size as usize;
+        }
+        if let Some(size) = env_value("FORMDATA_FIELD_MEMORY_LIMIT", parse_size)? {
+            config.field_memory_limit = size.map(|size| size as usize);
+        }
+        if let Some(size) = env_value("FORMDATA_MAX_BODY_SIZE", parse_size)? {
+            config.max_body_size = size;
+        }
+        if let Some(size) = env_value("FORMDATA_MAX_FILE_SIZE", parse_size)? {
+            config.max_file_size = size;
+        }
+        if let Some(secs) = env_value("FORMDATA_MAX_DURATION_SECS", parse_count)? {
+            config.max_duration = secs.map(Duration::from_secs);
+        }
+        if let Some(parts) = env_value("FORMDATA_MAX_PARTS", parse_count)? {
+            config.max_parts = parts.map(|parts| parts as usize);
+        }
+        if let Some(files) = env_value("FORMDATA_MAX_FILES", parse_count)? {
+            config.max_files = files.map(|files| files as usize);
+        }
+        if let Some(depth) = env_value("FORMDATA_MAX_NESTING", parse_count)? {
+            let depth = depth.ok_or_else(|| invalid("FORMDATA_MAX_NESTING"))?;
+            config.max_nesting = depth as usize;
+        }
+        Ok(self)
+    }
+
+    /// Change any other setting, e.g. `.with(|c| c.trim_values = true)`.
+    pub fn with<F: FnOnce(&mut ParseConfig)>(mut self, f: F) -> ParseConfigBuilder {
+        f(&mut self.config);
+        self
+    }
+
+    /// Finish the config, ready to be shared between threads.
+    pub fn build(self) -> Arc<ParseConfig> {
+        Arc::new(self.config)
+    }
+}
+
+impl Default for ParseConfigBuilder {
+    fn default() -> ParseConfigBuilder {
+        ParseConfigBuilder::new()
+    }
+}
+
+// Read the variable `name` with `parse`, giving `None` if it is not set, and `Some(None)` if
+// it is `none`.
+fn env_value<F>(name: &str, parse: F) -> Result<Option<Option<u64>>, Error>
+    where F: Fn(&str) -> Option<u64>
+{
+    let value = match env::var_os(name).map(OsString::into_string) {
+        None => return Ok(None),
+        Some(Ok(value)) => value,
+        Some(Err(_)) => return Err(invalid(name)),
+    };
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("none") {
+        return Ok(Some(None));
+    }
+    parse(value).map(|n| Some(Some(n))).ok_or_else(|| invalid(name))
+}
+
+// A number of bytes, optionally with a binary `K`, `M` or `G` suffix.
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, shift) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 10),
+        Some('M') => (&value[..value.len() - 1], 20),
+        Some('G') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    parse_count(digits.trim())?.checked_mul(1 << shift)
+}
+
+fn parse_count(value: &str) -> Option<u64> {
+    value.parse().ok()
+}
+
+fn invalid(name: &str) -> Error {
+    Error::InvalidConfig(name.to_owned())
+}
+
+/// How the Content-Disposition of each part is laid out when written (see
+/// `WriteConfig::disposition_format`).  The default writes `name`, then `filename`, then any
+/// other parameters, with lowercase keys and every value quoted, as browsers do.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DispositionFormat {
+    /// The order of the parameters.  Defaults to `ParamOrder::NameFirst`.
+    pub order: ParamOrder,
+    /// Write parameter keys in lowercase, whatever case the part was given them in.
+    /// Defaults to `true`.
+    pub lowercase_keys: bool,
+    /// When to quote parameter values.  Defaults to `ParamQuoting::Always`.
+    pub quoting: ParamQuoting,
+}
+
+impl Default for DispositionFormat {
+    fn default() -> DispositionFormat {
+        DispositionFormat {
+            order: ParamOrder::NameFirst,
+            lowercase_keys: true,
+            quoting: ParamQuoting::Always,
+        }
+    }
+}
+
+/// The order of Content-Disposition parameters when written.  See `DispositionFormat`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamOrder {
+    /// `name`, then `filename`, then other parameters in the order the part had them.  Some
+    /// older receivers find the name only if it comes first.
+    NameFirst,
+    /// The order of the part's own Content-Disposition, as parsed or as given to
+    /// `FilePart::new()`, with any parameters it lacked after those it had.
+    Preserve,
+}
+
+/// When to quote Content-Disposition parameter values.  See `DispositionFormat`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamQuoting {
+    /// Quote every value, e.g. `size="1024"`.
+    Always,
+    /// Quote only values that are not a valid token (RFC 7230), e.g. `size=1024` but
+    /// `filename="my photo.jpg"`.
+    WhenNeeded,
+}
+
+/// Settings that control how `multipart/form-data` is written.
+///
+/// `WriteConfig::default()` matches the behaviour of `write_formdata()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteConfig {
+    /// Add `size`, `creation-date` and `modification-date` parameters (RFC 2183) to the
+    /// Content-Disposition of each file, taken from the file's metadata, so that the
+    /// receiver can preallocate space.  Parameters the part already has are left as they
+    /// are, as are dates the platform does not record.  Defaults to `false`.
+    pub disposition_metadata: bool,
+    /// Add a Content-Length header to each part, giving the size of its content, for
+    /// receivers that preallocate or enforce limits per part.  The size of each file is
+    /// taken from its metadata.  Defaults to `false`.
+    pub content_lengths: bool,
+    /// Copy file contents to the output through a memory map rather than by reading them,
+    /// which is faster on some platforms for large files.  Requires the `mmap` feature;
+    /// otherwise ignored.  Defaults to `false`.
+    pub mmap: bool,
+    /// A pool to take the buffer used to copy file contents from, rather than allocating
+    /// one for each request.
+    pub buffer_pool: Option<BufferPool>,
+    /// Limit how fast the body is written.  Not applied by `write_formdata_zero_copy()`.
+    pub rate_limiter: Option<RateLimiter>,
+    /// Filters to rename, rewrite or drop parts as they are written.  `None` (the default)
+    /// writes every part as it is.
+    pub part_filters: Option<PartFilters>,
+    /// Headers to add to every part written.  `None` (the default) adds none.  See also
+    /// `default_header()`.
+    pub default_headers: Option<DefaultHeaders>,
+    /// How the Content-Disposition of each part is laid out.
+    pub disposition_format: DispositionFormat,
+    /// Give each file the filename in its own Content-Disposition, if it has one (such as
+    /// the relative paths set by `FormData::from_dir()`), rather than the name of the file
+    /// at its path.  The filename is written as it is, so a client-supplied one keeps any
+    /// directory components the client sent.  Defaults to `false`.
+    pub part_filenames: bool,
+}
+
+impl WriteConfig {
+    pub fn new() -> WriteConfig {
+        Default::default()
+    }
+
+    /// Add header `name` with `value` to every part written, e.g.
+    /// `WriteConfig::new().default_header("X-Origin", "gateway")`.
+    pub fn default_header(mut self, name: &str, value: &str) -> WriteConfig {
+        self.default_headers = Some(self.default_headers.take().unwrap_or_default()
+                                    .header(name, value));
+        self
+    }
+
+    /// Add header `name` to each part written for which `value` returns a value, given the
+    /// part's index and name.
+    pub fn default_header_with<F>(mut self, name: &str, value: F) -> WriteConfig
+        where F: Fn(usize, &str) -> Option<String> + Send + Sync + 'static
+    {
+        self.default_headers = Some(self.default_headers.take().unwrap_or_default()
+                                    .header_with(name, value));
+        self
+    }
+}
@@ -0,0 +1,30 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Reading uploaded CSV and TSV files a record at a time, for bulk-import endpoints.
+
+use std::fs::File;
+use std::io::Take;
+use csv::{Reader, ReaderBuilder, StringRecordsIntoIter};
+use error::Error;
+use file_part::FilePart;
+
+impl FilePart {
+    /// A CSV reader over the file's content, which is read as it is needed rather than all
+    /// at once.  Fields are separated by tabs if the part's Content-Type is
+    /// `text/tab-separated-values` or its filename ends in `.tsv`, and by commas otherwise.
+    /// The first record is taken as the header row.
+    pub fn csv_reader(&self) -> Result<Reader<Take<File>>, Error> {
+        let tsv = self.mime_essence().as_deref() == Some("text/tab-separated-values") ||
+            self.extension().as_deref() == Some("tsv");
+        Ok(ReaderBuilder::new()
+           .delimiter(if tsv { b'\t' } else { b',' })
+           .flexible(true)
+           .from_reader(self.open_content()?))
+    }
+
+    /// The records of the file, after the header row, as with `csv_reader()`.
+    pub fn csv_records(&self) -> Result<StringRecordsIntoIter<Take<File>>, Error> {
+        Ok(self.csv_reader()?.into_records())
+    }
+}
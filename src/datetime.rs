@@ -0,0 +1,110 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Parsing of the values sent by the HTML date and time inputs, whose formats are fixed by
+//! the WHATWG HTML standard.  Each helper returns `Ok(None)` if there is no such field (or
+//! its value is empty, as browsers send for an input left blank), and
+//! `Err(Error::InvalidFieldValue)` if the value is malformed.
+
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use error::Error;
+use form_data::FormData;
+
+impl FormData {
+    /// The value of an `<input type=date>`, e.g. `2017-06-01`.
+    pub fn get_date(&self, name: &str) -> Result<Option<NaiveDate>, Error> {
+        self.parse_field(name, parse_date)
+    }
+
+    /// The value of an `<input type=time>`, e.g. `08:30`, `08:30:15` or `08:30:15.250`.
+    pub fn get_time(&self, name: &str) -> Result<Option<NaiveTime>, Error> {
+        self.parse_field(name, parse_time)
+    }
+
+    /// The value of an `<input type=datetime-local>`, e.g. `2017-06-01T08:30`.
+    pub fn get_datetime(&self, name: &str) -> Result<Option<NaiveDateTime>, Error> {
+        self.parse_field(name, |value| {
+            let i = value.find(['T', ' '])?;
+            Some(NaiveDateTime::new(parse_date(&value[..i])?, parse_time(&value[i + 1..])?))
+        })
+    }
+
+    /// The value of an `<input type=month>`, e.g. `2017-06`, as the first day of the month.
+    pub fn get_month(&self, name: &str) -> Result<Option<NaiveDate>, Error> {
+        self.parse_field(name, |value| {
+            let (year, month) = split_number(value, "-")?;
+            if month.len() != 2 {
+                return None;
+            }
+            NaiveDate::from_ymd_opt(year, number(month)?, 1)
+        })
+    }
+
+    /// The value of an `<input type=week>`, e.g. `2017-W01`, as the Monday of the ISO week.
+    pub fn get_week(&self, name: &str) -> Result<Option<NaiveDate>, Error> {
+        self.parse_field(name, |value| {
+            let (year, week) = split_number(value, "-W")?;
+            if week.len() != 2 {
+                return None;
+            }
+            NaiveDate::from_isoywd_opt(year, number(week)?, Weekday::Mon)
+        })
+    }
+
+    fn parse_field<T, F>(&self, name: &str, parse: F) -> Result<Option<T>, Error>
+        where F: Fn(&str) -> Option<T>
+    {
+        match self.get_field(name) {
+            None | Some("") => Ok(None),
+            Some(value) => match parse(value) {
+                Some(parsed) => Ok(Some(parsed)),
+                None => Err(Error::InvalidFieldValue(name.to_owned())),
+            },
+        }
+    }
+}
+
+// A year of at least four digits, followed by `separator` and the rest.
+fn split_number<'a>(value: &'a str, separator: &str) -> Option<(i32, &'a str)> {
+    let i = value.find(separator)?;
+    let year = &value[..i];
+    if year.len() < 4 {
+        return None;
+    }
+    Some((number(year)? as i32, &value[i + separator.len()..]))
+}
+
+// A string of ASCII digits as a number.
+fn number(s: &str) -> Option<u32> {
+    if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) { s.parse().ok() } else { None }
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    let (year, rest) = split_number(value, "-")?;
+    let (month, day) = (rest.get(..2)?, rest.get(2..)?);
+    if !day.starts_with('-') || day.len() != 3 {
+        return None;
+    }
+    NaiveDate::from_ymd_opt(year, number(month)?, number(&day[1..])?)
+}
+
+fn parse_time(value: &str) -> Option<NaiveTime> {
+    // HH:MM, optionally followed by :SS and then by a fraction of one to three digits
+    let bytes = value.as_bytes();
+    let digits = |range: ::std::ops::Range<usize>| value.get(range).and_then(number);
+    if bytes.get(2) != Some(&b':') {
+        return None;
+    }
+    let (hour, minute) = (digits(0..2)?, digits(3..5)?);
+    let (second, milli) = match value.len() {
+        5 => (0, 0),
+        8 if bytes[5] == b':' => (digits(6..8)?, 0),
+        9..=12 if bytes[5] == b':' && bytes[8] == b'.' => {
+            let fraction = &value[9..];
+            let milli = digits(9..value.len())? * 10u32.pow(3 - fraction.len() as u32);
+            (digits(6..8)?, milli)
+        },
+        _ => return None,
+    };
+    NaiveTime::from_hms_milli_opt(hour, minute, second, milli)
+}
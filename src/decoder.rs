@@ -0,0 +1,104 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Streaming decoding of field values, for `ParseConfig::field_decoders`.
+
+use base64::Engine;
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use error::Error;
+
+// Browsers' `btoa()` and `FileReader.readAsDataURL()` both pad, but hand-rolled encoders
+// often do not.
+const ENGINE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent));
+
+// The longest `data:<media type>;base64,` prefix we will wait for.
+const MAX_PREFIX: usize = 1024;
+
+// Decodes base64 a piece at a time, optionally preceded by a `data:` URL prefix such as
+// `FileReader.readAsDataURL()` produces.  Whitespace is ignored.
+pub(crate) struct Base64Stream {
+    // What has arrived of the start of the value, until we know whether it is a data URL
+    prefix: Option<Vec<u8>>,
+    // Characters left over from the last piece, fewer than four
+    pending: Vec<u8>,
+    // The media type from the data URL, if any
+    pub media_type: Option<String>,
+}
+
+impl Base64Stream {
+    pub fn new() -> Base64Stream {
+        Base64Stream { prefix: Some(Vec::new()), pending: Vec::new(), media_type: None }
+    }
+
+    // Decode as much of `data` as can be decoded yet.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let data = match self.prefix.take() {
+            Some(mut prefix) => {
+                prefix.extend_from_slice(data);
+                match self.strip_prefix(prefix)? {
+                    Some(rest) => rest,
+                    None => return Ok(Vec::new()),
+                }
+            },
+            None => data.to_vec(),
+        };
+        self.pending.extend(data.into_iter().filter(|b| !b.is_ascii_whitespace()));
+        let whole = self.pending.len() / 4 * 4;
+        let decoded = decode(&self.pending[..whole])?;
+        self.pending.drain(..whole);
+        Ok(decoded)
+    }
+
+    // Decode whatever is left at the end of the value.
+    pub fn finish(&mut self) -> Result<Vec<u8>, Error> {
+        let mut decoded = match self.prefix.take() {
+            Some(prefix) if prefix.starts_with(b"data:") =>
+                return Err(Error::Decoding("Unterminated data URL prefix".into())),
+            Some(prefix) => self.push(&prefix)?,
+            None => Vec::new(),
+        };
+        decoded.extend(decode(&self.pending)?);
+        self.pending.clear();
+        Ok(decoded)
+    }
+
+    // Given what has arrived of the start of the value, return the data following any data
+    // URL prefix, or `None` (holding on to it) if that cannot be told yet.
+    fn strip_prefix(&mut self, prefix: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        let start = prefix.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(prefix.len());
+        let head = &prefix[start..];
+        if head.len() < 5 && b"data:".starts_with(head) {
+            self.prefix = Some(prefix);
+            return Ok(None);
+        }
+        if !head.starts_with(b"data:") {
+            return Ok(Some(prefix));
+        }
+        match head.iter().position(|&b| b == b',') {
+            Some(comma) => {
+                let media_type = String::from_utf8_lossy(&head[5..comma]);
+                let media_type = match media_type.strip_suffix(";base64") {
+                    Some(media_type) => media_type,
+                    None => return Err(Error::Decoding("Data URL is not base64".into())),
+                };
+                if !media_type.is_empty() {
+                    self.media_type = Some(media_type.to_owned());
+                }
+                Ok(Some(head[comma + 1..].to_vec()))
+            },
+            None if head.len() > MAX_PREFIX =>
+                Err(Error::Decoding("Data URL prefix too long".into())),
+            None => {
+                self.prefix = Some(prefix);
+                Ok(None)
+            },
+        }
+    }
+}
+
+fn decode(data: &[u8]) -> Result<Vec<u8>, Error> {
+    ENGINE.decode(data).map_err(|e| Error::Decoding(e.to_string().into()))
+}
@@ -0,0 +1,55 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use shared::Shared;
+
+/// The SHA-256 digest of a file's content.
+pub type ContentHash = [u8; 32];
+
+type Lookup = dyn Fn(&ContentHash) -> Option<PathBuf> + Send + Sync;
+
+/// Deduplication of uploaded files by content.  Give one to `ParseConfig::dedup` and each
+/// file's content is hashed as it arrives.  A file identical to one earlier in the same
+/// submission is not kept: its `FilePart` refers to the earlier one's file instead.  With
+/// a lookup, files identical to one already stored elsewhere are treated likewise.
+///
+/// Cloning a `Deduplicator` gives another handle to the same lookup.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Deduplicator {
+    lookup: Option<Shared<Lookup>>,
+}
+
+impl Deduplicator {
+    /// Deduplicate within each submission only.
+    pub fn new() -> Deduplicator {
+        Default::default()
+    }
+
+    /// Deduplicate within each submission, and against files `lookup` knows of: given a
+    /// content hash, it returns the path of a stored file with that content, if there is
+    /// one.
+    pub fn with_lookup<F>(lookup: F) -> Deduplicator
+        where F: Fn(&ContentHash) -> Option<PathBuf> + Send + Sync + 'static
+    {
+        Deduplicator { lookup: Some(Shared(Arc::new(lookup))) }
+    }
+
+    pub(crate) fn lookup(&self, hash: &ContentHash) -> Option<PathBuf> {
+        self.lookup.as_ref().and_then(|lookup| lookup(hash))
+    }
+}
+
+/// What an uploaded file found to be a duplicate is a duplicate of.  See
+/// `FilePart::duplicate()`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Duplicate {
+    /// The file at this index of `FormData::files`.  Both refer to the same file on disk,
+    /// which belongs to the earlier one: it is deleted when that `FilePart` drops, and
+    /// moved if that `FilePart` is saved.
+    Earlier(usize),
+    /// A file the `Deduplicator`'s lookup knew of.  It is never deleted.
+    Known,
+}
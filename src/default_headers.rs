@@ -0,0 +1,53 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::sync::Arc;
+use hyper::header::Headers;
+use shared::Shared;
+
+type HeaderValue = dyn Fn(usize, &str) -> Option<String> + Send + Sync;
+
+/// Headers added to every part written (see `WriteConfig::default_headers`), such as a
+/// vendor `X-Part-Id`.  Each header's value is either fixed or computed from the part's
+/// index (its position in the body, counting from 0) and name.  Headers are added after
+/// any `WriteConfig::part_filters` have run, and a header the part already has is left as
+/// it is.
+///
+/// Cloning a `DefaultHeaders` gives a set sharing the same hooks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DefaultHeaders {
+    headers: Vec<(String, Shared<HeaderValue>)>,
+}
+
+impl DefaultHeaders {
+    pub fn new() -> DefaultHeaders {
+        Default::default()
+    }
+
+    /// Add header `name` with `value` to every part.
+    pub fn header(self, name: &str, value: &str) -> DefaultHeaders {
+        let value = value.to_owned();
+        self.header_with(name, move |_, _| Some(value.clone()))
+    }
+
+    /// Add header `name` to each part for which `value` returns a value, given the part's
+    /// index and name.
+    pub fn header_with<F>(mut self, name: &str, value: F) -> DefaultHeaders
+        where F: Fn(usize, &str) -> Option<String> + Send + Sync + 'static
+    {
+        self.headers.push((name.to_owned(), Shared(Arc::new(value))));
+        self
+    }
+
+    // Add the headers to the part at `index` named `name`.
+    pub(crate) fn apply(&self, index: usize, name: &str, headers: &mut Headers) {
+        for (header, value) in &self.headers {
+            if headers.get_raw(header).is_some() {
+                continue;
+            }
+            if let Some(value) = value(index, name) {
+                headers.set_raw(header.clone(), vec![value.into_bytes()]);
+            }
+        }
+    }
+}
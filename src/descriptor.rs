@@ -0,0 +1,79 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::io::Read;
+use mime::{Attr, Mime, TopLevel, SubLevel};
+use config::ParseConfig;
+use error::Error;
+use form_data::FormData;
+use headers::RequestHeaders;
+use parser;
+use reader;
+
+/// What the request headers say about a `multipart/form-data` body, for parsing it in two
+/// stages: first `from_headers()`, which checks the headers, and then, having made any
+/// routing or limit decisions on what it found, `parse()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultipartDescriptor {
+    /// The boundary parameter of the Content-Type, without the leading dashes.
+    pub boundary: Vec<u8>,
+    /// The charset parameter of the Content-Type, if any.
+    pub charset: Option<String>,
+    /// The declared Content-Length, if any.
+    pub content_length: Option<u64>,
+}
+
+impl MultipartDescriptor {
+    /// Describe the body of a request with these `headers`.  Fails with
+    /// `Error::NoRequestContentType`, `Error::NotMultipart`, `Error::NotFormData`,
+    /// `Error::BoundaryNotSpecified` or `Error::InvalidBoundary`, as `precheck()` does.
+    pub fn from_headers<H: RequestHeaders>(headers: &H) -> Result<MultipartDescriptor, Error> {
+        match headers.content_type() {
+            Some(Mime(TopLevel::Multipart, SubLevel::FormData, _)) => {},
+            Some(Mime(TopLevel::Multipart, _, _)) => return Err(Error::NotFormData),
+            Some(_) => return Err(Error::NotMultipart),
+            None => return Err(Error::NoRequestContentType),
+        }
+        MultipartDescriptor::from_any_multipart(headers)
+    }
+
+    // Like `from_headers()`, but accepting any `multipart/*` Content-Type.
+    pub(crate) fn from_any_multipart<H: RequestHeaders>(headers: &H)
+                                                        -> Result<MultipartDescriptor, Error>
+    {
+        let mime = match headers.content_type() {
+            Some(mime) => mime,
+            None => return Err(Error::NoRequestContentType),
+        };
+        let content_length = match headers.transfer_encoding() {
+            Some(_) => None,
+            None => headers.content_length(),
+        };
+        Ok(MultipartDescriptor {
+            boundary: parser::content_type_boundary(&mime)?,
+            charset: mime.get_param(Attr::Charset).map(|charset| charset.to_string()),
+            content_length,
+        })
+    }
+
+    /// Check the declared size against `config`, failing with `Error::BodyTooLarge` if it
+    /// exceeds `ParseConfig::max_body_size`.
+    pub fn check(&self, config: &ParseConfig) -> Result<(), Error> {
+        match (config.max_body_size, self.content_length) {
+            (Some(max), Some(len)) if len > max => Err(Error::BodyTooLarge),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parse the body from `stream` as a `FormData`.
+    pub fn parse<S: Read>(&self, stream: &mut S) -> Result<FormData, Error> {
+        self.parse_with_config(stream, &ParseConfig::default())
+    }
+
+    /// Parse the body from `stream` as a `FormData`, as directed by `config`.
+    pub fn parse_with_config<S: Read>(&self, stream: &mut S, config: &ParseConfig)
+                                      -> Result<FormData, Error>
+    {
+        reader::read(stream, self, config, None, &[])
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A tolerant Content-Disposition parser, for clients that do not escape quotes or
+//! semicolons within quoted parameter values, and the layout of the Content-Disposition
+//! written for each part.
+
+use hyper::header::{Headers, ContentDisposition, DispositionType, DispositionParam, Charset};
+use hyper::header::parsing::{parse_extended_value, ExtendedValue};
+use config::{DispositionFormat, ParamOrder, ParamQuoting};
+
+/// Parse a Content-Disposition header value.  A quoted value ends only at a quote that is
+/// followed by the end of the header or by `;` and another parameter, so
+/// `filename="my "best" photo; v2.png"` is read as intended.  Only `\"` is unescaped, since
+/// clients that send Windows paths do not escape backslashes.
+pub fn parse_lenient(value: &str) -> Option<ContentDisposition> {
+    let (disposition, mut rest) = match value.find(';') {
+        Some(i) => (value[..i].trim(), &value[i + 1..]),
+        None => (value.trim(), ""),
+    };
+    if disposition.is_empty() {
+        return None;
+    }
+    let disposition = match &*disposition.to_ascii_lowercase() {
+        "inline" => DispositionType::Inline,
+        "attachment" => DispositionType::Attachment,
+        _ => DispositionType::Ext(disposition.to_owned()),
+    };
+
+    let mut parameters = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let eq = rest.find('=')?;
+        let key = rest[..eq].trim();
+        rest = rest[eq + 1..].trim_start();
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = closing_quote(quoted)?;
+            rest = &quoted[end + 1..];
+            rest = rest.trim_start();
+            rest = rest.strip_prefix(';').unwrap_or(rest);
+            quoted[..end].replace("\\\"", "\"")
+        } else {
+            let end = rest.find(';').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_owned();
+            rest = rest.get(end + 1..).unwrap_or("");
+            value
+        };
+
+        parameters.push(if key.eq_ignore_ascii_case("filename") {
+            DispositionParam::Filename(Charset::Ext("UTF-8".to_owned()), None,
+                                       value.into_bytes())
+        } else if key.eq_ignore_ascii_case("filename*") {
+            let ext = parse_extended_value(&value).ok()?;
+            DispositionParam::Filename(ext.charset, ext.language_tag, ext.value)
+        } else {
+            DispositionParam::Ext(key.to_owned(), value)
+        });
+    }
+    Some(ContentDisposition { disposition, parameters })
+}
+
+// The position of the quote closing a quoted value: the first unescaped quote followed by
+// either the end of the header or a `;` and another `key=`.
+fn closing_quote(s: &str) -> Option<usize> {
+    s.match_indices('"').map(|(i, _)| i).find(|&i| {
+        if i > 0 && s.as_bytes()[i - 1] == b'\\' {
+            return false;
+        }
+        let after = s[i + 1..].trim_start();
+        match after.strip_prefix(';') {
+            Some(next) => next.trim().is_empty() || starts_with_parameter(next),
+            None => after.is_empty(),
+        }
+    })
+}
+
+// Whether `s` begins with a parameter name and `=`.
+fn starts_with_parameter(s: &str) -> bool {
+    let s = s.trim_start();
+    match s.find('=') {
+        Some(i) => i > 0 && s[..i].trim_end().bytes().all(|b| {
+            b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+        }),
+        None => false,
+    }
+}
+
+// Replace the Content-Disposition in `headers` with `form-data` and `parameters`, laid out as
+// `format` directs.  The header is set raw, so that its layout survives being written.
+pub(crate) fn set_form_data(headers: &mut Headers, mut parameters: Vec<DispositionParam>,
+                            format: &DispositionFormat)
+{
+    if format.order == ParamOrder::Preserve {
+        let original: Vec<String> = match headers.get::<ContentDisposition>() {
+            Some(cd) => cd.parameters.iter().map(param_key).collect(),
+            None => Vec::new(),
+        };
+        parameters.sort_by_key(|p| {
+            let key = param_key(p);
+            original.iter().position(|k| *k == key).unwrap_or(original.len())
+        });
+    }
+
+    let mut value = "form-data".to_owned();
+    for param in &parameters {
+        let (key, text) = match *param {
+            DispositionParam::Ext(ref key, ref text) => (key.clone(), text.clone()),
+            DispositionParam::Filename(ref charset, ref language_tag, ref bytes) => {
+                let utf8 = match *charset {
+                    Charset::Ext(ref c) => c.eq_ignore_ascii_case("UTF-8"),
+                    _ => false,
+                };
+                match ::std::str::from_utf8(bytes) {
+                    Ok(text) if utf8 && language_tag.is_none() =>
+                        ("filename".to_owned(), text.to_owned()),
+                    _ => {
+                        let ext = ExtendedValue { charset: charset.clone(),
+                                                  language_tag: language_tag.clone(),
+                                                  value: bytes.clone() };
+                        // An extended value is never quoted
+                        value.push_str(&format!("; filename*={}", ext));
+                        continue;
+                    }
+                }
+            }
+        };
+        let key = if format.lowercase_keys { key.to_ascii_lowercase() } else { key };
+        if format.quoting == ParamQuoting::WhenNeeded && is_token(&text) {
+            value.push_str(&format!("; {}={}", key, text));
+        } else {
+            value.push_str(&format!("; {}=\"{}\"", key, text));
+        }
+    }
+
+    while headers.remove::<ContentDisposition>() { };
+    headers.set_raw("Content-Disposition", vec![value.into_bytes()]);
+}
+
+// The lowercase key of a parameter, counting `filename*` as `filename`.
+fn param_key(param: &DispositionParam) -> String {
+    match *param {
+        DispositionParam::Filename(..) => "filename".to_owned(),
+        DispositionParam::Ext(ref key, _) =>
+            key.trim_end_matches('*').to_ascii_lowercase(),
+    }
+}
+
+// Whether `s` may be written as a parameter value without quotes.
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| {
+        b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+    })
+}
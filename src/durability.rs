@@ -0,0 +1,57 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// How durable to make an upload once it is written: when a part finishes (see
+/// `ParseConfig::durability`), and when it is saved (see `SaveOptions::durability`).  For
+/// applications that acknowledge an upload to the client only once it is safely stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DurabilityPolicy {
+    /// Leave it to the operating system to write the file out when it sees fit.
+    #[default]
+    None,
+    /// Flush anything buffered within the process to the operating system, so that the
+    /// file survives the process crashing but not the machine.
+    Flush,
+    /// Also wait for the file's content and metadata to reach the disk (`fsync`).
+    SyncFile,
+    /// Also wait for the file's directory entry to reach the disk, so that the file is
+    /// still found under its name after a crash.  The same as `SyncFile` except on Unix.
+    SyncDir,
+}
+
+impl DurabilityPolicy {
+    // Make the content of `file`, just written, as durable as asked.
+    pub(crate) fn sync_file(self, file: &mut File) -> io::Result<()> {
+        match self {
+            DurabilityPolicy::None => Ok(()),
+            DurabilityPolicy::Flush => file.flush(),
+            DurabilityPolicy::SyncFile | DurabilityPolicy::SyncDir => {
+                file.flush()?;
+                file.sync_all()
+            },
+        }
+    }
+
+    // Make the entry of a file just created in `dir` durable, if asked.
+    pub(crate) fn sync_dir(self, dir: &Path) -> io::Result<()> {
+        if self == DurabilityPolicy::SyncDir {
+            sync_dir(dir)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
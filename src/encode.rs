@@ -0,0 +1,203 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Writing of multipart bodies, with a choice of how file contents are copied to the
+//! output.
+
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use hyper::header::Headers;
+use mime_multipart::Node;
+use error::Error;
+use file_part::open_range;
+use parser;
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+#[cfg(feature = "mmap")]
+const MMAP_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Check that no in-memory part of `nodes` (or of any group within them) contains the
+/// delimiter for `boundary`, which would end the part early.  File contents are not checked.
+pub(crate) fn check_nodes(boundary: &[u8], nodes: &[Node]) -> Result<(), Error> {
+    let mut delimiter = b"\r\n--".to_vec();
+    delimiter.extend_from_slice(boundary);
+    for node in nodes {
+        match *node {
+            Node::Part(ref part) => {
+                // The line break ending the part's headers also serves as the start of a
+                // delimiter at the very beginning of the body.
+                if part.body.starts_with(&delimiter[2..]) ||
+                    parser::find(&part.body, &delimiter).is_some()
+                {
+                    return Err(Error::BoundaryInContent);
+                }
+            },
+            Node::Multipart((ref headers, ref subnodes)) => {
+                check_nodes(&parser::get_multipart_boundary(headers)?, subnodes)?;
+            },
+            Node::File(_) => {},
+        }
+    }
+    Ok(())
+}
+
+/// Stream out a multipart body made up of `nodes`, copying each file's content to the
+/// output with `copy`, which returns the number of bytes copied.  Where `ranges` has a range
+/// for a file node (they are taken in order), only that range of the file is copied.  The
+/// output is identical to that of `mime_multipart::write_multipart`.
+pub(crate) fn write_multipart<S, F>(stream: &mut S, boundary: &[u8], nodes: &[Node],
+                                    ranges: &[Option<Range<u64>>], copy: &mut F)
+                                    -> Result<usize, Error>
+    where S: Write, F: FnMut(&mut S, &Path, Option<&Range<u64>>) -> io::Result<u64>
+{
+    let mut ranges = ranges.iter();
+    let mut count: usize = 0;
+    for node in nodes {
+        count += write_all_count(stream, b"--")?;
+        count += write_all_count(stream, boundary)?;
+        count += write_all_count(stream, b"\r\n")?;
+        match *node {
+            Node::Part(ref part) => {
+                count += write_headers(stream, &part.headers)?;
+                count += write_all_count(stream, &part.body)?;
+            },
+            Node::File(ref filepart) => {
+                count += write_headers(stream, &filepart.headers)?;
+                let range = ranges.next().and_then(|range| range.as_ref());
+                count += copy(stream, &filepart.path, range)? as usize;
+            },
+            Node::Multipart((ref headers, ref subnodes)) => {
+                let boundary = parser::get_multipart_boundary(headers)?;
+                count += write_headers(stream, headers)?;
+                count += write_multipart(stream, &boundary, subnodes, &[], copy)?;
+            },
+        }
+        count += write_all_count(stream, b"\r\n")?;
+    }
+    count += write_all_count(stream, b"--")?;
+    count += write_all_count(stream, boundary)?;
+    count += write_all_count(stream, b"--")?;
+    Ok(count)
+}
+
+/// Copy a file, or `range` of it, to the output by reading it.
+pub(crate) fn read_copy<S: Write>(stream: &mut S, path: &Path, range: Option<&Range<u64>>)
+                                  -> io::Result<u64>
+{
+    io::copy(&mut open_range(path, range)?, stream)
+}
+
+/// Copy a file, or `range` of it, to the output by reading it through `buf`, which is grown
+/// to a useful size if need be.
+pub(crate) fn buffered_copy<S: Write>(stream: &mut S, path: &Path, range: Option<&Range<u64>>,
+                                      buf: &mut Vec<u8>)
+                                      -> io::Result<u64>
+{
+    if buf.len() < COPY_BUFFER_SIZE {
+        buf.resize(COPY_BUFFER_SIZE, 0);
+    }
+    let mut file = open_range(path, range)?;
+    let mut count: u64 = 0;
+    loop {
+        let n = match file.read(buf) {
+            Ok(0) => return Ok(count),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        stream.write_all(&buf[..n])?;
+        count += n as u64;
+    }
+}
+
+/// Copy a file, or `range` of it, to the output through a memory map, a piece at a time.
+#[cfg(feature = "mmap")]
+pub(crate) fn mmap_copy<S: Write>(stream: &mut S, path: &Path, range: Option<&Range<u64>>)
+                                  -> io::Result<u64>
+{
+    let file = ::std::fs::File::open(path)?;
+    let range = match range {
+        Some(range) => range.clone(),
+        None => 0..file.metadata()?.len(),
+    };
+    if range.start == range.end {
+        // An empty file cannot be mapped
+        return Ok(0);
+    }
+    let map = unsafe {
+        ::memmap2::MmapOptions::new()
+            .offset(range.start)
+            .len((range.end - range.start) as usize)
+            .map(&file)?
+    };
+    #[cfg(unix)]
+    {
+        let _ = map.advise(::memmap2::Advice::Sequential);
+    }
+    for chunk in map.chunks(MMAP_CHUNK_SIZE) {
+        stream.write_all(chunk)?;
+    }
+    Ok(range.end - range.start)
+}
+
+/// Copy a file, or `range` of it, to the output within the kernel with `sendfile(2)`,
+/// falling back to reading it if the output does not support that.  Fails with
+/// `UnexpectedEof` if the file turns out shorter than it was.
+#[cfg(all(feature = "sendfile", target_os = "linux"))]
+pub(crate) fn sendfile_copy<S>(stream: &mut S, path: &Path, range: Option<&Range<u64>>)
+                               -> io::Result<u64>
+    where S: Write + ::std::os::unix::io::AsRawFd
+{
+    use std::os::unix::io::AsRawFd;
+    // sendfile() transfers at most this much at once
+    const MAX_SENDFILE: u64 = 0x7fff_f000;
+
+    let file = ::std::fs::File::open(path)?;
+    let (mut offset, len) = match range {
+        Some(range) => (range.start, range.end - range.start),
+        None => (0, file.metadata()?.len()),
+    };
+    let mut sent: u64 = 0;
+    while sent < len {
+        let mut off = offset as ::libc::off_t;
+        let n = unsafe {
+            ::libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut off,
+                             (len - sent).min(MAX_SENDFILE) as usize)
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(::libc::EINTR) => continue,
+                Some(::libc::EINVAL) | Some(::libc::ENOSYS) if sent == 0 =>
+                    return read_copy(stream, path, range),
+                _ => return Err(err),
+            }
+        }
+        if n == 0 {
+            // The file was truncated while we were sending it
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        sent += n as u64;
+        offset += n as u64;
+    }
+    Ok(sent)
+}
+
+// Write the headers of a part, and the blank line that follows them.
+fn write_headers<S: Write>(stream: &mut S, headers: &Headers) -> io::Result<usize> {
+    let mut count = 0;
+    for header in headers.iter() {
+        count += write_all_count(stream, header.name().as_bytes())?;
+        count += write_all_count(stream, b": ")?;
+        count += write_all_count(stream, header.value_string().as_bytes())?;
+        count += write_all_count(stream, b"\r\n")?;
+    }
+    count += write_all_count(stream, b"\r\n")?;
+    Ok(count)
+}
+
+fn write_all_count<S: Write>(stream: &mut S, buf: &[u8]) -> io::Result<usize> {
+    stream.write_all(buf)?;
+    Ok(buf.len())
+}
@@ -8,8 +8,10 @@ use std::io;
 use std::string::FromUtf8Error;
 
 use super::{httparse, hyper, mime_multipart};
+use form_data::FormData;
 
-/// An error type for the `formdata` crate.
+/// An error type for the `formdata` crate.  It is `Send`, but not `Sync`, since
+/// `EofWithPartial` carries a `FormData`.
 pub enum Error {
     /// The Hyper request did not have a Content-Type header.
     NoRequestContentType,
@@ -19,6 +21,9 @@ pub enum Error {
     NotFormData,
     /// The Content-Type header failed to specify boundary token.
     BoundaryNotSpecified,
+    /// The boundary token was not 1 to 70 of the characters RFC 2046 allows, or ended with
+    /// a space.
+    InvalidBoundary,
     /// A multipart section contained only partial headers.
     PartialHeaders,
     /// A multipart section did not have the required Content-Disposition header.
@@ -27,8 +32,9 @@ pub enum Error {
     InvalidDisposition,
     /// A multipart section Content-Disposition header failed to specify a name.
     NoName,
-    /// The request body ended prior to reaching the expected terminating boundary.
-    Eof,
+    /// The request body ended prior to reaching the expected terminating boundary.  Carries
+    /// the part that was being received at the time, if any.
+    Eof(Option<InterruptedPart>),
     /// An HTTP parsing error from a multipart section.
     Httparse(httparse::Error),
     /// An I/O error.
@@ -43,6 +49,240 @@ pub enum Error {
     Multipart(mime_multipart::Error),
     /// Filepart is not a file
     NotAFile,
+    /// A file was larger than the caller was prepared to accept.
+    FileTooLarge,
+    /// A boundary was not followed by a line terminator.
+    NoCrLfAfterBoundary,
+    /// The headers of a multipart section exceeded the configured maximum size.
+    HeadersTooLarge,
+    /// The body had more parts than `ParseConfig::max_parts` allows.  Carries the number of
+    /// bytes that had been read from the stream.
+    TooManyParts(u64),
+    /// The body had more files than `ParseConfig::max_files` allows.  Carries the number of
+    /// bytes that had been read from the stream.
+    TooManyFiles(u64),
+    /// The body was larger than `ParseConfig::max_body_size` allows.
+    BodyTooLarge,
+    /// The request body ended prior to reaching the expected terminating boundary.  Carries
+    /// what had been received.  Returned instead of `Eof` if `ParseConfig::keep_partial` is
+    /// set.
+    EofWithPartial(Box<FormData>, Option<InterruptedPart>),
+    /// The value of the named field was not in the form expected.
+    InvalidFieldValue(String),
+    /// A file was rejected by the `ParseConfig::mime_policy`.  Carries the reason.
+    FileNotAllowed(String),
+    /// A part to be written contained the boundary delimiter, so the body would be
+    /// misread.  Choose another boundary, e.g. with `generate_boundary()`.
+    BoundaryInContent,
+    /// The upload would have taken the tenant over its `TenantQuota`.
+    QuotaExceeded,
+    /// The `FormData` had files, which cannot be represented in the form requested.
+    FilesNotSupported,
+    /// A nested multipart used the boundary of a multipart enclosing it.
+    RepeatedBoundary,
+    /// The body ended after a closing boundary that lacked its `--` terminator.  See
+    /// `ParseConfig::lenient_termination`.
+    MissingTerminator,
+    /// The value, name or filename of the named part contained control characters.  See
+    /// `ParseConfig::control_chars`.
+    ControlCharacters(String),
+    /// Parsing took longer than `ParseConfig::max_duration` allows.
+    ParseTimeout,
+    /// A setting could not be read.  Carries where it came from, e.g. the environment
+    /// variable (see `ParseConfigBuilder::apply_env()`).
+    InvalidConfig(String),
+    /// A part could not be written as it is.  Carries the reason.  See
+    /// `FormData::validate_for_write()`.
+    InvalidPart(String),
+    /// There was no space left to store an upload, in the temporary directory or anywhere
+    /// `ParseConfig::spill_over` offered.
+    StorageFull,
+    /// A file was rejected by the `ParseConfig::quarantine` scanner.  Carries the name of
+    /// the part and the scanner's reason.
+    ScanRejected(String),
+    /// The request's Content-Type was refused by `ParseConfig::strict_content_type`.
+    /// Carries the reason.
+    InvalidContentType(String),
+    /// A chunk of a file sent over several requests did not follow on from those before it,
+    /// or the file was not complete when the `UploadSession` finished.  Carries the reason.
+    InvalidChunk(String),
+}
+
+/// What to tell a client whose upload failed, from `Error::response()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorResponse {
+    /// The HTTP status code, as from `Error::http_status()`.
+    pub status: u16,
+    /// The error code, as from `Error::code()`.
+    pub code: &'static str,
+    /// A message safe to show the client, as from `Error::public_message()`.
+    pub message: &'static str,
+}
+
+/// The part that was being received when the request body ended early.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InterruptedPart {
+    /// The position of the part among all parts of the body, counting from zero.  This is
+    /// also its position in `ParseStats::part_bytes`.
+    pub index: usize,
+    /// The name of the part.
+    pub name: String,
+    /// The number of bytes of its content that had been received.
+    pub received: u64,
+}
+
+impl Error {
+    /// A stable, machine-readable code for the kind of error, for mapping to a localized
+    /// message.  Codes are never changed or reused once released:
+    ///
+    /// | Code                      | Variant                          | `http_status()` |
+    /// |---------------------------|----------------------------------|-----------------|
+    /// | `no_content_type`         | `NoRequestContentType`           | 415             |
+    /// | `not_multipart`           | `NotMultipart`                   | 415             |
+    /// | `not_form_data`           | `NotFormData`                    | 415             |
+    /// | `no_boundary`             | `BoundaryNotSpecified`           | 400             |
+    /// | `invalid_boundary`        | `InvalidBoundary`                | 400             |
+    /// | `partial_headers`         | `PartialHeaders`                 | 400             |
+    /// | `missing_disposition`     | `MissingDisposition`             | 400             |
+    /// | `invalid_disposition`     | `InvalidDisposition`             | 400             |
+    /// | `no_name`                 | `NoName`                         | 400             |
+    /// | `unexpected_eof`          | `Eof`, `EofWithPartial`          | 400             |
+    /// | `invalid_headers`         | `Httparse`                       | 400             |
+    /// | `io`                      | `Io`                             | 500             |
+    /// | `http`                    | `Hyper`                          | 400             |
+    /// | `invalid_utf8`            | `Utf8`                           | 400             |
+    /// | `invalid_encoding`        | `Decoding`                       | 400             |
+    /// | `multipart`               | `Multipart`                      | 500             |
+    /// | `not_a_file`              | `NotAFile`                       | 500             |
+    /// | `file_too_large`          | `FileTooLarge`                   | 413             |
+    /// | `no_crlf_after_boundary`  | `NoCrLfAfterBoundary`            | 400             |
+    /// | `headers_too_large`       | `HeadersTooLarge`                | 413             |
+    /// | `too_many_parts`          | `TooManyParts`                   | 413             |
+    /// | `too_many_files`          | `TooManyFiles`                   | 413             |
+    /// | `body_too_large`          | `BodyTooLarge`                   | 413             |
+    /// | `invalid_field_value`     | `InvalidFieldValue`              | 422             |
+    /// | `file_not_allowed`        | `FileNotAllowed`                 | 415             |
+    /// | `boundary_in_content`     | `BoundaryInContent`              | 500             |
+    /// | `quota_exceeded`          | `QuotaExceeded`                  | 413             |
+    /// | `files_not_supported`     | `FilesNotSupported`              | 400             |
+    /// | `repeated_boundary`       | `RepeatedBoundary`               | 400             |
+    /// | `missing_terminator`      | `MissingTerminator`              | 400             |
+    /// | `control_characters`      | `ControlCharacters`              | 400             |
+    /// | `parse_timeout`           | `ParseTimeout`                   | 408             |
+    /// | `invalid_config`          | `InvalidConfig`                  | 500             |
+    /// | `invalid_part`            | `InvalidPart`                    | 500             |
+    /// | `storage_full`            | `StorageFull`                    | 507             |
+    /// | `scan_rejected`           | `ScanRejected`                   | 422             |
+    /// | `invalid_content_type`    | `InvalidContentType`             | 400             |
+    /// | `invalid_chunk`           | `InvalidChunk`                   | 409             |
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::NoRequestContentType => "no_content_type",
+            Error::NotMultipart => "not_multipart",
+            Error::NotFormData => "not_form_data",
+            Error::BoundaryNotSpecified => "no_boundary",
+            Error::InvalidBoundary => "invalid_boundary",
+            Error::PartialHeaders => "partial_headers",
+            Error::MissingDisposition => "missing_disposition",
+            Error::InvalidDisposition => "invalid_disposition",
+            Error::NoName => "no_name",
+            Error::Eof(_) | Error::EofWithPartial(..) => "unexpected_eof",
+            Error::Httparse(_) => "invalid_headers",
+            Error::Io(_) => "io",
+            Error::Hyper(_) => "http",
+            Error::Utf8(_) => "invalid_utf8",
+            Error::Decoding(_) => "invalid_encoding",
+            Error::Multipart(_) => "multipart",
+            Error::NotAFile => "not_a_file",
+            Error::FileTooLarge => "file_too_large",
+            Error::NoCrLfAfterBoundary => "no_crlf_after_boundary",
+            Error::HeadersTooLarge => "headers_too_large",
+            Error::TooManyParts(_) => "too_many_parts",
+            Error::TooManyFiles(_) => "too_many_files",
+            Error::BodyTooLarge => "body_too_large",
+            Error::InvalidFieldValue(_) => "invalid_field_value",
+            Error::FileNotAllowed(_) => "file_not_allowed",
+            Error::BoundaryInContent => "boundary_in_content",
+            Error::QuotaExceeded => "quota_exceeded",
+            Error::FilesNotSupported => "files_not_supported",
+            Error::RepeatedBoundary => "repeated_boundary",
+            Error::MissingTerminator => "missing_terminator",
+            Error::ControlCharacters(_) => "control_characters",
+            Error::ParseTimeout => "parse_timeout",
+            Error::InvalidConfig(_) => "invalid_config",
+            Error::InvalidPart(_) => "invalid_part",
+            Error::StorageFull => "storage_full",
+            Error::ScanRejected(_) => "scan_rejected",
+            Error::InvalidContentType(_) => "invalid_content_type",
+            Error::InvalidChunk(_) => "invalid_chunk",
+        }
+    }
+
+    /// The HTTP status code to respond with when parsing a request fails with this error:
+    /// 413 for anything over a limit, 415 for content of the wrong type, 408 for a body that
+    /// took too long, 400 for a malformed body, 507 when there is no room to store an upload,
+    /// and 500 for other failures on the server's side (including errors that only arise
+    /// when writing a body).  See `code()` for the full table.
+    pub fn http_status(&self) -> u16 {
+        match *self {
+            Error::NoRequestContentType | Error::NotMultipart | Error::NotFormData |
+            Error::FileNotAllowed(_) => 415,
+            Error::FileTooLarge | Error::HeadersTooLarge | Error::TooManyParts(_) |
+            Error::TooManyFiles(_) | Error::BodyTooLarge | Error::QuotaExceeded => 413,
+            Error::InvalidFieldValue(_) | Error::ScanRejected(_) => 422,
+            Error::ParseTimeout => 408,
+            Error::InvalidChunk(_) => 409,
+            Error::StorageFull => 507,
+            Error::Io(_) | Error::Multipart(_) | Error::NotAFile |
+            Error::BoundaryInContent | Error::InvalidConfig(_) | Error::InvalidPart(_) => 500,
+            _ => 400,
+        }
+    }
+
+    /// A message describing the error that is safe to show the client.  Unlike `Display`,
+    /// it never includes details such as file paths, field values or the underlying error,
+    /// and errors on the server's side are all reported alike.
+    pub fn public_message(&self) -> &'static str {
+        match *self {
+            Error::NoRequestContentType | Error::NotMultipart | Error::NotFormData =>
+                "The request must be sent as multipart/form-data.",
+            Error::BoundaryNotSpecified => "The request's Content-Type has no boundary.",
+            Error::InvalidBoundary => "The request's Content-Type has an invalid boundary.",
+            Error::PartialHeaders | Error::MissingDisposition | Error::InvalidDisposition |
+            Error::NoName | Error::Httparse(_) | Error::Hyper(_) | Error::Utf8(_) |
+            Error::Decoding(_) | Error::NoCrLfAfterBoundary | Error::RepeatedBoundary =>
+                "The form data was malformed.",
+            Error::Eof(_) | Error::EofWithPartial(..) | Error::MissingTerminator =>
+                "The upload was incomplete.",
+            Error::FileTooLarge => "A file was too large.",
+            Error::HeadersTooLarge => "A part of the form had headers that were too large.",
+            Error::TooManyParts(_) => "The form had too many parts.",
+            Error::TooManyFiles(_) => "The form had too many files.",
+            Error::BodyTooLarge => "The upload was too large.",
+            Error::QuotaExceeded => "The upload would exceed your storage quota.",
+            Error::FilesNotSupported => "Files cannot be sent here.",
+            Error::InvalidFieldValue(_) => "A field's value was not valid.",
+            Error::FileNotAllowed(_) => "A file was of a type that is not allowed.",
+            Error::ControlCharacters(_) => "The form contained control characters.",
+            Error::ParseTimeout => "The upload took too long.",
+            Error::StorageFull => "There is no room to store the upload.",
+            Error::ScanRejected(_) => "A file did not pass a content scan.",
+            Error::InvalidContentType(_) => "The request's Content-Type is not acceptable.",
+            Error::InvalidChunk(_) => "A piece of the upload did not follow on from the last.",
+            Error::Io(_) | Error::Multipart(_) | Error::NotAFile | Error::BoundaryInContent |
+            Error::InvalidConfig(_) | Error::InvalidPart(_) =>
+                "The upload could not be processed.",
+        }
+    }
+
+    /// The status, code and public message to respond to the client with.
+    pub fn response(&self) -> ErrorResponse {
+        ErrorResponse {
+            status: self.http_status(),
+            code: self.code(),
+            message: self.public_message(),
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -76,21 +316,38 @@ impl From<mime_multipart::Error> for Error {
 }
 
 impl Display for Error {
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Httparse(ref e) =>
-                format!("{}: {:?}", self, e).fmt(f),
+                format!("{}: {:?}", self.description(), e).fmt(f),
             Error::Io(ref e) =>
-                format!("{}: {}", self, e).fmt(f),
+                format!("{}: {}", self.description(), e).fmt(f),
             Error::Hyper(ref e) =>
-                format!("{}: {}", self, e).fmt(f),
+                format!("{}: {}", self.description(), e).fmt(f),
             Error::Utf8(ref e) =>
-                format!("{}: {}", self, e).fmt(f),
+                format!("{}: {}", self.description(), e).fmt(f),
             Error::Decoding(ref e) =>
-                format!("{}: {}", self, e).fmt(f),
+                format!("{}: {}", self.description(), e).fmt(f),
             Error::Multipart(ref e) =>
-                format!("{}: {}", self, e).fmt(f),
-            _ => format!("{}", self).fmt(f),
+                format!("{}: {}", self.description(), e).fmt(f),
+            Error::InvalidFieldValue(ref name) =>
+                format!("{}: {}", self.description(), name).fmt(f),
+            Error::FileNotAllowed(ref reason) =>
+                format!("{}: {}", self.description(), reason).fmt(f),
+            Error::ControlCharacters(ref name) =>
+                format!("{}: {:?}", self.description(), name).fmt(f),
+            Error::InvalidConfig(ref source) =>
+                format!("{}: {}", self.description(), source).fmt(f),
+            Error::InvalidPart(ref reason) =>
+                format!("{}: {}", self.description(), reason).fmt(f),
+            Error::ScanRejected(ref reason) =>
+                format!("{}: {}", self.description(), reason).fmt(f),
+            Error::InvalidContentType(ref reason) =>
+                format!("{}: {}", self.description(), reason).fmt(f),
+            Error::InvalidChunk(ref reason) =>
+                format!("{}: {}", self.description(), reason).fmt(f),
+            _ => self.description().fmt(f),
         }
     }
 }
@@ -115,6 +372,7 @@ impl StdError for Error {
                 "The Hyper request Content-Type sub-level Mime was not form-data.",
             Error::BoundaryNotSpecified =>
                 "The Content-Type header failed to specify a boundary token.",
+            Error::InvalidBoundary => "The boundary token contained illegal characters.",
             Error::PartialHeaders => "A multipart section contained only partial headers.",
             Error::MissingDisposition =>
                 "A multipart section did not have the required Content-Disposition header.",
@@ -122,7 +380,7 @@ impl StdError for Error {
                 "A multipart section did not have a valid corresponding Content-Disposition.",
             Error::NoName =>
                 "A multipart section Content-Disposition header failed to specify a name.",
-            Error::Eof =>
+            Error::Eof(_) =>
                 "The request body ended prior to reaching the expected terminating boundary.",
             Error::Httparse(_) =>
                 "A parse error occurred while parsing the headers of a multipart section.",
@@ -132,6 +390,30 @@ impl StdError for Error {
             Error::Decoding(_) => "A decoding error occurred.",
             Error::Multipart(_) => "A MIME multipart error occurred.",
             Error::NotAFile => "FilePart is not a file.",
+            Error::FileTooLarge => "A file was larger than the permitted maximum.",
+            Error::NoCrLfAfterBoundary => "A boundary was not followed by a line terminator.",
+            Error::HeadersTooLarge =>
+                "The headers of a multipart section exceeded the maximum size.",
+            Error::TooManyParts(_) => "The body had more parts than permitted.",
+            Error::TooManyFiles(_) => "The body had more files than permitted.",
+            Error::BodyTooLarge => "The body was larger than permitted.",
+            Error::EofWithPartial(..) =>
+                "The request body ended prior to reaching the expected terminating boundary.",
+            Error::InvalidFieldValue(_) => "A field value was not in the form expected.",
+            Error::FileNotAllowed(_) => "A file was not allowed.",
+            Error::BoundaryInContent => "A part contained the boundary delimiter.",
+            Error::QuotaExceeded => "The upload would exceed the tenant's quota.",
+            Error::FilesNotSupported => "Files are not supported in this form.",
+            Error::RepeatedBoundary => "A nested multipart reused an enclosing boundary.",
+            Error::MissingTerminator => "The closing boundary lacked its terminator.",
+            Error::ControlCharacters(_) => "A part contained control characters.",
+            Error::ParseTimeout => "Parsing took longer than permitted.",
+            Error::InvalidConfig(_) => "A setting could not be read.",
+            Error::InvalidPart(_) => "A part could not be written.",
+            Error::StorageFull => "There was no space left to store an upload.",
+            Error::ScanRejected(_) => "A file was rejected by a scan.",
+            Error::InvalidContentType(_) => "The Content-Type was refused in strict mode.",
+            Error::InvalidChunk(_) => "A chunk of a file did not follow on from the last.",
         }
     }
 }
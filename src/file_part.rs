@@ -0,0 +1,754 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::borrow::Cow;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Take, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use encoding::{all, Encoding, DecoderTrap};
+use hyper::header::{Headers, ContentType, ContentDisposition, DispositionParam, Charset};
+use mime::{Attr, Mime};
+use mime_multipart;
+use sha2::{Digest, Sha256};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use textnonce::TextNonce;
+use config::ParseConfig;
+use dedup::{ContentHash, Duplicate};
+use durability::DurabilityPolicy;
+use error::Error;
+use form_data::FormData;
+use random::RandomSource;
+use reader;
+use temp_file;
+
+/// Options controlling how `FilePart::save_to_dir_with` stores a file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SaveOptions {
+    /// Also write the part's Content-Type into a `<filename>.content-type` file beside the
+    /// saved file, so it is not lost once the part's headers are gone.
+    pub content_type_sidecar: bool,
+    /// What to do if a file of the same name is already in the directory.  Defaults to
+    /// `Collision::Rename`.
+    pub on_collision: Collision,
+    /// Treat names that differ only in case as the same, as Windows and macOS filesystems
+    /// do, so that a directory to be shared with them never holds both `Photo.png` and
+    /// `photo.png`.  With `Collision::Overwrite` the existing file is replaced, keeping its
+    /// name.  Defaults to `false`.
+    pub case_insensitive: bool,
+    /// How durable to make the saved file before returning.  Defaults to
+    /// `DurabilityPolicy::None`.
+    pub durability: DurabilityPolicy,
+}
+
+/// What `FilePart::save_to_dir_with` does when the name it would save a file under is
+/// already taken.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Collision {
+    /// Add a counter to the name: `photo (1).png`, `photo (2).png`, ...
+    #[default]
+    Rename,
+    /// Replace the existing file.
+    Overwrite,
+    /// Fail with an `Error::Io` of kind `AlreadyExists`, leaving the existing file alone.
+    Fail,
+}
+
+/// A file that is to be inserted into a `multipart/form-data` body, or alternatively an
+/// uploaded file that was received as part of `multipart/form-data` parsing.
+///
+/// A temporary file is shared by a FilePart and its clones, and is deleted once the last
+/// of them drops, so a `FormData` can be cloned, or its files moved into another, freely.
+/// Use `clone_file()` for a copy with a temporary file of its own.
+///
+/// Like `FormData`, a FilePart is `Send` but not `Sync`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilePart {
+    /// The headers of the part
+    pub headers: Headers,
+    /// A temporary file containing the file content
+    pub path: PathBuf,
+    /// Optionally, the size of the file.  This is filled when multiparts are parsed, but is
+    /// not necessary when they are generated.
+    pub size: Option<usize>,
+    // The temporary file the upload was put into, if this owns one (with its clones)
+    temp: Option<Arc<TempFile>>,
+    // If the content is only part of the file at `path`, which part
+    pub(crate) range: Option<Range<u64>>,
+    // Set by `ParseConfig::dedup`
+    pub(crate) hash: Option<ContentHash>,
+    pub(crate) duplicate: Option<Duplicate>,
+    // Set when parsed: the position among the files of the same name
+    pub(crate) index: Option<usize>,
+    // For a range of another file, where `materialize()` is to copy it out to
+    pub(crate) home: Option<Box<Home>>,
+    // Set when written to a `StorageSink` instead of to `path`: the id of its upload
+    #[cfg(feature = "async")]
+    pub(crate) stored: Option<usize>,
+}
+
+// Where `FilePart::materialize()` copies a file out to: the upload directory, permission
+// bits and source of names of the `ParseConfig` it was parsed with.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Home {
+    pub(crate) dir: PathBuf,
+    pub(crate) mode: u32,
+    pub(crate) random: Option<RandomSource>,
+}
+
+impl FilePart {
+    /// A file part with `headers` whose content is the existing file at `path`, e.g. to be
+    /// written out in a body.  The file is not deleted when the part drops.
+    pub fn new(headers: Headers, path: &Path) -> FilePart
+    {
+        FilePart {
+            headers,
+            path: path.to_owned(),
+            size: None,
+            temp: None,
+            range: None,
+            hash: None,
+            duplicate: None,
+            index: None,
+            home: None,
+            #[cfg(feature = "async")]
+            stored: None,
+        }
+    }
+
+    /// Create a new temporary FilePart within `dir` (when created this way, the file will
+    /// be deleted once the FilePart object goes out of scope).  The file itself is not
+    /// created.
+    pub fn create_in(dir: &Path, headers: Headers) -> Result<FilePart, Error> {
+        FilePart::create_in_mode(dir, headers, 0o600, None)
+    }
+
+    // Like `create_in()`, for a file to be created with permission bits `mode`, named from
+    // `random` if given.
+    pub(crate) fn create_in_mode(dir: &Path, headers: Headers, mode: u32,
+                                 random: Option<&RandomSource>) -> Result<FilePart, Error>
+    {
+        // Each upload gets its own directory, so the name of the file itself can be
+        // anything.
+        let mut path = dir.join(format!("formdata{}", nonce(random)));
+        temp_file::create_dir(&path, mode)?;
+        let dir = path.clone();
+        path.push(nonce(random));
+        let temp = TempFile { path: path.clone(), dir, kept: AtomicBool::new(false) };
+        Ok(FilePart {
+            headers,
+            path,
+            size: None,
+            temp: Some(Arc::new(temp)),
+            range: None,
+            hash: None,
+            duplicate: None,
+            index: None,
+            home: None,
+            #[cfg(feature = "async")]
+            stored: None,
+        })
+    }
+
+    /// If the content of this file is not the whole file at `path`, but a byte range of it,
+    /// that range.  This is so of files parsed by `read_formdata_in_place()`, which are left
+    /// where they lie in the request body rather than copied out, until `materialize()` is
+    /// called.
+    pub fn source_range(&self) -> Option<Range<u64>> {
+        self.range.clone()
+    }
+
+    /// The SHA-256 digest of the content, if it was computed for `ParseConfig::dedup`.
+    pub fn content_hash(&self) -> Option<&ContentHash> {
+        self.hash.as_ref()
+    }
+
+    /// If `ParseConfig::dedup` found this file to be a duplicate, what of.  Its `path` is
+    /// then that of the file it duplicates.
+    pub fn duplicate(&self) -> Option<&Duplicate> {
+        self.duplicate.as_ref()
+    }
+
+    /// The position of this file among those uploaded under the same name, counting from
+    /// zero, as for each file chosen in an `<input type=file multiple>`.  Parsed files are
+    /// kept in `FormData::files` in the order they were sent, so the file with index `n`
+    /// pairs with the `n`th value of a parallel field such as `captions`.  The methods of
+    /// `FormData` that change its files keep this up to date.  `None` for a file that was
+    /// not parsed from a body.
+    pub fn index(&self) -> Option<usize> {
+        self.index
+    }
+
+    /// Whether this file and `other` have the same filename and Content-Type, and the same
+    /// size where both sizes are known, whatever their paths.  `==` compares paths too, and
+    /// so never finds two parses of the same upload equal.  The content is not compared;
+    /// see `eq_deep()`.
+    pub fn eq_metadata(&self, other: &FilePart) -> bool {
+        let sizes_match = match (self.size, other.size) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        sizes_match
+            && self.raw_filename().ok() == other.raw_filename().ok()
+            && self.content_type() == other.content_type()
+    }
+
+    /// Like `eq_metadata()`, but also compares the content, by its SHA-256 digest (which is
+    /// taken from `content_hash()` where it was computed already).
+    pub fn eq_deep(&self, other: &FilePart) -> Result<bool, Error> {
+        Ok(self.eq_metadata(other) && self.digest()? == other.digest()?)
+    }
+
+    // The SHA-256 digest of the content.
+    fn digest(&self) -> Result<ContentHash, Error> {
+        if let Some(hash) = self.hash {
+            return Ok(hash);
+        }
+        let mut hasher = Sha256::new();
+        io::copy(&mut self.open_content()?, &mut hasher)?;
+        Ok(hasher.finalize().into())
+    }
+
+    /// Copy the content of a file that is a range of another (see `source_range()`) out into
+    /// a temporary file of its own, to be deleted once the FilePart object goes out of
+    /// scope.  The file is put where the `ParseConfig` it was parsed with puts uploads (see
+    /// `ParseConfig::upload_dir()`), with its `file_mode`.  Does nothing to any other file.
+    pub fn materialize(&mut self) -> Result<(), Error> {
+        if self.range.is_none() {
+            return Ok(());
+        }
+        let (mut copy, mode) = match self.home {
+            Some(ref home) => {
+                fs::create_dir_all(&home.dir)?;
+                (FilePart::create_in_mode(&home.dir, self.headers.clone(), home.mode,
+                                          home.random.as_ref())?, home.mode)
+            },
+            None => (FilePart::create_in(&env::temp_dir(), self.headers.clone())?, 0o600),
+        };
+        io::copy(&mut self.open_content()?, &mut temp_file::create(&copy.path, mode)?)?;
+        copy.size = self.size;
+        copy.index = self.index;
+        *self = copy;
+        Ok(())
+    }
+
+    /// Make a copy of this FilePart with a temporary file of its own, alongside the
+    /// original's if it has one, to be deleted once the copy goes out of scope.  The content
+    /// is hard-linked where possible, so the two files share storage (and neither should be
+    /// written to in place), and copied otherwise, as is a file that is a range of another.
+    pub fn clone_file(&self) -> Result<FilePart, Error> {
+        let dir = match self.temp.as_ref().and_then(|temp| temp.dir.parent()) {
+            Some(dir) => dir.to_owned(),
+            None => env::temp_dir(),
+        };
+        let mut copy = FilePart::create_in(&dir, self.headers.clone())?;
+        let linked = self.range.is_none() && fs::hard_link(&self.path, &copy.path).is_ok();
+        if !linked {
+            io::copy(&mut self.open_content()?, &mut temp_file::create(&copy.path, 0o600)?)?;
+        }
+        copy.size = self.size;
+        copy.hash = self.hash;
+        copy.index = self.index;
+        Ok(copy)
+    }
+
+    // The temporary file this owns (with its clones), if any.
+    pub(crate) fn temp_file(&self) -> Option<Arc<TempFile>> {
+        self.temp.clone()
+    }
+
+    // Move this file's temporary directory into `to`: renamed if it is on the same
+    // filesystem, and copied otherwise (with permission bits `mode`).  `moved` pairs each
+    // temporary file moved already with where it went, so that one shared by clones is
+    // moved once.  A file without a temporary file of its own is left where it is.
+    pub(crate) fn move_temp(&mut self, to: &Path, mode: u32,
+                            moved: &mut Vec<(Arc<TempFile>, Arc<TempFile>)>)
+                            -> io::Result<()>
+    {
+        let temp = match self.temp {
+            Some(ref temp) => temp.clone(),
+            None => return Ok(()),
+        };
+        let new = match moved.iter().find(|(old, _)| Arc::ptr_eq(old, &temp)) {
+            Some((_, new)) => new.clone(),
+            None => {
+                let dir = to.join(temp.dir.file_name().unwrap_or_default());
+                let path = dir.join(temp.path.file_name().unwrap_or_default());
+                match fs::rename(&temp.dir, &dir) {
+                    Ok(()) => {},
+                    Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                        temp_file::create_dir(&dir, mode)?;
+                        fs::copy(&temp.path, &path)?;
+                        fs::remove_file(&temp.path)?;
+                        fs::remove_dir(&temp.dir)?;
+                    },
+                    Err(e) => return Err(e),
+                }
+                // Nothing is left at the old place to delete
+                temp.kept.store(true, Ordering::SeqCst);
+                let new = Arc::new(TempFile { path, dir, kept: AtomicBool::new(false) });
+                moved.push((temp, new.clone()));
+                new
+            },
+        };
+        self.path = new.path.clone();
+        self.temp = Some(new);
+        Ok(())
+    }
+
+    // Check that the content can be read and is the size it claims to be, and that the
+    // headers can be written, for `FormData::validate_for_write()`.
+    pub(crate) fn check_writable(&self) -> Result<(), Error> {
+        for header in self.headers.iter() {
+            let raw = self.headers.get_raw(header.name()).unwrap_or(&[]);
+            if raw.iter().any(|line| ::std::str::from_utf8(line).is_err()) {
+                return Err(Error::InvalidPart(
+                    format!("header {} is not valid UTF-8", header.name())));
+            }
+        }
+        let metadata = fs::metadata(&self.path)?;
+        if !metadata.is_file() {
+            return Err(Error::NotAFile);
+        }
+        File::open(&self.path)?;
+        let len = match self.range {
+            Some(ref range) if range.end > metadata.len() => return Err(Error::InvalidPart(
+                format!("range ends at {} but the file has {} bytes", range.end,
+                        metadata.len()))),
+            Some(ref range) => range.end - range.start,
+            None => metadata.len(),
+        };
+        match self.size {
+            Some(size) if size as u64 != len => Err(Error::InvalidPart(
+                format!("size is {} but the content has {} bytes", size, len))),
+            _ => Ok(()),
+        }
+    }
+
+    // The size of the content in bytes: that of its range, or else of the file on disk.
+    pub(crate) fn content_length(&self) -> io::Result<u64> {
+        match self.range {
+            Some(ref range) => Ok(range.end - range.start),
+            None => Ok(fs::metadata(&self.path)?.len()),
+        }
+    }
+
+    // The content of the file, for reading.
+    pub(crate) fn open_content(&self) -> io::Result<Take<File>> {
+        open_range(&self.path, self.range.as_ref())
+    }
+
+    // A `mime_multipart` view of this file, for writing.  It never deletes anything.
+    pub(crate) fn to_multipart(&self) -> mime_multipart::FilePart {
+        let mut part = mime_multipart::FilePart::new(self.headers.clone(), &self.path);
+        part.size = self.size;
+        part
+    }
+
+    /// If you do not want the file on disk to be deleted when Self (and every clone of it)
+    /// drops, call this function.  It will become your responsability to clean up.
+    pub fn do_not_delete_on_drop(&mut self) {
+        if let Some(temp) = self.temp.take() {
+            temp.kept.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // Make `other`, a reference to this file, share ownership of its temporary file.
+    pub(crate) fn share_temp(&self, other: &mut FilePart) {
+        other.temp = self.temp.clone();
+    }
+
+    // Whether a clone shares this file's temporary file.
+    fn is_shared(&self) -> bool {
+        self.temp.as_ref().is_some_and(|temp| Arc::strong_count(temp) > 1)
+    }
+
+    /// Filename that was specified when the file was uploaded, without any client-side
+    /// directory components (some browsers, such as old versions of Internet Explorer, send
+    /// the full path, e.g. `C:\Users\bob\photo.jpg`).  Returns `Ok<None>` if there was no
+    /// content-disposition header supplied.
+    pub fn filename(&self) -> Result<Option<String>, Error> {
+        Ok(self.raw_filename()?.map(|f| basename(&f).to_owned()))
+    }
+
+    /// Filename exactly as it was specified when the file was uploaded, including any
+    /// directory components.  Returns `Ok<None>` if there was no content-disposition header
+    /// supplied.
+    pub fn raw_filename(&self) -> Result<Option<String>, Error> {
+        let cd: Option<&ContentDisposition> = self.headers.get();
+        match cd {
+            Some(cd) => get_content_disposition_filename(cd),
+            None => Ok(None),
+        }
+    }
+
+    /// The uploaded filename reduced to something safe to create on the local filesystem:
+    /// any client-side directory components (with either separator), control characters
+    /// and characters reserved on common filesystems are removed, and a name Windows
+    /// reserves for a device (`CON`, `NUL`, `COM1`, ...) is prefixed with an underscore.
+    /// Returns `Ok<None>` if no filename was supplied.
+    pub fn sanitized_filename(&self) -> Result<Option<String>, Error> {
+        Ok(self.filename()?.map(|f| sanitize_filename(&f)))
+    }
+
+    /// The extension of the sanitized filename, lowercased and without the dot, e.g.
+    /// `"jpg"`.  Returns `None` if there is no filename, or it has no extension.
+    pub fn extension(&self) -> Option<String> {
+        filename_extension(&self.headers)
+    }
+
+    /// Mime content-type specified in the header
+    pub fn content_type(&self) -> Option<Mime> {
+        let ct: Option<&ContentType> = self.headers.get();
+        ct.map(|ct| ct.0.clone())
+    }
+
+    /// The charset parameter of the Content-Type, e.g. `"utf-8"`.
+    pub fn charset(&self) -> Option<&str> {
+        let ct: Option<&ContentType> = self.headers.get();
+        ct.and_then(|ct| ct.0.get_param(Attr::Charset)).map(|charset| charset.as_str())
+    }
+
+    /// The Content-Type without its parameters, e.g. `"text/plain"`.
+    pub fn mime_essence(&self) -> Option<String> {
+        let ct: Option<&ContentType> = self.headers.get();
+        ct.map(|&ContentType(Mime(ref top, ref sub, _))| format!("{}/{}", top, sub))
+    }
+
+    /// Open the file content for buffered reading.
+    pub fn open(&self) -> Result<impl BufRead, Error> {
+        Ok(BufReader::new(self.open_content()?))
+    }
+
+    /// Read the entire file content into memory.  Fails with `Error::FileTooLarge` rather
+    /// than reading more than `max` bytes.
+    pub fn read_to_vec(&self, max: usize) -> Result<Vec<u8>, Error> {
+        let mut buf: Vec<u8> = Vec::new();
+        self.open()?.take(max as u64 + 1).read_to_end(&mut buf)?;
+        if buf.len() > max {
+            return Err(Error::FileTooLarge);
+        }
+        Ok(buf)
+    }
+
+    /// Read the entire file content into memory as UTF-8 text.  Fails with
+    /// `Error::FileTooLarge` rather than reading more than `max` bytes.
+    pub fn read_to_string(&self, max: usize) -> Result<String, Error> {
+        Ok(String::from_utf8(self.read_to_vec(max)?)?)
+    }
+
+    /// Parse the file content as a multipart body in its own right, using the boundary from
+    /// the part's Content-Type.  Some gateways wrap an entire `multipart/form-data` request
+    /// up as a single file of another.
+    pub fn parse_as_formdata(&self) -> Result<FormData, Error> {
+        self.parse_as_formdata_with_config(&ParseConfig::default())
+    }
+
+    /// Like `parse_as_formdata`, but as directed by `config`.
+    pub fn parse_as_formdata_with_config(&self, config: &ParseConfig) -> Result<FormData, Error> {
+        reader::read_formdata_with_config(&mut self.open_content()?, &self.headers, config)
+    }
+
+    /// Move the file into `dir`, named after the sanitized client filename.  If that name
+    /// is already taken, a counter is added (`photo (1).png`, `photo (2).png`, ...); see
+    /// `SaveOptions::on_collision` for alternatives.
+    ///
+    /// Afterwards `path` refers to the new location and the file will no longer be deleted
+    /// when this `FilePart` drops.  Returns the new path.  A file that is a range of another
+    /// (see `source_range()`) is copied out, leaving the other file alone.
+    pub fn save_to_dir(&mut self, dir: &Path) -> Result<PathBuf, Error> {
+        self.save_to_dir_with(dir, &SaveOptions::default())
+    }
+
+    /// Like `save_to_dir`, but with `SaveOptions`.
+    pub fn save_to_dir_with(&mut self, dir: &Path, options: &SaveOptions)
+                            -> Result<PathBuf, Error>
+    {
+        let filename = self.sanitized_filename()?.unwrap_or_else(|| "file".to_owned());
+        let dir = long_path(dir.to_owned());
+        let existing = if options.case_insensitive {
+            find_ignoring_case(&dir, &filename)?
+        } else {
+            None
+        };
+        let dest = match (options.on_collision, existing) {
+            (Collision::Rename, _) => create_unique(&dir, &filename, options.case_insensitive)?,
+            (Collision::Overwrite, Some(existing)) => dir.join(existing),
+            (Collision::Overwrite, None) => dir.join(&filename),
+            (Collision::Fail, Some(_)) =>
+                return Err(From::from(io::Error::from(io::ErrorKind::AlreadyExists))),
+            (Collision::Fail, None) => {
+                let dest = dir.join(&filename);
+                OpenOptions::new().write(true).create_new(true).open(&dest)?;
+                dest
+            },
+        };
+
+        if self.range.is_some() {
+            // Only part of the file at `path` is ours to take.
+            let copied = File::create(&dest)
+                .and_then(|mut f| io::copy(&mut self.open_content()?, &mut f));
+            if let Err(e) = copied {
+                let _ = fs::remove_file(&dest);
+                return Err(From::from(e));
+            }
+            self.range = None;
+        } else if self.is_shared() {
+            // A clone still needs the file, so it is copied.
+            if let Err(e) = fs::copy(&self.path, &dest) {
+                let _ = fs::remove_file(&dest);
+                return Err(From::from(e));
+            }
+        } else {
+            match fs::rename(&self.path, &dest) {
+                Ok(()) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                    if let Err(e) = fs::copy(&self.path, &dest) {
+                        let _ = fs::remove_file(&dest);
+                        return Err(From::from(e));
+                    }
+                    // A temporary file is deleted below; any other file is removed here, so
+                    // that it is moved all the same.
+                    if self.temp.is_none() {
+                        fs::remove_file(&self.path)?;
+                    }
+                },
+                Err(e) => {
+                    if options.on_collision != Collision::Overwrite {
+                        let _ = fs::remove_file(&dest);
+                    }
+                    return Err(From::from(e));
+                },
+            }
+        }
+        // Whatever is left of the temporary file goes once no clone refers to it.
+        self.temp = None;
+        self.path = dest.clone();
+        if options.durability != DurabilityPolicy::None {
+            options.durability.sync_file(&mut OpenOptions::new().write(true).open(&dest)?)?;
+            options.durability.sync_dir(&dir)?;
+        }
+
+        if options.content_type_sidecar {
+            if let Some(ct) = self.content_type() {
+                let mut sidecar = dest.clone().into_os_string();
+                sidecar.push(".content-type");
+                let mut f = File::create(sidecar)?;
+                writeln!(f, "{}", ct)?;
+            }
+        }
+
+        Ok(dest)
+    }
+}
+
+// An uploaded file and the directory made for it, deleted when the last FilePart sharing it
+// drops unless `kept`.
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    path: PathBuf,
+    dir: PathBuf,
+    kept: AtomicBool,
+}
+
+impl TempFile {
+    // Delete the file and its directory now, rather than when the last FilePart drops.
+    pub(crate) fn discard(&self) {
+        if !self.kept.swap(true, Ordering::SeqCst) {
+            let _ = fs::remove_file(&self.path);
+            let _ = fs::remove_dir(&self.dir);
+        }
+    }
+
+    // Leave the file in place when the last FilePart drops.
+    pub(crate) fn keep(&self) {
+        self.kept.store(true, Ordering::SeqCst);
+    }
+}
+
+impl PartialEq for TempFile {
+    fn eq(&self, other: &TempFile) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.kept.load(Ordering::SeqCst) {
+            let _ = fs::remove_file(&self.path);
+            let _ = fs::remove_dir(&self.dir);
+        }
+    }
+}
+
+// Open the file at `path` for reading `range` of it, or all of it.
+pub(crate) fn open_range(path: &Path, range: Option<&Range<u64>>) -> io::Result<Take<File>> {
+    let mut file = File::open(path)?;
+    match range {
+        Some(range) => {
+            file.seek(SeekFrom::Start(range.start))?;
+            Ok(file.take(range.end - range.start))
+        },
+        None => Ok(file.take(u64::MAX)),
+    }
+}
+
+fn nonce(random: Option<&RandomSource>) -> String {
+    match random {
+        Some(random) => URL_SAFE_NO_PAD.encode(random.bytes(24)),
+        None => TextNonce::sized_urlsafe(32).unwrap().into_string(),
+    }
+}
+
+// Reserve a name for `filename` within `dir` that is not yet taken (in any case, if
+// `case_insensitive`), by creating an empty file there.
+fn create_unique(dir: &Path, filename: &str, case_insensitive: bool) -> Result<PathBuf, Error> {
+    let (stem, ext) = match filename.rfind('.') {
+        Some(i) if i > 0 => (&filename[..i], &filename[i..]),
+        _ => (filename, ""),
+    };
+    let mut n: usize = 0;
+    loop {
+        let candidate = if n == 0 {
+            filename.to_owned()
+        } else {
+            format!("{} ({}){}", stem, n, ext)
+        };
+        if case_insensitive && find_ignoring_case(dir, &candidate)?.is_some() {
+            n += 1;
+            continue;
+        }
+        let candidate = dir.join(candidate);
+        match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(_) => return Ok(candidate),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(From::from(e)),
+        }
+    }
+}
+
+// The name of the entry in `dir` that matches `filename` ignoring case, if any.
+fn find_ignoring_case(dir: &Path, filename: &str) -> io::Result<Option<String>> {
+    let filename = filename.to_lowercase();
+    for entry in fs::read_dir(dir)? {
+        if let Some(name) = entry?.file_name().to_str() {
+            if name.to_lowercase() == filename {
+                return Ok(Some(name.to_owned()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// `path` in the `\\?\` form Windows needs for paths longer than `MAX_PATH`, if it is long
+// enough to need it.  Elsewhere, `path` unchanged.
+#[cfg(windows)]
+pub(crate) fn long_path(path: PathBuf) -> PathBuf {
+    // Directories are limited to MAX_PATH less room for an 8.3 filename
+    const MAX_DIR_PATH: usize = 248;
+    match path.to_str() {
+        Some(s) if path.is_absolute() && s.len() >= MAX_DIR_PATH && !s.starts_with(r"\\?\") => {
+            let s = s.replace('/', "\\");
+            match s.strip_prefix(r"\\") {
+                Some(unc) => PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+                None => PathBuf::from(format!(r"\\?\{}", s)),
+            }
+        },
+        _ => path,
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+// The final component of a client-supplied path, which may use either separator.
+fn basename(filename: &str) -> &str {
+    filename.rsplit(&['/', '\\'][..]).next().unwrap_or("")
+}
+
+// The filename in `headers` without any directory components, as for
+// `FilePart::filename()`.  `None` if there is none, or it cannot be decoded.
+pub(crate) fn headers_filename(headers: &Headers) -> Option<String> {
+    let cd: &ContentDisposition = headers.get()?;
+    Some(basename(&get_content_disposition_filename(cd).ok()??).to_owned())
+}
+
+// The extension of the sanitized filename in `headers`, as for `FilePart::extension()`.
+pub(crate) fn filename_extension(headers: &Headers) -> Option<String> {
+    let cd: &ContentDisposition = headers.get()?;
+    let filename = sanitize_filename(basename(&get_content_disposition_filename(cd).ok()??));
+    match filename.rfind('.') {
+        Some(i) if i > 0 && i + 1 < filename.len() => Some(filename[i + 1..].to_lowercase()),
+        _ => None,
+    }
+}
+
+// Strip a client-supplied filename down to a single safe path component.
+fn sanitize_filename(filename: &str) -> String {
+    let cleaned: String = basename(filename).chars()
+        .filter(|c| !c.is_control() && !"<>:\"|?*".contains(*c))
+        .take(200)
+        .collect();
+    let cleaned = cleaned.trim().trim_matches('.');
+    if cleaned.is_empty() {
+        "file".to_owned()
+    } else if is_device_name(cleaned) {
+        format!("_{}", cleaned)
+    } else {
+        cleaned.to_owned()
+    }
+}
+
+// Whether Windows takes `filename` to be a device, with or without an extension.
+fn is_device_name(filename: &str) -> bool {
+    let stem = filename.split('.').next().unwrap_or("").trim_end().to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" | "CONIN$" | "CONOUT$" => true,
+        _ => (stem.starts_with("COM") || stem.starts_with("LPT")) && stem.len() == 4 &&
+            matches!(stem.as_bytes()[3], b'1'..=b'9'),
+    }
+}
+
+#[inline]
+fn get_content_disposition_filename(cd: &ContentDisposition) -> Result<Option<String>, Error> {
+    if let Some(DispositionParam::Filename(charset, _, bytes)) =
+        cd.parameters.iter().find(|x| matches!(**x, DispositionParam::Filename(..)))
+    {
+        match charset_decode(charset, bytes) {
+            Ok(filename) => Ok(Some(filename)),
+            Err(e) => Err(Error::Decoding(e)),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+// This decodes bytes encoded according to a hyper::header::Charset encoding, using the
+// rust-encoding crate.  Only supports encodings defined in both crates.
+fn charset_decode(charset: &Charset, bytes: &[u8]) -> Result<String, Cow<'static, str>> {
+    Ok(match *charset {
+        Charset::Us_Ascii => all::ASCII.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_8859_1 => all::ISO_8859_1.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_8859_2 => all::ISO_8859_2.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_8859_3 => all::ISO_8859_3.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_8859_4 => all::ISO_8859_4.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_8859_5 => all::ISO_8859_5.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_8859_6 => all::ISO_8859_6.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_8859_7 => all::ISO_8859_7.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_8859_8 => all::ISO_8859_8.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_8859_10 => all::ISO_8859_10.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Euc_Jp => all::EUC_JP.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Iso_2022_Jp => all::ISO_2022_JP.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Big5 => all::BIG5_2003.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Koi8_R => all::KOI8_R.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Ext(ref s) if s.eq_ignore_ascii_case("UTF-8") =>
+            all::UTF_8.decode(bytes, DecoderTrap::Strict)?,
+        ref other => return Err(format!("{} is not supported", other).into()),
+    })
+}
@@ -0,0 +1,155 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::sync::Arc;
+use hyper::header::{Headers, ContentType};
+use mime::Mime;
+use shared::Shared;
+
+type Predicate = dyn Fn(&str, &Headers) -> bool + Send + Sync;
+type Transform = dyn Fn(&mut String, &mut Headers) -> FilterAction + Send + Sync;
+
+/// What a `PartFilters` transform decides about a part.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterAction {
+    /// Keep the part, and pass it on to the next filter.
+    Keep,
+    /// Leave the part out altogether.  Later filters do not see it.
+    Drop,
+}
+
+/// A chain of filters applied to each part as it is parsed (see `ParseConfig::part_filters`)
+/// or written (see `WriteConfig::part_filters`), for enforcing policy such as "strip parts
+/// named `debug_*`".  Each filter is a predicate on the part's name and headers, and a
+/// transform applied to the parts it matches, which may change the name and headers or
+/// drop the part.  Filters run in the order they were added, each seeing the changes made
+/// by those before it.
+///
+/// When parsing, the filters run once a part's headers have been read, before any
+/// `ParseConfig::mime_policy` check; a dropped part still counts towards
+/// `ParseConfig::max_parts`.  When writing, changes to the Content-Disposition are
+/// overwritten; change the name instead.
+///
+/// Cloning a `PartFilters` gives a chain sharing the same filters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PartFilters {
+    filters: Vec<(Shared<Predicate>, Shared<Transform>)>,
+}
+
+impl PartFilters {
+    pub fn new() -> PartFilters {
+        Default::default()
+    }
+
+    /// Add a filter that applies `transform` to the parts for which `predicate` holds.
+    pub fn filter<P, T>(mut self, predicate: P, transform: T) -> PartFilters
+        where P: Fn(&str, &Headers) -> bool + Send + Sync + 'static,
+              T: Fn(&mut String, &mut Headers) -> FilterAction + Send + Sync + 'static
+    {
+        self.filters.push((Shared(Arc::new(predicate)), Shared(Arc::new(transform))));
+        self
+    }
+
+    /// Add a filter that drops the parts for which `predicate` holds.
+    pub fn drop_parts<P>(self, predicate: P) -> PartFilters
+        where P: Fn(&str, &Headers) -> bool + Send + Sync + 'static
+    {
+        self.filter(predicate, |_, _| FilterAction::Drop)
+    }
+
+    /// Add a filter that renames each part for which `rename` returns a new name.
+    pub fn rename<F>(self, rename: F) -> PartFilters
+        where F: Fn(&str) -> Option<String> + Send + Sync + 'static
+    {
+        self.filter(|_, _| true, move |name, _| {
+            if let Some(new_name) = rename(name) {
+                *name = new_name;
+            }
+            FilterAction::Keep
+        })
+    }
+
+    /// Add a filter that sets the Content-Type of the parts for which `predicate` holds.
+    pub fn set_content_type<P>(self, predicate: P, content_type: Mime) -> PartFilters
+        where P: Fn(&str, &Headers) -> bool + Send + Sync + 'static
+    {
+        self.filter(predicate, move |_, headers| {
+            headers.set(ContentType(content_type.clone()));
+            FilterAction::Keep
+        })
+    }
+
+    /// Add a filter that sets header `name` to `value` on the parts for which `predicate`
+    /// holds, e.g. to annotate them for later processing.
+    pub fn set_header<P>(self, predicate: P, name: &str, value: &str) -> PartFilters
+        where P: Fn(&str, &Headers) -> bool + Send + Sync + 'static
+    {
+        let (name, value) = (name.to_owned(), value.as_bytes().to_vec());
+        self.filter(predicate, move |_, headers| {
+            headers.set_raw(name.clone(), vec![value.clone()]);
+            FilterAction::Keep
+        })
+    }
+
+    // Run the chain over a part.
+    pub(crate) fn apply(&self, name: &mut String, headers: &mut Headers) -> FilterAction {
+        for (predicate, transform) in &self.filters {
+            if predicate(name, headers) && transform(name, headers) == FilterAction::Drop {
+                return FilterAction::Drop;
+            }
+        }
+        FilterAction::Keep
+    }
+}
+
+type Mapping = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// A hook that rewrites or drops part names as they are parsed (see
+/// `ParseConfig::name_mapper`), so that they match the names used downstream, e.g.
+/// `firstName` read as `first_name`.  The hook is given each name and returns the name to
+/// use, or `None` to drop the part.
+///
+/// Cloning a `NameMapper` gives one sharing the same hook.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NameMapper {
+    mapping: Shared<Mapping>,
+}
+
+impl NameMapper {
+    pub fn new<F>(mapping: F) -> NameMapper
+        where F: Fn(&str) -> Option<String> + Send + Sync + 'static
+    {
+        NameMapper { mapping: Shared(Arc::new(mapping)) }
+    }
+
+    /// A mapper that converts names to snake case: `firstName`, `FirstName` and
+    /// `first-name` all become `first_name`.  Brackets and dots are left alone, so
+    /// `user[homeAddress]` becomes `user[home_address]`.
+    pub fn snake_case() -> NameMapper {
+        NameMapper::new(|name| Some(snake_case(name)))
+    }
+
+    // The name to use for the part named `name`, or `None` to drop it.
+    pub(crate) fn map(&self, name: &str) -> Option<String> {
+        (self.mapping)(name)
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    let mut previous: Option<char> = None;
+    for c in name.chars() {
+        if c == '-' || c == ' ' {
+            snake.push('_');
+        } else if c.is_uppercase() {
+            if previous.is_some_and(|p| p.is_lowercase() || p.is_ascii_digit()) {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+        previous = Some(c);
+    }
+    snake
+}
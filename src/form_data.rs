@@ -1,65 +1,655 @@
 // Copyright © 2015 by Michael Dilger (of New Zealand)
 // This code is licensed under the MIT license (see LICENSE-MIT for details)
 
-use mime_multipart::{Node, Part, FilePart};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use time;
+use mime_multipart::{self, Node, Part};
 use hyper::header::{Headers, ContentDisposition, DispositionParam, DispositionType,
-                    ContentType};
+                    ContentLength, ContentType, Charset, HttpDate};
 use mime::{Mime, TopLevel, SubLevel};
+use config::WriteConfig;
+use disposition;
 use error::Error;
+use file_part::FilePart;
+use filter::FilterAction;
+use redact::{Redacted, SensitiveFields};
+use stats::ParseStats;
+
+/// The type of text field names and values.  Parsed fields are owned, while fields built
+/// for writing can borrow string literals without allocating.
+pub type Text = Cow<'static, str>;
+
+/// How `FormData::from_dir` names the file parts it creates.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldNaming {
+    /// Give every file the same field name, as a form with a single multiple-file input
+    /// would (e.g. `"files[]"`).
+    Same(String),
+    /// Name each field after the file's path relative to the directory.
+    RelativePath,
+}
+
+/// A change made to a text field, or something noticed about a file, while parsing, as asked
+/// for by `ParseConfig`.  Each carries the name of the part.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldWarning {
+    /// CRLF line endings in the value were replaced with LF (`normalize_newlines`).
+    NewlinesNormalized(String),
+    /// Whitespace was trimmed from the value (`trim_values`).
+    Trimmed(String),
+    /// The field was left out for having an empty value (`drop_empty_fields`).
+    DroppedEmpty(String),
+    /// The file's content begins like HTML or script, though it was declared as an image or
+    /// as binary data (`markup_guard`).
+    MarkupInFile(String),
+    /// Control characters were removed from the value, name or filename (`control_chars`).
+    /// Carries the name as it is after removal.
+    ControlCharsStripped(String),
+}
+
+/// A part that was received but left out of the `FormData`.  See `FormData::skipped`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SkippedPart {
+    /// The part's name, as it was received, if it had one.
+    pub name: Option<String>,
+    /// The part's filename, without any directory components, if it had one.
+    pub filename: Option<String>,
+    /// The part's Content-Type, if it declared one.
+    pub content_type: Option<Mime>,
+    /// The size of the part's content, in bytes.
+    pub bytes: u64,
+    /// Why it was left out.
+    pub reason: SkipReason,
+}
+
+/// Why a part was left out of the `FormData`.  See `SkippedPart`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SkipReason {
+    /// `ParseConfig::name_mapper` dropped its name.
+    NameDropped,
+    /// `ParseConfig::part_filters` dropped it.
+    Filtered,
+    /// It is a multipart group nested more deeply than `ParseConfig::max_nesting` allows.
+    TooDeep,
+    /// Its idempotency key (see `ParseConfig::idempotency_header`) was that of a part
+    /// received before, so it was sent again.
+    Repeated,
+}
+
+/// A part of a `FormData`, as given to the predicate of `FormData::retain()`.
+#[derive(Clone, Copy, Debug)]
+pub enum PartRef<'a> {
+    /// A text field held in memory: its name and value.
+    Field(&'a str, &'a str),
+    /// An uploaded file.
+    File(&'a str, &'a FilePart),
+    /// A text field stored in a temporary file.
+    LargeField(&'a str, &'a FilePart),
+    /// A part parsed as JSON.
+    #[cfg(feature = "serde_json")]
+    Json(&'a str, &'a ::serde_json::Value),
+}
+
+impl<'a> PartRef<'a> {
+    /// The name of the part.
+    pub fn name(&self) -> &'a str {
+        match *self {
+            PartRef::Field(name, _) => name,
+            PartRef::File(name, _) | PartRef::LargeField(name, _) => name,
+            #[cfg(feature = "serde_json")]
+            PartRef::Json(name, _) => name,
+        }
+    }
+}
+
+/// A part exactly as it was received.  See `ParseConfig::raw_parts`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawPart {
+    /// Where the part lies within the body, from the first byte of its headers to the last
+    /// byte of its content.
+    pub range: Range<u64>,
+    /// The bytes of the part, if `RawParts::Bytes` was asked for.
+    pub bytes: Option<Vec<u8>>,
+}
 
 /// The extracted text fields and uploaded files from a `multipart/form-data` request.
 ///
 /// Use `parse_multipart` to devise this object from a request.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// A `FormData` is `Send`, so it can be handed to a worker thread once parsed, but not
+/// `Sync`, as the hyper `Headers` of its files parse their values lazily.  To share one
+/// between threads, put it behind a `Mutex`.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct FormData {
     /// Name-value pairs for plain text fields. Technically, these are form data parts with no
     /// filename specified in the part's `Content-Disposition`.
-    pub fields: Vec<(String, String)>,
+    pub fields: Vec<(Text, Text)>,
     /// Name-value pairs for temporary files. Technically, these are form data parts with a filename
-    /// specified in the part's `Content-Disposition`.
+    /// specified in the part's `Content-Disposition`.  Parsed files are in the order they
+    /// were sent; see `FilePart::index()`.
     pub files: Vec<(String, FilePart)>,
+    /// Name-value pairs for text fields whose values were too large to hold in memory (see
+    /// `ParseConfig::field_memory_limit`), each stored in a temporary file.
+    pub large_fields: Vec<(String, FilePart)>,
+    /// Statistics gathered while parsing.  See `stats()`.
+    pub stats: ParseStats,
+    /// Each top-level part as it was received, in the order received, if
+    /// `ParseConfig::raw_parts` was set.  A nested `multipart/mixed` group is one part.
+    pub raw_parts: Vec<RawPart>,
+    /// Changes made to text fields while parsing.  See `FieldWarning`.
+    pub warnings: Vec<FieldWarning>,
+    /// The parts that were received but left out, in the order received, so that they can
+    /// be reported back to the user.  See `SkippedPart`.
+    pub skipped: Vec<SkippedPart>,
+    /// The idempotency keys of the parts received, if `ParseConfig::idempotency_header` was
+    /// set, in the order received.  Parts whose key was seen before are in `skipped`
+    /// instead.
+    pub idempotency_keys: Vec<String>,
+    /// Name-value pairs for JSON parts, fields and files alike, if `ParseConfig::parse_json`
+    /// was set.  Such parts appear here instead of in `fields` or `files`.
+    #[cfg(feature = "serde_json")]
+    pub json_parts: Vec<(String, ::serde_json::Value)>,
+    /// Multipart groups nested more than one level deep, each with the parts within it, if
+    /// `ParseConfig::max_nesting` allows them.  A group and its parts take their name from
+    /// the outermost group.  Statistics, raw parts, warnings and skipped parts are kept in
+    /// the outermost `FormData` only.  Groups are not written out by `to_multipart()`.
+    pub groups: Vec<(String, FormData)>,
 }
 
 impl FormData {
     pub fn new() -> FormData {
-        FormData { fields: vec![], files: vec![] }
+        FormData {
+            fields: vec![],
+            files: vec![],
+            large_fields: vec![],
+            stats: ParseStats::default(),
+            raw_parts: vec![],
+            warnings: vec![],
+            skipped: vec![],
+            idempotency_keys: vec![],
+            #[cfg(feature = "serde_json")]
+            json_parts: vec![],
+            groups: vec![],
+        }
+    }
+
+    /// The value of the first text field named `name`.
+    pub fn get_field(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|f| f.0 == name).map(|f| &*f.1)
+    }
+
+    /// Whether the HTML checkbox `name` was checked.  Browsers send nothing at all for an
+    /// unchecked box, and its `value` (`"on"` by default) for a checked one.  Where there are
+    /// several fields by that name (as with a hidden `"0"` input placed before the box), the
+    /// last one counts, and a value of `"0"`, `"false"`, `"off"` or `""` is unchecked.
+    pub fn is_checked(&self, name: &str) -> bool {
+        match self.last_field(name) {
+            Some(value) => parse_bool(value) != Some(false),
+            None => false,
+        }
+    }
+
+    /// The value of the last text field named `name` as a boolean: `"1"`, `"true"`, `"on"`
+    /// and `"yes"` are true, and `"0"`, `"false"`, `"off"`, `"no"` and `""` false, ignoring
+    /// case.  Returns `default` if there is no such field, or its value is none of those.
+    pub fn get_bool(&self, name: &str, default: bool) -> bool {
+        self.last_field(name).and_then(parse_bool).unwrap_or(default)
+    }
+
+    fn last_field(&self, name: &str) -> Option<&str> {
+        self.fields.iter().rev().find(|f| f.0 == name).map(|f| &*f.1)
+    }
+
+    /// The first file named `name`.
+    pub fn get_file(&self, name: &str) -> Option<&FilePart> {
+        self.files.iter().find(|f| f.0 == name).map(|f| &f.1)
+    }
+
+    /// The files grouped by part name, each group in the order received.  A field with
+    /// `multiple` set sends one part per file, all under the same name.
+    pub fn files_by_name(&self) -> BTreeMap<&str, Vec<&FilePart>> {
+        let mut map: BTreeMap<&str, Vec<&FilePart>> = BTreeMap::new();
+        for (name, file) in &self.files {
+            map.entry(name).or_default().push(file);
+        }
+        map
+    }
+
+    /// The files grouped by client filename (see `FilePart::filename()`), each group in the
+    /// order received.  Files without a filename, or whose filename cannot be decoded, are
+    /// left out.
+    pub fn files_by_filename(&self) -> BTreeMap<String, Vec<&FilePart>> {
+        let mut map: BTreeMap<String, Vec<&FilePart>> = BTreeMap::new();
+        for (_, file) in &self.files {
+            if let Ok(Some(filename)) = file.filename() {
+                map.entry(filename).or_default().push(file);
+            }
+        }
+        map
+    }
+
+    /// Remove the text fields named `name`, including those stored in temporary files and
+    /// those within groups, returning how many were removed.
+    pub fn remove_field(&mut self, name: &str) -> usize {
+        let mut removed = 0;
+        self.retain(|part| match part {
+            PartRef::Field(n, _) | PartRef::LargeField(n, _) if n == name => {
+                removed += 1;
+                false
+            },
+            _ => true,
+        });
+        removed
+    }
+
+    /// Rename every part named `from`, of whatever kind, to `to`, along with any groups of
+    /// that name and the parts within them.  Returns how many parts were renamed.
+    pub fn rename_field(&mut self, from: &str, to: &str) -> usize {
+        let mut renamed = 0;
+        for field in &mut self.fields {
+            if field.0 == from {
+                field.0 = Text::Owned(to.to_owned());
+                renamed += 1;
+            }
+        }
+        let named = self.files.iter_mut().chain(self.large_fields.iter_mut())
+            .map(|part| &mut part.0);
+        #[cfg(feature = "serde_json")]
+        let named = named.chain(self.json_parts.iter_mut().map(|part| &mut part.0));
+        for name in named.filter(|name| *name == from) {
+            *name = to.to_owned();
+            renamed += 1;
+        }
+        for &mut (ref mut name, ref mut group) in &mut self.groups {
+            if name == from {
+                *name = to.to_owned();
+                renamed += group.rename_field(from, to);
+            }
+        }
+        self.reindex();
+        renamed
+    }
+
+    /// Put `file` in place of the first file named `name`, returning the file it replaced,
+    /// or add it at the end if there was none.  A replaced file is handed back rather than
+    /// dropped, so any temporary file it owns lives for as long as the caller keeps it.
+    pub fn replace_file(&mut self, name: &str, file: FilePart) -> Option<FilePart> {
+        let replaced = match self.files.iter_mut().find(|f| f.0 == name) {
+            Some(existing) => Some(::std::mem::replace(&mut existing.1, file)),
+            None => {
+                self.files.push((name.to_owned(), file));
+                None
+            },
+        };
+        self.reindex();
+        replaced
+    }
+
+    /// Keep only the parts for which `keep` returns true, in their order, including those
+    /// within groups.  A group left with no parts is removed.  Statistics, raw parts and
+    /// warnings describe the body as it was received, and are left as they are.
+    pub fn retain<F: FnMut(PartRef) -> bool>(&mut self, mut keep: F) {
+        self.retain_with(&mut keep);
+        self.reindex();
+    }
+
+    fn retain_with(&mut self, keep: &mut dyn FnMut(PartRef) -> bool) {
+        self.fields.retain(|f| keep(PartRef::Field(&f.0, &f.1)));
+        self.files.retain(|f| keep(PartRef::File(&f.0, &f.1)));
+        self.large_fields.retain(|f| keep(PartRef::LargeField(&f.0, &f.1)));
+        #[cfg(feature = "serde_json")]
+        self.json_parts.retain(|p| keep(PartRef::Json(&p.0, &p.1)));
+        for group in &mut self.groups {
+            group.1.retain_with(keep);
+        }
+        self.groups.retain(|group| !group.1.is_empty());
     }
 
-    /// Create a mime-multipart Vec<Node> from this FormData
+    // Number each parsed file (see `FilePart::index()`) by its position among the files of
+    // the same name, in this `FormData` and its groups, after they change.
+    pub(crate) fn reindex(&mut self) {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for &mut (ref name, ref mut file) in &mut self.files {
+            let count = counts.entry(name).or_insert(0);
+            if file.index.is_some() {
+                file.index = Some(*count);
+            }
+            *count += 1;
+        }
+        for group in &mut self.groups {
+            group.1.reindex();
+        }
+    }
+
+    // Whether there are no parts, in this `FormData` or its groups.
+    fn is_empty(&self) -> bool {
+        #[cfg(feature = "serde_json")]
+        {
+            if !self.json_parts.is_empty() {
+                return false;
+            }
+        }
+        self.fields.is_empty() && self.files.is_empty() && self.large_fields.is_empty() &&
+            self.groups.is_empty()
+    }
+
+    /// Create a `FormData` with one file part per file in `dir`, in name order.  If
+    /// `recursive`, files in subdirectories are included too.  Each part's filename is the
+    /// file's path relative to `dir`, using `/` as the separator; write it with
+    /// `WriteConfig::part_filenames`, as otherwise only the file's own name is written.
+    pub fn from_dir<P: AsRef<Path>>(dir: P, naming: FieldNaming, recursive: bool)
+                                    -> Result<FormData, Error>
+    {
+        let mut formdata = FormData::new();
+        add_dir(&mut formdata, dir.as_ref(), "", &naming, recursive)?;
+        Ok(formdata)
+    }
+
+    /// Totals gathered while parsing: the number of parts, fields and files, the size of
+    /// each part, and so on.
+    pub fn stats(&self) -> &ParseStats {
+        &self.stats
+    }
+
+    /// A view of this `FormData` for logging, which masks the fields named in `sensitive`
+    /// and truncates long values.  Files are shown by size only.
+    pub fn redacted<'a>(&'a self, sensitive: &'a SensitiveFields) -> Redacted<'a> {
+        Redacted { formdata: self, sensitive }
+    }
+
+    /// Whether this and `other` have the same fields, files, large fields, JSON parts and
+    /// groups, in the same order, comparing files and large fields by `FilePart::eq_metadata()`
+    /// rather than by path.  Statistics, raw parts and warnings, which describe a parse
+    /// rather than what was submitted, are ignored.  See `eq_deep()` to compare the content
+    /// of files as well.
+    pub fn eq_metadata(&self, other: &FormData) -> bool {
+        self.eq_with(other, &mut |a, b| Ok(a.eq_metadata(b))).unwrap_or(false)
+    }
+
+    /// Like `eq_metadata()`, but also compares the content of files and large fields.  See
+    /// `FilePart::eq_deep()`.
+    pub fn eq_deep(&self, other: &FormData) -> Result<bool, Error> {
+        self.eq_with(other, &mut |a, b| a.eq_deep(b))
+    }
+
+    fn eq_with<F>(&self, other: &FormData, eq_file: &mut F) -> Result<bool, Error>
+        where F: FnMut(&FilePart, &FilePart) -> Result<bool, Error>
+    {
+        if self.fields != other.fields {
+            return Ok(false);
+        }
+        #[cfg(feature = "serde_json")]
+        {
+            if self.json_parts != other.json_parts {
+                return Ok(false);
+            }
+        }
+        for (mine, theirs) in [(&self.files, &other.files),
+                               (&self.large_fields, &other.large_fields)] {
+            if mine.len() != theirs.len() {
+                return Ok(false);
+            }
+            for ((a_name, a), (b_name, b)) in mine.iter().zip(theirs.iter()) {
+                if a_name != b_name || !eq_file(a, b)? {
+                    return Ok(false);
+                }
+            }
+        }
+        if self.groups.len() != other.groups.len() {
+            return Ok(false);
+        }
+        for ((a_name, a), (b_name, b)) in self.groups.iter().zip(other.groups.iter()) {
+            if a_name != b_name || !a.eq_with(b, eq_file)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Check, before writing this FormData out, that every file and large field can be read
+    /// and is the size it claims to be, and that no part has a header value that is not
+    /// UTF-8 (which cannot be written).  Returns every problem found, each with the name of
+    /// its part, so that they can be reported together rather than a write failing part way
+    /// through the body.
+    /// A file that is missing or unreadable is reported with `Error::Io`, a directory with
+    /// `Error::NotAFile`, and anything else with `Error::InvalidPart`.
+    pub fn validate_for_write(&self) -> Result<(), Vec<(String, Error)>> {
+        let problems: Vec<(String, Error)> = self.files.iter()
+            .chain(self.large_fields.iter())
+            .filter_map(|(name, file)| file.check_writable().err().map(|e| (name.clone(), e)))
+            .collect();
+        if problems.is_empty() { Ok(()) } else { Err(problems) }
+    }
+
+    /// Create a mime-multipart Vec<Node> from this FormData.  Content-Disposition
+    /// parameters of files and large fields other than the name and filename (such as
+    /// `creation-date` or vendor extensions) are preserved.
     pub fn to_multipart(&self) -> Result<Vec<Node>, Error> {
-        // Translate to Nodes
-        let mut nodes: Vec<Node> = Vec::with_capacity(self.fields.len() + self.files.len());
+        self.to_multipart_with_config(&WriteConfig::default())
+    }
 
-        for &(ref name, ref value) in &self.fields {
+    /// Create a mime-multipart Vec<Node> from this FormData, as directed by `config`.  Files
+    /// that are a range of another file (see `FilePart::source_range()`) are read into
+    /// memory.
+    pub fn to_multipart_with_config(&self, config: &WriteConfig) -> Result<Vec<Node>, Error> {
+        Ok(self.nodes(config, true)?.0)
+    }
+
+    // Translate to Nodes, applying `config.part_filters`, along with the source range of
+    // each file node in order.  Unless `inline_ranges`, a file that is a range of another is
+    // left as a node for the whole file, to be written out with its range.
+    pub(crate) fn nodes(&self, config: &WriteConfig, inline_ranges: bool)
+                        -> Result<(Vec<Node>, FileRanges), Error>
+    {
+        let mut nodes: Vec<Node> = Vec::with_capacity(
+            self.fields.len() + self.large_fields.len() + self.files.len());
+        let mut ranges: FileRanges = Vec::new();
+        let filter = |name: &mut String, headers: &mut Headers| match config.part_filters {
+            Some(ref filters) => filters.apply(name, headers),
+            None => FilterAction::Keep,
+        };
+        // Each part's index is the number of nodes before it
+        let add_defaults = |index: usize, name: &str, headers: &mut Headers| {
+            if let Some(ref defaults) = config.default_headers {
+                defaults.apply(index, name, headers);
+            }
+        };
+
+        for (name, value) in &self.fields {
             let mut h = Headers::new();
             h.set(ContentType(Mime(TopLevel::Text, SubLevel::Plain, vec![])));
-            h.set(ContentDisposition {
-                disposition: DispositionType::Ext("form-data".to_owned()),
-                parameters: vec![DispositionParam::Ext("name".to_owned(), name.clone())],
-            });
+            let mut name = name.to_string();
+            if filter(&mut name, &mut h) == FilterAction::Drop {
+                continue;
+            }
+            add_defaults(nodes.len(), &name, &mut h);
+            if config.content_lengths {
+                h.set(ContentLength(value.len() as u64));
+            }
+            let parameters = vec![DispositionParam::Ext("name".to_owned(), escape_param(&name))];
+            disposition::set_form_data(&mut h, parameters, &config.disposition_format);
             nodes.push( Node::Part( Part {
                 headers: h,
                 body: value.as_bytes().to_owned(),
             }));
         }
 
-        for &(ref name, ref filepart) in &self.files {
-            let mut filepart = filepart.clone();
+        for (name, file) in &self.large_fields {
+            let mut filepart = file.to_multipart();
+            let mut name = name.clone();
+            if filter(&mut name, &mut filepart.headers) == FilterAction::Drop {
+                continue;
+            }
+            add_defaults(nodes.len(), &name, &mut filepart.headers);
+            if config.content_lengths {
+                filepart.headers.set(ContentLength(file.content_length()?));
+            }
+            let mut parameters =
+                vec![DispositionParam::Ext("name".to_owned(), escape_param(&name))];
+            parameters.extend(extra_disposition_params(&filepart.headers));
+            disposition::set_form_data(&mut filepart.headers, parameters,
+                                       &config.disposition_format);
+            nodes.push(file_node(file, filepart, inline_ranges)?);
+            ranges.push(file.source_range());
+        }
+
+        for (name, file) in &self.files {
+            let mut filepart = file.to_multipart();
+            let mut name = name.clone();
+            if filter(&mut name, &mut filepart.headers) == FilterAction::Drop {
+                continue;
+            }
+            add_defaults(nodes.len(), &name, &mut filepart.headers);
+            if config.content_lengths {
+                filepart.headers.set(ContentLength(file.content_length()?));
+            }
             // We leave all headers that the caller specified, except that we rewrite
-            // Content-Disposition.
-            while filepart.headers.remove::<ContentDisposition>() { };
-            let filename = match filepart.path.file_name() {
-                Some(fname) => fname.to_string_lossy().into_owned(),
-                None => return Err(Error::NotAFile),
+            // Content-Disposition.  The filename is taken from the path, unless the part's
+            // own is wanted.  Other disposition parameters are passed through.
+            let own = file.raw_filename().unwrap_or(None).filter(|_| config.part_filenames);
+            let filename = match own {
+                Some(filename) => filename,
+                None => match filepart.path.file_name() {
+                    Some(fname) => fname.to_string_lossy().into_owned(),
+                    None => return Err(Error::NotAFile),
+                },
             };
-            filepart.headers.set(ContentDisposition {
-                disposition: DispositionType::Ext("form-data".to_owned()),
-                parameters: vec![DispositionParam::Ext("name".to_owned(), name.clone()),
-                                 DispositionParam::Ext("filename".to_owned(), filename)],
-            });
-            nodes.push( Node::File( filepart ) );
+            let mut parameters = vec![
+                DispositionParam::Ext("name".to_owned(), escape_param(&name)),
+                DispositionParam::Ext("filename".to_owned(), escape_param(&filename)),
+            ];
+            parameters.extend(extra_disposition_params(&filepart.headers));
+            if config.disposition_metadata {
+                let size = file.range.as_ref().map(|range| range.end - range.start);
+                add_metadata_params(&mut parameters, &filepart.path, size)?;
+            }
+            disposition::set_form_data(&mut filepart.headers, parameters,
+                                       &config.disposition_format);
+            nodes.push(file_node(file, filepart, inline_ranges)?);
+            ranges.push(file.source_range());
         }
 
-        Ok(nodes)
+        Ok((nodes, ranges))
+    }
+}
+
+// The source range of each file node, in order.
+pub(crate) type FileRanges = Vec<Option<Range<u64>>>;
+
+// Escape a name or filename for a quoted Content-Disposition parameter as browsers do (see
+// the HTML standard's multipart/form-data encoding algorithm), so that a quote or line break
+// within it cannot end the parameter or the header early.
+fn escape_param(value: &str) -> String {
+    value.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+// The node for `file`, given its `mime_multipart` view.
+fn file_node(file: &FilePart, filepart: mime_multipart::FilePart, inline_ranges: bool)
+             -> Result<Node, Error>
+{
+    if inline_ranges && file.range.is_some() {
+        let mut body = Vec::new();
+        file.open_content()?.read_to_end(&mut body)?;
+        return Ok(Node::Part(Part { headers: filepart.headers.clone(), body }));
+    }
+    Ok(Node::File(filepart))
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    let value = value.trim();
+    if ["1", "true", "on", "yes"].iter().any(|v| value.eq_ignore_ascii_case(v)) {
+        Some(true)
+    } else if ["", "0", "false", "off", "no"].iter().any(|v| value.eq_ignore_ascii_case(v)) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+// The Content-Disposition parameters other than the name and filename, such as
+// `creation-date` or `size`, which are passed through unchanged.
+fn extra_disposition_params(headers: &Headers) -> Vec<DispositionParam> {
+    let cd: &ContentDisposition = match headers.get() {
+        Some(cd) => cd,
+        None => return Vec::new(),
+    };
+    cd.parameters.iter()
+        .filter(|p| match **p {
+            DispositionParam::Filename(..) => false,
+            DispositionParam::Ext(ref key, _) =>
+                !["name", "filename", "filename*"].iter().any(|k| key.eq_ignore_ascii_case(k)),
+        })
+        .cloned()
+        .collect()
+}
+
+// Add `size`, `creation-date` and `modification-date` parameters describing the file at
+// `path`, except where `parameters` already has them.  `size` overrides the size of the file.
+fn add_metadata_params(parameters: &mut Vec<DispositionParam>, path: &Path, size: Option<u64>)
+                       -> Result<(), Error>
+{
+    let metadata = fs::metadata(path)?;
+    let size = size.unwrap_or(metadata.len());
+    let values = vec![("size", Some(size.to_string())),
+                      ("creation-date", metadata.created().ok().and_then(http_date)),
+                      ("modification-date", metadata.modified().ok().and_then(http_date))];
+    for (key, value) in values {
+        let present = parameters.iter().any(|p| match *p {
+            DispositionParam::Ext(ref k, _) => k.eq_ignore_ascii_case(key),
+            _ => false,
+        });
+        if let (false, Some(value)) = (present, value) {
+            parameters.push(DispositionParam::Ext(key.to_owned(), value));
+        }
+    }
+    Ok(())
+}
+
+// Format a time as an RFC 822 date, e.g. `Wed, 12 Feb 1997 16:29:51 GMT`.
+fn http_date(t: SystemTime) -> Option<String> {
+    let secs = t.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(HttpDate(time::at_utc(time::Timespec::new(secs as i64, 0))).to_string())
+}
+
+// Add the files in `dir` (whose path relative to the top directory is `prefix`) to
+// `formdata`.
+fn add_dir(formdata: &mut FormData, dir: &Path, prefix: &str, naming: &FieldNaming,
+           recursive: bool) -> Result<(), Error>
+{
+    let mut entries: Vec<fs::DirEntry> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let relative = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if recursive {
+                add_dir(formdata, &entry.path(), &format!("{}/", relative), naming, recursive)?;
+            }
+            continue;
+        }
+        if !file_type.is_file() {
+            continue;
+        }
+        let name = match *naming {
+            FieldNaming::Same(ref name) => name.clone(),
+            FieldNaming::RelativePath => relative.clone(),
+        };
+        let mut headers = Headers::new();
+        headers.set(ContentDisposition {
+            disposition: DispositionType::Ext("form-data".to_owned()),
+            parameters: vec![DispositionParam::Ext("name".to_owned(), name.clone()),
+                             DispositionParam::Filename(Charset::Ext("UTF-8".to_owned()), None,
+                                                        relative.into_bytes())],
+        });
+        let mut file = FilePart::new(headers, &entry.path());
+        file.size = Some(entry.metadata()?.len() as usize);
+        formdata.files.push((name, file));
     }
+    Ok(())
 }
@@ -0,0 +1,128 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use hyper::header::{Headers, ContentType, ContentLength, TransferEncoding};
+use mime::Mime;
+use config::ParseConfig;
+use error::Error;
+use parser;
+
+// The Content-Type parameters `strict_content_type` allows
+const KNOWN_PARAMETERS: &[&str] = &["boundary", "charset"];
+
+/// The request headers that reading `multipart/form-data` needs.  Implemented for hyper's
+/// `Headers`, and with the `http` feature for `http::HeaderMap`, so that the read functions
+/// can be given either.
+pub trait RequestHeaders {
+    /// The Content-Type, if present and valid.
+    fn content_type(&self) -> Option<Mime>;
+    /// The Content-Length, if present and valid.
+    fn content_length(&self) -> Option<u64>;
+    /// The Transfer-Encoding, if present, e.g. `"chunked"`.  When there is one, any
+    /// Content-Length is disregarded.
+    fn transfer_encoding(&self) -> Option<String>;
+    /// Every Content-Type header value as sent, for `ParseConfig::strict_content_type`.  By
+    /// default, the one from `content_type()`.
+    fn content_type_values(&self) -> Vec<String> {
+        self.content_type().map(|mime| mime.to_string()).into_iter().collect()
+    }
+}
+
+impl RequestHeaders for Headers {
+    fn content_type(&self) -> Option<Mime> {
+        let mime = self.get::<ContentType>()?.0.clone();
+        let raw = self.get_raw("Content-Type")
+            .and_then(|raw| raw.first())
+            .and_then(|raw| ::std::str::from_utf8(raw).ok());
+        Some(parser::restore_boundary(mime, raw))
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.get::<ContentLength>().map(|cl| cl.0)
+    }
+
+    fn transfer_encoding(&self) -> Option<String> {
+        self.get::<TransferEncoding>().map(|te| te.to_string())
+    }
+
+    fn content_type_values(&self) -> Vec<String> {
+        self.get_raw("Content-Type").map(|raw| {
+            raw.iter().map(|line| String::from_utf8_lossy(line).into_owned()).collect()
+        }).unwrap_or_default()
+    }
+}
+
+#[cfg(feature = "http")]
+impl RequestHeaders for ::http::HeaderMap {
+    fn content_type(&self) -> Option<Mime> {
+        let raw = self.get(::http::header::CONTENT_TYPE)?.to_str().ok()?;
+        Some(parser::restore_boundary(raw.parse().ok()?, Some(raw)))
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.get(::http::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+    }
+
+    fn transfer_encoding(&self) -> Option<String> {
+        self.get(::http::header::TRANSFER_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned())
+    }
+
+    fn content_type_values(&self) -> Vec<String> {
+        self.get_all(::http::header::CONTENT_TYPE).iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .collect()
+    }
+}
+
+// If `config.strict_content_type` is set, check that `headers` have a single Content-Type,
+// with no parameter but those known and none repeated.
+pub(crate) fn check_content_type<H: RequestHeaders>(headers: &H, config: &ParseConfig)
+                                                    -> Result<(), Error>
+{
+    if !config.strict_content_type {
+        return Ok(());
+    }
+    let values = headers.content_type_values();
+    if values.len() > 1 {
+        return Err(Error::InvalidContentType(
+            format!("{} Content-Type headers", values.len())));
+    }
+    let mut seen: Vec<String> = Vec::new();
+    for name in values.first().map(|value| parameter_names(value)).unwrap_or_default() {
+        if !KNOWN_PARAMETERS.contains(&&*name) {
+            return Err(Error::InvalidContentType(format!("unknown parameter {:?}", name)));
+        }
+        if seen.contains(&name) {
+            return Err(Error::InvalidContentType(format!("repeated parameter {:?}", name)));
+        }
+        seen.push(name);
+    }
+    Ok(())
+}
+
+// The names of the parameters in the Content-Type `value`, in lowercase, in order.
+fn parameter_names(value: &str) -> Vec<String> {
+    let mut segments: Vec<String> = vec![String::new()];
+    let (mut quoted, mut escaped) = (false, false);
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                segments.push(String::new());
+                continue;
+            },
+            _ => {},
+        }
+        segments.last_mut().unwrap().push(c);
+    }
+    segments.iter().skip(1)
+        .map(|segment| segment.split('=').next().unwrap_or("").trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
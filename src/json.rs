@@ -0,0 +1,34 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Parsing of `application/json` parts, for `ParseConfig::parse_json`.
+
+use std::io::Read;
+use hyper::header::{Headers, ContentType};
+use mime::{Mime, TopLevel, SubLevel};
+use serde_json::{self, Value};
+use error::Error;
+use form_data::FormData;
+
+impl FormData {
+    /// The parsed value of the first JSON part named `name`.  See `ParseConfig::parse_json`.
+    pub fn get_json(&self, name: &str) -> Option<&Value> {
+        self.json_parts.iter().find(|p| p.0 == name).map(|p| &p.1)
+    }
+}
+
+// Whether the part is JSON: `application/json`, or a type with the `+json` suffix such as
+// `application/ld+json`.
+pub(crate) fn is_json(headers: &Headers) -> bool {
+    match headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => true,
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::Ext(ref sub), _))) =>
+            sub.ends_with("+json"),
+        _ => false,
+    }
+}
+
+// Parse the content of the JSON part `name`.
+pub(crate) fn parse<R: Read>(name: &str, content: R) -> Result<Value, Error> {
+    serde_json::from_reader(content).map_err(|_| Error::InvalidFieldValue(name.to_owned()))
+}
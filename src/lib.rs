@@ -58,10 +58,11 @@
 //!   // no need to set Content-Disposition (in fact it will be rewritten)
 //!
 //!   let formdata = FormData {
-//!     fields: vec![ ("name".to_owned(), "Baxter".to_owned()),
-//!                   ("age".to_owned(), "1 month".to_owned()) ],
+//!     fields: vec![ ("name".into(), "Baxter".into()),
+//!                   ("age".into(), "1 month".into()) ],
 //!     files: vec![ ("photo".to_owned(), FilePart::new(
 //!          photo_headers, Path::new("/tmp/puppy.gif"))) ],
+//!     ..FormData::new()
 //!   };
 //!
 //!   let boundary = formdata::generate_boundary();
@@ -80,130 +81,288 @@ extern crate mime;
 extern crate textnonce;
 extern crate log;
 extern crate encoding;
+extern crate unicode_normalization;
+extern crate base64;
+extern crate time;
+extern crate sha2;
+extern crate memchr;
 
 extern crate mime_multipart;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(any(feature = "fallocate", feature = "sendfile", feature = "tmpfile"))]
+extern crate libc;
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+#[cfg(feature = "http")]
+extern crate http;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
+#[cfg(feature = "csv")]
+extern crate csv;
 
+mod audit;
+pub mod bench;
+mod body;
+mod boundary;
+pub mod bridge;
+mod capture;
+mod config;
+#[cfg(feature = "csv")]
+mod csv_part;
+mod dedup;
+mod durability;
+#[cfg(feature = "chrono")]
+mod datetime;
+mod decoder;
+mod default_headers;
+mod descriptor;
+mod disposition;
+mod encode;
 mod error;
+mod file_part;
+mod filter;
 mod form_data;
+mod headers;
+#[cfg(feature = "serde_json")]
+mod json;
+mod parser;
+mod plain;
+mod policy;
+mod quarantine;
+mod random;
+mod query;
+mod pool;
+mod prealloc;
+mod reader;
+mod record;
+mod redact;
+pub mod replay;
+mod respond;
+mod session;
+mod shared;
+mod spill;
+mod split;
+mod stats;
+mod temp_file;
+mod tenant;
+mod throttle;
+#[cfg(feature = "threaded")]
+mod writer;
 #[cfg(test)]
 mod mock;
+#[cfg(feature = "async")]
+pub mod storage;
 
-pub use error::Error;
-pub use form_data::FormData;
+pub use audit::{AuditSink, Auditor, RejectedPart};
+pub use body::{BodyChunks, FormBody};
+pub use boundary::BoundaryStyle;
+pub use capture::{read_captured, BodyEncoding};
+pub use config::{ParseConfig, ParseConfigBuilder, ControlChars, DispositionFormat, FieldDecoder,
+                 FieldOverflow, MarkupGuard, ParamOrder, ParamQuoting, Quirks, RawParts,
+                 UnnamedFiles, WriteConfig};
+pub use dedup::{ContentHash, Deduplicator, Duplicate};
+pub use default_headers::DefaultHeaders;
+pub use durability::DurabilityPolicy;
+pub use descriptor::MultipartDescriptor;
+pub use error::{Error, ErrorResponse, InterruptedPart};
+pub use file_part::{Collision, FilePart, SaveOptions};
+pub use filter::{FilterAction, NameMapper, PartFilters};
+pub use form_data::{FormData, FieldNaming, FieldWarning, PartRef, RawPart, SkippedPart, SkipReason,
+                    Text};
+pub use headers::RequestHeaders;
+pub use parser::{Parser, Event};
+pub use policy::MimePolicy;
+pub use quarantine::Quarantine;
+pub use random::RandomSource;
+pub use query::QueryFiles;
+pub use pool::BufferPool;
+pub use record::BodyRecorder;
+pub use tenant::{Tenant, TenantQuota};
+pub use respond::respond_with_file;
+pub use session::UploadSession;
+pub use spill::SpillOver;
+pub use split::split_formdata;
+pub use reader::{read_formdata_with_config, read_formdata_in_place, precheck};
+pub use redact::{SensitiveFields, Redacted};
+pub use stats::{ParseStats, PartTiming};
+pub use throttle::RateLimiter;
 
 use std::io::{Read, Write};
-use hyper::header::{Headers, ContentDisposition, DispositionParam};
-use mime_multipart::Node;
-pub use mime_multipart::FilePart;
+use std::ops::Range;
+use std::path::Path;
+use mime::{Mime, TopLevel, SubLevel};
 pub use mime_multipart::generate_boundary;
 
 /// Parse MIME `multipart/form-data` information from a stream as a `FormData`.
-pub fn read_formdata<S: Read>(stream: &mut S, headers: &Headers) -> Result<FormData, Error>
+pub fn read_formdata<S: Read, H: RequestHeaders>(stream: &mut S, headers: &H)
+                                                 -> Result<FormData, Error>
 {
-    let nodes = mime_multipart::read_multipart_body(stream, headers, false)?;
+    read_formdata_with_config(stream, headers, &ParseConfig::default())
+}
 
-    let mut formdata = FormData::new();
-    fill_formdata(&mut formdata, nodes)?;
-    Ok(formdata)
+/// Like `read_formdata()`, but takes the stream by value and hands it back alongside the
+/// result, so that a connection can be kept for the next request where borrowing it across
+/// an await point or a thread is awkward.  The stream is returned whether parsing succeeds
+/// or not; after an error it may be part way through the body.
+pub fn read_formdata_owned<S: Read, H: RequestHeaders>(stream: S, headers: &H)
+                                                       -> (S, Result<FormData, Error>)
+{
+    read_formdata_owned_with_config(stream, headers, &ParseConfig::default())
 }
 
-// order and nesting are irrelevant, so we interate through the nodes and put them
-// into one of two buckets (fields and files);  If a multipart node is found, it uses
-// the name in its headers as the key (rather than the name in the headers of the
-// subparts), which is how multiple file uploads work.
-fn fill_formdata(formdata: &mut FormData, nodes: Vec<Node>) -> Result<(), Error>
+/// Like `read_formdata_with_config()`, but takes the stream by value and hands it back
+/// alongside the result.  See `read_formdata_owned()`.
+pub fn read_formdata_owned_with_config<S, H>(mut stream: S, headers: &H, config: &ParseConfig)
+                                             -> (S, Result<FormData, Error>)
+    where S: Read, H: RequestHeaders
 {
-    for node in nodes {
-        match node {
-            Node::Part(part) => {
-                let cd_name: Option<String> = {
-                    let cd: &ContentDisposition = match part.headers.get() {
-                        Some(cd) => cd,
-                        None => return Err(Error::MissingDisposition),
-                    };
-                    get_content_disposition_name(&cd)
-                };
-                let key = cd_name.ok_or(Error::NoName)?;
-                let val = String::from_utf8(part.body)?;
-                formdata.fields.push((key, val));
-            },
-            Node::File(part) => {
-                let cd_name: Option<String> = {
-                    let cd: &ContentDisposition = match part.headers.get() {
-                        Some(cd) => cd,
-                        None => return Err(Error::MissingDisposition),
-                    };
-                    get_content_disposition_name(&cd)
-                };
-                let key = cd_name.ok_or(Error::NoName)?;
-                formdata.files.push((key, part));
-            }
-            Node::Multipart((headers, nodes)) => {
-                let cd_name: Option<String> = {
-                    let cd: &ContentDisposition = match headers.get() {
-                        Some(cd) => cd,
-                        None => return Err(Error::MissingDisposition),
-                    };
-                    get_content_disposition_name(&cd)
-                };
-                let key = cd_name.ok_or(Error::NoName)?;
-                for node in nodes {
-                    match node {
-                        Node::Part(part) => {
-                            let val = String::from_utf8(part.body)?;
-                            formdata.fields.push((key.clone(), val));
-                        },
-                        Node::File(part) => {
-                            formdata.files.push((key.clone(), part));
-                        },
-                        _ => { } // don't recurse deeper
-                    }
-                }
-            }
-        }
-    }
-    Ok(())
+    let result = read_formdata_with_config(&mut stream, headers, config);
+    (stream, result)
 }
 
-#[inline]
-fn get_content_disposition_name(cd: &ContentDisposition) -> Option<String> {
-    if let Some(&DispositionParam::Ext(_, ref value)) = cd.parameters.iter()
-        .find(|&x| match *x {
-            DispositionParam::Ext(ref token,_) => &*token == "name",
-            _ => false,
-        })
-    {
-        Some(value.clone())
-    } else {
-        None
-    }
+/// Parse a multipart response body, such as a `multipart/mixed` reply from a batch API, as
+/// a `FormData`.  `headers` are the response headers (e.g. those of a hyper client
+/// `Response`, which can itself be passed as `stream`).  Parts without a
+/// `Content-Disposition` are accepted; see `ParseConfig::anonymous_parts`.
+pub fn read_formdata_response<H: RequestHeaders, S: Read>(headers: &H, stream: &mut S)
+                                                          -> Result<FormData, Error>
+{
+    let config = ParseConfig { anonymous_parts: true, ..ParseConfig::default() };
+    read_formdata_with_config(stream, headers, &config)
 }
 
+/// Parse a form in whichever of the encodings an HTML form can use the request's
+/// Content-Type names: `multipart/form-data` (as `read_formdata_with_config()`),
+/// `application/x-www-form-urlencoded` (as `FormData::from_query_string()`) or `text/plain`
+/// (as `FormData::from_plain_text()`).  The latter two are read into memory, up to
+/// `ParseConfig::max_body_size`, and give only fields.  Fails with
+/// `Error::NoRequestContentType` or `Error::NotFormData` for anything else.
+pub fn read_any_form<S: Read, H: RequestHeaders>(stream: &mut S, headers: &H,
+                                                 config: &ParseConfig)
+                                                 -> Result<FormData, Error>
+{
+    match headers.content_type() {
+        Some(Mime(TopLevel::Multipart, _, _)) =>
+            read_formdata_with_config(stream, headers, config),
+        Some(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, _)) =>
+            FormData::from_query_string(&plain::read_text(stream, config)?),
+        Some(Mime(TopLevel::Text, SubLevel::Plain, _)) =>
+            Ok(FormData::from_plain_text(&plain::read_text(stream, config)?)),
+        Some(_) => Err(Error::NotFormData),
+        None => Err(Error::NoRequestContentType),
+    }
+}
 
 /// Stream out `multipart/form-data` body content matching the passed in `formdata`.  This
 /// does not stream out headers, so the caller must stream those out before calling
 /// write_formdata().
-pub fn write_formdata<S: Write>(stream: &mut S, boundary: &Vec<u8>, formdata: &FormData)
+///
+/// A `formdata` with no parts is written as a valid empty body, and fields may hold any
+/// value, line breaks included, except one containing the boundary delimiter, which fails
+/// with `Error::BoundaryInContent`.  Quotes and line breaks in names and filenames are
+/// percent-encoded, as browsers do.  A file that cannot be read fails the write part way
+/// through the body; check with `FormData::validate_for_write()` first to avoid that.
+pub fn write_formdata<S: Write>(stream: &mut S, boundary: &[u8], formdata: &FormData)
                                 -> Result<usize, Error>
 {
-    let nodes = formdata.to_multipart()?;
+    write_formdata_with_config(stream, boundary, formdata, &WriteConfig::default())
+}
+
+/// Stream out `multipart/form-data` body content matching the passed in `formdata`, as
+/// directed by `config`.  This does not stream out headers.
+pub fn write_formdata_with_config<S: Write>(stream: &mut S, boundary: &[u8],
+                                            formdata: &FormData, config: &WriteConfig)
+                                            -> Result<usize, Error>
+{
+    match config.rate_limiter {
+        Some(ref limiter) => {
+            let mut throttled = throttle::ThrottledWriter { inner: stream, limiter };
+            write_body(&mut throttled, boundary, formdata, config)
+        },
+        None => write_body(stream, boundary, formdata, config),
+    }
+}
+
+fn write_body<S: Write>(stream: &mut S, boundary: &[u8], formdata: &FormData,
+                        config: &WriteConfig)
+                        -> Result<usize, Error>
+{
+    let (nodes, ranges) = formdata.nodes(config, false)?;
+    encode::check_nodes(boundary, &nodes)?;
 
     // Write out
-    let count = ::mime_multipart::write_multipart(stream, boundary, &nodes)?;
+    #[cfg(feature = "mmap")]
+    {
+        if config.mmap {
+            return encode::write_multipart(stream, boundary, &nodes, &ranges,
+                                           &mut encode::mmap_copy);
+        }
+    }
+    match config.buffer_pool {
+        Some(ref pool) => {
+            let mut buf = pool.take(0);
+            let result = encode::write_multipart(
+                stream, boundary, &nodes, &ranges,
+                &mut |s: &mut S, p: &Path, r: Option<&Range<u64>>| {
+                    encode::buffered_copy(s, p, r, &mut buf)
+                });
+            pool.give(buf);
+            result
+        },
+        None => encode::write_multipart(stream, boundary, &nodes, &ranges,
+                                        &mut encode::read_copy),
+    }
+}
+
+/// Stream out `multipart/form-data` body content matching the passed in `formdata` directly
+/// to a file or socket.  On Linux with the `sendfile` feature, file contents are copied
+/// within the kernel using `sendfile(2)`; otherwise, or where the output does not support
+/// it, they are copied as `write_formdata()` would.  This does not stream out headers.
+#[cfg(unix)]
+pub fn write_formdata_zero_copy<S>(stream: &mut S, boundary: &[u8], formdata: &FormData)
+                                   -> Result<usize, Error>
+    where S: Write + ::std::os::unix::io::AsRawFd
+{
+    let (nodes, ranges) = formdata.nodes(&WriteConfig::default(), false)?;
+    encode::check_nodes(boundary, &nodes)?;
 
-    Ok(count)
+    #[cfg(all(feature = "sendfile", target_os = "linux"))]
+    let mut copy = encode::sendfile_copy;
+    #[cfg(not(all(feature = "sendfile", target_os = "linux")))]
+    let mut copy = encode::read_copy;
+    encode::write_multipart(stream, boundary, &nodes, &ranges, &mut copy)
 }
 
 /// Stream out `multipart/form-data` body content matching the passed in `formdata` as
 /// Transfer-Encoding: Chunked.  This does not stream out headers, so the caller must stream
 /// those out before calling write_formdata().
-pub fn write_formdata_chunked<S: Write>(stream: &mut S, boundary: &Vec<u8>, formdata: &FormData)
+pub fn write_formdata_chunked<S: Write>(stream: &mut S, boundary: &[u8], formdata: &FormData)
                                         -> Result<(), Error>
 {
-    let nodes = formdata.to_multipart()?;
+    write_formdata_chunked_with_config(stream, boundary, formdata, &WriteConfig::default())
+}
+
+/// Stream out `multipart/form-data` body content matching the passed in `formdata` as
+/// Transfer-Encoding: Chunked, as directed by `config`.  This does not stream out headers.
+pub fn write_formdata_chunked_with_config<S: Write>(stream: &mut S, boundary: &[u8],
+                                                    formdata: &FormData, config: &WriteConfig)
+                                                    -> Result<(), Error>
+{
+    let nodes = formdata.to_multipart_with_config(config)?;
+    encode::check_nodes(boundary, &nodes)?;
 
     // Write out
-    ::mime_multipart::write_multipart_chunked(stream, boundary, &nodes)?;
+    let boundary = boundary.to_vec();
+    match config.rate_limiter {
+        Some(ref limiter) => {
+            let mut throttled = throttle::ThrottledWriter { inner: stream, limiter };
+            ::mime_multipart::write_multipart_chunked(&mut throttled, &boundary, &nodes)?;
+        },
+        None => ::mime_multipart::write_multipart_chunked(stream, &boundary, &nodes)?,
+    }
 
     Ok(())
 }
@@ -213,19 +372,35 @@ pub fn write_formdata_chunked<S: Write>(stream: &mut S, boundary: &Vec<u8>, form
 mod tests {
     extern crate tempdir;
 
-    use super::{FormData, read_formdata, write_formdata, write_formdata_chunked,
-                FilePart, generate_boundary};
+    use super::{FormData, Error, read_formdata, read_formdata_with_config,
+                read_formdata_in_place, read_formdata_response, read_any_form, precheck,
+                read_captured, read_formdata_owned,
+                write_formdata, write_formdata_chunked, write_formdata_chunked_with_config,
+                write_formdata_with_config,
+                write_formdata_zero_copy, BufferPool, RateLimiter,
+                FilePart, SaveOptions, Collision, WriteConfig, FormBody, DurabilityPolicy,
+                ParseConfig, FieldOverflow, FieldDecoder, Quirks, RawParts, UnnamedFiles,
+                SensitiveFields, FieldNaming, MarkupGuard, ControlChars, PartRef,
+                BodyEncoding, MultipartDescriptor, FieldWarning, MimePolicy,
+                Deduplicator, Duplicate, BodyRecorder, PartFilters, FilterAction,
+                NameMapper, Quarantine, AuditSink, Auditor, RejectedPart, Tenant, TenantQuota,
+                QueryFiles, SkippedPart, SkipReason, UploadSession,
+                split_formdata, respond_with_file, generate_boundary, BoundaryStyle,
+                RandomSource, DispositionFormat, ParamOrder, ParamQuoting, SpillOver, bench,
+                replay};
 
     use std::net::SocketAddr;
-    use std::fs::File;
-    use std::io::Write;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
 
     use hyper::buffer::BufReader;
     use hyper::net::NetworkStream;
     use hyper::server::Request as HyperRequest;
     use hyper::header::{Headers, ContentDisposition, DispositionParam, ContentType,
-                        DispositionType};
-    use mime::{Mime, TopLevel, SubLevel};
+                        ContentLength, DispositionType, Charset};
+    use mime::{Attr, Mime, TopLevel, SubLevel, Value};
 
     use mock::MockStream;
 
@@ -411,37 +586,2752 @@ mod tests {
     }
 
     #[test]
-    fn simple_writer() {
-        // Create a simple short file for testing
+    fn file_part_read_accessors() {
+        let input = b"POST / HTTP/1.1\r\n\
+                      Host: example.domain\r\n\
+                      Content-Type: multipart/form-data; boundary=\"abcdefg\"\r\n\
+                      Content-Length: 1000\r\n\
+                      \r\n\
+                      --abcdefg\r\n\
+                      Content-Disposition: form-data; name=\"field1\"; filename=\"file.txt\"\r\n\
+                      \r\n\
+                      This is a file\r\n\
+                      with two lines\r\n\
+                      --abcdefg--";
+
+        let mut mock = MockStream::with_input(input);
+
+        let mock: &mut dyn NetworkStream = &mut mock;
+        let mut stream = BufReader::new(mock);
+        let sock: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let req = HyperRequest::new(&mut stream, sock).unwrap();
+        let (_, _, headers, _, _, mut reader) = req.deconstruct();
+
+        let form_data = read_formdata(&mut reader, &headers).unwrap();
+        let (_, ref file) = form_data.files[0];
+
+        let mut first_line = String::new();
+        file.open().unwrap().read_line(&mut first_line).unwrap();
+        assert_eq!(first_line, "This is a file\r\n");
+
+        assert_eq!(file.read_to_vec(30).unwrap().len(), 30);
+        assert_eq!(file.read_to_string(64).unwrap(), "This is a file\r\nwith two lines");
+        match file.read_to_vec(29) {
+            Err(Error::FileTooLarge) => {},
+            other => panic!("expected FileTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn file_part_save_to_dir() {
         let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
-        let tmppath = tmpdir.path().join("testfile");
-        let mut tmpfile = File::create(tmppath.clone()).unwrap();
-        writeln!(tmpfile, "this is example file content").unwrap();
+        let destdir = tmpdir.path().join("dest");
+        ::std::fs::create_dir(&destdir).unwrap();
 
-        let mut photo_headers = Headers::new();
-        photo_headers.set(ContentType(Mime(TopLevel::Image, SubLevel::Gif, vec![])));
-        photo_headers.set(ContentDisposition {
+        let mut headers = Headers::new();
+        headers.set(ContentType(Mime(TopLevel::Image, SubLevel::Png, vec![])));
+        headers.set(ContentDisposition {
             disposition: DispositionType::Ext("form-data".to_owned()),
             parameters: vec![DispositionParam::Ext("name".to_owned(), "photo".to_owned()),
-                             DispositionParam::Ext("filename".to_owned(), "mike.gif".to_owned())],
+                             DispositionParam::Filename(
+                                 Charset::Ext("UTF-8".to_owned()), None,
+                                 b"../../photo.png".to_vec())],
         });
 
-        let formdata = FormData {
-            fields: vec![ ("name".to_owned(), "Mike".to_owned()),
-                            ("age".to_owned(), "46".to_owned()) ],
-            files: vec![ ("photo".to_owned(), FilePart::new(photo_headers, &tmppath)) ],
+        let mut saved = Vec::new();
+        for i in 0..2 {
+            let src = tmpdir.path().join(format!("upload{}", i));
+            File::create(&src).unwrap().write_all(b"png").unwrap();
+            let mut file = FilePart::new(headers.clone(), &src);
+            let options = SaveOptions { content_type_sidecar: true, ..SaveOptions::default() };
+            let dest = file.save_to_dir_with(&destdir, &options).unwrap();
+            assert_eq!(file.path, dest);
+            assert!(!src.exists());
+            saved.push(dest);
+        }
+
+        assert_eq!(saved[0], destdir.join("photo.png"));
+        assert_eq!(saved[1], destdir.join("photo (1).png"));
+        let mut ct = String::new();
+        File::open(destdir.join("photo.png.content-type")).unwrap()
+            .read_to_string(&mut ct).unwrap();
+        assert_eq!(ct, "image/png\n");
+
+        let src = tmpdir.path().join("upload2");
+        File::create(&src).unwrap().write_all(b"new").unwrap();
+        let mut file = FilePart::new(headers.clone(), &src);
+        let options = SaveOptions { on_collision: Collision::Fail, ..SaveOptions::default() };
+        match file.save_to_dir_with(&destdir, &options) {
+            Err(Error::Io(ref e)) if e.kind() == ::std::io::ErrorKind::AlreadyExists => {},
+            other => panic!("expected AlreadyExists, got {:?}", other),
+        }
+        let options = SaveOptions { on_collision: Collision::Overwrite, ..SaveOptions::default() };
+        assert_eq!(file.save_to_dir_with(&destdir, &options).unwrap(), saved[0]);
+        assert_eq!(fs::read(&saved[0]).unwrap(), b"new");
+
+        // Moved to another filesystem, where there is one
+        if let Ok(otherdir) = tempdir::TempDir::new_in("/dev/shm", "formdata_test") {
+            let src = tmpdir.path().join("upload3");
+            File::create(&src).unwrap().write_all(b"png").unwrap();
+            let mut file = FilePart::new(headers.clone(), &src);
+            let dest = file.save_to_dir(otherdir.path()).unwrap();
+            assert_eq!(fs::read(&dest).unwrap(), b"png");
+            assert!(!src.exists());
+        }
+    }
+
+    #[test]
+    fn windows_filenames() {
+        fn file_named(filename: &str, path: &::std::path::Path) -> FilePart {
+            let mut headers = Headers::new();
+            headers.set(ContentDisposition {
+                disposition: DispositionType::Ext("form-data".to_owned()),
+                parameters: vec![DispositionParam::Filename(
+                    Charset::Ext("UTF-8".to_owned()), None, filename.as_bytes().to_vec())],
+            });
+            FilePart::new(headers, path)
+        }
+
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let sanitized = |filename| file_named(filename, tmpdir.path())
+            .sanitized_filename().unwrap().unwrap();
+        assert_eq!(sanitized("C:\\Users\\me\\CON.txt"), "_CON.txt");
+        assert_eq!(sanitized("nul"), "_nul");
+        assert_eq!(sanitized("Com1 .tar.gz"), "_Com1 .tar.gz");
+        assert_eq!(sanitized("COM10.txt"), "COM10.txt");
+        assert_eq!(sanitized("console.txt"), "console.txt");
+        assert_eq!(sanitized("dir\\sub/photo.png. "), "photo.png");
+
+        let destdir = tmpdir.path().join("dest");
+        fs::create_dir(&destdir).unwrap();
+        File::create(destdir.join("photo.png")).unwrap();
+        let options = SaveOptions { case_insensitive: true, ..SaveOptions::default() };
+        let save = |contents: &[u8], options: &SaveOptions| {
+            let src = tmpdir.path().join("upload");
+            File::create(&src).unwrap().write_all(contents).unwrap();
+            file_named("Photo.PNG", &src).save_to_dir_with(&destdir, options)
+        };
+        assert_eq!(save(b"1", &options).unwrap(), destdir.join("Photo (1).PNG"));
+        let overwrite = SaveOptions { on_collision: Collision::Overwrite, ..options.clone() };
+        assert_eq!(save(b"2", &overwrite).unwrap(), destdir.join("photo.png"));
+        assert_eq!(fs::read(destdir.join("photo.png")).unwrap(), b"2");
+        let fail = SaveOptions { on_collision: Collision::Fail, ..options };
+        assert!(save(b"3", &fail).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_temp_dir() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let mut deep = tmpdir.path().to_owned();
+        while deep.as_os_str().len() < 300 {
+            deep.push("a_rather_long_directory_name");
+        }
+        let config = ParseConfig { temp_dir: Some(deep.clone()), ..ParseConfig::default() };
+        fs::create_dir_all(config.upload_dir()).unwrap();
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     content\r\n\
+                     --abcdefg--";
+        let formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                 &config).unwrap();
+        assert_eq!(formdata.files[0].1.read_to_vec(10).unwrap(), b"content");
+    }
+
+    #[test]
+    fn temp_file_permissions() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     content\r\n\
+                     --abcdefg--";
+        for &unnamed in &[false, true] {
+            let config = ParseConfig { unnamed_temp_files: unnamed, ..ParseConfig::default() };
+            let formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                     &config).unwrap();
+            let file = &formdata.files[0].1;
+            assert_eq!(file.read_to_vec(10).unwrap(), b"content");
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = |path: &::std::path::Path|
+                    fs::metadata(path).unwrap().permissions().mode() & 0o777;
+                assert_eq!(mode(&file.path), 0o600);
+                assert_eq!(mode(file.path.parent().unwrap()), 0o700);
+            }
+        }
+    }
+
+    #[test]
+    fn durable_uploads() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     content\r\n\
+                     --abcdefg--";
+        let config = ParseConfig { durability: DurabilityPolicy::SyncDir,
+                                   ..ParseConfig::default() };
+        let mut formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                     &config).unwrap();
+        let options = SaveOptions { durability: DurabilityPolicy::SyncDir,
+                                    ..SaveOptions::default() };
+        let dest = formdata.files[0].1.save_to_dir_with(tmpdir.path(), &options).unwrap();
+        assert_eq!(fs::read(dest).unwrap(), b"content");
+    }
+
+    #[test]
+    fn control_characters() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"na\xc2\x85me\"\r\n\
+                     \r\n\
+                     a\x00b\tc\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"doc\"; filename*=UTF-8''a%1B.txt\r\n\
+                     Content-Type: text/plain\r\n\
+                     \r\n\
+                     text\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+
+        let formdata = read_formdata(&mut &body[..], &headers).unwrap();
+        assert_eq!(formdata.fields, vec![("na\u{85}me".into(), "a\x00b\tc".into())]);
+
+        let config = ParseConfig { control_chars: ControlChars::Strip, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(formdata.fields, vec![("name".into(), "ab\tc".into())]);
+        assert_eq!(formdata.files[0].1.filename().unwrap(), Some("a.txt".to_owned()));
+        assert_eq!(formdata.warnings,
+                   vec![FieldWarning::ControlCharsStripped("name".to_owned()),
+                        FieldWarning::ControlCharsStripped("name".to_owned()),
+                        FieldWarning::ControlCharsStripped("doc".to_owned())]);
+
+        let config = ParseConfig { control_chars: ControlChars::Reject, ..ParseConfig::default() };
+        match read_formdata_with_config(&mut &body[..], &headers, &config) {
+            Err(Error::ControlCharacters(name)) => assert_eq!(name, "na\u{85}me"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn markup_guard() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"avatar\"; filename=\"a.png\"\r\n\
+                     Content-Type: image/png\r\n\
+                     \r\n\
+                     \r\n  <HTML><script>alert(1)</script>\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"page\"; filename=\"a.html\"\r\n\
+                     Content-Type: text/html\r\n\
+                     \r\n\
+                     <html></html>\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+
+        let formdata = read_formdata(&mut &body[..], &headers).unwrap();
+        assert!(formdata.warnings.is_empty());
+
+        let config = ParseConfig { markup_guard: Some(MarkupGuard::Warn),
+                                   ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(formdata.files.len(), 2);
+        assert_eq!(formdata.warnings, vec![FieldWarning::MarkupInFile("avatar".to_owned())]);
+
+        let config = ParseConfig { markup_guard: Some(MarkupGuard::Reject),
+                                   ..ParseConfig::default() };
+        match read_formdata_with_config(&mut &body[..], &headers, &config) {
+            Err(Error::FileNotAllowed(reason)) =>
+                assert_eq!(reason, "content of image/png file looks like HTML"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn multipart_response() {
+        let body = b"--batch\r\n\
+                     Content-Type: application/json\r\n\
+                     Content-ID: <item1>\r\n\
+                     \r\n\
+                     {\"ok\":true}\r\n\
+                     --batch\r\n\
+                     Content-Type: text/plain\r\n\
+                     \r\n\
+                     done\r\n\
+                     --batch--";
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![b"multipart/mixed; boundary=batch".to_vec()]);
+
+        match read_formdata(&mut &body[..], &headers) {
+            Err(Error::MissingDisposition) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let formdata = read_formdata_response(&headers, &mut &body[..]).unwrap();
+        assert_eq!(formdata.fields, vec![("item1".into(), "{\"ok\":true}".into()),
+                                         ("part_2".into(), "done".into())]);
+    }
+
+    #[test]
+    fn files_grouped() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"docs\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     1\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"photo\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     2\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"docs\"; filename=\"b.txt\"\r\n\
+                     \r\n\
+                     3\r\n\
+                     --abcdefg--";
+        let formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
+        let read = |files: &Vec<&FilePart>| {
+            files.iter().map(|f| f.read_to_string(10).unwrap()).collect::<Vec<_>>()
+        };
+
+        let by_name = formdata.files_by_name();
+        assert_eq!(by_name.keys().collect::<Vec<_>>(), vec![&"docs", &"photo"]);
+        assert_eq!(read(&by_name["docs"]), vec!["1", "3"]);
+
+        let by_filename = formdata.files_by_filename();
+        assert_eq!(read(&by_filename["a.txt"]), vec!["1", "2"]);
+        assert_eq!(read(&by_filename["b.txt"]), vec!["3"]);
+    }
+
+    #[test]
+    fn large_field_overflow() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"small\"\r\n\
+                     \r\n\
+                     tiny\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"large\"\r\n\
+                     \r\n\
+                     caf\xc3\xa9 caf\xc3\xa9 caf\xc3\xa9\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+
+        let mut config = ParseConfig { field_memory_limit: Some(8), ..ParseConfig::default() };
+        let form_data = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(form_data.fields, vec![("small".into(), "tiny".into())]);
+        assert_eq!(form_data.large_fields.len(), 1);
+        let (ref name, ref file) = form_data.large_fields[0];
+        assert_eq!(name, "large");
+        assert_eq!(file.read_to_string(100).unwrap(), "caf\u{e9} caf\u{e9} caf\u{e9}");
+
+        config.field_overflow = FieldOverflow::Truncate;
+        let form_data = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert!(form_data.large_fields.is_empty());
+        assert_eq!(form_data.fields[1], ("large".into(), "caf\u{e9} ca".into()));
+
+        config.field_memory_limit = Some(4);
+        let form_data = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(form_data.fields[1], ("large".into(), "caf".into()));
+    }
+
+    #[test]
+    fn part_and_file_limits() {
+        let mut body = bench::many_fields_body("abcdefg", 5000);
+        let len = body.len() as u64;
+        body.extend_from_slice(b"trailing data for the next request");
+        let headers = bench::headers("abcdefg");
+
+        let config = ParseConfig { max_parts: Some(10), ..ParseConfig::default() };
+        let mut stream = &body[..];
+        match read_formdata_with_config(&mut stream, &headers, &config) {
+            Err(Error::TooManyParts(n)) => assert_eq!(n, body.len() as u64),
+            other => panic!("expected TooManyParts, got {:?}", other),
+        }
+        assert!(stream.is_empty());
+
+        let config = ParseConfig { abort_on_limit: true, ..config };
+        let mut stream = &body[..];
+        match read_formdata_with_config(&mut stream, &headers, &config) {
+            Err(Error::TooManyParts(n)) => assert!(n < len),
+            other => panic!("expected TooManyParts, got {:?}", other),
+        }
+        assert!(!stream.is_empty());
+
+        // The remainder is read no further than the body may go, and not past an error
+        let config = ParseConfig { max_parts: Some(10), max_body_size: Some(len),
+                                   ..ParseConfig::default() };
+        let mut stream = &body[..];
+        match read_formdata_with_config(&mut stream, &headers, &config) {
+            Err(Error::TooManyParts(n)) => assert_eq!(n, len),
+            other => panic!("expected TooManyParts, got {:?}", other),
+        }
+        assert_eq!(stream.len() as u64, body.len() as u64 - len);
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+            }
+        }
+        let config = ParseConfig { max_body_size: None, ..config };
+        match read_formdata_with_config(&mut (&body[..len as usize]).chain(Broken), &headers,
+                                        &config) {
+            Err(Error::Io(_)) => {},
+            other => panic!("expected Io, got {:?}", other),
+        }
+
+        let body = bench::pathological_body("abcdefg", 10);
+        let config = ParseConfig { max_files: Some(0), ..ParseConfig::default() };
+        match read_formdata_with_config(&mut &body[..], &headers, &config) {
+            Err(Error::TooManyFiles(_)) => {},
+            other => panic!("expected TooManyFiles, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn name_normalization() {
+        let body = "--abcdefg\r\n\
+                    Content-Disposition: form-data; name=\"Cafe\u{301}\"\r\n\
+                    \r\n\
+                    latte\r\n\
+                    --abcdefg\r\n\
+                    Content-Disposition: form-data; name=\"menu\"; filename=\"Cafe\u{301}.txt\"\r\n\
+                    \r\n\
+                    espresso\r\n\
+                    --abcdefg--";
+        let headers = bench::headers("abcdefg");
+
+        let form_data = read_formdata(&mut body.as_bytes(), &headers).unwrap();
+        assert_eq!(form_data.get_field("Caf\u{e9}"), None);
+
+        let config = ParseConfig { normalize_names: true, fold_case: true,
+                                   ..ParseConfig::default() };
+        let form_data = read_formdata_with_config(&mut body.as_bytes(), &headers, &config)
+            .unwrap();
+        assert_eq!(form_data.get_field(&config.normalize_name("CAF\u{c9}")), Some("latte"));
+        let file = form_data.get_file("menu").unwrap();
+        assert_eq!(file.filename().unwrap().unwrap(), "caf\u{e9}.txt");
+    }
+
+    #[test]
+    fn latin1_header_fallback() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"menu\"; filename=\"caf\xe9.txt\"\r\n\
+                     \r\n\
+                     espresso\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+
+        let config = ParseConfig { header_fallback_encoding: Some("iso-8859-1".to_owned()),
+                                   ..ParseConfig::default() };
+        let form_data = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        let file = form_data.get_file("menu").unwrap();
+        assert_eq!(file.filename().unwrap().unwrap(), "caf\u{e9}.txt");
+    }
+
+    #[test]
+    fn windows_path_filename() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"photo\"; \
+                     filename=\"C:\\Users\\bob\\photo.jpg\"\r\n\
+                     \r\n\
+                     image data\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+
+        let form_data = read_formdata(&mut &body[..], &headers).unwrap();
+        let file = form_data.get_file("photo").unwrap();
+        assert_eq!(file.filename().unwrap().unwrap(), "photo.jpg");
+        assert_eq!(file.raw_filename().unwrap().unwrap(), "C:\\Users\\bob\\photo.jpg");
+    }
+
+    #[cfg(feature = "threaded")]
+    #[test]
+    fn writer_thread() {
+        let config = ParseConfig { write_queue: Some(4), ..ParseConfig::default() };
+        let mut body = bench::LargeFileBody::new("abcdefg", 100_000);
+        let formdata = read_formdata_with_config(&mut body, &bench::headers("abcdefg"),
+                                                 &config).unwrap();
+        let file = formdata.get_file("file").unwrap();
+        assert_eq!(file.size, Some(100_000));
+        let content = file.read_to_vec(100_000).unwrap();
+        assert_eq!(&content[..3], b"abc");
+        assert_eq!(content.len(), 100_000);
+    }
+
+    #[test]
+    fn preallocated_files_are_trimmed() {
+        let body = bench::many_fields_body("abcdefg", 0);
+        let mut full = b"--abcdefg\r\n\
+                         Content-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\
+                         \r\n\
+                         short\r\n".to_vec();
+        full.extend(body);
+        let mut headers = bench::headers("abcdefg");
+        headers.set(ContentLength(1_000_000));
+
+        let config = ParseConfig { preallocate: true, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &full[..], &headers, &config).unwrap();
+        let file = formdata.get_file("f").unwrap();
+        assert_eq!(std::fs::metadata(&file.path).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn preallocated_fields() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     Content-Length: 1000\r\n\
+                     \r\n\
+                     short\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"b\"\r\n\
+                     Content-Length: 1000000000\r\n\
+                     \r\n\
+                     short\r\n\
+                     --abcdefg--";
+        let config = ParseConfig { preallocate: true, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                 &config).unwrap();
+        let capacities: Vec<usize> = formdata.fields.iter().map(|f| match f.1 {
+            ::std::borrow::Cow::Owned(ref value) => value.capacity(),
+            ::std::borrow::Cow::Borrowed(_) => 0,
+        }).collect();
+        assert_eq!(capacities, vec![1000, 64 * 1024]);
+        assert_eq!(formdata.get_field("b"), Some("short"));
+    }
+
+    // Bodies read through buffers of random capacity, smaller as well as larger than the
+    // boundary, in pieces of random size, with content full of near-misses of the delimiter.
+    #[test]
+    fn random_buffer_capacities() {
+        struct Pieces<'a> { body: &'a [u8], seed: u64 }
+        impl<'a> Read for Pieces<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+                let n = (next_random(&mut self.seed) as usize % buf.len().max(1) + 1)
+                    .min(buf.len()).min(self.body.len());
+                buf[..n].copy_from_slice(&self.body[..n]);
+                self.body = &self.body[n..];
+                Ok(n)
+            }
+        }
+        fn next_random(seed: &mut u64) -> u64 {
+            *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            *seed >> 33
+        }
+
+        let mut seed = 1;
+        for _ in 0..200 {
+            let len = next_random(&mut seed) as usize % 70 + 1;
+            let boundary: String = (0..len).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+            let near_miss = format!("\r\n--{}", &boundary[..len - 1]);
+            let value = format!("x{}-{}\r\r\n-", near_miss, near_miss);
+            let body = format!("--{b}\r\n\
+                                Content-Disposition: form-data; name=\"a\"\r\n\
+                                \r\n\
+                                {v}\r\n\
+                                --{b}\r\n\
+                                Content-Disposition: form-data; name=\"f\"; filename=\"f\"\r\n\
+                                \r\n\
+                                {v}\r\n\
+                                --{b}--\r\n", b = boundary, v = value);
+            let capacity = next_random(&mut seed) as usize % (2 * len + 8) + 1;
+            let pieces = Pieces { body: body.as_bytes(), seed };
+            let mut stream = ::std::io::BufReader::with_capacity(capacity, pieces);
+            let formdata = read_formdata(&mut stream, &bench::headers(&boundary))
+                .unwrap_or_else(|e| panic!("boundary {}, capacity {}: {}", len, capacity, e));
+            assert_eq!(formdata.get_field("a"), Some(&*value));
+            let file = formdata.get_file("f").unwrap();
+            assert_eq!(file.read_to_string(1000).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn upload_session() {
+        let chunk = |range: &str, content: &str| format!("--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"video\"; filename=\"v.mp4\"\r\n\
+                     {}\
+                     \r\n\
+                     {}\r\n\
+                     --abcdefg--", range, content);
+        let headers = bench::headers("abcdefg");
+        let mut session = UploadSession::new(ParseConfig::default()).max_files(1);
+
+        let first = format!("--abcdefg\r\n\
+                             Content-Disposition: form-data; name=\"title\"\r\n\
+                             \r\n\
+                             Holiday\r\n\
+                             {}", chunk("Content-Range: bytes 0-3/10\r\n", "abcd"));
+        session.feed(&mut first.as_bytes(), &headers).unwrap();
+        let skipped = chunk("Content-Range: bytes 5-8/10\r\n", "fghi");
+        match session.feed(&mut skipped.as_bytes(), &headers) {
+            Err(Error::InvalidChunk(_)) => {},
+            other => panic!("expected InvalidChunk, got {:?}", other),
+        }
+        let second = chunk("Content-Range: bytes 4-7/10\r\n", "efgh");
+        session.feed(&mut second.as_bytes(), &headers).unwrap();
+        match session.feed(&mut chunk("", "another").as_bytes(), &headers) {
+            Err(Error::TooManyFiles(_)) => {},
+            other => panic!("expected TooManyFiles, got {:?}", other),
+        }
+        assert!(!session.is_complete());
+        session.feed_chunk(&mut chunk("", "ij").as_bytes(), &headers, 8, None).unwrap();
+        assert!(session.is_complete());
+        assert_eq!((session.requests(), session.size()), (3, 17));
+
+        let formdata = session.finish().unwrap();
+        assert_eq!(formdata.get_field("title"), Some("Holiday"));
+        assert_eq!(formdata.files.len(), 1);
+        let video = formdata.get_file("video").unwrap();
+        assert_eq!(video.read_to_string(100).unwrap(), "abcdefghij");
+        assert_eq!(video.size, Some(10));
+
+        let mut session = UploadSession::new(ParseConfig::default());
+        let first = chunk("Content-Range: bytes 0-3/10\r\n", "abcd");
+        session.feed(&mut first.as_bytes(), &headers).unwrap();
+        let path = session.formdata().files[0].1.path.clone();
+        match session.finish() {
+            Err(Error::InvalidChunk(_)) => {},
+            other => panic!("expected InvalidChunk, got {:?}", other),
+        }
+        assert!(!path.exists());
+
+        let mut session = UploadSession::new(ParseConfig::default());
+        let both = chunk("Content-Range: bytes 0-3/6\r\n", "abcd").replace("--abcdefg--", "")
+            + &chunk("Content-Range: bytes 4-5/6\r\n", "ef");
+        session.feed(&mut both.as_bytes(), &headers).unwrap();
+        assert!(session.is_complete());
+        let formdata = session.finish().unwrap();
+        assert_eq!(formdata.files.len(), 1);
+        assert_eq!(formdata.get_file("video").unwrap().read_to_string(100).unwrap(), "abcdef");
+    }
+
+    #[test]
+    fn idempotency_keys() {
+        let part = |key: &str, name: &str| format!("--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"{}\"; filename=\"{}.txt\"\r\n\
+                     Idempotency-Key: {}\r\n\
+                     \r\n\
+                     content\r\n", name, name, key);
+        let body = |parts: &[String]| format!("{}--abcdefg--", parts.concat());
+        let headers = bench::headers("abcdefg");
+        let config = ParseConfig { idempotency_header: Some("Idempotency-Key".to_owned()),
+                                   ..ParseConfig::default() };
+
+        let repeated = body(&[part("k1", "a"), part("k2", "b"), part("k1", "a")]);
+        let formdata = read_formdata_with_config(&mut repeated.as_bytes(), &headers, &config)
+            .unwrap();
+        assert_eq!(formdata.files.len(), 2);
+        assert_eq!(formdata.idempotency_keys, vec!["k1".to_owned(), "k2".to_owned()]);
+        assert_eq!(formdata.skipped.len(), 1);
+        assert_eq!(formdata.skipped[0].reason, SkipReason::Repeated);
+        assert_eq!(formdata.skipped[0].bytes, 7);
+        let formdata = read_formdata(&mut repeated.as_bytes(), &headers).unwrap();
+        assert_eq!(formdata.files.len(), 3);
+
+        let mut session = UploadSession::new(config);
+        session.feed(&mut body(&[part("k1", "a")]).as_bytes(), &headers).unwrap();
+        session.feed(&mut body(&[part("k1", "a"), part("k2", "b")]).as_bytes(), &headers)
+            .unwrap();
+        let formdata = session.finish().unwrap();
+        let names: Vec<&str> = formdata.files.iter().map(|f| &*f.0).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(formdata.skipped[0].name.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn skipped_parts() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"csrfToken\"\r\n\
+                     \r\n\
+                     xyz\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"c:\\\\x\\\\a.exe\"\r\n\
+                     Content-Type: application/x-msdownload\r\n\
+                     \r\n\
+                     MZ123456\r\n\
+                     --abcdefg--";
+        let mapper = NameMapper::new(|name| match name {
+            "csrfToken" => None,
+            name => Some(name.to_owned()),
+        });
+        let filters = PartFilters::new().drop_parts(|_, headers| {
+            headers.get::<ContentType>().is_some_and(|ct| ct.0.to_string().contains("msdownload"))
+        });
+        let config = ParseConfig { name_mapper: Some(mapper), part_filters: Some(filters),
+                                   ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                 &config).unwrap();
+        assert_eq!(formdata.get_field("a"), Some("1234"));
+        assert!(formdata.files.is_empty());
+        assert_eq!(formdata.skipped, vec![
+            SkippedPart { name: Some("csrfToken".to_owned()), filename: None, content_type: None,
+                          bytes: 3, reason: SkipReason::NameDropped },
+            SkippedPart { name: Some("f".to_owned()), filename: Some("a.exe".to_owned()),
+                          content_type: Some("application/x-msdownload".parse().unwrap()),
+                          bytes: 8, reason: SkipReason::Filtered },
+        ]);
+    }
+
+    #[test]
+    fn strict_content_type() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg--";
+        let config = ParseConfig { strict_content_type: true, ..ParseConfig::default() };
+        let parse = |values: &[&str]| {
+            let mut headers = Headers::new();
+            headers.set_raw("Content-Type",
+                            values.iter().map(|v| v.as_bytes().to_vec()).collect::<Vec<_>>());
+            precheck(&headers, &config)?;
+            read_formdata_with_config(&mut &body[..], &headers, &config)
+        };
+        assert!(parse(&["multipart/form-data; boundary=abcdefg"]).is_ok());
+        assert!(parse(&["multipart/form-data; Charset=utf-8; boundary=\"abcdefg\";"]).is_ok());
+        let reason = |values: &[&str]| match parse(values) {
+            Err(Error::InvalidContentType(reason)) => reason,
+            other => panic!("expected InvalidContentType, got {:?}", other),
+        };
+        assert_eq!(reason(&["multipart/form-data; boundary=abcdefg; foo=\"a;b\""]),
+                   "unknown parameter \"foo\"");
+        assert_eq!(reason(&["multipart/form-data; boundary=abcdefg; BOUNDARY=x"]),
+                   "repeated parameter \"boundary\"");
+        assert_eq!(reason(&["multipart/form-data; boundary=abcdefg", "text/plain"]),
+                   "2 Content-Type headers");
+
+        let mut headers = Headers::new();
+        let lax = b"multipart/form-data; boundary=abcdefg; x=1".to_vec();
+        headers.set_raw("Content-Type", vec![lax]);
+        assert!(read_formdata(&mut &body[..], &headers).is_ok());
+    }
+
+    #[test]
+    fn random_source() {
+        let first = BoundaryStyle::Default.generate_with(&RandomSource::seeded(7)).unwrap();
+        let second = BoundaryStyle::Default.generate_with(&RandomSource::seeded(7)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 68);
+        assert!(first.iter().all(u8::is_ascii_alphanumeric));
+        let zeros = RandomSource::new(|buf: &mut [u8]| for b in buf.iter_mut() { *b = 0 });
+        assert_eq!(BoundaryStyle::Curl.generate_with(&zeros).unwrap(),
+                   format!("{}{}", "-".repeat(24), "0".repeat(16)).into_bytes());
+
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg--";
+        let tmpdir = tempdir::TempDir::new("formdata_random").unwrap();
+        let parse = |seed: u64, dir: &str| {
+            let dir = tmpdir.path().join(dir);
+            std::fs::create_dir(&dir).unwrap();
+            let config = ParseConfig { temp_dir: Some(dir.clone()),
+                                       random: Some(RandomSource::seeded(seed)),
+                                       ..ParseConfig::default() };
+            let formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                     &config).unwrap();
+            let path = formdata.get_file("f").unwrap().path.clone();
+            path.strip_prefix(&dir).unwrap().to_owned()
         };
+        assert_eq!(parse(7, "a"), parse(7, "b"));
+        assert!(parse(7, "c") != parse(8, "d"));
+    }
+
+    #[test]
+    fn buffers_from_pool() {
+        let pool = BufferPool::new(4);
+        let config = ParseConfig { buffer_pool: Some(pool.clone()), ..ParseConfig::default() };
+        let body = bench::many_fields_body("abcdefg", 10);
+        for _ in 0..3 {
+            let formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                     &config).unwrap();
+            assert_eq!(formdata.fields.len(), 10);
+            assert_eq!(pool.idle(), 2);
+        }
 
+        let formdata = FormData {
+            fields: vec![ ("a".into(), "b".into()) ],
+            ..FormData::new()
+        };
+        let config = WriteConfig { buffer_pool: Some(pool.clone()), ..WriteConfig::default() };
         let mut output: Vec<u8> = Vec::new();
-        let boundary = generate_boundary();
-        match write_formdata(&mut output, &boundary, &formdata) {
-            Ok(count) => assert_eq!(count, 568),
-            Err(e) => panic!("Unable to write formdata: {}", e),
+        write_formdata_with_config(&mut output, b"abcdefg", &formdata, &config).unwrap();
+        assert_eq!(pool.idle(), 2);
+    }
+
+    #[test]
+    fn rate_limited_read() {
+        let body = bench::many_fields_body("abcdefg", 10);
+        let limiter = RateLimiter::new(body.len() as u64 * 5, 0);
+        let config = ParseConfig { rate_limiter: Some(limiter), ..ParseConfig::default() };
+        let start = std::time::Instant::now();
+        read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"), &config).unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+    }
+
+    #[test]
+    fn formdata_mutation() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let path = tmpdir.path().join("a.txt");
+        fs::write(&path, b"a").unwrap();
+        let mut group = FormData::new();
+        group.fields.push(("mail".into(), "plain".into()));
+        let mut formdata = FormData {
+            fields: vec![("name".into(), "Mike".into()), ("age".into(), "46".into()),
+                         ("name".into(), "Michael".into())],
+            files: vec![("photo".to_owned(), FilePart::new(Headers::new(), &path))],
+            groups: vec![("mail".to_owned(), group)],
+            ..FormData::new()
+        };
+
+        assert_eq!(formdata.remove_field("name"), 2);
+        assert_eq!(formdata.fields, vec![("age".into(), "46".into())]);
+
+        assert_eq!(formdata.rename_field("mail", "message"), 1);
+        assert_eq!(formdata.groups[0].0, "message");
+        assert_eq!(formdata.groups[0].1.get_field("message"), Some("plain"));
+
+        let other = tmpdir.path().join("b.txt");
+        let old = formdata.replace_file("photo", FilePart::new(Headers::new(), &other));
+        assert_eq!(old.unwrap().path, path);
+        assert!(formdata.replace_file("extra", FilePart::new(Headers::new(), &path)).is_none());
+        assert_eq!(formdata.files.len(), 2);
+
+        formdata.retain(|part| match part {
+            PartRef::File(name, _) => name == "photo",
+            part => part.name() != "message",
+        });
+        assert_eq!(formdata.files.len(), 1);
+        assert_eq!(formdata.files[0].1.path, other);
+        assert!(formdata.groups.is_empty());
+        assert_eq!(formdata.fields.len(), 1);
+    }
+
+    #[test]
+    fn clone_file_part() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"doc\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     content\r\n\
+                     --abcdefg--";
+        let mut formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
+        let (_, original) = formdata.files.remove(0);
+        let copy = original.clone_file().unwrap();
+        assert_ne!(copy.path, original.path);
+        assert_eq!(copy.size, Some(7));
+        let original_path = original.path.clone();
+        drop(original);
+        assert!(!original_path.exists());
+        assert_eq!(fs::read(&copy.path).unwrap(), b"content");
+
+        let copy_path = copy.path.clone();
+        drop(copy);
+        assert!(!copy_path.exists());
+    }
+
+    #[test]
+    fn multiple_file_order() {
+        let mut body: Vec<u8> = Vec::new();
+        for (name, filename) in &[("photos", "c.png"), ("doc", "cv.pdf"), ("photos", "a.png"),
+                                  ("photos", "b.png")] {
+            write!(body, "--abcdefg\r\n\
+                          Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\
+                          \r\n\
+                          {}\r\n", name, filename, filename).unwrap();
+            write!(body, "--abcdefg\r\n\
+                          Content-Disposition: form-data; name=\"captions\"\r\n\
+                          \r\n\
+                          about {}\r\n", filename).unwrap();
         }
+        body.extend_from_slice(b"--abcdefg--");
+        let formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
 
-        println!("{}", String::from_utf8_lossy(&output));
+        let files: Vec<(&str, String, Option<usize>)> = formdata.files.iter()
+            .map(|(name, file)| (&name[..], file.filename().unwrap().unwrap(), file.index()))
+            .collect();
+        assert_eq!(files, vec![("photos", "c.png".to_owned(), Some(0)),
+                               ("doc", "cv.pdf".to_owned(), Some(0)),
+                               ("photos", "a.png".to_owned(), Some(1)),
+                               ("photos", "b.png".to_owned(), Some(2))]);
+        assert_eq!(formdata.fields[2], ("captions".into(), "about a.png".into()));
+        assert_eq!(formdata.files[2].1.clone_file().unwrap().index(), Some(1));
+        assert_eq!(FilePart::new(Headers::new(), ::std::path::Path::new("x")).index(), None);
+
+        // Changing the files renumbers them
+        let mut formdata = formdata;
+        formdata.retain(|part| match part {
+            PartRef::File(_, file) => file.filename().unwrap() != Some("c.png".to_owned()),
+            _ => true,
+        });
+        formdata.rename_field("doc", "photos");
+        let indexes: Vec<Option<usize>> = formdata.files.iter().map(|f| f.1.index()).collect();
+        assert_eq!(indexes, vec![Some(0), Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn quarantine() {
+        let held = tempdir::TempDir::new("formdata_test").unwrap();
+        let uploads = tempdir::TempDir::new("formdata_test").unwrap();
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     clean\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"b\"; filename=\"b.txt\"\r\n\
+                     \r\n\
+                     EICAR\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+        let held_dir = held.path().to_owned();
+        let scanner = Quarantine::new(held.path()).scanner(move |_, file| {
+            assert!(file.path.starts_with(&held_dir));
+            match file.read_to_vec(100) {
+                Ok(ref content) if content == b"EICAR" => Err("infected".to_owned()),
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.to_string()),
+            }
+        });
+        let entries = |dir: &::std::path::Path| fs::read_dir(dir).unwrap().count();
+
+        let config = ParseConfig { temp_dir: Some(uploads.path().to_owned()),
+                                   quarantine: Some(Quarantine::new(held.path())),
+                                   ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(entries(held.path()), 0);
+        assert_eq!(entries(uploads.path()), 2);
+        let file = &formdata.files[0].1;
+        assert!(file.path.starts_with(uploads.path()));
+        assert_eq!(file.read_to_vec(100).unwrap(), b"clean");
+        drop(formdata);
+        assert_eq!(entries(uploads.path()), 0);
+
+        let config = ParseConfig { quarantine: Some(scanner), ..config };
+        match read_formdata_with_config(&mut &body[..], &headers, &config) {
+            Err(Error::ScanRejected(ref reason)) => assert_eq!(reason, "b: infected"),
+            other => panic!("expected ScanRejected, got {:?}", other),
+        }
+        assert_eq!(entries(held.path()), 0);
+        assert_eq!(entries(uploads.path()), 0);
     }
 
+    #[test]
+    fn cleanup_on_error() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     complete\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"b\"; filename=\"b.txt\"\r\n\
+                     \r\n\
+                     0123456789012345678901234567890123456789\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+        let entries = |dir: &::std::path::Path| fs::read_dir(dir).unwrap().count();
+        let config = ParseConfig { temp_dir: Some(tmpdir.path().to_owned()),
+                                   ..ParseConfig::default() };
+
+        // The body ends part way through the second file
+        match read_formdata_with_config(&mut &body[..body.len() - 30], &headers, &config) {
+            Err(Error::Eof(_)) => {},
+            other => panic!("expected Eof, got {:?}", other),
+        }
+        assert_eq!(entries(tmpdir.path()), 0);
+
+        // The second file is too large
+        let limited = ParseConfig { max_file_size: Some(20), ..config.clone() };
+        match read_formdata_with_config(&mut &body[..], &headers, &limited) {
+            Err(Error::FileTooLarge) => {},
+            other => panic!("expected FileTooLarge, got {:?}", other),
+        }
+        assert_eq!(entries(tmpdir.path()), 0);
+
+        // A file still held elsewhere is deleted all the same
+        let held: Arc<Mutex<Vec<FilePart>>> = Arc::new(Mutex::new(Vec::new()));
+        let holder = held.clone();
+        let scanner = Quarantine::new(tmpdir.path()).scanner(move |name, file| {
+            holder.lock().unwrap().push(file.clone());
+            if name == "b" { Err("rejected".to_owned()) } else { Ok(()) }
+        });
+        let scanned = ParseConfig { quarantine: Some(scanner), ..config.clone() };
+        assert!(read_formdata_with_config(&mut &body[..], &headers, &scanned).is_err());
+        assert_eq!(held.lock().unwrap().len(), 2);
+        assert_eq!(entries(tmpdir.path()), 0);
+        held.lock().unwrap().clear();
+
+        let kept = ParseConfig { keep_files_on_error: true, ..limited };
+        assert!(read_formdata_with_config(&mut &body[..], &headers, &kept).is_err());
+        assert_eq!(entries(tmpdir.path()), 2);
+    }
+
+    #[test]
+    fn spill_over() {
+        let primary = tempdir::TempDir::new("formdata_test").unwrap();
+        let secondary = tempdir::TempDir::new("formdata_test").unwrap();
+        let mut file = FilePart::create_in(primary.path(), Headers::new()).unwrap();
+        File::create(&file.path).unwrap().write_all(b"hello, wor").unwrap();
+        let original_path = file.path.clone();
+
+        let dir = secondary.path().to_owned();
+        let spill_over = SpillOver::new()
+            .allocate_with(|_, _| None)
+            .allocate_with(move |name, needed| {
+                assert_eq!((name, needed), ("doc", 8));
+                Some(dir.clone())
+            });
+        let mut out = spill_over.move_file("doc", &mut file, 5, b"!!!", 0o600, None).unwrap();
+        out.write_all(b"?").unwrap();
+        assert!(file.path.starts_with(secondary.path()));
+        assert!(!original_path.exists());
+        assert_eq!(fs::read(&file.path).unwrap(), b"hello!!!?");
+
+        match SpillOver::new().move_file("doc", &mut file, 9, b"", 0o600, None) {
+            Err(Error::StorageFull) => {},
+            other => panic!("expected StorageFull, got {:?}", other.map(|_| ())),
+        }
+        let full = ::spill::storage_error(io::Error::from(io::ErrorKind::StorageFull));
+        assert_eq!(full.http_status(), 507);
+    }
+
+    #[test]
+    fn shared_temp_files() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"doc\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     content\r\n\
+                     --abcdefg--";
+        let formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
+        let path = formdata.files[0].1.path.clone();
+        let clone = formdata.clone();
+        drop(formdata);
+        assert!(path.exists());
+
+        // Saving one clone copies the file, leaving it for the other
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let mut saved = clone.clone();
+        let dest = saved.files[0].1.save_to_dir(tmpdir.path()).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"content");
+        assert_eq!(fs::read(&path).unwrap(), b"content");
+        drop(saved);
+        assert!(path.exists() && dest.exists());
+
+        drop(clone);
+        assert!(!path.exists() && !path.parent().unwrap().exists());
+        assert!(dest.exists());
+    }
+
+    #[test]
+    fn thread_safety() {
+        // Fails to compile, rather than to run, if any of these lose Send or Sync
+        fn send_sync<T: Send + Sync>() {}
+        send_sync::<ParseConfig>();
+        send_sync::<std::sync::Arc<ParseConfig>>();
+        send_sync::<super::ParseConfigBuilder>();
+        send_sync::<WriteConfig>();
+        send_sync::<PartFilters>();
+        send_sync::<super::DefaultHeaders>();
+        fn send<T: Send>() {}
+        send::<FormData>();
+        send::<FilePart>();
+        send::<Error>();
+        send::<FormBody>();
+        send::<super::BodyChunks>();
+
+        let body = bench::many_fields_body("abcdefg", 2);
+        let formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
+        let fields = std::thread::spawn(move || formdata.fields.len()).join().unwrap();
+        assert_eq!(fields, 2);
+    }
+
+    #[test]
+    fn validate_before_write() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let good = tmpdir.path().join("good");
+        fs::write(&good, b"content").unwrap();
+        let mut wrong_size = FilePart::new(Headers::new(), &good);
+        wrong_size.size = Some(3);
+        let mut latin1 = Headers::new();
+        latin1.set_raw("X-Note", vec![b"caf\xe9".to_vec()]);
+        let mut formdata = FormData::new();
+        formdata.files = vec![
+            ("good".to_owned(), FilePart::new(Headers::new(), &good)),
+            ("missing".to_owned(), FilePart::new(Headers::new(), &tmpdir.path().join("none"))),
+            ("dir".to_owned(), FilePart::new(Headers::new(), tmpdir.path())),
+            ("sized".to_owned(), wrong_size),
+            ("header".to_owned(), FilePart::new(latin1, &good)),
+        ];
+
+        let problems = formdata.validate_for_write().unwrap_err();
+        let names: Vec<&str> = problems.iter().map(|p| &*p.0).collect();
+        assert_eq!(names, vec!["missing", "dir", "sized", "header"]);
+        match problems[0].1 {
+            Error::Io(ref e) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+            ref other => panic!("unexpected error: {:?}", other),
+        }
+        match (&problems[1].1, &problems[2].1, &problems[3].1) {
+            (&Error::NotAFile, &Error::InvalidPart(_), &Error::InvalidPart(_)) => {},
+            other => panic!("unexpected errors: {:?}", other),
+        }
+
+        formdata.files.truncate(1);
+        assert!(formdata.validate_for_write().is_ok());
+    }
+
+    #[test]
+    fn boundary_styles() {
+        let webkit = BoundaryStyle::WebKit.generate().unwrap();
+        assert!(webkit.starts_with(b"----WebKitFormBoundary") && webkit.len() == 38);
+        assert!(webkit[22..].iter().all(u8::is_ascii_alphanumeric));
+        let firefox = BoundaryStyle::Firefox.generate().unwrap();
+        assert!(firefox[..27].iter().all(|&b| b == b'-'));
+        assert!(firefox[27..].iter().all(u8::is_ascii_digit) && firefox.len() == 55);
+        let curl = BoundaryStyle::Curl.generate().unwrap();
+        assert!(curl[..24].iter().all(|&b| b == b'-') && curl.len() == 40);
+        let uuid = String::from_utf8(BoundaryStyle::OkHttp.generate().unwrap()).unwrap();
+        let groups: Vec<usize> = uuid.split('-').map(str::len).collect();
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+        assert_eq!(&uuid[14..15], "4");
+        let python = BoundaryStyle::PythonRequests.generate().unwrap();
+        assert!(python.len() == 32 && python.iter().all(u8::is_ascii_hexdigit));
+        assert_ne!(BoundaryStyle::WebKit.generate().unwrap(), webkit);
+
+        let prefixed = BoundaryStyle::Prefixed("acme-".to_owned()).generate().unwrap();
+        assert!(prefixed.starts_with(b"acme-") && prefixed.len() == 29);
+        match BoundaryStyle::Prefixed("x".repeat(47)).generate() {
+            Err(Error::InvalidBoundary) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match BoundaryStyle::Prefixed("a\"b".to_owned()).generate() {
+            Err(Error::InvalidBoundary) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    // Check each request captured from a real client in `tests/compat` against the golden
+    // description beside it.  See `tests/compat/README.md`.
+    #[cfg(feature = "compat")]
+    #[test]
+    fn compat_corpus() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/compat");
+        let mut captures: Vec<_> = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "http"))
+            .collect();
+        captures.sort();
+        assert!(!captures.is_empty());
+        for capture in captures {
+            let request = fs::read(&capture).unwrap();
+            let split = ::parser::find(&request, b"\r\n\r\n").unwrap();
+            let formdata = read_captured(&request[..split], &request[split + 4..],
+                                         BodyEncoding::Raw, &ParseConfig::default())
+                .unwrap_or_else(|e| panic!("{}: {}", capture.display(), e));
+
+            let mut described = String::new();
+            for (name, value) in &formdata.fields {
+                described.push_str(&format!("field {:?} {:?}\n", name, value));
+            }
+            for (name, file) in &formdata.files {
+                let content_type = file.content_type().map(|m| m.to_string());
+                described.push_str(&format!("file {:?} {:?} {} {}\n", name,
+                                            file.filename().unwrap().unwrap_or_default(),
+                                            content_type.as_deref().unwrap_or("-"),
+                                            file.size.unwrap()));
+            }
+            let expected = fs::read_to_string(capture.with_extension("expected")).unwrap();
+            assert_eq!(described, expected, "{}", capture.display());
+        }
+    }
+
+    #[test]
+    fn config_builder() {
+        let config = ParseConfig::builder().max_parts(Some(2)).build();
+        assert_eq!(config.max_file_size, Some(50 * 1024 * 1024));
+        assert_eq!(config.field_memory_limit, Some(2 * 1024 * 1024));
+        assert_eq!(config.max_parts, Some(2));
+
+        let body = bench::many_fields_body("abcdefg", 2);
+        let shared = config.clone();
+        let handle = std::thread::spawn(move || {
+            read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"), &shared)
+        });
+        assert_eq!(handle.join().unwrap().unwrap().fields.len(), 2);
+
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"doc\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     0123456789\r\n\
+                     --abcdefg--";
+        let config = ParseConfig::builder().max_file_size(Some(9)).build();
+        match read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"), &config) {
+            Err(Error::FileTooLarge) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let config = ParseConfig::builder().max_file_size(Some(10)).build();
+        read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"), &config).unwrap();
+    }
+
+    #[test]
+    fn config_from_env() {
+        std::env::set_var("FORMDATA_MAX_FILE_SIZE", "3M");
+        std::env::set_var("FORMDATA_MAX_PARTS", "none");
+        std::env::set_var("FORMDATA_MAX_DURATION_SECS", "30");
+        std::env::set_var("FORMDATA_TEMP_DIR", "/srv/uploads");
+        let config = ParseConfig::builder().apply_env().unwrap().build();
+        assert_eq!(config.max_file_size, Some(3 * 1024 * 1024));
+        assert_eq!(config.max_parts, None);
+        assert_eq!(config.max_duration, Some(std::time::Duration::from_secs(30)));
+        assert_eq!(config.temp_dir, Some("/srv/uploads".into()));
+
+        std::env::set_var("FORMDATA_MAX_FILES", "lots");
+        match ParseConfig::builder().apply_env() {
+            Err(Error::InvalidConfig(name)) => assert_eq!(name, "FORMDATA_MAX_FILES"),
+            other => panic!("unexpected result: {:?}", other.map(|b| b.build())),
+        }
+        for name in &["FORMDATA_MAX_FILE_SIZE", "FORMDATA_MAX_PARTS", "FORMDATA_MAX_DURATION_SECS",
+                      "FORMDATA_TEMP_DIR", "FORMDATA_MAX_FILES"] {
+            std::env::remove_var(name);
+        }
+    }
+
+    #[test]
+    fn max_parse_duration() {
+        let body = bench::many_fields_body("abcdefg", 10);
+        let headers = bench::headers("abcdefg");
+        let limiter = RateLimiter::new(body.len() as u64 * 4, 0);
+        let config = ParseConfig { rate_limiter: Some(limiter),
+                                   max_duration: Some(std::time::Duration::from_millis(50)),
+                                   ..ParseConfig::default() };
+        match read_formdata_with_config(&mut &body[..], &headers, &config) {
+            Err(Error::ParseTimeout) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let config = ParseConfig { max_duration: Some(std::time::Duration::from_secs(60)),
+                                   ..ParseConfig::default() };
+        read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+    }
+
+    #[test]
+    fn recorded_body() {
+        let body = bench::many_fields_body("abcdefg", 3);
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let path = tmpdir.path().join("body");
+        let recorder = BodyRecorder::new(File::create(&path).unwrap());
+        let config = ParseConfig { record_body: Some(recorder), ..ParseConfig::default() };
+        read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"), &config).unwrap();
+
+        // Recorded even when parsing fails
+        let truncated = &body[..body.len() - 4];
+        assert!(read_formdata_with_config(&mut &truncated[..], &bench::headers("abcdefg"),
+                                          &config).is_err());
+        let recorded = fs::read(&path).unwrap();
+        assert_eq!(recorded, [&body[..], truncated].concat());
+    }
+
+    #[test]
+    fn replayed_body() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let path = tmpdir.path().join("body");
+        let body = bench::many_fields_body("abcdefg", 3);
+        File::create(&path).unwrap().write_all(&body).unwrap();
+
+        let config = ParseConfig::default();
+        let formdata = replay::parse_file(&path, "multipart/form-data; boundary=abcdefg",
+                                          &config).unwrap();
+        assert_eq!(formdata.fields.len(), 3);
+        let formdata = replay::parse_file(&path, "content-type: multipart/form-data; \
+                                                  boundary=abcdefg\r\n", &config).unwrap();
+        assert_eq!(formdata.fields.len(), 3);
+
+        let config = ParseConfig { max_parts: Some(2), ..ParseConfig::default() };
+        match replay::parse_file(&path, "multipart/form-data; boundary=abcdefg", &config) {
+            Err(Error::TooManyParts(_)) => {},
+            other => panic!("expected TooManyParts, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn quirk_presets() {
+        // Bare LFs mixed with CRLFs, a Windows path and an unescaped quote in the filename
+        let body = b"--abcdefg\n\
+                     Content-Disposition: form-data; name=\"note\"\r\n\
+                     \n\
+                     hello\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"photo\"; \
+                     filename=\"C:\\Users\\me\\my \"best\" photo.png\"\n\
+                     \n\
+                     image data\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+
+        let config = ParseConfig::new().quirks(Quirks::IE11 | Quirks::ANDROID_WEBVIEW);
+        let form_data = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(form_data.get_field("note"), Some("hello"));
+        let file = form_data.get_file("photo").unwrap();
+        assert_eq!(file.filename().unwrap().unwrap(), "my \"best\" photo.png");
+        assert_eq!(file.read_to_string(100).unwrap(), "image data");
+    }
+
+    #[test]
+    fn captured_request() {
+        let head = b"POST /upload HTTP/1.1\r\n\
+                     Host: example.domain\r\n\
+                     Content-Type: multipart/form-data; boundary=abcdefg\r\n";
+        let body = b"LS1hYmNkZWZnDQpDb250ZW50LURpc3Bvc2l0aW9uOiBmb3JtLWRhdGE7IG5hbWU9InEiDQoNCmhp\n\
+                     IHRoZXJlDQotLWFiY2RlZmctLQ0K";
+        let config = ParseConfig::default();
+        let formdata = read_captured(head, body, BodyEncoding::Base64, &config).unwrap();
+        assert_eq!(formdata.get_field("q"), Some("hi there"));
+
+        // Headers alone, without the request line
+        let formdata = read_captured(&head[23..], b"--abcdefg\r\n\r\nhi\r\n--abcdefg--",
+                                     BodyEncoding::Raw, &config);
+        match formdata {
+            Err(Error::MissingDisposition) => {},
+            other => panic!("expected MissingDisposition, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn precheck_headers() {
+        let config = ParseConfig { max_body_size: Some(1000), ..ParseConfig::default() };
+        let mut headers = bench::headers("abcdefg");
+        assert!(precheck(&headers, &config).is_ok());
+
+        headers.set(ContentLength(1001));
+        match precheck(&headers, &config) {
+            Err(Error::BodyTooLarge) => {},
+            other => panic!("expected BodyTooLarge, got {:?}", other),
+        }
+
+        headers.set(ContentType(Mime(TopLevel::Multipart, SubLevel::Ext("mixed".to_owned()),
+                                     vec![])));
+        match precheck(&headers, &config) {
+            Err(Error::NotFormData) => {},
+            other => panic!("expected NotFormData, got {:?}", other),
+        }
+        headers.remove::<ContentType>();
+        match precheck(&headers, &config) {
+            Err(Error::NoRequestContentType) => {},
+            other => panic!("expected NoRequestContentType, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn multipart_descriptor() {
+        let mut headers = Headers::new();
+        headers.set(ContentType(Mime(TopLevel::Multipart, SubLevel::FormData,
+                                     vec![(Attr::Boundary, Value::Ext("abcdefg".to_owned())),
+                                          (Attr::Charset, Value::Utf8)])));
+        headers.set(ContentLength(56));
+        let descriptor = MultipartDescriptor::from_headers(&headers).unwrap();
+        assert_eq!(descriptor.boundary, b"abcdefg");
+        assert_eq!(descriptor.charset, Some("utf-8".to_owned()));
+        assert_eq!(descriptor.content_length, Some(56));
+
+        let config = ParseConfig { max_body_size: Some(50), ..ParseConfig::default() };
+        match descriptor.check(&config) {
+            Err(Error::BodyTooLarge) => {},
+            other => panic!("expected BodyTooLarge, got {:?}", other),
+        }
+
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg--";
+        let formdata = descriptor.parse(&mut &body[..]).unwrap();
+        assert_eq!(formdata.fields, vec![("a".into(), "1234".into())]);
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn http_header_map() {
+        let mut headers = ::http::HeaderMap::new();
+        headers.insert(::http::header::CONTENT_TYPE,
+                       "multipart/form-data; boundary=abcdefg".parse().unwrap());
+        headers.insert(::http::header::CONTENT_LENGTH, "56".parse().unwrap());
+        let descriptor = MultipartDescriptor::from_headers(&headers).unwrap();
+        assert_eq!(descriptor.content_length, Some(56));
+        headers.insert(::http::header::TRANSFER_ENCODING, "chunked".parse().unwrap());
+        let descriptor = MultipartDescriptor::from_headers(&headers).unwrap();
+        assert_eq!(descriptor.content_length, None);
+
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg--";
+        let formdata = read_formdata(&mut &body[..], &headers).unwrap();
+        assert_eq!(formdata.fields, vec![("a".into(), "1234".into())]);
+    }
+
+    #[test]
+    fn part_content_type() {
+        let mut headers = Headers::new();
+        headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Plain,
+                                     vec![(Attr::Charset, Value::Utf8)])));
+        let file = FilePart::new(headers, ::std::path::Path::new("/dev/null"));
+        assert_eq!(file.charset(), Some("utf-8"));
+        assert_eq!(file.mime_essence(), Some("text/plain".to_owned()));
+
+        let file = FilePart::new(Headers::new(), ::std::path::Path::new("/dev/null"));
+        assert_eq!(file.charset(), None);
+        assert_eq!(file.mime_essence(), None);
+    }
+
+    #[test]
+    fn field_normalization() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     \x20one\r\ntwo\t\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"b\"\r\n\
+                     \r\n\
+                     \x20\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"c\"\r\n\
+                     \r\n\
+                     three\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+        let formdata = read_formdata(&mut &body[..], &headers).unwrap();
+        assert_eq!(formdata.fields.len(), 3);
+        assert!(formdata.warnings.is_empty());
+
+        let config = ParseConfig {
+            trim_values: true,
+            normalize_newlines: true,
+            drop_empty_fields: true,
+            ..ParseConfig::default()
+        };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(formdata.fields, vec![("a".into(), "one\ntwo".into()),
+                                         ("c".into(), "three".into())]);
+        assert_eq!(formdata.warnings, vec![FieldWarning::NewlinesNormalized("a".to_owned()),
+                                           FieldWarning::Trimmed("a".to_owned()),
+                                           FieldWarning::Trimmed("b".to_owned()),
+                                           FieldWarning::DroppedEmpty("b".to_owned())]);
+    }
+
+    #[test]
+    fn checkbox_fields() {
+        let formdata = FormData {
+            // What a browser sends for: a checked box, an unchecked box with a hidden
+            // fallback, a checked box with a hidden fallback, and a checked box with a value.
+            fields: vec![ ("subscribe".into(), "on".into()),
+                          ("terms".into(), "0".into()),
+                          ("admin".into(), "false".into()),
+                          ("admin".into(), "true".into()),
+                          ("colour".into(), "red".into()) ],
+            ..FormData::new()
+        };
+        assert!(formdata.is_checked("subscribe"));
+        assert!(!formdata.is_checked("terms"));
+        assert!(formdata.is_checked("admin"));
+        assert!(formdata.is_checked("colour"));
+        // An unchecked box without a fallback is not sent at all
+        assert!(!formdata.is_checked("newsletter"));
+
+        assert!(formdata.get_bool("subscribe", false));
+        assert!(!formdata.get_bool("terms", true));
+        assert!(formdata.get_bool("admin", false));
+        assert!(formdata.get_bool("colour", true));
+        assert!(!formdata.get_bool("colour", false));
+        assert!(formdata.get_bool("newsletter", true));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn date_fields() {
+        use chrono::{NaiveDate, NaiveTime};
+
+        let formdata = FormData {
+            fields: vec![ ("date".into(), "2017-06-01".into()),
+                          ("time".into(), "08:30".into()),
+                          ("seconds".into(), "08:30:15.25".into()),
+                          ("datetime".into(), "2017-06-01T08:30:15".into()),
+                          ("month".into(), "2017-06".into()),
+                          ("week".into(), "2017-W01".into()),
+                          ("blank".into(), "".into()),
+                          ("bad".into(), "2017-6-1".into()) ],
+            ..FormData::new()
+        };
+        let date = NaiveDate::from_ymd_opt(2017, 6, 1).unwrap();
+        assert_eq!(formdata.get_date("date").unwrap(), Some(date));
+        assert_eq!(formdata.get_time("time").unwrap(), NaiveTime::from_hms_opt(8, 30, 0));
+        assert_eq!(formdata.get_time("seconds").unwrap(),
+                   NaiveTime::from_hms_milli_opt(8, 30, 15, 250));
+        assert_eq!(formdata.get_datetime("datetime").unwrap(), date.and_hms_opt(8, 30, 15));
+        assert_eq!(formdata.get_month("month").unwrap(), Some(date));
+        assert_eq!(formdata.get_week("week").unwrap(), NaiveDate::from_ymd_opt(2017, 1, 2));
+        assert_eq!(formdata.get_date("blank").unwrap(), None);
+        assert_eq!(formdata.get_date("absent").unwrap(), None);
+        match formdata.get_date("bad") {
+            Err(Error::InvalidFieldValue(ref name)) if name == "bad" => {},
+            other => panic!("expected InvalidFieldValue, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mime_policy() {
+        fn body(filename: &str, content_type: &str) -> Vec<u8> {
+            format!("--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"{}\"\r\n\
+                     Content-Type: {}\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg--", filename, content_type).into_bytes()
+        }
+        let headers = bench::headers("abcdefg");
+        let policy = MimePolicy {
+            allowed_types: vec!["image/*".to_owned()],
+            denied_extensions: vec!["svg".to_owned()],
+            check_consistency: true,
+            ..MimePolicy::new()
+        };
+        let config = ParseConfig { mime_policy: Some(policy), ..ParseConfig::default() };
+
+        let formdata = read_formdata_with_config(&mut &body("C:\\My Photo.PNG", "image/png")[..],
+                                                 &headers, &config).unwrap();
+        assert_eq!(formdata.files[0].1.extension(), Some("png".to_owned()));
+
+        for &(filename, content_type) in &[("a.pdf", "application/pdf"),
+                                           ("a.svg", "image/svg+xml"),
+                                           ("a.exe", "image/png")] {
+            match read_formdata_with_config(&mut &body(filename, content_type)[..], &headers,
+                                            &config) {
+                Err(Error::FileNotAllowed(_)) => {},
+                other => panic!("expected FileNotAllowed for {}, got {:?}", filename, other),
+            }
+        }
+    }
+
+    #[test]
+    fn dedup_files() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"b\"; filename=\"b.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"c\"; filename=\"c.txt\"\r\n\
+                     \r\n\
+                     654321\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+        let config = ParseConfig { dedup: Some(Deduplicator::new()), ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        let files = &formdata.files;
+        assert_eq!(files[0].1.duplicate(), None);
+        assert_eq!(files[1].1.duplicate(), Some(&Duplicate::Earlier(0)));
+        assert_eq!(files[1].1.path, files[0].1.path);
+        assert_eq!(files[1].1.content_hash(), files[0].1.content_hash());
+        assert_eq!(fs::read(&files[1].1.path).unwrap(), b"123456");
+        assert_eq!(files[2].1.duplicate(), None);
+        assert!(files[2].1.content_hash() != files[0].1.content_hash());
+
+        let known = files[2].1.path.clone();
+        let hash = *files[2].1.content_hash().unwrap();
+        let lookup = move |h: &_| if *h == hash { Some(known.clone()) } else { None };
+        let config = ParseConfig {
+            dedup: Some(Deduplicator::with_lookup(lookup)),
+            ..ParseConfig::default()
+        };
+        let again = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(again.files[2].1.duplicate(), Some(&Duplicate::Known));
+        assert_eq!(again.files[2].1.path, files[2].1.path);
+    }
+
+    #[test]
+    fn unnamed_files() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; filename=\"C:\\docs\\a.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"\"; filename=\"b.txt\"\r\n\
+                     \r\n\
+                     654321\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+        let names = |unnamed_files| {
+            let config = ParseConfig { unnamed_files, ..ParseConfig::default() };
+            read_formdata_with_config(&mut &body[..], &headers, &config)
+                .map(|formdata| formdata.files.iter().map(|f| f.0.clone()).collect::<Vec<_>>())
+        };
+        match names(UnnamedFiles::Error) {
+            Err(Error::NoName) => {},
+            other => panic!("expected NoName, got {:?}", other),
+        }
+        assert_eq!(names(UnnamedFiles::Generate).unwrap(), vec!["file_0", "file_1"]);
+        assert_eq!(names(UnnamedFiles::Filename).unwrap(), vec!["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn file_dispositions() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: attachment; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: file; name=\"b\"\r\n\
+                     \r\n\
+                     654321\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+        match read_formdata(&mut &body[..], &headers) {
+            Err(Error::NoName) => {},
+            other => panic!("expected NoName, got {:?}", other),
+        }
+
+        let config = ParseConfig { file_dispositions: true, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert!(formdata.fields.is_empty());
+        assert_eq!(formdata.files.len(), 2);
+        assert_eq!(formdata.files[0].0, "a.txt");
+        assert_eq!(formdata.files[1].0, "b");
+        assert_eq!(formdata.files[1].1.size, Some(6));
+    }
+
+    #[test]
+    fn redacted_output() {
+        let formdata = FormData {
+            fields: vec![ ("user".into(), "mike".into()),
+                          ("Password".into(), "hunter2".into()),
+                          ("bio".into(), "x".repeat(100).into()) ],
+            ..FormData::new()
+        };
+        let sensitive = SensitiveFields::default();
+        let shown = format!("{}", formdata.redacted(&sensitive));
+        assert_eq!(shown, format!("user=mike, Password=[REDACTED], bio={}... (100 bytes)",
+                                  "x".repeat(64)));
+        assert!(!format!("{:?}", formdata.redacted(&sensitive)).contains("hunter2"));
+    }
+
+    #[test]
+    fn parse_stats() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg--";
+        let config = ParseConfig { timing: true, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                 &config).unwrap();
+        let stats = formdata.stats();
+        assert_eq!((stats.parts, stats.fields, stats.files), (2, 1, 1));
+        assert_eq!(stats.part_bytes, vec![4, 6]);
+        assert_eq!(stats.header_bytes, 44 + 62);
+        assert_eq!(stats.body_bytes, body.len() as u64);
+        assert!(stats.elapsed.is_some());
+    }
+
+    #[test]
+    fn owned_stream() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg--\r\n\
+                     GET /next HTTP/1.1\r\n";
+        let headers = bench::headers("abcdefg");
+        let (mut stream, result) = read_formdata_owned(::std::io::Cursor::new(&body[..]),
+                                                       &headers);
+        assert_eq!(result.unwrap().get_field("a"), Some("1234"));
+        assert!(stream.position() <= body.len() as u64);
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(body.ends_with(&rest));
+
+        let (_, result) = read_formdata_owned(&b"--abcdefg\r\n"[..], &headers);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn compare_formdata() {
+        let body = |content: &str| format!("--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\
+                     Content-Type: text/plain\r\n\
+                     \r\n\
+                     {}\r\n\
+                     --abcdefg--", content);
+        let headers = bench::headers("abcdefg");
+        let parse = |body: String| read_formdata(&mut body.as_bytes(), &headers).unwrap();
+        let first = parse(body("123456"));
+        let second = parse(body("123456"));
+        let changed = parse(body("654321"));
+
+        assert!(first != second);
+        assert!(first.eq_metadata(&second));
+        assert!(first.eq_deep(&second).unwrap());
+        assert!(first.eq_metadata(&changed));
+        assert!(!first.eq_deep(&changed).unwrap());
+
+        let mut renamed = parse(body("123456"));
+        renamed.fields[0].1 = "5678".into();
+        assert!(!first.eq_metadata(&renamed));
+    }
+
+    #[test]
+    fn part_timings() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+        let formdata = read_formdata(&mut &body[..], &headers).unwrap();
+        assert!(formdata.stats().part_timings.is_empty());
+
+        let config = ParseConfig { timing: true, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        let timings = &formdata.stats().part_timings;
+        let parts: Vec<(&str, u64)> = timings.iter().map(|t| (&*t.name, t.bytes)).collect();
+        assert_eq!(parts, vec![("a", 4), ("f", 6)]);
+        assert_eq!(timings[0].write, ::std::time::Duration::ZERO);
+        assert!(timings.iter().all(|t| t.write <= t.receive));
+    }
+
+    #[test]
+    fn partial_on_eof() {
+        let mut body = b"--abcdefg\r\n\
+                         Content-Disposition: form-data; name=\"a\"\r\n\
+                         \r\n\
+                         1234\r\n\
+                         --abcdefg\r\n\
+                         Content-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\
+                         \r\n".to_vec();
+        body.extend_from_slice(&[b'x'; 100]);
+        let headers = bench::headers("abcdefg");
+
+        let interrupted = match read_formdata(&mut &body[..], &headers) {
+            Err(Error::Eof(Some(interrupted))) => interrupted,
+            other => panic!("expected Eof, got {:?}", other),
+        };
+        assert_eq!((interrupted.index, &*interrupted.name), (1, "f"));
+        assert!(interrupted.received > 0 && interrupted.received <= 100);
+
+        let config = ParseConfig { keep_partial: true, ..ParseConfig::default() };
+        let formdata = match read_formdata_with_config(&mut &body[..], &headers, &config) {
+            Err(Error::EofWithPartial(formdata, Some(part))) => {
+                assert_eq!(part, interrupted);
+                formdata
+            },
+            other => panic!("expected EofWithPartial, got {:?}", other),
+        };
+        assert_eq!(formdata.fields, vec![("a".into(), "1234".into())]);
+        assert_eq!(formdata.files.len(), 1);
+        let size = formdata.files[0].1.size.unwrap();
+        assert_eq!(size as u64, interrupted.received);
+        assert_eq!(fs::read(&formdata.files[0].1.path).unwrap(), vec![b'x'; size]);
+    }
+
+    #[test]
+    fn nested_body_as_file() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"wrapped\"; filename=\"body\"\r\n\
+                     Content-Type: multipart/form-data; boundary=inner\r\n\
+                     \r\n\
+                     --inner\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --inner--\r\n\
+                     --abcdefg--";
+        let formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
+        assert!(formdata.fields.is_empty());
+        assert_eq!(formdata.files.len(), 1);
+        let inner = formdata.files[0].1.parse_as_formdata().unwrap();
+        assert_eq!(inner.fields, vec![("a".into(), "1234".into())]);
+    }
+
+    #[test]
+    fn raw_parts() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+        for &mode in &[RawParts::Ranges, RawParts::Bytes] {
+            // Arrive in pieces that split the parts
+            let mut stream = (&body[..30]).chain(&body[30..90]).chain(&body[90..]);
+            let config = ParseConfig { raw_parts: Some(mode), ..ParseConfig::default() };
+            let formdata = read_formdata_with_config(&mut stream, &headers, &config).unwrap();
+            let ranges: Vec<_> = formdata.raw_parts.iter().map(|p| p.range.clone()).collect();
+            assert_eq!(ranges, vec![11..59, 72..140]);
+            for part in &formdata.raw_parts {
+                let expected = match mode {
+                    RawParts::Ranges => None,
+                    RawParts::Bytes =>
+                        Some(body[part.range.start as usize..part.range.end as usize].to_vec()),
+                };
+                assert_eq!(part.bytes, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn in_place() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     1234\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg--";
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let body_path = tmpdir.path().join("body");
+        File::create(&body_path).unwrap().write_all(body).unwrap();
+        let headers = bench::headers("abcdefg");
+
+        let mut formdata = read_formdata_in_place(&body_path, &headers, &ParseConfig::default())
+            .unwrap();
+        assert_eq!(formdata.fields, vec![("a".into(), "1234".into())]);
+        let file = &formdata.files[0].1;
+        assert_eq!(file.path, body_path);
+        assert_eq!(file.source_range(), Some(134..140));
+        assert_eq!(file.read_to_vec(100).unwrap(), b"123456");
+
+        // Written out just as if the file had been copied out
+        let copied = read_formdata(&mut &body[..], &headers).unwrap();
+        let config = WriteConfig { part_filenames: true, ..WriteConfig::default() };
+        let (mut expected, mut output) = (Vec::new(), Vec::new());
+        write_formdata_with_config(&mut expected, b"abcdefg", &copied, &config).unwrap();
+        write_formdata_with_config(&mut output, b"abcdefg", &formdata, &config).unwrap();
+        assert_eq!(output, expected);
+        let (mut expected, mut output) = (Vec::new(), Vec::new());
+        write_formdata_chunked_with_config(&mut expected, b"abcdefg", &copied, &config).unwrap();
+        write_formdata_chunked_with_config(&mut output, b"abcdefg", &formdata, &config).unwrap();
+        assert_eq!(output, expected);
+
+        let file = &mut formdata.files[0].1;
+        file.materialize().unwrap();
+        assert_eq!(file.source_range(), None);
+        assert!(file.path != body_path);
+        assert_eq!(file.read_to_vec(100).unwrap(), b"123456");
+        assert_eq!(fs::read(&body_path).unwrap(), &body[..]);
+
+        // Copied out to where the config puts uploads, with its permissions
+        let uploads = tmpdir.path().join("uploads");
+        let config = ParseConfig { temp_dir: Some(uploads.clone()), file_mode: 0o640,
+                                   ..ParseConfig::default() };
+        let mut formdata = read_formdata_in_place(&body_path, &headers, &config).unwrap();
+        let file = &mut formdata.files[0].1;
+        file.materialize().unwrap();
+        assert!(file.path.starts_with(config.upload_dir()));
+        assert_eq!(file.read_to_vec(100).unwrap(), b"123456");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&file.path).unwrap().permissions().mode() & 0o777, 0o640);
+        }
+    }
+
+    #[test]
+    fn simple_writer() {
+        // Create a simple short file for testing
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let tmppath = tmpdir.path().join("testfile");
+        let mut tmpfile = File::create(tmppath.clone()).unwrap();
+        writeln!(tmpfile, "this is example file content").unwrap();
+
+        let mut photo_headers = Headers::new();
+        photo_headers.set(ContentType(Mime(TopLevel::Image, SubLevel::Gif, vec![])));
+        photo_headers.set(ContentDisposition {
+            disposition: DispositionType::Ext("form-data".to_owned()),
+            parameters: vec![DispositionParam::Ext("name".to_owned(), "photo".to_owned()),
+                             DispositionParam::Ext("filename".to_owned(), "mike.gif".to_owned())],
+        });
+
+        let formdata = FormData {
+            fields: vec![ ("name".into(), "Mike".into()),
+                            ("age".into(), "46".into()) ],
+            files: vec![ ("photo".to_owned(), FilePart::new(photo_headers, &tmppath)) ],
+            ..FormData::new()
+        };
+
+        let mut output: Vec<u8> = Vec::new();
+        let boundary = generate_boundary();
+        match write_formdata(&mut output, &boundary, &formdata) {
+            Ok(count) => assert_eq!(count, 568),
+            Err(e) => panic!("Unable to write formdata: {}", e),
+        }
+
+        println!("{}", String::from_utf8_lossy(&output));
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(Error::BodyTooLarge.code(), "body_too_large");
+        assert_eq!(Error::BodyTooLarge.http_status(), 413);
+        assert_eq!(Error::NotFormData.http_status(), 415);
+        assert_eq!(Error::Eof(None).code(), "unexpected_eof");
+
+        let err = read_formdata(&mut &b"--abcdefg\r\nContent-Disposition: form-data\r\n\r\n\
+                                         x\r\n--abcdefg--"[..],
+                                &bench::headers("abcdefg")).unwrap_err();
+        assert_eq!((err.code(), err.http_status()), ("no_name", 400));
+    }
+
+    #[test]
+    fn error_response() {
+        let err = Error::Io(::std::io::Error::other("/srv/uploads/tmp: disk full"));
+        let response = err.response();
+        assert_eq!(response.status, 500);
+        assert_eq!(response.code, "io");
+        assert!(!response.message.contains("/srv"));
+
+        let response = Error::FileNotAllowed("image/svg+xml".to_owned()).response();
+        assert_eq!((response.status, response.code), (415, "file_not_allowed"));
+        assert!(!response.message.contains("svg"));
+    }
+
+    #[test]
+    fn audited_rejections() {
+        struct Log(::std::sync::Mutex<Vec<RejectedPart>>);
+        impl AuditSink for ::std::sync::Arc<Log> {
+            fn rejected(&self, part: &RejectedPart) {
+                self.0.lock().unwrap().push(part.clone());
+            }
+        }
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     1\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"b\"; filename=\"C:\\b.exe\"\r\n\
+                     Content-Length: 1\r\n\
+                     \r\n\
+                     2\r\n\
+                     --abcdefg--";
+        let log = ::std::sync::Arc::new(Log(::std::sync::Mutex::new(Vec::new())));
+        let config = ParseConfig {
+            max_files: Some(1),
+            audit: Some(Auditor::new(log.clone())),
+            ..ParseConfig::default()
+        };
+        assert!(read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                          &config).is_err());
+        assert_eq!(*log.0.lock().unwrap(), vec![RejectedPart {
+            name: Some("b".to_owned()),
+            filename: Some("b.exe".to_owned()),
+            declared_size: Some(1),
+            reason: "too_many_files",
+        }]);
+    }
+
+    #[test]
+    fn tenant_partitioning() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg--";
+        let quota = TenantQuota::new(10);
+        let config = ParseConfig {
+            temp_dir: Some(tmpdir.path().to_owned()),
+            tenant: Some(Tenant { id: "acme/../corp".to_owned(), quota: Some(quota.clone()) }),
+            ..ParseConfig::default()
+        };
+        assert_eq!(config.upload_dir(), tmpdir.path().join("acme%2F..%2Fcorp"));
+
+        let formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                 &config).unwrap();
+        assert!(formdata.files[0].1.path.starts_with(tmpdir.path().join("acme%2F..%2Fcorp")));
+        assert_eq!(quota.used(), 6);
+
+        match read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"), &config) {
+            Err(Error::QuotaExceeded) => {},
+            other => panic!("expected QuotaExceeded, got {:?}", other),
+        }
+        assert_eq!(quota.used(), 6);
+        quota.release(6);
+        assert!(read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                          &config).is_ok());
+
+        // A request that fails part way through takes nothing from the quota
+        quota.reset();
+        let two = [&body[..body.len() - 11], &body[..]].concat();
+        match read_formdata_with_config(&mut &two[..], &bench::headers("abcdefg"), &config) {
+            Err(Error::QuotaExceeded) => {},
+            other => panic!("expected QuotaExceeded, got {:?}", other),
+        }
+        assert_eq!(quota.used(), 0);
+
+        let dirs: Vec<PathBuf> = ["acme/corp", "acme corp", "acme_corp", "acme%2Fcorp", "", "_"]
+            .iter()
+            .map(|id| Tenant::new(id).temp_dir(tmpdir.path()))
+            .collect();
+        for (i, dir) in dirs.iter().enumerate() {
+            assert!(dir.starts_with(tmpdir.path()) && dir != tmpdir.path());
+            assert!(!dirs[..i].contains(dir), "{} shares a directory", dir.display());
+        }
+    }
+
+    #[test]
+    fn base64_field_to_file() {
+        // Fed a few bytes at a time, so the value arrives in many pieces
+        struct Trickle<'a>(&'a [u8]);
+        impl<'a> Read for Trickle<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+                let n = self.0.len().min(buf.len()).min(3);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"avatar\"\r\n\
+                     \r\n\
+                     data:image/png;base64,aGVsbG8g\r\nd29ybGQ\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"note\"\r\n\
+                     \r\n\
+                     aGVsbG8=\r\n\
+                     --abcdefg--";
+        let config = ParseConfig {
+            field_decoders: vec![("avatar".to_owned(), FieldDecoder::Base64ToFile)],
+            ..ParseConfig::default()
+        };
+        let formdata = read_formdata_with_config(&mut Trickle(&body[..]),
+                                                 &bench::headers("abcdefg"), &config).unwrap();
+        assert_eq!(formdata.fields, vec![("note".into(), "aGVsbG8=".into())]);
+        let (ref name, ref file) = formdata.files[0];
+        assert_eq!(name, "avatar");
+        assert_eq!(file.read_to_string(100).unwrap(), "hello world");
+        assert_eq!(file.size, Some(11));
+        assert_eq!(file.content_type().unwrap().to_string(), "image/png");
+
+        let bad = b"--abcdefg\r\n\
+                    Content-Disposition: form-data; name=\"avatar\"\r\n\
+                    \r\n\
+                    not*base64\r\n\
+                    --abcdefg--";
+        match read_formdata_with_config(&mut &bad[..], &bench::headers("abcdefg"), &config) {
+            Err(Error::Decoding(_)) => {},
+            other => panic!("expected Decoding, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn json_parts() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"meta\"\r\n\
+                     Content-Type: application/json\r\n\
+                     \r\n\
+                     {\"title\": \"Holiday\", \"tags\": [1, 2]}\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"extra\"; filename=\"blob\"\r\n\
+                     Content-Type: application/ld+json\r\n\
+                     \r\n\
+                     [true]\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"photo\"; filename=\"a.png\"\r\n\
+                     Content-Type: image/png\r\n\
+                     \r\n\
+                     PNG\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+        let config = ParseConfig { parse_json: true, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert!(formdata.fields.is_empty());
+        assert_eq!(formdata.files.len(), 1);
+        assert_eq!(formdata.get_json("meta").unwrap()["title"], "Holiday");
+        assert_eq!(formdata.get_json("meta").unwrap()["tags"][1], 2);
+        assert_eq!(*formdata.get_json("extra").unwrap(), ::serde_json::json!([true]));
+
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"meta\"\r\n\
+                     Content-Type: application/json\r\n\
+                     \r\n\
+                     {oops\r\n\
+                     --abcdefg--";
+        match read_formdata_with_config(&mut &body[..], &headers, &config) {
+            Err(Error::InvalidFieldValue(ref name)) if name == "meta" => {},
+            other => panic!("expected InvalidFieldValue, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn csv_records() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"; filename=\"people.csv\"\r\n\
+                     Content-Type: text/csv\r\n\
+                     \r\n\
+                     name,age\nMike,46\n\"Smith, J\",30\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"b\"; filename=\"people.tsv\"\r\n\
+                     \r\n\
+                     name\tage\nAnn\t29\r\n\
+                     --abcdefg--";
+        let formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
+
+        let file = formdata.get_file("a").unwrap();
+        assert_eq!(file.csv_reader().unwrap().headers().unwrap(), vec!["name", "age"]);
+        let records: Vec<_> = file.csv_records().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1], vec!["Smith, J", "30"]);
+
+        let records: Vec<_> = formdata.get_file("b").unwrap().csv_records().unwrap()
+            .map(|r| r.unwrap()).collect();
+        assert_eq!(records[0], vec!["Ann", "29"]);
+    }
+
+    #[test]
+    fn query_strings() {
+        let formdata = FormData::from_query_string("?name=Mike+D&note=a%26b%3Dc%20%E2%9C%93&x")
+            .unwrap();
+        assert_eq!(formdata.fields, vec![("name".into(), "Mike D".into()),
+                                         ("note".into(), "a&b=c \u{2713}".into()),
+                                         ("x".into(), "".into())]);
+        assert_eq!(formdata.to_query_string(QueryFiles::Reject).unwrap(),
+                   "name=Mike+D&note=a%26b%3Dc+%E2%9C%93&x=");
+        assert_eq!(FormData::from_query_string("a=100%").unwrap().fields[0].1, "100%");
+
+        let mut headers = Headers::new();
+        headers.set(ContentDisposition {
+            disposition: DispositionType::Ext("form-data".to_owned()),
+            parameters: vec![DispositionParam::Filename(Charset::Ext("UTF-8".to_owned()), None,
+                                                        b"cv.pdf".to_vec())],
+        });
+        let formdata = FormData {
+            files: vec![ ("cv".to_owned(), FilePart::new(headers, ::std::path::Path::new("x"))) ],
+            ..FormData::new()
+        };
+        match formdata.to_query_string(QueryFiles::Reject) {
+            Err(Error::FilesNotSupported) => {},
+            other => panic!("expected FilesNotSupported, got {:?}", other),
+        }
+        assert_eq!(formdata.to_query_string(QueryFiles::Filename).unwrap(), "cv=cv.pdf");
+    }
+
+    #[test]
+    fn any_form_encoding() {
+        let config = ParseConfig::default();
+        let mut headers = Headers::new();
+        headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Plain,
+                                     vec![(Attr::Charset, Value::Utf8)])));
+        let body = b"name=Mike D\r\nequation=a=b\r\nflag\r\n";
+        let formdata = read_any_form(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(formdata.fields, vec![("name".into(), "Mike D".into()),
+                                         ("equation".into(), "a=b".into()),
+                                         ("flag".into(), "".into())]);
+
+        headers.set(ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded,
+                                     vec![])));
+        let formdata = read_any_form(&mut &b"name=Mike+D&x=%26"[..], &headers, &config)
+            .unwrap();
+        assert_eq!(formdata.fields, vec![("name".into(), "Mike D".into()),
+                                         ("x".into(), "&".into())]);
+        let limited = ParseConfig { max_body_size: Some(8), ..ParseConfig::default() };
+        match read_any_form(&mut &b"name=Mike+D"[..], &headers, &limited) {
+            Err(Error::BodyTooLarge) => {},
+            other => panic!("expected BodyTooLarge, got {:?}", other),
+        }
+
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"name\"\r\n\
+                     \r\n\
+                     Mike\r\n\
+                     --abcdefg--";
+        let formdata = read_any_form(&mut &body[..], &bench::headers("abcdefg"), &config)
+            .unwrap();
+        assert_eq!(formdata.fields, vec![("name".into(), "Mike".into())]);
+
+        headers.set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])));
+        match read_any_form(&mut &b"{}"[..], &headers, &config) {
+            Err(Error::NotFormData) => {},
+            other => panic!("expected NotFormData, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn nested_groups() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"mail\"\r\n\
+                     Content-Type: multipart/mixed; boundary=outer\r\n\
+                     \r\n\
+                     --outer\r\n\
+                     Content-Disposition: attachment; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     A\r\n\
+                     --outer\r\n\
+                     Content-Type: multipart/alternative; boundary=inner\r\n\
+                     \r\n\
+                     --inner\r\n\
+                     Content-Type: text/plain\r\n\
+                     \r\n\
+                     plain\r\n\
+                     --inner--\r\n\
+                     --outer--\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+
+        // By default the inner group is skipped
+        let formdata = read_formdata(&mut &body[..], &headers).unwrap();
+        assert_eq!(formdata.files.len(), 1);
+        assert!(formdata.fields.is_empty() && formdata.groups.is_empty());
+        assert_eq!(formdata.skipped.len(), 1);
+        assert_eq!(formdata.skipped[0].name.as_deref(), Some("mail"));
+        assert_eq!(formdata.skipped[0].reason, SkipReason::TooDeep);
+
+        let config = ParseConfig { max_nesting: 2, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(formdata.files[0].0, "mail");
+        assert_eq!(formdata.groups.len(), 1);
+        let (ref name, ref group) = formdata.groups[0];
+        assert_eq!(name, "mail");
+        assert_eq!(group.fields, vec![("mail".into(), "plain".into())]);
+        assert_eq!(formdata.stats.parts, 2);
+
+        let config = ParseConfig { max_nesting: 0, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert!(formdata.files.is_empty());
+
+        let looped = String::from_utf8_lossy(body).replace("boundary=inner", "boundary=outer");
+        let config = ParseConfig { max_nesting: 2, ..ParseConfig::default() };
+        match read_formdata_with_config(&mut looped.as_bytes(), &headers, &config) {
+            Err(Error::RepeatedBoundary) => {},
+            other => panic!("expected RepeatedBoundary, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn boundary_termination() {
+        let headers = bench::headers("abcdefg");
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"a\"\r\n\
+                     \r\n\
+                     b\r\n\
+                     --abcdefg--\r\nepilogue";
+        let formdata = read_formdata(&mut &body[..], &headers).unwrap();
+        assert!(formdata.stats.terminated);
+        assert_eq!(formdata.stats.epilogue_bytes, 10);
+
+        let unterminated = &body[..body.len() - 12];
+        match read_formdata(&mut &unterminated[..], &headers) {
+            Err(Error::MissingTerminator) => {},
+            other => panic!("expected MissingTerminator, got {:?}", other),
+        }
+        let config = ParseConfig { lenient_termination: true, ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &unterminated[..], &headers, &config)
+            .unwrap();
+        assert_eq!(formdata.fields, vec![("a".into(), "b".into())]);
+        assert!(!formdata.stats.terminated);
+
+        // A body cut off within a part is still incomplete
+        match read_formdata_with_config(&mut &body[..40], &headers, &config) {
+            Err(Error::Eof(_)) => {},
+            other => panic!("expected Eof, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn default_part_headers() {
+        let formdata = FormData {
+            fields: vec![ ("a".into(), "1".into()), ("b".into(), "2".into()) ],
+            ..FormData::new()
+        };
+        let config = WriteConfig::new()
+            .default_header("X-Origin", "gateway")
+            .default_header_with("X-Part-Id", |index, name| {
+                if name == "b" { None } else { Some(format!("part-{}", index)) }
+            })
+            .default_header("Content-Type", "text/csv");
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata_with_config(&mut output, b"abcdefg", &formdata, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("X-Origin: gateway\r\n").count(), 2);
+        assert!(output.contains("X-Part-Id: part-0\r\n"));
+        assert_eq!(output.matches("X-Part-Id").count(), 1);
+        // Headers the part already has are kept
+        assert!(!output.contains("text/csv"));
+    }
+
+    #[test]
+    fn part_content_lengths() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let path = tmpdir.path().join("file.txt");
+        File::create(&path).unwrap().write_all(b"file content").unwrap();
+        let formdata = FormData {
+            fields: vec![ ("a".into(), "1".into()) ],
+            files: vec![ ("f".to_owned(), FilePart::new(Headers::new(), &path)) ],
+            ..FormData::new()
+        };
+        let config = WriteConfig { content_lengths: true, ..WriteConfig::default() };
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata_with_config(&mut output, b"abcdefg", &formdata, &config).unwrap();
+        let text = String::from_utf8_lossy(&output);
+        assert!(text.contains("Content-Length: 1\r\n"));
+        assert!(text.contains("Content-Length: 12\r\n"));
+        let parsed = read_formdata(&mut &output[..], &bench::headers("abcdefg")).unwrap();
+        assert_eq!(parsed.files[0].1.size, Some(12));
+    }
+
+    #[test]
+    fn restartable_body() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let path = tmpdir.path().join("file.txt");
+        File::create(&path).unwrap().write_all(b"file content").unwrap();
+        let formdata = FormData {
+            fields: vec![ ("a".into(), "1".into()) ],
+            files: vec![ ("f".to_owned(), FilePart::new(Headers::new(), &path)) ],
+            ..FormData::new()
+        };
+        let mut expected: Vec<u8> = Vec::new();
+        write_formdata(&mut expected, b"abcdefg", &formdata).unwrap();
+
+        let mut body = FormBody::new(&formdata, b"abcdefg").unwrap();
+        assert_eq!(body.content_length(), expected.len() as u64);
+        let mut first = vec![0; 50];
+        body.read_exact(&mut first).unwrap();
+        // The upload failed part way; send it again from the start
+        body.reset();
+        let mut output: Vec<u8> = Vec::new();
+        body.read_to_end(&mut output).unwrap();
+        assert_eq!(output, expected);
+
+        let end = expected.len() as u64;
+        assert_eq!(body.seek(SeekFrom::End(-20)).unwrap(), end - 20);
+        let mut tail: Vec<u8> = Vec::new();
+        body.read_to_end(&mut tail).unwrap();
+        assert_eq!(&tail[..], &expected[expected.len() - 20..]);
+        assert!(body.seek(SeekFrom::Current(-(end as i64) - 1)).is_err());
+    }
+
+    #[test]
+    fn formdata_into_reader() {
+        // Its file is a temporary one, which must last as long as the reader
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                     \r\n\
+                     file content\r\n\
+                     --abcdefg--";
+        let formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
+        let mut expected: Vec<u8> = Vec::new();
+        write_formdata(&mut expected, b"abcdefg", &formdata).unwrap();
+        let mut reader = formdata.into_reader(b"abcdefg").unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        // Read in small pieces, as a client might
+        let mut buf = [0; 7];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                n => output.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn body_chunks() {
+        let formdata = FormData {
+            fields: vec![ ("a".into(), "1".into()), ("b".into(), "2".into()) ],
+            ..FormData::new()
+        };
+        let mut expected: Vec<u8> = Vec::new();
+        write_formdata(&mut expected, b"abcdefg", &formdata).unwrap();
+        let chunks: Vec<Vec<u8>> = formdata.into_chunks(b"abcdefg", 64).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(chunks.len(), expected.len().div_ceil(64));
+        assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() == 64));
+        assert_eq!(chunks.concat(), expected);
+    }
+
+    #[test]
+    fn part_filters() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"debug_trace\"\r\n\
+                     \r\n\
+                     secret\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"user\"\r\n\
+                     \r\n\
+                     mike\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\
+                     Content-Type: application/octet-stream\r\n\
+                     \r\n\
+                     123456\r\n\
+                     --abcdefg--";
+        let filters = PartFilters::new()
+            .drop_parts(|name, _| name.starts_with("debug_"))
+            .rename(|name| if name == "user" { Some("username".to_owned()) } else { None })
+            .set_content_type(|name, _| name == "f",
+                              Mime(TopLevel::Text, SubLevel::Plain, vec![]))
+            .set_header(|_, headers| headers.has::<ContentType>(), "X-Checked", "yes");
+        let config = ParseConfig { part_filters: Some(filters.clone()),
+                                   ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &bench::headers("abcdefg"),
+                                                 &config).unwrap();
+        assert_eq!(formdata.fields, vec![("username".into(), "mike".into())]);
+        let file = &formdata.files[0].1;
+        assert_eq!(file.content_type().unwrap().to_string(), "text/plain");
+        assert!(file.headers.get_raw("X-Checked").is_some());
+
+        // On the way out, drop the file and tag the remaining parts
+        let config = WriteConfig {
+            part_filters: Some(PartFilters::new()
+                .filter(|_, _| true, |name, _| {
+                    name.insert_str(0, "x_");
+                    FilterAction::Keep
+                })
+                .drop_parts(|_, headers| headers.get_raw("X-Checked").is_some())),
+            ..WriteConfig::default()
+        };
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata_with_config(&mut output, b"abcdefg", &formdata, &config).unwrap();
+        let rewritten = read_formdata(&mut &output[..], &bench::headers("abcdefg")).unwrap();
+        assert_eq!(rewritten.fields, vec![("x_username".into(), "mike".into())]);
+        assert!(rewritten.files.is_empty());
+    }
+
+    #[test]
+    fn name_mapper() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"firstName\"\r\n\
+                     \r\n\
+                     Mike\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"user[home-Address]\"\r\n\
+                     \r\n\
+                     Home\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"csrfToken\"\r\n\
+                     \r\n\
+                     xyz\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"ProfilePhoto\"; filename=\"a.png\"\r\n\
+                     \r\n\
+                     123\r\n\
+                     --abcdefg--";
+        let headers = bench::headers("abcdefg");
+
+        let config = ParseConfig { name_mapper: Some(NameMapper::snake_case()),
+                                   ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(formdata.fields, vec![("first_name".into(), "Mike".into()),
+                                         ("user[home_address]".into(), "Home".into()),
+                                         ("csrf_token".into(), "xyz".into())]);
+        assert_eq!(formdata.files[0].0, "profile_photo");
+
+        let mapper = NameMapper::new(|name| match name {
+            "csrfToken" => None,
+            name => Some(name.to_uppercase()),
+        });
+        let config = ParseConfig { name_mapper: Some(mapper), ..ParseConfig::default() };
+        let formdata = read_formdata_with_config(&mut &body[..], &headers, &config).unwrap();
+        assert_eq!(formdata.fields, vec![("FIRSTNAME".into(), "Mike".into()),
+                                         ("USER[HOME-ADDRESS]".into(), "Home".into())]);
+        assert_eq!(formdata.stats.parts, 3);
+    }
+
+    #[test]
+    fn split_bodies() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let path = tmpdir.path().join("big.bin");
+        File::create(&path).unwrap().write_all(&[0; 300]).unwrap();
+        let formdata = FormData {
+            fields: (0..10).map(|i| (format!("f{}", i).into(), "x".repeat(20).into())).collect(),
+            files: vec![ ("big".to_owned(), FilePart::new(Headers::new(), &path)) ],
+            ..FormData::new()
+        };
+        let mut whole: Vec<u8> = Vec::new();
+        write_formdata(&mut whole, b"abcdefg", &formdata).unwrap();
+
+        let batches = split_formdata(formdata, b"abcdefg", 400).unwrap();
+        assert!(batches.len() > 2);
+        let mut fields = 0;
+        for batch in &batches {
+            let mut output: Vec<u8> = Vec::new();
+            let count = write_formdata(&mut output, b"abcdefg", batch).unwrap();
+            assert!(count <= 400);
+            fields += batch.fields.len();
+        }
+        assert_eq!(fields, 10);
+        assert_eq!(batches.last().unwrap().files.len(), 1);
+
+        let formdata = FormData {
+            files: vec![ ("big".to_owned(), FilePart::new(Headers::new(), &path)) ],
+            ..FormData::new()
+        };
+        match split_formdata(formdata, b"abcdefg", 200) {
+            Err(Error::FileTooLarge) => {},
+            other => panic!("expected FileTooLarge, got {:?}", other),
+        }
+        assert!(split_formdata(FormData::new(), b"abcdefg", 200).unwrap().is_empty());
+        assert!(whole.len() > 400);
+    }
+
+    #[test]
+    fn writer_edge_cases() {
+        let headers = bench::headers("abcdefg");
+        let config = WriteConfig { part_filenames: true, ..WriteConfig::default() };
+        let round_trip = |formdata: &FormData| {
+            let mut output: Vec<u8> = Vec::new();
+            write_formdata_with_config(&mut output, b"abcdefg", formdata, &config).unwrap();
+            read_formdata(&mut &output[..], &headers).unwrap()
+        };
+
+        // No parts at all
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata(&mut output, b"abcdefg", &FormData::new()).unwrap();
+        assert_eq!(output, b"--abcdefg--");
+        let parsed = round_trip(&FormData::new());
+        assert!(parsed.fields.is_empty() && parsed.files.is_empty());
+
+        // Values with line breaks, and names that need escaping
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let path = tmpdir.path().join("empty");
+        File::create(&path).unwrap();
+        let mut file_headers = Headers::new();
+        file_headers.set(ContentDisposition {
+            disposition: DispositionType::Ext("form-data".to_owned()),
+            parameters: vec![DispositionParam::Filename(Charset::Ext("UTF-8".to_owned()), None,
+                                                        b"say \"hi\".txt".to_vec())],
+        });
+        let formdata = FormData {
+            fields: vec![ ("text".into(), "one\r\ntwo\r\n".into()),
+                          ("crlf".into(), "\r\n".into()),
+                          ("empty".into(), "".into()),
+                          ("a \"quoted\"\r\nname".into(), "x".into()) ],
+            files: vec![ ("file".to_owned(), FilePart::new(file_headers, &path)) ],
+            ..FormData::new()
+        };
+        let parsed = round_trip(&formdata);
+        assert_eq!(parsed.fields[..3], formdata.fields[..3]);
+        assert_eq!(parsed.fields[3].0, "a %22quoted%22%0D%0Aname");
+        assert_eq!(parsed.files[0].1.size, Some(0));
+        assert_eq!(parsed.files[0].1.filename().unwrap(), Some("say %22hi%22.txt".to_owned()));
+
+        // Content that would end the part early
+        for value in &["--abcdefg", "x\r\n--abcdefg--"] {
+            let formdata = FormData { fields: vec![("a".into(), (*value).into())],
+                                      ..FormData::new() };
+            match write_formdata(&mut Vec::new(), b"abcdefg", &formdata) {
+                Err(Error::BoundaryInContent) => {},
+                other => panic!("expected BoundaryInContent, got {:?}", other),
+            }
+        }
+    }
+
+
+    #[test]
+    fn formdata_from_dir() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        std::fs::create_dir(tmpdir.path().join("sub")).unwrap();
+        File::create(tmpdir.path().join("b.txt")).unwrap().write_all(b"bee").unwrap();
+        File::create(tmpdir.path().join("sub/a.txt")).unwrap().write_all(b"ay").unwrap();
+
+        let formdata = FormData::from_dir(tmpdir.path(), FieldNaming::RelativePath, false)
+            .unwrap();
+        assert_eq!(formdata.files.len(), 1);
+
+        let formdata = FormData::from_dir(tmpdir.path(), FieldNaming::Same("files[]".to_owned()),
+                                          true).unwrap();
+        let filenames: Vec<String> = formdata.files.iter()
+            .map(|(_, f)| f.raw_filename().unwrap().unwrap())
+            .collect();
+        assert_eq!(filenames, vec!["b.txt", "sub/a.txt"]);
+
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata(&mut output, &generate_boundary(), &formdata).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("name=\"files[]\"; filename=\"a.txt\""));
+        let config = WriteConfig { part_filenames: true, ..WriteConfig::default() };
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata_with_config(&mut output, &generate_boundary(), &formdata, &config)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("name=\"files[]\"; filename=\"sub/a.txt\""));
+    }
+
+    #[test]
+    fn disposition_params_round_trip() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"doc\"; filename=\"a.txt\"; \
+                     creation-date=\"Wed, 12 Feb 1997 16:29:51 -0500\"; x-vendor=\"42\"\r\n\
+                     \r\n\
+                     text\r\n\
+                     --abcdefg--";
+        let formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
+
+        let config = WriteConfig { part_filenames: true, ..WriteConfig::default() };
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata_with_config(&mut output, b"abcdefg", &formdata, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("name=\"doc\"; filename=\"a.txt\"; \
+                                 creation-date=\"Wed, 12 Feb 1997 16:29:51 -0500\"; \
+                                 x-vendor=\"42\""));
+    }
+
+    #[test]
+    fn disposition_metadata() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        File::create(tmpdir.path().join("a.txt")).unwrap().write_all(b"12345").unwrap();
+        let formdata = FormData::from_dir(tmpdir.path(), FieldNaming::RelativePath, false)
+            .unwrap();
+
+        let config = WriteConfig { disposition_metadata: true, ..WriteConfig::default() };
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata_with_config(&mut output, &generate_boundary(), &formdata, &config)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("filename=\"a.txt\"; size=\"5\"; "));
+        assert!(output.contains("modification-date=\""));
+    }
+
+    #[test]
+    fn disposition_format() {
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; NAME=\"title\"\r\n\
+                     \r\n\
+                     Hi\r\n\
+                     --abcdefg\r\n\
+                     Content-Disposition: form-data; FILENAME=\"a.txt\"; Size=\"1\"; \
+                     NAME=\"doc\"\r\n\
+                     \r\n\
+                     A\r\n\
+                     --abcdefg--";
+        let formdata = read_formdata(&mut &body[..], &bench::headers("abcdefg")).unwrap();
+        assert_eq!(formdata.fields, vec![("title".into(), "Hi".into())]);
+        assert_eq!(formdata.files[0].0, "doc");
+
+        let config = WriteConfig { part_filenames: true, ..WriteConfig::default() };
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata_with_config(&mut output, b"abc", &formdata, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Content-Disposition: form-data; name=\"title\"\r\n"));
+        assert!(output.contains(
+            "Content-Disposition: form-data; name=\"doc\"; filename=\"a.txt\"; size=\"1\"\r\n"));
+
+        let config = WriteConfig {
+            disposition_format: DispositionFormat { order: ParamOrder::Preserve,
+                                                    lowercase_keys: false,
+                                                    quoting: ParamQuoting::WhenNeeded },
+            ..config
+        };
+        let mut output: Vec<u8> = Vec::new();
+        write_formdata_with_config(&mut output, b"abc", &formdata, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Content-Disposition: form-data; name=title\r\n"));
+        assert!(output.contains(
+            "Content-Disposition: form-data; filename=a.txt; Size=1; name=doc\r\n"));
+    }
+
+    #[test]
+    fn respond_with_file_part() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let path = tmpdir.path().join("upload");
+        File::create(&path).unwrap().write_all(b"<p>hello</p>").unwrap();
+        let filename = "C:\\docs\\r\u{e9}sum\u{e9}.html".as_bytes().to_vec();
+        let mut headers = Headers::new();
+        headers.set(ContentDisposition {
+            disposition: DispositionType::Ext("form-data".to_owned()),
+            parameters: vec![DispositionParam::Filename(Charset::Ext("UTF-8".to_owned()), None,
+                                                        filename)],
+        });
+        let part = FilePart::new(headers, &path);
+
+        let mut headers_out = Headers::new();
+        let mut body: Vec<u8> = Vec::new();
+        assert_eq!(respond_with_file(&part, &mut headers_out, &mut body).unwrap(), 12);
+        assert_eq!(body, b"<p>hello</p>");
+        assert_eq!(headers_out.get::<ContentType>().unwrap().to_string(),
+                   "application/octet-stream");
+        assert_eq!(headers_out.get::<ContentLength>(), Some(&ContentLength(12)));
+        assert_eq!(headers_out.get_raw("Content-Disposition").unwrap()[0],
+                   &b"attachment; filename=\"r_sum_.html\"; \
+                      filename*=UTF-8''r%C3%A9sum%C3%A9.html"[..]);
+        assert_eq!(headers_out.get_raw("X-Content-Type-Options").unwrap()[0], b"nosniff");
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_writer() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        File::create(tmpdir.path().join("a.bin")).unwrap().write_all(&[7; 10_000]).unwrap();
+        File::create(tmpdir.path().join("empty")).unwrap();
+        let formdata = FormData::from_dir(tmpdir.path(), FieldNaming::RelativePath, false)
+            .unwrap();
+
+        let mut expected: Vec<u8> = Vec::new();
+        let count = write_formdata(&mut expected, b"abcdefg", &formdata).unwrap();
+        let config = WriteConfig { mmap: true, ..WriteConfig::default() };
+        let mut output: Vec<u8> = Vec::new();
+        assert_eq!(write_formdata_with_config(&mut output, b"abcdefg", &formdata,
+                                              &config).unwrap(), count);
+        assert_eq!(output, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn zero_copy_writer() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        File::create(tmpdir.path().join("a.bin")).unwrap().write_all(&[7; 100_000]).unwrap();
+        let formdata = FormData::from_dir(tmpdir.path(), FieldNaming::RelativePath, false)
+            .unwrap();
+
+        let mut expected: Vec<u8> = Vec::new();
+        let count = write_formdata(&mut expected, b"abcdefg", &formdata).unwrap();
+        let out_path = tmpdir.path().join("body");
+        let mut out = File::create(&out_path).unwrap();
+        assert_eq!(write_formdata_zero_copy(&mut out, b"abcdefg", &formdata).unwrap(), count);
+        assert_eq!(std::fs::read(&out_path).unwrap(), expected);
+    }
+
+    #[cfg(all(feature = "sendfile", target_os = "linux"))]
+    #[test]
+    fn zero_copy_truncated_file() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let body = b"--abcdefg\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"a.bin\"\r\n\
+                     \r\n\
+                     0123456789\r\n\
+                     --abcdefg--";
+        let body_path = tmpdir.path().join("body");
+        File::create(&body_path).unwrap().write_all(body).unwrap();
+        let formdata = read_formdata_in_place(&body_path, &bench::headers("abcdefg"),
+                                              &ParseConfig::default()).unwrap();
+
+        // The file shrinks after it was parsed, to end part way through the part
+        let range = formdata.files[0].1.source_range().unwrap();
+        File::options().write(true).open(&body_path).unwrap().set_len(range.start + 4).unwrap();
+        let mut out = File::create(tmpdir.path().join("out")).unwrap();
+        match write_formdata_zero_copy(&mut out, b"abcdefg", &formdata) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+            other => panic!("expected UnexpectedEof, got {:?}", other),
+        }
+    }
 
     #[test]
     fn chunked_writer() {
@@ -460,9 +3350,10 @@ mod tests {
         });
 
         let formdata = FormData {
-            fields: vec![ ("name".to_owned(), "Mike".to_owned()),
-                            ("age".to_owned(), "46".to_owned()) ],
+            fields: vec![ ("name".into(), "Mike".into()),
+                            ("age".into(), "46".into()) ],
             files: vec![ ("photo".to_owned(), FilePart::new(photo_headers, &tmppath)) ],
+            ..FormData::new()
         };
 
         let mut output: Vec<u8> = Vec::new();
@@ -0,0 +1,625 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A resumable push parser for MIME multipart bodies.
+//!
+//! The `Parser` does no I/O of its own.  Body bytes are handed to it with `push()` in
+//! whatever pieces they happen to arrive in, and it returns the `Event`s that those bytes
+//! completed.  This makes it usable with non-blocking I/O or any other source of bytes;
+//! `read_formdata()` is built on top of it.
+
+use std::ops::Range;
+use encoding::DecoderTrap;
+use encoding::label::encoding_from_whatwg_label;
+use httparse;
+use memchr::memchr;
+use hyper::header::{Headers, ContentType};
+use mime::{Attr, Mime, TopLevel, Value};
+use config::ParseConfig;
+use error::Error;
+
+const MAX_HEADERS: usize = 32;
+
+/// Something the `Parser` found in the body.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A new part has begun, and these are its headers.
+    Headers(Headers),
+    /// The next piece of the current part's body.
+    Data(Vec<u8>),
+    /// The current part is complete.
+    PartEnd,
+    /// The closing boundary was found.  Anything pushed afterwards is ignored.
+    End,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Preamble,
+    AfterBoundary,
+    Headers,
+    Body,
+    Done,
+}
+
+/// A push parser for the body of a `multipart/*` entity.
+#[derive(Clone, Debug)]
+pub struct Parser {
+    // The boundary as it appears in the body: "--" followed by the boundary parameter.
+    boundary: Vec<u8>,
+    // The line terminator the sender uses, learned from the first boundary line.
+    lt: Vec<u8>,
+    // Unconsumed input.
+    buf: Vec<u8>,
+    state: State,
+    max_header_size: usize,
+    header_fallback_encoding: Option<String>,
+    lenient_line_endings: bool,
+    lenient_termination: bool,
+    // Whether the closing boundary had its terminator, and the bytes pushed after it
+    terminated: bool,
+    epilogue: u64,
+    // The total size of the header sections seen so far
+    header_bytes: u64,
+    // The position within the body of the start of `buf`
+    offset: u64,
+    // The position within the body of the current part
+    part_start: u64,
+    // Where each part lay, if `ParseConfig::raw_parts` is set
+    record_ranges: bool,
+    ranges: Vec<Range<u64>>,
+}
+
+impl Parser {
+    /// Create a parser for a body delimited by `boundary` (the value of the Content-Type
+    /// `boundary` parameter, without the leading dashes).
+    pub fn new(boundary: &[u8], config: &ParseConfig) -> Parser {
+        let mut full = Vec::with_capacity(2 + boundary.len());
+        full.extend_from_slice(b"--");
+        full.extend_from_slice(boundary);
+        Parser {
+            buf: Vec::with_capacity(min_buffer(&full)),
+            boundary: full,
+            lt: Vec::new(),
+            state: State::Preamble,
+            max_header_size: config.max_header_size,
+            header_fallback_encoding: config.header_fallback_encoding.clone(),
+            lenient_line_endings: config.lenient_line_endings,
+            lenient_termination: config.lenient_termination,
+            terminated: false,
+            epilogue: 0,
+            header_bytes: 0,
+            offset: 0,
+            part_start: 0,
+            record_ranges: config.raw_parts.is_some(),
+            ranges: Vec::new(),
+        }
+    }
+
+    // Use `buf` (which must be empty) as the working buffer, e.g. one from a `BufferPool`.
+    pub(crate) fn set_buffer(&mut self, mut buf: Vec<u8>) {
+        debug_assert!(self.buf.is_empty());
+        buf.reserve(min_buffer(&self.boundary));
+        self.buf = buf;
+    }
+
+    // Take back the working buffer.
+    pub(crate) fn take_buffer(&mut self) -> Vec<u8> {
+        ::std::mem::take(&mut self.buf)
+    }
+
+    /// The total size in bytes of the part header sections seen so far, including the blank
+    /// lines that end them.
+    pub fn header_bytes(&self) -> u64 {
+        self.header_bytes
+    }
+
+    /// Where each part that has ended lay within the body, from the first byte of its
+    /// headers to the last byte of its content.  Only recorded if `ParseConfig::raw_parts`
+    /// is set.
+    pub fn part_ranges(&self) -> &[Range<u64>] {
+        &self.ranges
+    }
+
+    // Record `part_ranges()` regardless of `ParseConfig::raw_parts`.
+    pub(crate) fn record_ranges(&mut self) {
+        self.record_ranges = true;
+    }
+
+    /// Whether the closing boundary has been seen.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Whether the closing boundary had its `--` terminator.  False until it has been seen.
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    /// The number of bytes pushed after the closing boundary, which are ignored.
+    pub fn epilogue_bytes(&self) -> u64 {
+        self.epilogue
+    }
+
+    /// Feed the next piece of the body to the parser, returning the events it completed.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Event>, Error> {
+        let mut events: Vec<Event> = Vec::new();
+        self.push_into(data, &mut events)?;
+        Ok(events)
+    }
+
+    /// Like `push()`, but appends the events to `events`, so that one `Vec` can be reused
+    /// for the whole body.
+    pub fn push_into(&mut self, data: &[u8], events: &mut Vec<Event>) -> Result<(), Error> {
+        if self.state == State::Done {
+            self.epilogue += data.len() as u64;
+            return Ok(());
+        }
+        self.buf.extend_from_slice(data);
+        while self.step(events)? { }
+        Ok(())
+    }
+
+    /// Signal that the body has ended.  Fails with `Error::Eof(None)` if the closing
+    /// boundary has not been seen, or with `Error::MissingTerminator` if the body ended
+    /// after a boundary without its `--` terminator (unless
+    /// `ParseConfig::lenient_termination` is set, in which case that is the end).
+    pub fn finish(&mut self) -> Result<Vec<Event>, Error> {
+        let unterminated = match self.state {
+            State::Done => return Ok(Vec::new()),
+            // Perhaps followed by a line terminator, but not by headers
+            State::AfterBoundary | State::Headers =>
+                self.buf.iter().all(|&b| b == b'\r' || b == b'\n' || b == b' ' || b == b'\t'),
+            _ => false,
+        };
+        if !unterminated {
+            return Err(Error::Eof(None));
+        }
+        if !self.lenient_termination {
+            return Err(Error::MissingTerminator);
+        }
+        self.epilogue = self.buf.len() as u64;
+        self.buf.clear();
+        self.state = State::Done;
+        Ok(vec![Event::End])
+    }
+
+    // Make as much progress as the buffered input allows.  Returns false once more input
+    // is needed.
+    fn step(&mut self, events: &mut Vec<Event>) -> Result<bool, Error> {
+        match self.state {
+            State::Preamble => {
+                match find(&self.buf, &self.boundary) {
+                    Some(i) => {
+                        let n = i + self.boundary.len();
+                        self.consume(n);
+                        self.state = State::AfterBoundary;
+                        Ok(true)
+                    },
+                    None => {
+                        // Discard the preamble, keeping only what could be the start of
+                        // the boundary.
+                        let keep = self.boundary.len() - 1;
+                        if self.buf.len() > keep {
+                            let discard = self.buf.len() - keep;
+                            self.consume(discard);
+                        }
+                        Ok(false)
+                    },
+                }
+            },
+            State::AfterBoundary => {
+                // Transport padding may follow a boundary.
+                let padding = self.buf.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+                self.consume(padding);
+                if self.buf.len() < 2 {
+                    return Ok(false);
+                }
+                if &self.buf[..2] == b"--" {
+                    self.terminated = true;
+                    self.epilogue = self.buf.len() as u64 - 2;
+                    self.buf.clear();
+                    self.state = State::Done;
+                    events.push(Event::End);
+                    return Ok(false);
+                }
+                let lt_len = if &self.buf[..2] == b"\r\n" {
+                    2
+                } else if self.buf[0] == b'\n' {
+                    1
+                } else {
+                    return Err(Error::NoCrLfAfterBoundary);
+                };
+                if self.lt.is_empty() {
+                    self.lt = self.buf[..lt_len].to_vec();
+                }
+                self.consume(lt_len);
+                self.part_start = self.offset;
+                self.state = State::Headers;
+                Ok(true)
+            },
+            State::Headers => {
+                // A part with no headers at all begins directly with the blank line.
+                if let Some(lt_len) = self.line_terminator_at(0) {
+                    self.consume(lt_len);
+                    self.header_bytes += lt_len as u64;
+                    events.push(Event::Headers(Headers::new()));
+                    self.state = State::Body;
+                    return Ok(true);
+                }
+                match self.find_blank_line() {
+                    Some(end) => {
+                        if end > self.max_header_size {
+                            return Err(Error::HeadersTooLarge);
+                        }
+                        let headers = parse_headers(&self.buf[..end],
+                                                    self.header_fallback_encoding.as_ref())?;
+                        self.consume(end);
+                        self.header_bytes += end as u64;
+                        events.push(Event::Headers(headers));
+                        self.state = State::Body;
+                        Ok(true)
+                    },
+                    None => {
+                        if self.buf.len() > self.max_header_size {
+                            return Err(Error::HeadersTooLarge);
+                        }
+                        Ok(false)
+                    },
+                }
+            },
+            State::Body => {
+                match self.find_delimiter() {
+                    Some((data_end, end)) => {
+                        if data_end > 0 {
+                            events.push(Event::Data(self.buf[..data_end].to_vec()));
+                        }
+                        events.push(Event::PartEnd);
+                        if self.record_ranges {
+                            self.ranges.push(self.part_start..self.offset + data_end as u64);
+                        }
+                        self.consume(end);
+                        self.state = State::AfterBoundary;
+                        Ok(true)
+                    },
+                    None => {
+                        // Everything except what could be the start of the delimiter is
+                        // part content.
+                        // (When lenient, that includes a carriage return preceding it.)
+                        let lt_len = if self.lenient_line_endings { 2 } else { self.lt.len() };
+                        let keep = lt_len + self.boundary.len() - 1;
+                        if self.buf.len() > keep {
+                            let n = self.buf.len() - keep;
+                            events.push(Event::Data(self.buf.drain(..n).collect()));
+                            self.offset += n as u64;
+                        }
+                        Ok(false)
+                    },
+                }
+            },
+            State::Done => Ok(false),
+        }
+    }
+
+    // Discard the first `n` bytes of the buffer.
+    fn consume(&mut self, n: usize) {
+        self.buf.drain(..n);
+        self.offset += n as u64;
+    }
+
+    // The length of the line terminator at `pos` in the buffer, if there is one.
+    fn line_terminator_at(&self, pos: usize) -> Option<usize> {
+        let rest = &self.buf[pos..];
+        if self.lenient_line_endings {
+            if rest.starts_with(b"\r\n") {
+                Some(2)
+            } else if rest.starts_with(b"\n") {
+                Some(1)
+            } else {
+                None
+            }
+        } else if rest.starts_with(&self.lt) {
+            Some(self.lt.len())
+        } else {
+            None
+        }
+    }
+
+    // Find the blank line that ends a header section, returning the position just after
+    // it.
+    fn find_blank_line(&self) -> Option<usize> {
+        if !self.lenient_line_endings {
+            let mut ltlt = self.lt.clone();
+            ltlt.extend_from_slice(&self.lt);
+            return find(&self.buf, &ltlt).map(|i| i + ltlt.len());
+        }
+        let mut from = 0;
+        while let Some(i) = find(&self.buf[from..], b"\n") {
+            let after = from + i + 1;
+            if let Some(lt_len) = self.line_terminator_at(after) {
+                return Some(after + lt_len);
+            }
+            from = after;
+        }
+        None
+    }
+
+    // Find the next delimiter (line terminator and boundary), returning where the part
+    // content ends and where the delimiter ends.
+    fn find_delimiter(&self) -> Option<(usize, usize)> {
+        if !self.lenient_line_endings {
+            let mut delimiter = self.lt.clone();
+            delimiter.extend_from_slice(&self.boundary);
+            return find(&self.buf, &delimiter).map(|i| (i, i + delimiter.len()));
+        }
+        let mut delimiter = b"\n".to_vec();
+        delimiter.extend_from_slice(&self.boundary);
+        find(&self.buf, &delimiter).map(|i| {
+            let data_end = if i > 0 && self.buf[i - 1] == b'\r' { i - 1 } else { i };
+            (data_end, i + delimiter.len())
+        })
+    }
+}
+
+// The least capacity the working buffer is given: room for two whole delimiters (a line
+// terminator and `boundary`), so that one straddling the pieces pushed, however small they
+// are, is always held entire alongside what follows it.
+fn min_buffer(boundary: &[u8]) -> usize {
+    2 * (boundary.len() + 2)
+}
+
+fn parse_headers(buf: &[u8], fallback_encoding: Option<&String>) -> Result<Headers, Error> {
+    let mut header_memory = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let raw_headers = match httparse::parse_headers(buf, &mut header_memory)? {
+        httparse::Status::Complete((_, raw_headers)) => raw_headers,
+        httparse::Status::Partial => return Err(Error::PartialHeaders),
+    };
+    let label = match fallback_encoding {
+        Some(label) => label,
+        None => return Ok(Headers::from_raw(raw_headers)?),
+    };
+
+    // Re-encode any values that are not UTF-8 from the fallback encoding into UTF-8.
+    let mut headers = Headers::new();
+    for raw in raw_headers {
+        let value = match ::std::str::from_utf8(raw.value) {
+            Ok(_) => raw.value.to_vec(),
+            Err(_) => {
+                let encoding = encoding_from_whatwg_label(label)
+                    .ok_or_else(|| Error::Decoding(format!("Unknown encoding {}", label).into()))?;
+                encoding.decode(raw.value, DecoderTrap::Replace)
+                    .map_err(Error::Decoding)?
+                    .into_bytes()
+            },
+        };
+        headers.append_raw(raw.name.to_owned(), value);
+    }
+    Ok(headers)
+}
+
+// Position of the first occurrence of `needle` within `haystack`.  The delimiter of a
+// body begins with a line terminator, which is rare in binary content and no more than once
+// a line in text, so candidates for the first byte are found with `memchr` and the rest of
+// the needle compared only there.
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let (&first, rest) = match needle.split_first() {
+        Some(split) => split,
+        None => return Some(0),
+    };
+    let mut from = 0;
+    while haystack.len() - from >= needle.len() {
+        let i = from + memchr(first, &haystack[from..haystack.len() - rest.len()])?;
+        if haystack[i + 1..].starts_with(rest) {
+            return Some(i);
+        }
+        from = i + 1;
+    }
+    None
+}
+
+// Get the `multipart/*` boundary parameter from `hyper::Headers`.
+pub(crate) fn get_multipart_boundary(headers: &Headers) -> Result<Vec<u8>, Error> {
+    let ct: &ContentType = match headers.get() {
+        Some(ct) => ct,
+        None => return Err(Error::NoRequestContentType),
+    };
+    content_type_boundary(&ct.0)
+}
+
+// Get the boundary parameter from a `multipart/*` Mime.  Quotes around it are removed
+// (whether or not the Mime parser already did), and it is checked against RFC 2046.
+pub(crate) fn content_type_boundary(mime: &Mime) -> Result<Vec<u8>, Error> {
+    let Mime(ref top_level, _, ref params) = *mime;
+    if *top_level != TopLevel::Multipart {
+        return Err(Error::NotMultipart);
+    }
+    for (attr, val) in params {
+        if let (&Attr::Boundary, Value::Ext(val)) = (attr, val) {
+            let boundary = if val.len() >= 2 && val.starts_with('"') && val.ends_with('"') {
+                &val[1..val.len() - 1]
+            } else {
+                &val[..]
+            };
+            return if is_valid_boundary(boundary.as_bytes()) {
+                Ok(boundary.as_bytes().to_owned())
+            } else {
+                Err(Error::InvalidBoundary)
+            };
+        }
+    }
+    Err(Error::BoundaryNotSpecified)
+}
+
+// Add the boundary parameter of the raw Content-Type value `raw` to `mime` if it lacks one.
+// The Mime parser drops a quoted boundary that begins with a character not allowed in a
+// token, such as `'` or `(`.
+pub(crate) fn restore_boundary(mut mime: Mime, raw: Option<&str>) -> Mime {
+    if mime.0 == TopLevel::Multipart && mime.get_param(Attr::Boundary).is_none() {
+        if let Some(boundary) = raw.and_then(raw_boundary) {
+            mime.2.push((Attr::Boundary, Value::Ext(boundary)));
+        }
+    }
+    mime
+}
+
+// The boundary parameter of a raw Content-Type value, unquoted.
+fn raw_boundary(content_type: &str) -> Option<String> {
+    let mut rest = content_type;
+    while let Some(i) = rest.find(';') {
+        rest = rest[i + 1..].trim_start();
+        let eq = rest.find('=')?;
+        if !rest[..eq].trim_end().eq_ignore_ascii_case("boundary") {
+            continue;
+        }
+        let value = rest[eq + 1..].trim_start();
+        if !value.starts_with('"') {
+            return value.split(';').next().map(|token| token.trim().to_owned());
+        }
+        let mut boundary = String::new();
+        let mut chars = value[1..].chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => return Some(boundary),
+                '\\' => boundary.push(chars.next()?),
+                c => boundary.push(c),
+            }
+        }
+        return None;
+    }
+    None
+}
+
+// Whether `boundary` is 1 to 70 `bchars` (RFC 2046 section 5.1.1), not ending with a space.
+pub(crate) fn is_valid_boundary(boundary: &[u8]) -> bool {
+    let bchar = |b: &u8| b.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(b);
+    !boundary.is_empty() && boundary.len() <= 70 && boundary.iter().all(bchar) &&
+        boundary.last() != Some(&b' ')
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::Headers;
+    use super::{Parser, Event, content_type_boundary, find};
+    use config::ParseConfig;
+    use error::Error;
+    use headers::RequestHeaders;
+
+    const BODY: &[u8] = b"preamble\r\n\
+                          --xyz\r\n\
+                          Content-Disposition: form-data; name=\"a\"\r\n\
+                          \r\n\
+                          alpha\r\n\
+                          --xyz\r\n\
+                          Content-Disposition: form-data; name=\"b\"\r\n\
+                          \r\n\
+                          beta --xy\r\nbeta\r\n\
+                          --xyz--\r\n\
+                          epilogue";
+
+    fn collect(events: Vec<Event>) -> (usize, Vec<Vec<u8>>) {
+        let mut headers = 0;
+        let mut bodies: Vec<Vec<u8>> = Vec::new();
+        for event in events {
+            match event {
+                Event::Headers(_) => { headers += 1; bodies.push(Vec::new()); },
+                Event::Data(d) => bodies.last_mut().unwrap().extend(d),
+                Event::PartEnd | Event::End => {},
+            }
+        }
+        (headers, bodies)
+    }
+
+    #[test]
+    fn find_agrees_with_naive_search() {
+        let haystack = b"\r\r\n--xy\r\n-\r\n--xyz\r\n--xyz--\r";
+        for start in 0..haystack.len() {
+            for end in start..haystack.len() + 1 {
+                let needle = &haystack[start..end];
+                if needle.is_empty() {
+                    continue;
+                }
+                for from in 0..haystack.len() {
+                    let naive = haystack[from..].windows(needle.len())
+                        .position(|w| w == needle);
+                    assert_eq!(find(&haystack[from..], needle), naive);
+                }
+            }
+        }
+        assert_eq!(find(b"ab", b"abc"), None);
+    }
+
+    #[test]
+    fn push_in_any_piece_size() {
+        for size in 1..BODY.len() + 1 {
+            let mut parser = Parser::new(b"xyz", &ParseConfig::default());
+            let mut events = Vec::new();
+            for piece in BODY.chunks(size) {
+                events.extend(parser.push(piece).unwrap());
+            }
+            events.extend(parser.finish().unwrap());
+            assert_eq!(events.last(), Some(&Event::End));
+            let (headers, bodies) = collect(events);
+            assert_eq!(headers, 2);
+            assert_eq!(bodies, vec![b"alpha".to_vec(), b"beta --xy\r\nbeta".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn finish_before_closing_boundary() {
+        let mut parser = Parser::new(b"xyz", &ParseConfig::default());
+        parser.push(&BODY[..40]).unwrap();
+        match parser.finish() {
+            Err(Error::Eof(None)) => {},
+            other => panic!("expected Eof, got {:?}", other),
+        }
+    }
+
+    fn boundary_of(content_type: &str) -> Result<Vec<u8>, Error> {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+        content_type_boundary(&headers.content_type().unwrap())
+    }
+
+    #[test]
+    fn boundary_matrix() {
+        let valid = [
+            // Chrome, Safari and Edge
+            "----WebKitFormBoundary7MA4YWxkTrZu0gW",
+            // Firefox, older and newer
+            "---------------------------974767299852498929531610575",
+            "----geckoformboundary6a1c3f7e2b9d4e8f0a5b2c7d9e1f3a4",
+            // curl
+            "------------------------d74496d66958873e",
+            // okhttp
+            "e2b6d3c4-8f1a-4f0e-9a8b-1c2d3e4f5a6b",
+            // Python requests
+            "5d8a2c0f1e9b4a7d8c6e3f2b1a0d9c8e",
+            // Legal but unusual
+            "simple boundary",
+            "'()+_,-./:=?",
+            "a",
+        ];
+        for boundary in &valid {
+            let quoted = format!("multipart/form-data; boundary=\"{}\"", boundary);
+            assert_eq!(boundary_of(&quoted).expect(boundary), boundary.as_bytes());
+            // Only tokens may go unquoted
+            if boundary.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+                let unquoted = format!("multipart/form-data; boundary={}", boundary);
+                assert_eq!(boundary_of(&unquoted).unwrap(), boundary.as_bytes());
+            }
+
+            let mut parser = Parser::new(boundary.as_bytes(), &ParseConfig::default());
+            let body = format!("--{0}\r\n\r\nvalue\r\n--{0}--", boundary);
+            let events = parser.push(body.as_bytes()).unwrap();
+            assert_eq!(collect(events), (1, vec![b"value".to_vec()]));
+        }
+
+        let too_long = format!("\"{}\"", "x".repeat(71));
+        for boundary in &["\"\"", "\"trailing \"", "\"a@b\"", "caf\u{e9}", &too_long[..]] {
+            match boundary_of(&format!("multipart/form-data; boundary={}", boundary)) {
+                Err(Error::InvalidBoundary) => {},
+                other => panic!("expected InvalidBoundary for {}, got {:?}", boundary, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Reading the `text/plain` encoding of HTML forms, and the bodies of forms that are not
+//! `multipart/form-data`.
+
+use std::io::Read;
+use config::ParseConfig;
+use error::Error;
+use form_data::FormData;
+
+impl FormData {
+    /// Decode a body sent by an HTML form with `enctype="text/plain"`: one `name=value` line
+    /// per field, with nothing escaped, and a file given by its filename.  A line without
+    /// `=` is a field with an empty value.  Since nothing is escaped, a name containing `=`
+    /// or a value containing a line break cannot be told apart from what surrounds it, so
+    /// this encoding is fit for diagnostics rather than for data.
+    pub fn from_plain_text(body: &str) -> FormData {
+        let mut formdata = FormData::new();
+        for line in body.split('\n') {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = match line.find('=') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => (line, ""),
+            };
+            formdata.fields.push((name.to_owned().into(), value.to_owned().into()));
+        }
+        formdata
+    }
+}
+
+// Read a whole body as UTF-8 text, failing with `Error::BodyTooLarge` rather than reading
+// more than `config.max_body_size`.
+pub(crate) fn read_text<S: Read>(stream: &mut S, config: &ParseConfig) -> Result<String, Error> {
+    let max = config.max_body_size.unwrap_or(u64::MAX);
+    let mut body: Vec<u8> = Vec::new();
+    stream.take(max.saturating_add(1)).read_to_end(&mut body)?;
+    if body.len() as u64 > max {
+        return Err(Error::BodyTooLarge);
+    }
+    Ok(String::from_utf8(body)?)
+}
@@ -0,0 +1,148 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use hyper::header::{Headers, ContentType};
+use mime::{Mime, TopLevel, SubLevel};
+use error::Error;
+use file_part::filename_extension;
+
+/// How much of the start of a file is looked at for `ParseConfig::markup_guard`.
+pub(crate) const MARKUP_SNIFF_LEN: usize = 512;
+
+// What HTML or script may begin with (compared ignoring case).
+const MARKUP_STARTS: &[&[u8]] = &[b"<script", b"<html", b"<!doctype html"];
+
+// Extensions and the types a file with that extension may be declared as.
+const KNOWN_TYPES: &[(&str, &[&str])] = &[
+    ("jpg", &["image/jpeg", "image/pjpeg"]),
+    ("jpeg", &["image/jpeg", "image/pjpeg"]),
+    ("png", &["image/png"]),
+    ("gif", &["image/gif"]),
+    ("webp", &["image/webp"]),
+    ("svg", &["image/svg+xml"]),
+    ("bmp", &["image/bmp", "image/x-ms-bmp"]),
+    ("ico", &["image/x-icon", "image/vnd.microsoft.icon"]),
+    ("pdf", &["application/pdf"]),
+    ("txt", &["text/plain"]),
+    ("csv", &["text/csv", "text/plain", "application/vnd.ms-excel"]),
+    ("html", &["text/html"]),
+    ("htm", &["text/html"]),
+    ("json", &["application/json"]),
+    ("xml", &["application/xml", "text/xml"]),
+    ("zip", &["application/zip", "application/x-zip-compressed"]),
+    ("gz", &["application/gzip", "application/x-gzip"]),
+    ("mp3", &["audio/mpeg"]),
+    ("wav", &["audio/wav", "audio/x-wav", "audio/wave"]),
+    ("mp4", &["video/mp4"]),
+    ("webm", &["video/webm", "audio/webm"]),
+    ("doc", &["application/msword"]),
+    ("docx", &["application/vnd.openxmlformats-officedocument.wordprocessingml.document"]),
+    ("xls", &["application/vnd.ms-excel"]),
+    ("xlsx", &["application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"]),
+    ("exe", &["application/x-msdownload", "application/x-msdos-program"]),
+];
+
+/// Which uploaded files to accept, by declared Content-Type and by filename extension.
+/// Give one to `ParseConfig::mime_policy` and parsing fails with `Error::FileNotAllowed` at
+/// the first file part that breaks it, before any of its content is written to disk.
+///
+/// Types are compared without their parameters, and may be given as `type/*`.  Extensions
+/// are given lowercased and without the dot.  An empty allow list allows anything not
+/// denied.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MimePolicy {
+    /// The Content-Types to accept.  A file with none counts as `application/octet-stream`.
+    pub allowed_types: Vec<String>,
+    /// Content-Types to reject.
+    pub denied_types: Vec<String>,
+    /// The extensions to accept.  A file with no extension is only accepted if this is
+    /// empty.
+    pub allowed_extensions: Vec<String>,
+    /// Extensions to reject.
+    pub denied_extensions: Vec<String>,
+    /// Reject a file whose extension is a well-known one that does not go with its
+    /// declared Content-Type, e.g. `photo.exe` sent as `image/png`.  Files declared as
+    /// `application/octet-stream` are not checked.
+    pub check_consistency: bool,
+}
+
+impl MimePolicy {
+    pub fn new() -> MimePolicy {
+        Default::default()
+    }
+
+    /// Check a file part with these `headers` against the policy.
+    pub fn check(&self, headers: &Headers) -> Result<(), Error> {
+        let mime = match headers.get::<ContentType>() {
+            Some(&ContentType(Mime(ref top, ref sub, _))) => format!("{}/{}", top, sub),
+            None => "application/octet-stream".to_owned(),
+        }.to_lowercase();
+        let extension = filename_extension(headers);
+
+        if self.denied_types.iter().any(|t| type_matches(t, &mime)) ||
+            (!self.allowed_types.is_empty() &&
+             !self.allowed_types.iter().any(|t| type_matches(t, &mime)))
+        {
+            return Err(Error::FileNotAllowed(format!("type {} is not allowed", mime)));
+        }
+        if let Some(ref extension) = extension {
+            if self.denied_extensions.contains(extension) {
+                return Err(Error::FileNotAllowed(format!("extension {} is not allowed",
+                                                         extension)));
+            }
+        }
+        if !self.allowed_extensions.is_empty() &&
+            !extension.as_ref().is_some_and(|e| self.allowed_extensions.contains(e))
+        {
+            return Err(Error::FileNotAllowed(match extension {
+                Some(extension) => format!("extension {} is not allowed", extension),
+                None => "a file extension is required".to_owned(),
+            }));
+        }
+        if self.check_consistency && mime != "application/octet-stream" {
+            if let Some(extension) = extension {
+                let known = KNOWN_TYPES.iter().find(|&&(e, _)| e == extension);
+                if let Some(&(_, types)) = known {
+                    if !types.contains(&&*mime) {
+                        return Err(Error::FileNotAllowed(
+                            format!("extension {} does not match type {}", extension, mime)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a file with these `headers` is declared as something that should not be markup: an
+/// image other than SVG, `application/octet-stream`, or nothing at all.
+pub(crate) fn markup_guarded(headers: &Headers) -> bool {
+    match headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Image, ref sub, _))) =>
+            !sub.as_str().eq_ignore_ascii_case("svg+xml"),
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::Ext(ref sub), _))) =>
+            sub.eq_ignore_ascii_case("octet-stream"),
+        Some(_) => false,
+        None => true,
+    }
+}
+
+/// Whether `start`, the first bytes of a file, begins like HTML or script, after any byte
+/// order mark and whitespace.
+pub(crate) fn looks_like_markup(start: &[u8]) -> bool {
+    let start = start.strip_prefix(b"\xef\xbb\xbf").unwrap_or(start);
+    let skip = start.iter().take_while(|b| b.is_ascii_whitespace()).count();
+    let start = &start[skip..];
+    MARKUP_STARTS.iter().any(|tag| {
+        start.len() >= tag.len() && start[..tag.len()].eq_ignore_ascii_case(tag)
+    })
+}
+
+// Whether `mime` (lowercase, without parameters) matches `pattern`, which may end in `/*`.
+fn type_matches(pattern: &str, mime: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some(top) => mime.split('/').next() == Some(top),
+        None => pattern == mime,
+    }
+}
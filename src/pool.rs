@@ -0,0 +1,65 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use shared::Shared;
+
+/// A pool of scratch buffers, shared across requests so that each one need not allocate
+/// its own.  Cloning a `BufferPool` gives another handle to the same pool.
+///
+/// Give one to `ParseConfig::buffer_pool` and `WriteConfig::buffer_pool`, and the read
+/// buffer, the parser's working buffer and the buffer used to copy files out are taken
+/// from the pool and returned to it afterwards.
+#[derive(Clone, PartialEq)]
+pub struct BufferPool {
+    buffers: Shared<Mutex<Vec<Vec<u8>>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Create a pool that keeps at most `max_buffers` idle buffers.
+    pub fn new(max_buffers: usize) -> BufferPool {
+        BufferPool { buffers: Shared(Arc::new(Mutex::new(Vec::new()))), max_buffers }
+    }
+
+    /// Take an empty buffer from the pool, or allocate one if the pool is empty.  The
+    /// buffer has room for at least `capacity` bytes.
+    pub fn take(&self, capacity: usize) -> Vec<u8> {
+        let buf = self.buffers.lock().ok().and_then(|mut buffers| buffers.pop());
+        let mut buf = buf.unwrap_or_default();
+        buf.clear();
+        buf.reserve(capacity);
+        buf
+    }
+
+    /// Return a buffer to the pool.  It is dropped if the pool is already full.
+    pub fn give(&self, buf: Vec<u8>) {
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < self.max_buffers {
+                buffers.push(buf);
+            }
+        }
+    }
+
+    /// The number of idle buffers in the pool.
+    pub fn idle(&self) -> usize {
+        self.buffers.lock().map(|buffers| buffers.len()).unwrap_or(0)
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool::new(64)
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("idle", &self.idle())
+            .field("max_buffers", &self.max_buffers)
+            .finish()
+    }
+}
@@ -0,0 +1,25 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Preallocation of temporary files, so that large uploads are written into space reserved
+//! up front rather than extended a piece at a time.  Only Linux with the `fallocate`
+//! feature does anything; elsewhere these are no-ops.
+
+use std::fs::File;
+
+/// Reserve `len` bytes for `file`, and advise the kernel that it will be written
+/// sequentially.  This is only a hint: failures are ignored.
+#[cfg(all(feature = "fallocate", target_os = "linux"))]
+pub(crate) fn preallocate(file: &File, len: u64) {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    unsafe {
+        libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+        if len > 0 {
+            libc::posix_fallocate(fd, 0, len as libc::off_t);
+        }
+    }
+}
+
+#[cfg(not(all(feature = "fallocate", target_os = "linux")))]
+pub(crate) fn preallocate(_file: &File, _len: u64) {}
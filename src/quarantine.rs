@@ -0,0 +1,89 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use error::Error;
+use file_part::{FilePart, TempFile};
+use form_data::FormData;
+use shared::Shared;
+
+type Scanner = dyn Fn(&str, &FilePart) -> Result<(), String> + Send + Sync;
+
+/// A directory that uploads are held in until the whole body has been parsed (see
+/// `ParseConfig::quarantine`), and optionally a scanner, such as a virus scanner, that must
+/// pass each of them.  Only once every part has been read and passed are the files moved
+/// to the upload directory (see `ParseConfig::upload_dir()`), by renaming them if it is on
+/// the same filesystem.  If parsing fails, or a file does not pass, every file of the
+/// request is deleted from quarantine and none reaches the upload directory.
+///
+/// Cloning a `Quarantine` gives one sharing the same scanner.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quarantine {
+    dir: PathBuf,
+    scanner: Option<Shared<Scanner>>,
+}
+
+impl Quarantine {
+    /// Hold uploads in `dir` until they are released.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Quarantine {
+        Quarantine { dir: dir.into(), scanner: None }
+    }
+
+    /// Check each file (and each field spilled to disk) with `scan`, given its name, before
+    /// any is released.  `scan` returns the reason for rejecting a file, and parsing then
+    /// fails with `Error::ScanRejected`.
+    pub fn scanner<F>(mut self, scan: F) -> Quarantine
+        where F: Fn(&str, &FilePart) -> Result<(), String> + Send + Sync + 'static
+    {
+        self.scanner = Some(Shared(Arc::new(scan)));
+        self
+    }
+
+    /// The directory uploads are held in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Scan the files of `formdata`, then move them to `to`.
+    pub(crate) fn release(&self, formdata: &mut FormData, to: &Path, mode: u32)
+                          -> Result<(), Error>
+    {
+        if let Some(ref scan) = self.scanner {
+            for (name, file) in quarantined(formdata) {
+                if let Err(reason) = scan(name, file) {
+                    return Err(Error::ScanRejected(format!("{}: {}", name, reason)));
+                }
+            }
+        }
+        fs::create_dir_all(to)?;
+        let mut moved = Vec::new();
+        move_all(formdata, to, mode, &mut moved)
+    }
+}
+
+// The files and spilled fields of `formdata` and its groups.
+fn quarantined(formdata: &FormData) -> Vec<(&str, &FilePart)> {
+    let mut files: Vec<(&str, &FilePart)> = formdata.files.iter()
+        .chain(formdata.large_fields.iter())
+        .map(|(name, file)| (&name[..], file))
+        .collect();
+    for (_, group) in &formdata.groups {
+        files.extend(quarantined(group));
+    }
+    files
+}
+
+fn move_all(formdata: &mut FormData, to: &Path, mode: u32,
+            moved: &mut Vec<(Arc<TempFile>, Arc<TempFile>)>)
+            -> Result<(), Error>
+{
+    for (_, file) in formdata.files.iter_mut().chain(formdata.large_fields.iter_mut()) {
+        file.move_temp(to, mode, moved)?;
+    }
+    for (_, group) in &mut formdata.groups {
+        move_all(group, to, mode, moved)?;
+    }
+    Ok(())
+}
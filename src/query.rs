@@ -0,0 +1,96 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Conversion between `FormData` and `application/x-www-form-urlencoded` query strings.
+
+use std::io::Read;
+use error::Error;
+use form_data::FormData;
+
+/// What `FormData::to_query_string()` does with files, which a query string cannot carry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryFiles {
+    /// Fail with `Error::FilesNotSupported` if there are any files.
+    Reject,
+    /// Include each file as a field whose value is its filename (empty if it has none).
+    Filename,
+}
+
+impl FormData {
+    /// Encode the fields as an `application/x-www-form-urlencoded` query string (without a
+    /// leading `?`), in order, e.g. for a signed callback URL.  Large fields are read back
+    /// from disk.  Files are treated as `files` directs.
+    pub fn to_query_string(&self, files: QueryFiles) -> Result<String, Error> {
+        let mut pairs: Vec<(String, String)> = self.fields.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        for (name, file) in &self.large_fields {
+            let mut value = String::new();
+            file.open_content()?.read_to_string(&mut value)?;
+            pairs.push((name.clone(), value));
+        }
+        for (name, file) in &self.files {
+            match files {
+                QueryFiles::Reject => return Err(Error::FilesNotSupported),
+                QueryFiles::Filename =>
+                    pairs.push((name.clone(), file.filename()?.unwrap_or_default())),
+            }
+        }
+        let encoded: Vec<String> = pairs.iter()
+            .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+            .collect();
+        Ok(encoded.join("&"))
+    }
+
+    /// Decode an `application/x-www-form-urlencoded` query string, with or without a
+    /// leading `?`, into a `FormData` of fields.
+    pub fn from_query_string(query: &str) -> Result<FormData, Error> {
+        let query = query.strip_prefix('?').unwrap_or(query);
+        let mut formdata = FormData::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = match pair.find('=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => (pair, ""),
+            };
+            formdata.fields.push((decode(name)?.into(), decode(value)?.into()));
+        }
+        Ok(formdata)
+    }
+}
+
+// Percent-encode as for `application/x-www-form-urlencoded`, with spaces as `+`.
+fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' =>
+                encoded.push(b as char),
+            b' ' => encoded.push('+'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+// Undo `encode()`.  A `%` not followed by two hex digits is kept as it is, as browsers do.
+fn decode(s: &str) -> Result<String, Error> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|hex| ::std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+                continue;
+            },
+            (b'+', _) => decoded.push(b' '),
+            (b, _) => decoded.push(b),
+        }
+        i += 1;
+    }
+    Ok(String::from_utf8(decoded)?)
+}
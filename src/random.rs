@@ -0,0 +1,54 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::sync::{Arc, Mutex};
+
+use shared::Shared;
+
+type Fill = dyn Fn(&mut [u8]) + Send + Sync;
+
+/// A source of the random bytes that generated boundaries and the names of temporary files
+/// are made from, in place of the default (the operating system's generator, by way of
+/// `textnonce`).  See `BoundaryStyle::generate_with()` and `ParseConfig::random`.
+///
+/// Cloning a `RandomSource` gives one drawing from the same source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RandomSource {
+    fill: Shared<Fill>,
+}
+
+impl RandomSource {
+    /// Draw random bytes from `fill`, which fills the buffer it is given, e.g. from a
+    /// deployment's approved CSPRNG.
+    pub fn new<F>(fill: F) -> RandomSource
+        where F: Fn(&mut [u8]) + Send + Sync + 'static
+    {
+        RandomSource { fill: Shared(Arc::new(fill)) }
+    }
+
+    /// A repeatable sequence of bytes determined by `seed`, so that tests can predict the
+    /// boundaries and file names generated.  The bytes are not secret: never use this
+    /// outside of tests.  Clones continue the same sequence.
+    pub fn seeded(seed: u64) -> RandomSource {
+        let state = Mutex::new(seed);
+        RandomSource::new(move |buf: &mut [u8]| {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            for chunk in buf.chunks_mut(8) {
+                // SplitMix64
+                *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+                chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+            }
+        })
+    }
+
+    // `n` random bytes.
+    pub(crate) fn bytes(&self, n: usize) -> Vec<u8> {
+        let mut buf = vec![0; n];
+        (self.fill)(&mut buf);
+        buf
+    }
+}
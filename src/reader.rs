@@ -0,0 +1,1283 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use hyper::header::{Headers, ContentType, ContentLength, ContentDisposition,
+                    DispositionParam, DispositionType, Charset};
+use mime::{Mime, TopLevel};
+use sha2::{Digest, Sha256};
+use config::{ParseConfig, ControlChars, FieldDecoder, FieldOverflow, MarkupGuard, RawParts,
+             UnnamedFiles};
+use decoder::Base64Stream;
+use dedup::{ContentHash, Duplicate};
+use descriptor::MultipartDescriptor;
+use disposition;
+use error::{Error, InterruptedPart};
+use file_part::{self, FilePart, Home, TempFile};
+use filter::FilterAction;
+use form_data::{FormData, FieldWarning, RawPart, SkippedPart, SkipReason};
+use headers::{self, RequestHeaders};
+#[cfg(feature = "serde_json")]
+use json;
+use parser::{self, Parser, Event};
+use policy::{self, MARKUP_SNIFF_LEN};
+use prealloc;
+use spill;
+use stats::PartTiming;
+#[cfg(feature = "async")]
+use storage::FileSink;
+use temp_file;
+#[cfg(feature = "threaded")]
+use writer::WriterThread;
+
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+// The most memory reserved up front for a field's value, whatever its Content-Length claims
+const FIELD_PREALLOCATE_LIMIT: usize = 64 * 1024;
+
+/// Parse MIME `multipart/form-data` information from a stream as a `FormData`, as directed
+/// by `config`.
+pub fn read_formdata_with_config<S: Read, H: RequestHeaders>(stream: &mut S, headers: &H,
+                                                             config: &ParseConfig)
+                                                             -> Result<FormData, Error>
+{
+    read_formdata_seen(stream, headers, config, &[])
+}
+
+// Like `read_formdata_with_config()`, taking the idempotency keys in `seen` to have been
+// received already.
+pub(crate) fn read_formdata_seen<S: Read, H: RequestHeaders>(stream: &mut S, headers: &H,
+                                                            config: &ParseConfig,
+                                                            seen: &[String])
+                                                            -> Result<FormData, Error>
+{
+    headers::check_content_type(headers, config)?;
+    read(stream, &MultipartDescriptor::from_any_multipart(headers)?, config, None, seen)
+}
+
+/// Parse MIME `multipart/form-data` information from a body that has already been written
+/// to the file at `path`, as directed by `config`.  Uploaded files are not copied out of
+/// the body: each `FilePart` refers to its range of `path` (see `FilePart::source_range()`),
+/// so `path` must be left in place for as long as they are in use.  Files within a nested
+/// `multipart/mixed` group, and fields spilled to disk, are copied out as usual.
+pub fn read_formdata_in_place<H: RequestHeaders>(path: &Path, headers: &H,
+                                                 config: &ParseConfig)
+                                                 -> Result<FormData, Error>
+{
+    headers::check_content_type(headers, config)?;
+    let descriptor = MultipartDescriptor::from_any_multipart(headers)?;
+    read(&mut File::open(path)?, &descriptor, config, Some(path), &[])
+}
+
+// Parse the body described by `descriptor`.  If `source` is given, it is the file `stream`
+// reads, and files are left in place within it.  Parts with an idempotency key in `seen`
+// are skipped.
+pub(crate) fn read<S: Read>(stream: &mut S, descriptor: &MultipartDescriptor,
+                            config: &ParseConfig, source: Option<&Path>, seen: &[String])
+                            -> Result<FormData, Error>
+{
+    descriptor.check(config)?;
+    collect(stream, descriptor, source, seen, Collector::new(config)?)
+}
+
+// Parse the body described by `descriptor`, writing files into `sink` rather than into
+// temporary files.
+#[cfg(feature = "async")]
+pub(crate) fn read_to_sink<'a, S: Read>(stream: &mut S, descriptor: &MultipartDescriptor,
+                                        config: &'a ParseConfig, sink: &'a mut dyn FileSink)
+                                        -> Result<FormData, Error>
+{
+    descriptor.check(config)?;
+    let mut collector = Collector::new(config)?;
+    collector.sink = Some(sink);
+    collect(stream, descriptor, None, &[], collector)
+}
+
+// Parse the body described by `descriptor` with `collector`, as for `read()`.
+fn collect<S: Read>(stream: &mut S, descriptor: &MultipartDescriptor, source: Option<&Path>,
+                    seen: &[String], mut collector: Collector) -> Result<FormData, Error>
+{
+    let config = collector.config;
+    let start = if config.timing { Some(Instant::now()) } else { None };
+    let mut parser = Parser::new(&descriptor.boundary, config);
+    collector.content_length = descriptor.content_length;
+    collector.boundaries.push(descriptor.boundary.clone());
+    collector.idempotency_keys = seen.to_vec();
+    if let Some(source) = source {
+        parser.record_ranges();
+        collector.source = Some(source.to_owned());
+    }
+
+    let result = match config.buffer_pool {
+        Some(ref pool) => {
+            parser.set_buffer(pool.take(READ_BUFFER_SIZE));
+            let result = collector.read(stream, &mut parser);
+            pool.give(parser.take_buffer());
+            result
+        },
+        None => collector.read(stream, &mut parser),
+    };
+    let mut formdata = collector.formdata;
+    formdata.stats.header_bytes += parser.header_bytes();
+    formdata.stats.body_bytes = collector.consumed;
+    formdata.stats.terminated = parser.is_terminated();
+    formdata.stats.epilogue_bytes = parser.epilogue_bytes();
+    formdata.stats.elapsed = start.map(|start| start.elapsed());
+    formdata.idempotency_keys = collector.idempotency_keys.split_off(seen.len());
+    let result = match result {
+        Ok(()) => match config.quarantine {
+            Some(ref quarantine) => quarantine
+                .release(&mut formdata, &config.upload_dir(), config.file_mode)
+                .map(|()| formdata),
+            None => Ok(formdata),
+        },
+        Err(err) => match err {
+            Error::Eof(part) if config.keep_partial =>
+                Err(Error::EofWithPartial(Box::new(formdata), part)),
+            Error::TooManyParts(_) | Error::TooManyFiles(_) => {
+                let drained = if config.abort_on_limit {
+                    Ok(())
+                } else {
+                    drain(stream, config, collector.started, &mut collector.consumed)
+                };
+                drained.and(Err(match err {
+                    Error::TooManyParts(_) => Error::TooManyParts(collector.consumed),
+                    _ => Error::TooManyFiles(collector.consumed),
+                }))
+            },
+            err => Err(err),
+        },
+    };
+    match result {
+        Err(Error::EofWithPartial(..)) | Ok(_) => {},
+        Err(_) => {
+            #[cfg(feature = "threaded")]
+            drop(collector.writer.take());
+            for temp in &collector.created {
+                if config.keep_files_on_error { temp.keep() } else { temp.discard() }
+            }
+            if let Some(quota) = config.tenant.as_ref().and_then(|t| t.quota.as_ref()) {
+                quota.release(collector.quota_used);
+            }
+        },
+    }
+    result
+}
+
+/// Check the request headers against `config` before reading any of the body, so that a
+/// server can reject the request at once.  Fails with `Error::NoRequestContentType`,
+/// `Error::NotMultipart` or `Error::NotFormData` (suitable for a 415 response),
+/// `Error::BoundaryNotSpecified` or `Error::InvalidBoundary` (400), or
+/// `Error::BodyTooLarge` if the declared Content-Length exceeds
+/// `ParseConfig::max_body_size` (413), or with `Error::InvalidContentType` as directed by
+/// `ParseConfig::strict_content_type` (400).
+pub fn precheck<H: RequestHeaders>(headers: &H, config: &ParseConfig) -> Result<(), Error> {
+    headers::check_content_type(headers, config)?;
+    MultipartDescriptor::from_headers(headers)?.check(config)
+}
+
+// A part in the process of being received.
+enum Part {
+    Field { name: String, headers: Headers, value: Vec<u8>, truncated: bool },
+    // A file, or a field that was spilled to disk or is being decoded into a file.  The
+    // content is hashed if it is to be deduplicated, and its start kept in `sniff` until it
+    // has been checked against `markup_guard`.
+    File { name: String, file: FilePart, out: Output, size: usize, is_field: bool,
+           hasher: Option<Sha256>, decoder: Option<Box<Base64Stream>>,
+           sniff: Option<Vec<u8>> },
+    // A nested multipart (e.g. `multipart/mixed`), as used for multiple file uploads under a
+    // single name.  Its subparts take their name from the group.  `depth` is 1 for a group
+    // at the top level.
+    Group { name: String, parser: Parser, current: Box<Option<Part>>, depth: usize },
+    // A part we have no use for, and what to report of it (if anything).
+    Skip(Option<SkippedPart>),
+}
+
+// Where the content of a file part is written.
+enum Output {
+    File(File),
+    // Left where it lies in the source file.
+    InPlace,
+    // Not yet linked into its directory; see `ParseConfig::unnamed_temp_files`.
+    Unnamed(File),
+    // Handed to the writer thread, under this id.
+    #[cfg(feature = "threaded")]
+    Queued(usize),
+    // Handed to the collector's `sink`, under this id.
+    #[cfg(feature = "async")]
+    Sink(usize),
+}
+
+// Builds a `FormData` out of parser events.  Order and nesting are irrelevant, so parts are
+// put into buckets (fields, files and large fields).
+struct Collector<'a> {
+    config: &'a ParseConfig,
+    formdata: FormData,
+    // Bytes read from the stream so far
+    consumed: u64,
+    parts: usize,
+    files: usize,
+    // When reading began, for `max_duration`
+    started: Instant,
+    // The request's declared Content-Length
+    content_length: Option<u64>,
+    // For `RawParts::Bytes`, the body from `raw_offset` onwards
+    raw: Vec<u8>,
+    raw_offset: u64,
+    // The file the body is being read from, if files are to be left in place
+    source: Option<PathBuf>,
+    // The number of top-level parts ended, and where in the body the last one ended
+    ended: usize,
+    part_end: u64,
+    // The boundaries of the body and of each group the current part is within
+    boundaries: Vec<Vec<u8>>,
+    // For each open group nested more than one level deep, the `FormData` it was begun
+    // within, which its own stands in for until it ends, and that one's `file_counts`
+    enclosing: Vec<(FormData, HashMap<String, usize>)>,
+    // The number of files of each name in `formdata`, to index the next
+    file_counts: HashMap<String, usize>,
+    // Every temporary file created, so that none outlives a failed parse
+    created: Vec<Arc<TempFile>>,
+    // The bytes taken from the tenant's quota, to be returned if the parse fails
+    quota_used: u64,
+    // The idempotency keys seen before this body, and then those of its parts
+    idempotency_keys: Vec<String>,
+    // For `timing`, when the current part began, and how long it has spent writing
+    part_started: Option<Instant>,
+    write_time: Duration,
+    #[cfg(feature = "threaded")]
+    writer: Option<WriterThread>,
+    // Where file content goes, in place of temporary files
+    #[cfg(feature = "async")]
+    sink: Option<&'a mut dyn FileSink>,
+}
+
+impl<'a> Collector<'a> {
+    fn new(config: &'a ParseConfig) -> Result<Collector<'a>, Error> {
+        Ok(Collector {
+            config,
+            formdata: FormData::new(),
+            consumed: 0,
+            parts: 0,
+            files: 0,
+            started: Instant::now(),
+            content_length: None,
+            raw: Vec::new(),
+            raw_offset: 0,
+            source: None,
+            ended: 0,
+            part_end: 0,
+            boundaries: Vec::new(),
+            enclosing: Vec::new(),
+            file_counts: HashMap::new(),
+            created: Vec::new(),
+            quota_used: 0,
+            idempotency_keys: Vec::new(),
+            part_started: None,
+            write_time: Duration::ZERO,
+            #[cfg(feature = "threaded")]
+            writer: match config.write_queue {
+                Some(capacity) => Some(WriterThread::spawn(capacity)?),
+                None => None,
+            },
+            #[cfg(feature = "async")]
+            sink: None,
+        })
+    }
+
+    // Read the body from `stream`, feeding it through `parser`.
+    fn read<S: Read>(&mut self, stream: &mut S, parser: &mut Parser) -> Result<(), Error> {
+        let mut buf = match self.config.buffer_pool {
+            Some(ref pool) => pool.take(READ_BUFFER_SIZE),
+            None => Vec::new(),
+        };
+        buf.resize(READ_BUFFER_SIZE, 0);
+        let mut result = self.read_with(stream, parser, &mut buf);
+        if let Some(ref recorder) = self.config.record_body {
+            let flushed = recorder.flush();
+            if result.is_ok() {
+                result = flushed.map_err(From::from);
+            }
+        }
+        if let Some(ref pool) = self.config.buffer_pool {
+            pool.give(buf);
+        }
+        result
+    }
+
+    fn read_with<S: Read>(&mut self, stream: &mut S, parser: &mut Parser, buf: &mut [u8])
+                          -> Result<(), Error>
+    {
+        let mut current: Option<Part> = None;
+        let mut events: Vec<Event> = Vec::new();
+        while !parser.is_done() {
+            let n = match stream.read(buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(From::from(e)),
+            };
+            self.consumed += n as u64;
+            if let Some(ref limiter) = self.config.rate_limiter {
+                limiter.consume(n);
+            }
+            if let Some(ref recorder) = self.config.record_body {
+                recorder.record(&buf[..n])?;
+            }
+            if self.consumed > self.config.max_body_size.unwrap_or(u64::MAX) {
+                self.audit(current.as_ref(), &Error::BodyTooLarge);
+                return Err(Error::BodyTooLarge);
+            }
+            if let Some(max_duration) = self.config.max_duration {
+                if self.started.elapsed() > max_duration {
+                    self.audit(current.as_ref(), &Error::ParseTimeout);
+                    return Err(Error::ParseTimeout);
+                }
+            }
+            if n == 0 {
+                events = match parser.finish() {
+                    Err(Error::Eof(_)) => {
+                        let interrupted = current.as_ref().and_then(|part| self.interrupted(part));
+                        if self.config.keep_partial {
+                            self.end_partial(current)?;
+                            self.finish_writer()?;
+                        }
+                        return Err(Error::Eof(interrupted));
+                    },
+                    result => result?,
+                };
+            } else {
+                if let Err(e) = parser.push_into(&buf[..n], &mut events) {
+                    self.audit(None, &e);
+                    return Err(e);
+                }
+                if let Some(mode) = self.config.raw_parts {
+                    self.collect_raw(parser, &buf[..n], mode);
+                }
+            }
+            for event in events.drain(..) {
+                if let (&Event::PartEnd, Some(range)) =
+                    (&event, parser.part_ranges().get(self.ended))
+                {
+                    self.part_end = range.end;
+                    self.ended += 1;
+                }
+                self.event(&mut current, None, event)?;
+            }
+        }
+        self.finish_writer()
+    }
+
+    // Keep the parts that `parser` has found the end of since last time, given that `data`
+    // was just pushed to it.
+    fn collect_raw(&mut self, parser: &Parser, data: &[u8], mode: RawParts) {
+        if mode == RawParts::Bytes {
+            self.raw.extend_from_slice(data);
+        }
+        let ranges = &parser.part_ranges()[self.formdata.raw_parts.len()..];
+        for range in ranges {
+            let bytes = match mode {
+                RawParts::Ranges => None,
+                RawParts::Bytes => Some(self.raw[(range.start - self.raw_offset) as usize..
+                                                 (range.end - self.raw_offset) as usize].to_vec()),
+            };
+            self.formdata.raw_parts.push(RawPart { range: range.clone(), bytes });
+        }
+        if let (RawParts::Bytes, Some(last)) = (mode, ranges.last()) {
+            // Nothing before the end of the last part is needed any more
+            self.raw.drain(..(last.end - self.raw_offset) as usize);
+            self.raw_offset = last.end;
+        }
+    }
+
+    // Wait for the writer thread, if any, to write out everything queued.
+    fn finish_writer(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "threaded")]
+        {
+            if let Some(ref mut writer) = self.writer {
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Handle an event for the part `current`.  `group` is the name of the enclosing group,
+    // if the event comes from within a nested multipart.
+    fn event(&mut self, current: &mut Option<Part>, group: Option<&str>, event: Event)
+             -> Result<(), Error>
+    {
+        match event {
+            Event::Headers(headers) => {
+                let audited = self.config.audit.as_ref().map(|_| headers.clone());
+                match self.begin(headers, group) {
+                    Ok(part) => *current = Some(part),
+                    Err(e) => {
+                        if let (Some(auditor), Some(headers)) = (&self.config.audit, audited) {
+                            let name = group.map(|name| name.to_owned())
+                                .or_else(|| get_content_disposition_name(&headers).ok());
+                            auditor.report(name.as_deref(), Some(&headers), &e);
+                        }
+                        return Err(e);
+                    },
+                }
+            },
+            Event::Data(data) => if let Some(ref mut part) = *current {
+                self.data(part, &data)?;
+            },
+            Event::PartEnd => if let Some(part) = current.take() {
+                self.end(part)?;
+            },
+            Event::End => {},
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self, mut headers: Headers, group: Option<&str>) -> Result<Part, Error> {
+        if self.config.timing {
+            self.part_started = Some(Instant::now());
+            self.write_time = Duration::ZERO;
+        }
+        if self.config.lenient_disposition {
+            reparse_disposition(&mut headers);
+        }
+        if self.config.strip_filename_paths {
+            strip_filename_path(&mut headers);
+        }
+        if self.config.normalize_names || self.config.fold_case {
+            normalize_filename(&mut headers, self.config);
+        }
+
+        // A multipart part that is itself an uploaded file is kept whole; see
+        // `FilePart::parse_as_formdata()`.
+        let boundary = match RequestHeaders::content_type(&headers) {
+            Some(ref mime @ Mime(TopLevel::Multipart, _, _))
+                if !is_file(&headers) => Some(parser::content_type_boundary(mime)?),
+            _ => None,
+        };
+        if let Some(boundary) = boundary {
+            return self.open_group(&headers, group, boundary);
+        }
+
+        if let Some(key) = self.idempotency_key(&headers) {
+            if self.idempotency_keys.contains(&key) {
+                let name = get_content_disposition_name(&headers).ok();
+                return Ok(skip(&headers, name, SkipReason::Repeated));
+            }
+            self.idempotency_keys.push(key);
+        }
+
+        self.parts += 1;
+        if self.parts > self.config.max_parts.unwrap_or(usize::MAX) {
+            return Err(Error::TooManyParts(self.consumed));
+        }
+
+        let file_disposition = group.is_none() && self.config.file_dispositions &&
+            is_file_disposition(&headers);
+        let file = file_disposition || is_file(&headers);
+        let strip = self.config.control_chars == ControlChars::Strip;
+        let filename_controls = self.config.control_chars != ControlChars::Allow &&
+            filename_controls(&mut headers, strip);
+        let name = match group {
+            Some(name) => name.to_owned(),
+            None => {
+                let name = self.part_name(&headers, file, file_disposition)?;
+                match self.map_name(name.clone()) {
+                    Some(name) => name,
+                    None => return Ok(skip(&headers, Some(name), SkipReason::NameDropped)),
+                }
+            },
+        };
+        let mut name = self.filter_controls(None, name, false)?;
+        if filename_controls {
+            match self.config.control_chars {
+                ControlChars::Reject => return Err(Error::ControlCharacters(name)),
+                _ => {
+                    let warning = FieldWarning::ControlCharsStripped(name.clone());
+                    self.formdata.warnings.push(warning);
+                },
+            }
+        }
+        if let Some(ref filters) = self.config.part_filters {
+            if filters.apply(&mut name, &mut headers) == FilterAction::Drop {
+                return Ok(skip(&headers, Some(name), SkipReason::Filtered));
+            }
+        }
+        if file {
+            self.files += 1;
+            if self.files > self.config.max_files.unwrap_or(usize::MAX) {
+                return Err(Error::TooManyFiles(self.consumed));
+            }
+            if let Some(ref policy) = self.config.mime_policy {
+                policy.check(&headers)?;
+            }
+            match (&self.source, group) {
+                (Some(source), None) => {
+                    let sniff = self.sniff_for(&headers, false);
+                    let mut file = FilePart::new(headers, source);
+                    file.home = Some(Box::new(self.home()));
+                    Ok(Part::File {
+                        name,
+                        sniff,
+                        file,
+                        out: Output::InPlace,
+                        size: 0,
+                        is_field: false,
+                        hasher: None,
+                        decoder: None,
+                    })
+                },
+                _ => self.create_file(name, headers, false),
+            }
+        } else if self.decoder_for(&name).is_some() {
+            let mut part = self.create_file(name, headers, false)?;
+            if let Part::File { ref mut decoder, .. } = part {
+                *decoder = Some(Box::new(Base64Stream::new()));
+            }
+            Ok(part)
+        } else {
+            let value = match self.size_hint(&headers) {
+                Some(hint) if self.config.preallocate && headers.has::<ContentLength>() => {
+                    let limit = self.config.field_memory_limit.unwrap_or(usize::MAX)
+                        .min(FIELD_PREALLOCATE_LIMIT);
+                    Vec::with_capacity(hint.min(limit as u64) as usize)
+                },
+                _ => Vec::new(),
+            };
+            Ok(Part::Field { name, headers, value, truncated: false })
+        }
+    }
+
+    // The part's idempotency key, if `idempotency_header` is set and it has one.
+    fn idempotency_key(&self, headers: &Headers) -> Option<String> {
+        let header = self.config.idempotency_header.as_ref()?;
+        let raw = headers.get_raw(header)?.first()?;
+        let key = String::from_utf8_lossy(raw).trim().to_owned();
+        if key.is_empty() { None } else { Some(key) }
+    }
+
+    // How large the content of a part with `headers` is expected to be: its own
+    // Content-Length, if it has one, and no more than what remains of the request's.
+    fn size_hint(&self, headers: &Headers) -> Option<u64> {
+        let remaining = self.content_length.map(|len| len.saturating_sub(self.consumed));
+        match (headers.get::<ContentLength>().map(|cl| cl.0), remaining) {
+            (Some(own), Some(remaining)) => Some(own.min(remaining)),
+            (own, remaining) => own.or(remaining),
+        }
+    }
+
+    // Begin a nested multipart delimited by `boundary`, within the group named `group` if
+    // any, or skip it if it is nested too deeply.
+    fn open_group(&mut self, headers: &Headers, group: Option<&str>, boundary: Vec<u8>)
+                  -> Result<Part, Error>
+    {
+        let depth = self.boundaries.len();
+        if depth > self.config.max_nesting {
+            let name = match group {
+                Some(name) => Some(name.to_owned()),
+                None => get_content_disposition_name(headers).ok(),
+            };
+            return Ok(skip(headers, name, SkipReason::TooDeep));
+        }
+        if self.boundaries.contains(&boundary) {
+            return Err(Error::RepeatedBoundary);
+        }
+        let name = match group {
+            Some(name) => name.to_owned(),
+            None => match self.anonymous_name(headers, self.parts + 1) {
+                Some(name) => name,
+                None => self.config.normalize_owned(get_content_disposition_name(headers)?),
+            },
+        };
+        let name = match self.map_name(name.clone()) {
+            Some(name) => self.filter_controls(None, name, false)?,
+            None => return Ok(skip(headers, Some(name), SkipReason::NameDropped)),
+        };
+        let parser = Parser::new(&boundary, self.config);
+        self.boundaries.push(boundary);
+        if depth > 1 {
+            // The group's parts are gathered into a `FormData` of its own
+            let enclosing = mem::take(&mut self.formdata);
+            self.enclosing.push((enclosing, mem::take(&mut self.file_counts)));
+            let enclosing = &mut self.enclosing.last_mut().unwrap().0;
+            carry(enclosing, &mut self.formdata);
+        }
+        Ok(Part::Group { name, parser, current: Box::new(None), depth })
+    }
+
+    // End the group named `name` at `depth`, begun by `open_group()`.
+    fn close_group(&mut self, name: String, depth: usize) {
+        self.boundaries.pop();
+        if depth > 1 {
+            if let Some((enclosing, file_counts)) = self.enclosing.pop() {
+                self.file_counts = file_counts;
+                let mut group = mem::replace(&mut self.formdata, enclosing);
+                carry(&mut group, &mut self.formdata);
+                self.formdata.groups.push((name, group));
+            }
+        }
+    }
+
+    // The name of a part, as set by the `unnamed_files` setting for file parts.  An unnamed
+    // part accepted by `file_dispositions` never fails for want of a name.
+    fn part_name(&self, headers: &Headers, file: bool, file_disposition: bool)
+                 -> Result<String, Error>
+    {
+        if let Some(name) = self.anonymous_name(headers, self.parts) {
+            return Ok(name);
+        }
+        let name = match get_content_disposition_name(headers) {
+            Ok(name) => Some(name),
+            Err(Error::NoName) => None,
+            Err(e) => return Err(e),
+        };
+        let unnamed = match name {
+            Some(ref name) => name.is_empty(),
+            None => true,
+        };
+        if !unnamed || !file {
+            return name.map(|name| self.config.normalize_owned(name)).ok_or(Error::NoName);
+        }
+        let generated = || format!("file_{}", self.files);
+        match self.config.unnamed_files {
+            UnnamedFiles::Error if !file_disposition => name.ok_or(Error::NoName),
+            UnnamedFiles::Generate => Ok(generated()),
+            // `begin()` has already normalized the filename
+            UnnamedFiles::Error | UnnamedFiles::Filename =>
+                Ok(file_part::headers_filename(headers).unwrap_or_else(generated)),
+        }
+    }
+
+    // `name` as rewritten by the `name_mapper`, or `None` if the part is to be dropped.
+    fn map_name(&self, name: String) -> Option<String> {
+        match self.config.name_mapper {
+            Some(ref mapper) => mapper.map(&name),
+            None => Some(name),
+        }
+    }
+
+    // The name for the `index`th part if it has no `Content-Disposition` and such parts are
+    // accepted.
+    fn anonymous_name(&self, headers: &Headers, index: usize) -> Option<String> {
+        if !self.config.anonymous_parts || headers.get_raw("Content-Disposition").is_some() {
+            return None;
+        }
+        let content_id = headers.get_raw("Content-ID")
+            .and_then(|raw| raw.first())
+            .and_then(|line| ::std::str::from_utf8(line).ok())
+            .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>').to_owned())
+            .filter(|id| !id.is_empty());
+        Some(match content_id {
+            Some(id) => self.config.normalize_owned(id),
+            None => format!("part_{}", index),
+        })
+    }
+
+    // Where a file left in place is to be copied out to, if it is.
+    fn home(&self) -> Home {
+        Home {
+            dir: self.config.upload_dir(),
+            mode: self.config.file_mode,
+            random: self.config.random.clone(),
+        }
+    }
+
+    fn decoder_for(&self, name: &str) -> Option<FieldDecoder> {
+        self.config.field_decoders.iter().find(|d| d.0 == name).map(|d| d.1)
+    }
+
+    fn create_file(&mut self, name: String, headers: Headers, is_field: bool)
+                   -> Result<Part, Error>
+    {
+        #[cfg(feature = "async")]
+        {
+            // A JSON part is read back once it ends, so it needs a file of its own
+            #[cfg(feature = "serde_json")]
+            let json = self.config.parse_json && json::is_json(&headers);
+            #[cfg(not(feature = "serde_json"))]
+            let json = false;
+            let sniff = self.sniff_for(&headers, is_field);
+            if let (Some(ref mut sink), false, false) = (self.sink.as_mut(), is_field, json) {
+                let id = sink.begin(&name, &headers)?;
+                let mut file = FilePart::new(headers, Path::new(""));
+                file.stored = Some(id);
+                return Ok(Part::File { name, file, out: Output::Sink(id), size: 0, is_field,
+                                       hasher: None, decoder: None, sniff });
+            }
+        }
+        let dir = match self.config.quarantine {
+            Some(ref quarantine) => quarantine.dir().to_owned(),
+            None => self.config.upload_dir(),
+        };
+        if self.config.tenant.is_some() || self.config.quarantine.is_some() {
+            fs::create_dir_all(&dir)?;
+        }
+        let sniff = self.sniff_for(&headers, is_field);
+        let mode = self.config.file_mode;
+        let random = self.config.random.as_ref();
+        let file = FilePart::create_in_mode(&dir, headers, mode, random).map_err(|e| match e {
+            Error::Io(e) => spill::storage_error(e),
+            e => e,
+        })?;
+        self.created.extend(file.temp_file());
+        let unnamed = match file.path.parent() {
+            Some(parent) if self.config.unnamed_temp_files && self.config.write_queue.is_none() =>
+                temp_file::create_unnamed(parent, mode).map_err(spill::storage_error)?,
+            _ => None,
+        };
+        let (out, unnamed) = match unnamed {
+            Some(out) => (out, true),
+            None => (temp_file::create(&file.path, mode).map_err(spill::storage_error)?, false),
+        };
+        if self.config.preallocate {
+            prealloc::preallocate(&out, self.size_hint(&file.headers).unwrap_or(0));
+        }
+        let out = if unnamed { Output::Unnamed(out) } else { Output::File(out) };
+        #[cfg(feature = "threaded")]
+        let out = match (out, self.writer.as_mut()) {
+            (Output::File(f), Some(writer)) => Output::Queued(writer.open(f)?),
+            (out, _) => out,
+        };
+        let hasher = match self.config.dedup {
+            Some(_) if !is_field => Some(Sha256::new()),
+            _ => None,
+        };
+        Ok(Part::File { name, file, out, size: 0, is_field, hasher, decoder: None, sniff })
+    }
+
+    // Somewhere to keep the start of a file with these `headers`, if it is to be checked
+    // against `markup_guard`.
+    fn sniff_for(&self, headers: &Headers, is_field: bool) -> Option<Vec<u8>> {
+        match self.config.markup_guard {
+            Some(_) if !is_field && policy::markup_guarded(headers) => Some(Vec::new()),
+            _ => None,
+        }
+    }
+
+    // Check `start`, the start of the file `name`, against `markup_guard`.
+    fn guard_markup(&mut self, name: &str, headers: &Headers, start: &[u8])
+                    -> Result<(), Error>
+    {
+        if !policy::looks_like_markup(start) {
+            return Ok(());
+        }
+        match self.config.markup_guard {
+            Some(MarkupGuard::Warn) =>
+                self.formdata.warnings.push(FieldWarning::MarkupInFile(name.to_owned())),
+            Some(MarkupGuard::Reject) => {
+                let declared = match headers.get::<ContentType>() {
+                    Some(&ContentType(Mime(ref top, ref sub, _))) => format!("{}/{}", top, sub),
+                    None => "application/octet-stream".to_owned(),
+                };
+                return Err(Error::FileNotAllowed(
+                    format!("content of {} file looks like HTML", declared)));
+            },
+            None => {},
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, part: &mut Part, data: &[u8]) -> Result<(), Error> {
+        if let Part::Field { ref mut value, ref mut truncated, .. } = *part {
+            let limit = self.config.field_memory_limit.unwrap_or(usize::MAX);
+            if value.len() + data.len() <= limit {
+                value.extend_from_slice(data);
+                return Ok(());
+            }
+            if self.config.field_overflow == FieldOverflow::Truncate {
+                let room = limit.saturating_sub(value.len());
+                value.extend_from_slice(&data[..room]);
+                *truncated = true;
+                return Ok(());
+            }
+        }
+        if let Part::Field { .. } = *part {
+            // The field has outgrown memory: move it, and what we had of it so far, to disk.
+            if let Part::Field { name, headers, value, .. } = mem::replace(part, Part::Skip(None)) {
+                *part = self.create_file(name, headers, true)?;
+                self.data(part, &value)?;
+            }
+        }
+        match *part {
+            Part::Field { .. } => {},
+            Part::File { ref name, ref mut file, ref mut out, ref mut size, is_field,
+                         ref mut hasher, ref mut decoder, ref mut sniff } => {
+                let decoded;
+                let data = match *decoder {
+                    Some(ref mut decoder) => {
+                        decoded = decoder.push(data)?;
+                        &decoded[..]
+                    },
+                    None => data,
+                };
+                if let Some(mut start) = sniff.take() {
+                    let room = MARKUP_SNIFF_LEN - start.len();
+                    start.extend_from_slice(&data[..data.len().min(room)]);
+                    if start.len() < MARKUP_SNIFF_LEN {
+                        *sniff = Some(start);
+                    } else {
+                        self.guard_markup(name, &file.headers, &start)?;
+                    }
+                }
+                if !is_field && (*size + data.len()) as u64 >
+                    self.config.max_file_size.unwrap_or(u64::MAX)
+                {
+                    return Err(Error::FileTooLarge);
+                }
+                if let Some(quota) = self.config.tenant.as_ref().and_then(|t| t.quota.as_ref()) {
+                    quota.consume(data.len() as u64)?;
+                    self.quota_used += data.len() as u64;
+                }
+                if let Some(ref mut hasher) = *hasher {
+                    hasher.update(data);
+                }
+                let writing = self.write_started();
+                let written = match *out {
+                    Output::File(ref mut f) | Output::Unnamed(ref mut f) => f.write_all(data),
+                    Output::InPlace => Ok(()),
+                    #[cfg(feature = "threaded")]
+                    Output::Queued(id) => {
+                        if let Some(ref mut writer) = self.writer {
+                            writer.write(id, data.to_vec())?;
+                        }
+                        Ok(())
+                    },
+                    #[cfg(feature = "async")]
+                    Output::Sink(id) => {
+                        if let Some(ref mut sink) = self.sink {
+                            sink.write(id, data)?;
+                        }
+                        Ok(())
+                    },
+                };
+                match written {
+                    Err(ref e) if spill::is_storage_full(e) =>
+                        self.spill(name, file, out, *size as u64, data)?,
+                    written => written?,
+                }
+                self.write_ended(writing);
+                *size += data.len();
+            },
+            Part::Group { ref name, ref mut parser, ref mut current, .. } => {
+                for event in parser.push(data)? {
+                    self.event(current, Some(name), event)?;
+                }
+            },
+            Part::Skip(Some(ref mut skipped)) => skipped.bytes += data.len() as u64,
+            Part::Skip(None) => {},
+        }
+        Ok(())
+    }
+
+    // For `timing`, when a write to disk began
+    fn write_started(&self) -> Option<Instant> {
+        self.part_started.map(|_| Instant::now())
+    }
+
+    fn write_ended(&mut self, started: Option<Instant>) {
+        if let Some(started) = started {
+            self.write_time += started.elapsed();
+        }
+    }
+
+    // For `timing`, record how long the part `name` took, now that it has ended
+    fn record_timing(&mut self, name: &str, bytes: u64) {
+        if let Some(started) = self.part_started.take() {
+            self.formdata.stats.part_timings.push(PartTiming {
+                name: name.to_owned(),
+                bytes,
+                receive: started.elapsed(),
+                write: mem::replace(&mut self.write_time, Duration::ZERO),
+            });
+        }
+    }
+
+    // Move the file `name`, of which `written` bytes are in `out`, to `spill_over` now that
+    // its directory is full, and write `data` there.
+    fn spill(&mut self, name: &str, file: &mut FilePart, out: &mut Output, written: u64,
+             data: &[u8]) -> Result<(), Error>
+    {
+        let spill_over = match self.config.spill_over {
+            Some(ref spill_over) => spill_over,
+            None => return Err(Error::StorageFull),
+        };
+        if let Output::Unnamed(ref f) = *out {
+            // Give it a name, so that what it has can be read back
+            temp_file::link(f, &file.path).map_err(spill::storage_error)?;
+        }
+        *out = Output::File(spill_over.move_file(name, file, written, data,
+                                                 self.config.file_mode,
+                                                 self.config.random.as_ref())?);
+        self.created.extend(file.temp_file());
+        Ok(())
+    }
+
+    fn end(&mut self, mut part: Part) -> Result<(), Error> {
+        if let Part::File { ref mut file, ref mut decoder, .. } = part {
+            if let Some(mut decoder) = decoder.take() {
+                let rest = decoder.finish()?;
+                if let Some(mime) = decoder.media_type.and_then(|m| m.parse::<Mime>().ok()) {
+                    file.headers.set(ContentType(mime));
+                }
+                self.data(&mut part, &rest)?;
+            }
+        }
+        if let Part::File { ref name, ref file, ref mut sniff, .. } = part {
+            if let Some(start) = sniff.take() {
+                self.guard_markup(name, &file.headers, &start)?;
+            }
+        }
+        match part {
+            #[cfg_attr(not(feature = "serde_json"), allow(unused_variables))]
+            Part::Field { name, mut value, truncated, headers } => {
+                if truncated {
+                    // Don't leave a partial character at the end.
+                    if let Err(e) = ::std::str::from_utf8(&value) {
+                        if e.error_len().is_none() {
+                            value.truncate(e.valid_up_to());
+                        }
+                    }
+                }
+                self.formdata.stats.parts += 1;
+                self.formdata.stats.fields += 1;
+                self.formdata.stats.part_bytes.push(value.len() as u64);
+                self.record_timing(&name, value.len() as u64);
+                #[cfg(feature = "serde_json")]
+                {
+                    if self.config.parse_json && json::is_json(&headers) {
+                        let json = json::parse(&name, &value[..])?;
+                        self.formdata.json_parts.push((name, json));
+                        return Ok(());
+                    }
+                }
+                let value = String::from_utf8(value)?;
+                let value = self.filter_controls(Some(&name), value, true)?;
+                if let Some(value) = self.tidy_value(&name, value) {
+                    self.formdata.fields.push((name.into(), value.into()));
+                }
+            },
+            Part::File { name, mut file, mut out, size, is_field, hasher, .. } => {
+                // Trim any preallocated space that went unused.
+                let len = if self.config.preallocate { Some(size as u64) } else { None };
+                let writing = self.write_started();
+                match out {
+                    Output::File(ref mut f) | Output::Unnamed(ref mut f) => {
+                        f.flush()?;
+                        if let Some(len) = len {
+                            f.set_len(len)?;
+                        }
+                        self.config.durability.sync_file(f).map_err(spill::storage_error)?;
+                    },
+                    Output::InPlace =>
+                        file.range = Some(self.part_end - size as u64..self.part_end),
+                    #[cfg(feature = "threaded")]
+                    Output::Queued(id) => if let Some(ref mut writer) = self.writer {
+                        writer.close(id, len, self.config.durability)?;
+                    },
+                    #[cfg(feature = "async")]
+                    Output::Sink(id) => if let Some(ref mut sink) = self.sink {
+                        sink.finish(id)?;
+                    },
+                }
+                if let Output::Unnamed(ref f) = out {
+                    temp_file::link(f, &file.path).map_err(spill::storage_error)?;
+                }
+                match (&out, file.path.parent()) {
+                    (&Output::InPlace, _) | (_, None) => {},
+                    (_, Some(dir)) => self.config.durability.sync_dir(dir)?,
+                }
+                self.write_ended(writing);
+                file.size = Some(size);
+                if let Some(hasher) = hasher {
+                    file = self.deduplicate(file, hasher.finalize().into());
+                }
+                self.formdata.stats.parts += 1;
+                self.formdata.stats.part_bytes.push(size as u64);
+                self.record_timing(&name, size as u64);
+                #[cfg(feature = "serde_json")]
+                {
+                    if self.config.parse_json && json::is_json(&file.headers) {
+                        let json = json::parse(&name, file.open_content()?)?;
+                        self.formdata.json_parts.push((name, json));
+                        return Ok(());
+                    }
+                }
+                if is_field {
+                    self.formdata.stats.fields += 1;
+                    self.formdata.large_fields.push((name, file));
+                } else {
+                    self.formdata.stats.files += 1;
+                    let count = self.file_counts.entry(name.clone()).or_insert(0);
+                    file.index = Some(*count);
+                    *count += 1;
+                    self.formdata.files.push((name, file));
+                }
+            },
+            Part::Group { name, mut parser, mut current, depth } => {
+                for event in parser.finish()? {
+                    self.event(&mut current, Some(&name), event)?;
+                }
+                self.formdata.stats.header_bytes += parser.header_bytes();
+                self.close_group(name, depth);
+            },
+            Part::Skip(skipped) => self.formdata.skipped.extend(skipped),
+        }
+        Ok(())
+    }
+
+    // Report `part` (or the lack of one) to the auditor, if any, as rejected with `error`.
+    fn audit(&self, part: Option<&Part>, error: &Error) {
+        let auditor = match self.config.audit {
+            Some(ref auditor) => auditor,
+            None => return,
+        };
+        match part {
+            Some(Part::Field { name, headers, .. }) =>
+                auditor.report(Some(name), Some(headers), error),
+            Some(Part::File { name, file, .. }) =>
+                auditor.report(Some(name), Some(&file.headers), error),
+            Some(Part::Group { name, current, .. }) => match **current {
+                Some(ref part) => self.audit(Some(part), error),
+                None => auditor.report(Some(name), None, error),
+            },
+            Some(Part::Skip(_)) | None => auditor.report(None, None, error),
+        }
+    }
+
+    // Describe the part `current`, which the body ended part way through.
+    fn interrupted(&self, current: &Part) -> Option<InterruptedPart> {
+        let (name, received) = match *current {
+            Part::Field { ref name, ref value, .. } => (name, value.len()),
+            Part::File { ref name, size, .. } => (name, size),
+            Part::Group { ref current, .. } =>
+                return current.as_ref().as_ref().and_then(|part| self.interrupted(part)),
+            Part::Skip(_) => return None,
+        };
+        Some(InterruptedPart { index: self.parts - 1, name: name.clone(), received: received as u64 })
+    }
+
+    // Replace `file`, whose content has the digest `hash`, with a reference to an identical
+    // file if there is one, deleting its own copy.
+    fn deduplicate(&self, mut file: FilePart, hash: ContentHash) -> FilePart {
+        let earlier = self.formdata.files.iter()
+            .position(|(_, f)| f.hash == Some(hash) && f.size == file.size);
+        let (path, duplicate) = match earlier {
+            Some(i) => (self.formdata.files[i].1.path.clone(), Duplicate::Earlier(i)),
+            None => match self.config.dedup.as_ref().and_then(|dedup| dedup.lookup(&hash)) {
+                Some(path) => (path, Duplicate::Known),
+                None => {
+                    file.hash = Some(hash);
+                    return file;
+                },
+            },
+        };
+        let mut reference = FilePart::new(file.headers.clone(), &path);
+        if let Duplicate::Earlier(i) = duplicate {
+            self.formdata.files[i].1.share_temp(&mut reference);
+        }
+        reference.size = file.size;
+        reference.hash = Some(hash);
+        reference.duplicate = Some(duplicate);
+        reference
+    }
+
+    // Apply `control_chars` to `text`, the value of the part `name` if given, or else the
+    // name of a part.
+    fn filter_controls(&mut self, name: Option<&str>, text: String, in_value: bool)
+                       -> Result<String, Error>
+    {
+        if self.config.control_chars == ControlChars::Allow ||
+            !text.chars().any(|c| is_unwanted_control(c, in_value))
+        {
+            return Ok(text);
+        }
+        if self.config.control_chars == ControlChars::Reject {
+            return Err(Error::ControlCharacters(name.unwrap_or(&text).to_owned()));
+        }
+        let text: String = text.chars().filter(|&c| !is_unwanted_control(c, in_value)).collect();
+        let name = name.unwrap_or(&text).to_owned();
+        self.formdata.warnings.push(FieldWarning::ControlCharsStripped(name));
+        Ok(text)
+    }
+
+    // Apply the value normalization settings to the value of field `name`, noting what was
+    // done.  Returns `None` if the field is to be dropped.
+    fn tidy_value(&mut self, name: &str, mut value: String) -> Option<String> {
+        let warnings = &mut self.formdata.warnings;
+        if self.config.normalize_newlines && value.contains("\r\n") {
+            value = value.replace("\r\n", "\n");
+            warnings.push(FieldWarning::NewlinesNormalized(name.to_owned()));
+        }
+        if self.config.trim_values {
+            let trimmed = value.trim();
+            if trimmed.len() != value.len() {
+                value = trimmed.to_owned();
+                warnings.push(FieldWarning::Trimmed(name.to_owned()));
+            }
+        }
+        if self.config.drop_empty_fields && value.is_empty() {
+            warnings.push(FieldWarning::DroppedEmpty(name.to_owned()));
+            return None;
+        }
+        Some(value)
+    }
+
+    // Keep what was received of the part `current` when the body ended part way through it.
+    // Fields still in memory are dropped, as a partial value would be mistaken for a whole
+    // one; a part on disk is kept with what had arrived.
+    fn end_partial(&mut self, current: Option<Part>) -> Result<(), Error> {
+        match current {
+            // Where it would have ended is not known
+            Some(Part::File { out: Output::InPlace, .. }) => Ok(()),
+            Some(part @ Part::File { .. }) => self.end(part),
+            Some(Part::Group { name, current, depth, .. }) => {
+                self.end_partial(*current)?;
+                self.close_group(name, depth);
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// Read and discard the remainder of the body, adding to `consumed`, but no further than
+// `max_body_size` allows and only until `max_duration` (from `started`) runs out.
+fn drain<S: Read>(stream: &mut S, config: &ParseConfig, started: Instant, consumed: &mut u64)
+                  -> Result<(), Error>
+{
+    let mut buf = vec![0; READ_BUFFER_SIZE];
+    loop {
+        let budget = config.max_body_size.unwrap_or(u64::MAX).saturating_sub(*consumed);
+        let late = config.max_duration.is_some_and(|max| started.elapsed() > max);
+        if budget == 0 || late {
+            return Ok(());
+        }
+        let len = cmp::min(buf.len() as u64, budget) as usize;
+        match stream.read(&mut buf[..len]) {
+            Ok(0) => return Ok(()),
+            Ok(n) => *consumed += n as u64,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(From::from(e)),
+        }
+    }
+}
+
+// Move what is gathered for the body as a whole from one `FormData` to another.
+fn carry(from: &mut FormData, to: &mut FormData) {
+    to.stats = mem::take(&mut from.stats);
+    to.raw_parts = mem::take(&mut from.raw_parts);
+    to.warnings = mem::take(&mut from.warnings);
+    to.skipped = mem::take(&mut from.skipped);
+}
+
+// A part with `headers` and `name` to be skipped for `reason`.
+fn skip(headers: &Headers, name: Option<String>, reason: SkipReason) -> Part {
+    Part::Skip(Some(SkippedPart {
+        name,
+        filename: file_part::headers_filename(headers),
+        content_type: headers.get::<ContentType>().map(|ct| ct.0.clone()),
+        bytes: 0,
+        reason,
+    }))
+}
+
+// Parts with an `attachment` disposition or a filename are stored as files.
+fn is_file(headers: &Headers) -> bool {
+    match headers.get::<ContentDisposition>() {
+        Some(cd) => cd.disposition == DispositionType::Attachment ||
+            cd.parameters.iter().any(|p| matches!(*p, DispositionParam::Filename(..))),
+        None => false,
+    }
+}
+
+// Whether the part's disposition is `attachment` or `file`, rather than `form-data`.
+fn is_file_disposition(headers: &Headers) -> bool {
+    match headers.get::<ContentDisposition>().map(|cd| &cd.disposition) {
+        Some(DispositionType::Attachment) => true,
+        Some(DispositionType::Ext(ref disposition)) => disposition.eq_ignore_ascii_case("file"),
+        _ => false,
+    }
+}
+
+// Replace the Content-Disposition with one parsed leniently, if that succeeds.
+fn reparse_disposition(headers: &mut Headers) {
+    let cd = headers.get_raw("Content-Disposition")
+        .and_then(|raw| raw.first())
+        .and_then(|raw| ::std::str::from_utf8(raw).ok())
+        .and_then(disposition::parse_lenient);
+    if let Some(cd) = cd {
+        headers.set(cd);
+    }
+}
+
+// Reduce the filename in the Content-Disposition to its final path component.
+fn strip_filename_path(headers: &mut Headers) {
+    if let Some(cd) = headers.get_mut::<ContentDisposition>() {
+        for param in &mut cd.parameters {
+            if let DispositionParam::Filename(_, _, ref mut bytes) = *param {
+                if let Some(i) = bytes.iter().rposition(|&b| b == b'/' || b == b'\\') {
+                    bytes.drain(..i + 1);
+                }
+            }
+        }
+    }
+}
+
+// Whether the filename in the Content-Disposition has control characters, removing them if
+// `strip` is set.
+fn filename_controls(headers: &mut Headers, strip: bool) -> bool {
+    let mut found = false;
+    if let Some(cd) = headers.get_mut::<ContentDisposition>() {
+        for param in &mut cd.parameters {
+            if let DispositionParam::Filename(_, _, ref mut bytes) = *param {
+                if bytes.iter().any(|b| b.is_ascii_control()) {
+                    found = true;
+                    if strip {
+                        bytes.retain(|b| !b.is_ascii_control());
+                    }
+                }
+            }
+        }
+    }
+    found
+}
+
+// Whether `c` is a control character to be kept out; tabs and line breaks are allowed in
+// values.
+fn is_unwanted_control(c: char, in_value: bool) -> bool {
+    c.is_control() && !(in_value && (c == '\t' || c == '\r' || c == '\n'))
+}
+
+// Apply the name normalization settings to the filename in the Content-Disposition, if it
+// is UTF-8.
+fn normalize_filename(headers: &mut Headers, config: &ParseConfig) {
+    if let Some(cd) = headers.get_mut::<ContentDisposition>() {
+        for param in &mut cd.parameters {
+            if let DispositionParam::Filename(ref mut charset, _, ref mut bytes) = *param {
+                if let Ok(filename) = ::std::str::from_utf8(bytes) {
+                    *bytes = config.normalize_name(filename).into_bytes();
+                    *charset = Charset::Ext("UTF-8".to_owned());
+                }
+            }
+        }
+    }
+}
+
+fn get_content_disposition_name(headers: &Headers) -> Result<String, Error> {
+    let cd: &ContentDisposition = match headers.get() {
+        Some(cd) => cd,
+        None => return Err(Error::MissingDisposition),
+    };
+    for param in &cd.parameters {
+        if let DispositionParam::Ext(ref token, ref value) = *param {
+            if token.eq_ignore_ascii_case("name") {
+                return Ok(value.clone());
+            }
+        }
+    }
+    Err(Error::NoName)
+}
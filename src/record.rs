@@ -0,0 +1,39 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use shared::Shared;
+
+/// A destination for a copy of each body read, exactly as received.  Give one to
+/// `ParseConfig::record_body` to find out what a client really sent when it fails to parse.
+///
+/// Cloning a `BodyRecorder` gives another handle to the same destination.  Bodies read
+/// through it one after another are simply appended to one another.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BodyRecorder {
+    sink: Shared<Mutex<dyn Write + Send>>,
+}
+
+impl BodyRecorder {
+    /// Record bodies to `sink`, e.g. a `File`.
+    pub fn new<W: Write + Send + 'static>(sink: W) -> BodyRecorder {
+        BodyRecorder { sink: Shared(Arc::new(Mutex::new(sink))) }
+    }
+
+    pub(crate) fn record(&self, data: &[u8]) -> io::Result<()> {
+        self.lock().write_all(data)
+    }
+
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.lock().flush()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, dyn Write + Send + 'static> {
+        match self.sink.lock() {
+            Ok(sink) => sink,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
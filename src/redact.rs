@@ -0,0 +1,100 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::fmt;
+use form_data::FormData;
+
+/// Which parts of a `FormData` to hide when it is logged.  See `FormData::redacted()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensitiveFields {
+    /// Names of fields whose values are replaced with `[REDACTED]`.  Matched without regard
+    /// to ASCII case.  Defaults to `password`, `token` and `secret`.
+    pub names: Vec<String>,
+    /// The most characters of any other field value to show.  Longer values are cut short
+    /// and their full length in bytes noted.  Defaults to 64.
+    pub max_value_len: usize,
+}
+
+impl SensitiveFields {
+    pub fn new() -> SensitiveFields {
+        Default::default()
+    }
+
+    /// Whether the field `name` is to be masked.
+    pub fn is_sensitive(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n.eq_ignore_ascii_case(name))
+    }
+}
+
+impl Default for SensitiveFields {
+    fn default() -> SensitiveFields {
+        SensitiveFields {
+            names: vec!["password".to_owned(), "token".to_owned(), "secret".to_owned()],
+            max_value_len: 64,
+        }
+    }
+}
+
+/// A view of a `FormData` that is safe to log, as returned by `FormData::redacted()`.
+/// Both its `Display` and `Debug` output mask sensitive fields and truncate long values.
+pub struct Redacted<'a> {
+    pub(crate) formdata: &'a FormData,
+    pub(crate) sensitive: &'a SensitiveFields,
+}
+
+impl<'a> Redacted<'a> {
+    fn value(&self, name: &str, value: &str) -> String {
+        if self.sensitive.is_sensitive(name) {
+            return "[REDACTED]".to_owned();
+        }
+        match value.char_indices().nth(self.sensitive.max_value_len) {
+            Some((i, _)) => format!("{}... ({} bytes)", &value[..i], value.len()),
+            None => value.to_owned(),
+        }
+    }
+
+    fn file(&self, name: &str, size: Option<usize>) -> String {
+        if self.sensitive.is_sensitive(name) {
+            return "[REDACTED]".to_owned();
+        }
+        match size {
+            Some(size) => format!("<file, {} bytes>", size),
+            None => "<file>".to_owned(),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Redacted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for (name, value) in &self.formdata.fields {
+            write!(f, "{}{}={}", if first { "" } else { ", " }, name, self.value(name, value))?;
+            first = false;
+        }
+        for (name, file) in self.formdata.large_fields.iter().chain(&self.formdata.files) {
+            write!(f, "{}{}={}", if first { "" } else { ", " }, name,
+                   self.file(name, file.size))?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Debug for Redacted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields: Vec<(&str, String)> = self.formdata.fields.iter()
+            .map(|(name, value)| (&**name, self.value(name, value)))
+            .collect();
+        let large_fields: Vec<(&str, String)> = self.formdata.large_fields.iter()
+            .map(|(name, file)| (&**name, self.file(name, file.size)))
+            .collect();
+        let files: Vec<(&str, String)> = self.formdata.files.iter()
+            .map(|(name, file)| (&**name, self.file(name, file.size)))
+            .collect();
+        f.debug_struct("FormData")
+            .field("fields", &fields)
+            .field("files", &files)
+            .field("large_fields", &large_fields)
+            .finish()
+    }
+}
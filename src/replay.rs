@@ -0,0 +1,34 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Reproducing failures from bodies recorded with `ParseConfig::record_body`.
+//!
+//! A recorded body is replayed through the same reader a server uses, so given the same
+//! Content-Type and `ParseConfig` it fails (or succeeds) in exactly the same way.  This makes
+//! a body captured in production a ready-made test case.
+
+use std::fs::File;
+use std::path::Path;
+use capture::parse_captured_head;
+use config::ParseConfig;
+use error::Error;
+use form_data::FormData;
+use reader::read_formdata_with_config;
+
+/// Parse the body recorded at `path` with `config`, as if it had arrived with the given
+/// Content-Type.  `content_type` may be the whole header line
+/// (`Content-Type: multipart/form-data; boundary=...`) or just its value.
+pub fn parse_file<P: AsRef<Path>>(path: P, content_type: &str, config: &ParseConfig)
+                                  -> Result<FormData, Error>
+{
+    let content_type = content_type.trim();
+    let line = match content_type.find(':') {
+        Some(colon) if content_type[..colon].trim().eq_ignore_ascii_case("content-type") => {
+            content_type.to_owned()
+        },
+        _ => format!("Content-Type: {}", content_type),
+    };
+    let headers = parse_captured_head(line.as_bytes())?;
+    let mut file = File::open(path)?;
+    read_formdata_with_config(&mut file, &headers, config)
+}
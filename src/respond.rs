@@ -0,0 +1,63 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::io::{self, Write};
+use hyper::header::{Headers, ContentLength, ContentType, Charset};
+use hyper::header::parsing::ExtendedValue;
+use mime::{Mime, TopLevel, SubLevel};
+use error::Error;
+use file_part::FilePart;
+
+/// Send `part` back as the body of a response, as when echoing an upload or proxying a
+/// download.  Content-Type (the part's own, or `application/octet-stream`), Content-Length
+/// and an `attachment` Content-Disposition carrying the sanitized filename are set in
+/// `headers_out`, along with `X-Content-Type-Options: nosniff` so that a browser does not
+/// render an upload as a page.  Other headers are left as they are.  The content (or range,
+/// see `FilePart::source_range()`) is then copied to `stream`, and the number of bytes
+/// written is returned.
+///
+/// The headers are all set before anything is written to `stream`.  If the file is
+/// truncated while it is being sent, this fails with an `Error::Io` after writing what there
+/// was, so the response will be shorter than its Content-Length.
+pub fn respond_with_file<S: Write>(part: &FilePart, headers_out: &mut Headers, stream: &mut S)
+                                   -> Result<u64, Error>
+{
+    let mut content = part.open_content()?;
+    let length = part.content_length()?;
+
+    let content_type = part.content_type()
+        .unwrap_or(Mime(TopLevel::Application, SubLevel::Ext("octet-stream".to_owned()),
+                        vec![]));
+    headers_out.set(ContentType(content_type));
+    headers_out.set(ContentLength(length));
+    headers_out.set_raw("Content-Disposition",
+                        vec![attachment(part.sanitized_filename().unwrap_or(None))
+                             .into_bytes()]);
+    headers_out.set_raw("X-Content-Type-Options", vec![b"nosniff".to_vec()]);
+
+    let sent = io::copy(&mut content, stream)?;
+    if sent < length {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                            "file was truncated while it was sent")));
+    }
+    Ok(sent)
+}
+
+// An `attachment` disposition for `filename`.  A filename that is not ASCII is given as
+// `filename*` (RFC 6266), after an ASCII `filename` for clients that do not understand it.
+fn attachment(filename: Option<String>) -> String {
+    let filename = match filename {
+        Some(filename) if !filename.is_empty() => filename,
+        _ => return "attachment".to_owned(),
+    };
+    if filename.is_ascii() {
+        return format!("attachment; filename=\"{}\"", filename);
+    }
+    let fallback: String = filename.chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    let extended = ExtendedValue { charset: Charset::Ext("UTF-8".to_owned()),
+                                   language_tag: None,
+                                   value: filename.into_bytes() };
+    format!("attachment; filename=\"{}\"; filename*={}", fallback, extended)
+}
@@ -0,0 +1,348 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::fs::OpenOptions;
+use std::io::{self, Read};
+use hyper::header::{ContentRange, ContentRangeSpec};
+use config::ParseConfig;
+use dedup::ContentHash;
+use error::Error;
+use file_part::FilePart;
+use form_data::FormData;
+use headers::RequestHeaders;
+use reader::read_formdata_seen;
+
+/// One upload spread over several requests, as sent by JavaScript uploaders that split a
+/// form, or a large file, into pieces.  Each request is parsed (with the session's
+/// `ParseConfig`) and its parts added to those received before; `formdata()` shows what
+/// has arrived so far, and `finish()` hands it all over once the upload is complete.
+///
+/// A file may be sent in chunks, each a part with the same name and filename and a
+/// `Content-Range` header (`bytes 0-999/5000`), or a request whose offset the caller
+/// knows, through `feed_chunk()`.  Each chunk must begin where the last one ended, and is
+/// appended to the file.
+///
+/// With `ParseConfig::idempotency_header` set, a part sent again in a later request, as a
+/// client retrying after a network error would, is skipped (see `FormData::skipped`).
+///
+/// A request that fails, to parse or to fit, adds nothing to the session.  Dropping the
+/// session, or calling `abort()`, deletes every file received.
+#[derive(Debug)]
+pub struct UploadSession {
+    config: ParseConfig,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    formdata: FormData,
+    size: u64,
+    requests: usize,
+    // The idempotency keys of the parts added
+    keys: Vec<String>,
+    // For each file being assembled from chunks, its position in `formdata.files` and its
+    // final size, if declared
+    assemblies: Vec<(usize, Option<u64>)>,
+}
+
+impl UploadSession {
+    /// Begin a session whose requests are each parsed as directed by `config`, which also
+    /// limits each request on its own.
+    pub fn new(config: ParseConfig) -> UploadSession {
+        UploadSession {
+            config,
+            max_size: None,
+            max_files: None,
+            formdata: FormData::new(),
+            size: 0,
+            requests: 0,
+            keys: Vec::new(),
+            assemblies: Vec::new(),
+        }
+    }
+
+    /// Refuse a request that would take the content of every part received, fields and
+    /// files together, beyond `max` bytes, with `Error::BodyTooLarge`.
+    pub fn max_size(mut self, max: u64) -> UploadSession {
+        self.max_size = Some(max);
+        self
+    }
+
+    /// Refuse a request that would take the number of files beyond `max`, with
+    /// `Error::TooManyFiles`.  A file sent in chunks counts once.
+    pub fn max_files(mut self, max: usize) -> UploadSession {
+        self.max_files = Some(max);
+        self
+    }
+
+    /// Parse the next request of the upload and add its parts.  File parts with a
+    /// `Content-Range` are chunks of a file; see `UploadSession`.
+    pub fn feed<S: Read, H: RequestHeaders>(&mut self, stream: &mut S, headers: &H)
+                                           -> Result<(), Error>
+    {
+        let formdata = read_formdata_seen(stream, headers, &self.config, &self.keys)?;
+        self.add(formdata, None)
+    }
+
+    /// Like `feed()`, for a request whose every file is a chunk beginning at `offset` in a
+    /// file of `total` bytes (if known), as given by a request-level `Content-Range` or by
+    /// the uploader's own parameters.
+    pub fn feed_chunk<S: Read, H: RequestHeaders>(&mut self, stream: &mut S, headers: &H,
+                                                  offset: u64, total: Option<u64>)
+                                                  -> Result<(), Error>
+    {
+        let formdata = read_formdata_seen(stream, headers, &self.config, &self.keys)?;
+        self.add(formdata, Some((offset, total)))
+    }
+
+    /// Everything received so far.  Files still being assembled hold the chunks received.
+    pub fn formdata(&self) -> &FormData {
+        &self.formdata
+    }
+
+    /// The number of requests added.
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+
+    /// The size in bytes of the content of every part received.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether every file sent in chunks has reached the size declared for it.
+    pub fn is_complete(&self) -> bool {
+        self.incomplete().is_none()
+    }
+
+    /// End the session, handing over everything received.  Fails with
+    /// `Error::InvalidChunk` if a file sent in chunks is not yet complete, in which case
+    /// every file is deleted.
+    pub fn finish(self) -> Result<FormData, Error> {
+        if let Some(reason) = self.incomplete().map(|name| format!("{} is incomplete", name)) {
+            self.abort();
+            return Err(Error::InvalidChunk(reason));
+        }
+        Ok(self.formdata)
+    }
+
+    /// End the session, deleting every file received, even if a clone of one is held.
+    pub fn abort(self) {
+        for (_, file) in self.formdata.files.iter().chain(self.formdata.large_fields.iter()) {
+            if let Some(temp) = file.temp_file() {
+                temp.discard();
+            }
+        }
+    }
+
+    // The name of a file sent in chunks that is short of its declared size.
+    fn incomplete(&self) -> Option<&str> {
+        self.assemblies.iter()
+            .map(|&(index, total)| (&self.formdata.files[index], total))
+            .find(|&(file, total)| total.is_some_and(|total| file_size(&file.1) < total))
+            .map(|(file, _)| &*file.0)
+    }
+
+    // Add the parts of one request, each file of which is a chunk at `chunk` if given.
+    fn add(&mut self, mut formdata: FormData, chunk: Option<(u64, Option<u64>)>)
+           -> Result<(), Error>
+    {
+        let size = content_size(&formdata);
+        if self.size + size > self.max_size.unwrap_or(u64::MAX) {
+            return Err(Error::BodyTooLarge);
+        }
+
+        // Check every chunk before changing anything, so a request is added whole or not
+        // at all.  A chunk may follow one earlier in the same request.
+        let mut appends: Vec<(usize, Target)> = Vec::new();
+        let mut staged: Vec<Staged> = Vec::new();
+        let mut new_files = 0;
+        for (index, (name, file)) in formdata.files.iter().enumerate() {
+            let (offset, total) = match chunk {
+                Some(chunk) => chunk,
+                None => match chunk_range(file)? {
+                    Some(chunk) => chunk,
+                    None => {
+                        new_files += 1;
+                        continue;
+                    },
+                },
+            };
+            let filename = file.raw_filename().ok().and_then(|f| f);
+            let position = staged.iter()
+                .position(|s| s.name == *name && s.filename == filename);
+            let (target, received) = match position {
+                Some(position) => (Some(staged[position].target), staged[position].received),
+                None => match self.assembly(name, &filename) {
+                    Some(target) => (Some(Target::Assembled(target)),
+                                     file_size(&self.formdata.files[target].1)),
+                    None => (None, 0),
+                },
+            };
+            if offset != received {
+                return Err(Error::InvalidChunk(format!(
+                    "{} chunk begins at {} but {} bytes were received", name, offset, received)));
+            }
+            if let Some(total) = total {
+                if received + file_size(file) > total {
+                    return Err(Error::InvalidChunk(format!(
+                        "{} chunk ends beyond its {} bytes", name, total)));
+                }
+            }
+            let received = received + file_size(file);
+            match position {
+                Some(position) => {
+                    staged[position].received = received;
+                    staged[position].total = total.or(staged[position].total);
+                },
+                None => {
+                    if target.is_none() {
+                        new_files += 1;
+                    }
+                    staged.push(Staged {
+                        name: name.clone(),
+                        filename,
+                        target: target.unwrap_or(Target::New(index)),
+                        received,
+                        total,
+                    });
+                },
+            }
+            if let Some(target) = target {
+                appends.push((index, target));
+            }
+        }
+        if self.formdata.files.len() + new_files > self.max_files.unwrap_or(usize::MAX) {
+            return Err(Error::TooManyFiles(self.size + size));
+        }
+
+        // Append the chunks, putting the files assembled before back as they were if one
+        // fails.
+        let before: Vec<(usize, Option<usize>, Option<ContentHash>)> = appends.iter()
+            .filter_map(|&(_, target)| match target {
+                Target::Assembled(target) => Some(target),
+                Target::New(_) => None,
+            })
+            .map(|target| {
+                let file = &self.formdata.files[target].1;
+                (target, file.size, file.hash)
+            })
+            .collect();
+        if let Err(e) = self.append(&mut formdata, &appends) {
+            for &(target, size, hash) in &before {
+                let file = &mut self.formdata.files[target].1;
+                let _ = OpenOptions::new().write(true).open(&file.path)
+                    .and_then(|out| out.set_len(size.unwrap_or(0) as u64));
+                file.size = size;
+                file.hash = hash;
+            }
+            return Err(e);
+        }
+        for staged in &staged {
+            if let Target::Assembled(target) = staged.target {
+                if let Some(assembly) = self.assemblies.iter_mut().find(|a| a.0 == target) {
+                    assembly.1 = staged.total.or(assembly.1);
+                }
+            }
+        }
+
+        let FormData { fields, files, large_fields, warnings, skipped, idempotency_keys, groups,
+                       .. } = formdata;
+        #[cfg(feature = "serde_json")]
+        self.formdata.json_parts.extend(formdata.json_parts);
+        self.formdata.fields.extend(fields);
+        for (index, (name, file)) in files.into_iter().enumerate() {
+            if appends.iter().any(|a| a.0 == index) {
+                continue;
+            }
+            if let Some(staged) = staged.iter().find(|s| s.target == Target::New(index)) {
+                self.assemblies.push((self.formdata.files.len(), staged.total));
+            }
+            self.formdata.files.push((name, file));
+        }
+        self.formdata.large_fields.extend(large_fields);
+        self.formdata.warnings.extend(warnings);
+        self.formdata.skipped.extend(skipped);
+        self.keys.extend(idempotency_keys.iter().cloned());
+        self.formdata.idempotency_keys.extend(idempotency_keys);
+        self.formdata.groups.extend(groups);
+        self.formdata.reindex();
+        self.size += size;
+        self.requests += 1;
+        Ok(())
+    }
+
+    // The position of the file named `name` and `filename` being assembled from chunks.
+    fn assembly(&self, name: &str, filename: &Option<String>) -> Option<usize> {
+        self.assemblies.iter().map(|a| a.0).find(|&i| {
+            let (ref other, ref assembled) = self.formdata.files[i];
+            other == name && assembled.raw_filename().ok().and_then(|f| f) == *filename
+        })
+    }
+
+    // Append each chunk of `formdata` in `appends` to its target.
+    fn append(&mut self, formdata: &mut FormData, appends: &[(usize, Target)])
+              -> Result<(), Error>
+    {
+        for &(index, target) in appends {
+            let chunk_size = file_size(&formdata.files[index].1);
+            let mut content = formdata.files[index].1.open_content()?;
+            let file = match target {
+                Target::Assembled(target) => &mut self.formdata.files[target].1,
+                Target::New(first) => &mut formdata.files[first].1,
+            };
+            let mut out = OpenOptions::new().append(true).open(&file.path)?;
+            io::copy(&mut content, &mut out)?;
+            file.size = Some((file_size(file) + chunk_size) as usize);
+            file.hash = None;
+        }
+        Ok(())
+    }
+}
+
+// Where a chunk goes: onto a file assembled from earlier requests, or onto the first chunk
+// of a file begun in the same request, by its position among the request's files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Assembled(usize),
+    New(usize),
+}
+
+// A file that chunks of a request go to, and its size and declared final size once they
+// are added.
+struct Staged {
+    name: String,
+    filename: Option<String>,
+    target: Target,
+    received: u64,
+    total: Option<u64>,
+}
+
+// The offset and total size of the chunk `file` is, from its Content-Range, if any.  The
+// range must match the size of the content.
+fn chunk_range(file: &FilePart) -> Result<Option<(u64, Option<u64>)>, Error> {
+    match file.headers.get::<ContentRange>() {
+        Some(&ContentRange(ContentRangeSpec::Bytes { range: Some((first, last)),
+                                                     instance_length })) => {
+            if last < first || last - first + 1 != file_size(file) {
+                return Err(Error::InvalidChunk(format!(
+                    "Content-Range {}-{} does not match {} bytes", first, last,
+                    file_size(file))));
+            }
+            Ok(Some((first, instance_length)))
+        },
+        Some(_) => Err(Error::InvalidChunk("Content-Range without a byte range".to_owned())),
+        None => Ok(None),
+    }
+}
+
+fn file_size(file: &FilePart) -> u64 {
+    file.size.unwrap_or(0) as u64
+}
+
+// The size of the content of every part of `formdata`.
+fn content_size(formdata: &FormData) -> u64 {
+    let fields: u64 = formdata.fields.iter().map(|f| f.1.len() as u64).sum();
+    let files: u64 = formdata.files.iter().chain(formdata.large_fields.iter())
+        .map(|f| file_size(&f.1))
+        .sum();
+    let groups: u64 = formdata.groups.iter().map(|g| content_size(&g.1)).sum();
+    fields + files + groups
+}
@@ -0,0 +1,109 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use error::Error;
+use file_part::FilePart;
+use random::RandomSource;
+use shared::Shared;
+use temp_file;
+
+type Allocate = dyn Fn(&str, u64) -> Option<PathBuf> + Send + Sync;
+
+/// Places to move a file to when the temporary directory fills up while it is being
+/// received (see `ParseConfig::spill_over`).  They are tried in the order they were added
+/// until one has room: a fixed directory, or a hook that is given the part's name and the
+/// number of bytes it needs so far, and returns a directory with room for it (perhaps after
+/// making some), or `None` to pass.  If none has room, parsing fails with
+/// `Error::StorageFull`.
+///
+/// Cloning a `SpillOver` gives one sharing the same hooks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpillOver {
+    places: Vec<Place>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Place {
+    Dir(PathBuf),
+    Hook(Shared<Allocate>),
+}
+
+impl SpillOver {
+    pub fn new() -> SpillOver {
+        Default::default()
+    }
+
+    /// Try the directory `dir`, such as one on a secondary volume.
+    pub fn dir<P: Into<PathBuf>>(mut self, dir: P) -> SpillOver {
+        self.places.push(Place::Dir(dir.into()));
+        self
+    }
+
+    /// Try the directory `allocate` returns, given the part's name and the number of bytes
+    /// it needs so far.
+    pub fn allocate_with<F>(mut self, allocate: F) -> SpillOver
+        where F: Fn(&str, u64) -> Option<PathBuf> + Send + Sync + 'static
+    {
+        self.places.push(Place::Hook(Shared(Arc::new(allocate))));
+        self
+    }
+
+    // Move `file`, of which `written` bytes had been written when its directory filled up,
+    // to the first place with room for that and `data`, and append `data`.  Returns the
+    // moved file, open for writing the rest.  The original is deleted as `file` is
+    // replaced.
+    pub(crate) fn move_file(&self, name: &str, file: &mut FilePart, written: u64, data: &[u8],
+                            mode: u32, random: Option<&RandomSource>) -> Result<File, Error>
+    {
+        let needed = written + data.len() as u64;
+        for place in &self.places {
+            let dir = match *place {
+                Place::Dir(ref dir) => dir.clone(),
+                Place::Hook(ref allocate) => match allocate(name, needed) {
+                    Some(dir) => dir,
+                    None => continue,
+                },
+            };
+            let headers = file.headers.clone();
+            let mut moved = match FilePart::create_in_mode(&dir, headers, mode, random) {
+                Ok(moved) => moved,
+                Err(Error::Io(ref e)) if is_storage_full(e) => continue,
+                Err(e) => return Err(e),
+            };
+            match copy_into(&file.path, &moved.path, written, data, mode) {
+                Ok(out) => {
+                    moved.size = file.size;
+                    *file = moved;
+                    return Ok(out);
+                },
+                Err(ref e) if is_storage_full(e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(Error::StorageFull)
+    }
+}
+
+// Create `to` with the first `written` bytes of `from` followed by `data`.
+fn copy_into(from: &Path, to: &Path, written: u64, data: &[u8], mode: u32)
+             -> io::Result<File>
+{
+    let mut out = temp_file::create(to, mode)?;
+    io::copy(&mut File::open(from)?.take(written), &mut out)?;
+    out.write_all(data)?;
+    Ok(out)
+}
+
+/// Whether `err` says that the disk or the user's disk quota is full.
+pub(crate) fn is_storage_full(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded)
+}
+
+/// `err` as an `Error`: `Error::StorageFull` if the disk is full, otherwise `Error::Io`.
+pub(crate) fn storage_error(err: io::Error) -> Error {
+    if is_storage_full(&err) { Error::StorageFull } else { Error::Io(err) }
+}
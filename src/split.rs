@@ -0,0 +1,68 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use config::WriteConfig;
+use encode;
+use error::Error;
+use form_data::FormData;
+
+/// Partition the parts of `formdata` into several `FormData`s, each of which
+/// `write_formdata()` writes with `boundary` in at most `max_body_bytes`, for services that
+/// cap the size of a request but accept a submission in batches.  Parts are kept in order
+/// (fields, then large fields, then files) and are never split.  Fails with
+/// `Error::FileTooLarge` if a single part does not fit on its own.
+///
+/// Only the parts are carried over: the `stats`, `raw_parts` and `warnings` of `formdata`
+/// are dropped.
+pub fn split_formdata(formdata: FormData, boundary: &[u8], max_body_bytes: u64)
+                      -> Result<Vec<FormData>, Error>
+{
+    // The closing delimiter, `--boundary--`
+    let closing = boundary.len() as u64 + 4;
+    let mut batches: Vec<FormData> = Vec::new();
+    let mut current = FormData::new();
+    let mut current_size = closing;
+
+    let singles = formdata.fields.into_iter()
+        .map(|field| FormData { fields: vec![field], ..FormData::new() })
+        .chain(formdata.large_fields.into_iter()
+               .map(|field| FormData { large_fields: vec![field], ..FormData::new() }))
+        .chain(formdata.files.into_iter()
+               .map(|file| FormData { files: vec![file], ..FormData::new() }));
+    for single in singles {
+        let size = encoded_size(&single, boundary)? - closing;
+        if closing + size > max_body_bytes {
+            return Err(Error::FileTooLarge);
+        }
+        if current_size + size > max_body_bytes {
+            batches.push(::std::mem::replace(&mut current, FormData::new()));
+            current_size = closing;
+        }
+        current_size += size;
+        current.fields.extend(single.fields);
+        current.large_fields.extend(single.large_fields);
+        current.files.extend(single.files);
+    }
+    if current_size > closing {
+        batches.push(current);
+    }
+    Ok(batches)
+}
+
+// The number of bytes `write_formdata()` would write for `formdata`, without reading any
+// file contents.
+fn encoded_size(formdata: &FormData, boundary: &[u8]) -> Result<u64, Error> {
+    let (nodes, ranges) = formdata.nodes(&WriteConfig::default(), false)?;
+    encode::check_nodes(boundary, &nodes)?;
+    let count = encode::write_multipart(
+        &mut io::sink(), boundary, &nodes, &ranges,
+        &mut |_: &mut io::Sink, path: &Path, range: Option<&Range<u64>>| match range {
+            Some(range) => Ok(range.end - range.start),
+            None => fs::metadata(path).map(|m| m.len()),
+        })?;
+    Ok(count as u64)
+}
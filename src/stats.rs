@@ -0,0 +1,60 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::time::Duration;
+
+/// Totals gathered while parsing a `FormData`.  All zero for a `FormData` that was built
+/// rather than parsed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParseStats {
+    /// The number of parts, including those nested within a `multipart/mixed` group but
+    /// not the groups themselves.
+    pub parts: usize,
+    /// The number of text fields, including large fields.
+    pub fields: usize,
+    /// The number of files.
+    pub files: usize,
+    /// The content size of each part in bytes, in the order the parts were received.  For
+    /// a truncated field this is the size that was kept.
+    pub part_bytes: Vec<u64>,
+    /// The total size of all part header sections in bytes.
+    pub header_bytes: u64,
+    /// The total size of the body in bytes.
+    pub body_bytes: u64,
+    /// Whether the closing boundary had its `--` terminator.  Only false for a body
+    /// accepted by `ParseConfig::lenient_termination`.
+    pub terminated: bool,
+    /// The bytes read after the closing boundary (the epilogue, or padding), which are
+    /// ignored.  Reading stops at the closing boundary, so this only counts what arrived
+    /// along with it.
+    pub epilogue_bytes: u64,
+    /// How long parsing took, if `ParseConfig::timing` was set.
+    pub elapsed: Option<Duration>,
+    /// How long each part took to receive, in the order the parts were received (as
+    /// `part_bytes`), if `ParseConfig::timing` was set.
+    pub part_timings: Vec<PartTiming>,
+}
+
+/// How long one part took to receive.  See `ParseStats::part_timings`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartTiming {
+    /// The name of the part.
+    pub name: String,
+    /// The content size in bytes.
+    pub bytes: u64,
+    /// The time from the end of the part's headers to the end of its content.
+    pub receive: Duration,
+    /// How much of `receive` was spent writing the content to disk, for a file or a field
+    /// spilled to disk.  A part that spent most of its time here was held up by the disk
+    /// rather than by the client.
+    pub write: Duration,
+}
+
+impl PartTiming {
+    /// The rate the content was received at, in bytes per second.  `None` if the part was
+    /// received too quickly to measure.
+    pub fn throughput(&self) -> Option<f64> {
+        let secs = self.receive.as_secs_f64();
+        if secs > 0.0 { Some(self.bytes as f64 / secs) } else { None }
+    }
+}
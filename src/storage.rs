@@ -0,0 +1,394 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Storing uploaded files somewhere other than the local filesystem.
+//!
+//! A `StorageSink` receives file content in chunks, so it can be passed straight on to
+//! something like an S3 multipart upload or a GCS resumable session.  Once stored, a file is
+//! represented by the sink's own `Token` rather than by a path.
+//!
+//! `read_formdata_to_sink()` hands each file to the sink as the body is parsed, so files
+//! never touch local disk; `store_formdata()` hands over the files of a `FormData` parsed
+//! already.
+
+use std::fs::File;
+use std::io::{self, Read, Take};
+use std::mem;
+use std::sync::Arc;
+use futures::executor::block_on;
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use futures::stream::{self, TryStreamExt};
+use hyper::header::Headers;
+use config::ParseConfig;
+use descriptor::MultipartDescriptor;
+use error::Error;
+use file_part::FilePart;
+use form_data::{FormData, Text};
+use headers::{self, RequestHeaders};
+use reader;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A destination for uploaded file content.
+pub trait StorageSink {
+    /// What the sink hands back to identify a stored file (an object key, an upload id...).
+    type Token: Send + 'static;
+    /// A single file being stored.
+    type Upload: StorageUpload<Token = Self::Token> + Send + 'static;
+
+    /// Begin storing the file part named `name`, which has the part headers `headers`.
+    fn begin(&self, name: &str, headers: &Headers) -> BoxFuture<'static, Result<Self::Upload, Error>>;
+
+    /// Delete a file stored by `read_formdata_to_sink()` from a body that then failed to
+    /// parse, so that nothing is left of it.  The default does nothing.
+    fn abandon(&self, token: Self::Token) -> BoxFuture<'static, Result<(), Error>> {
+        drop(token);
+        futures::future::ok(()).boxed()
+    }
+}
+
+/// A single file being stored by a `StorageSink`.  Dropping an upload before calling
+/// `finish` abandons it, and the sink should release anything stored so far.
+pub trait StorageUpload: Sized {
+    /// What the upload hands back once it is complete.
+    type Token;
+
+    /// Append the next chunk of file content.
+    fn write(self, chunk: Vec<u8>) -> BoxFuture<'static, Result<Self, Error>>;
+
+    /// Complete the upload.
+    fn finish(self) -> BoxFuture<'static, Result<Self::Token, Error>>;
+}
+
+/// A file that has been handed to a `StorageSink`.
+#[derive(Clone, Debug)]
+pub struct StoredFile<T> {
+    /// The headers of the part
+    pub headers: Headers,
+    /// The sink's reference to the stored content
+    pub token: T,
+    /// The size of the file content in bytes
+    pub size: usize,
+}
+
+/// Like `FormData`, but with files held by a `StorageSink` rather than on local disk.
+#[derive(Clone, Debug)]
+pub struct StoredFormData<T> {
+    /// Name-value pairs for plain text fields.
+    pub fields: Vec<(Text, Text)>,
+    /// Name-value pairs for files stored in the sink.
+    pub files: Vec<(String, StoredFile<T>)>,
+    /// Text fields too large to hold in memory, left in their temporary files.
+    pub large_fields: Vec<(String, FilePart)>,
+    /// Parts parsed as JSON; see `FormData::json_parts`.
+    #[cfg(feature = "serde_json")]
+    pub json_parts: Vec<(String, ::serde_json::Value)>,
+    /// The parts of nested `multipart/mixed` groups; see `FormData::groups`.
+    pub groups: Vec<(String, StoredFormData<T>)>,
+}
+
+/// Parse `multipart/form-data` from `stream`, as directed by `config`, handing the content
+/// of each file part to `sink` as it arrives rather than writing it to a temporary file.
+/// Fields are kept as by `read_formdata_with_config()` (including those spilled to disk),
+/// and so are parts parsed as JSON.
+///
+/// Each step of the sink is waited for in turn, blocking the thread, so call this from a
+/// thread that may block (see `bridge::channel_reader()`).  If parsing fails, files already
+/// stored are handed to `StorageSink::abandon()`, and the one being stored is dropped.
+pub fn read_formdata_to_sink<S, H, K>(stream: &mut S, headers: &H, config: &ParseConfig,
+                                      sink: &K)
+                                      -> Result<StoredFormData<K::Token>, Error>
+    where S: Read, H: RequestHeaders, K: StorageSink
+{
+    headers::check_content_type(headers, config)?;
+    let descriptor = MultipartDescriptor::from_any_multipart(headers)?;
+    let mut writer = SinkWriter { sink, uploads: Vec::new(), tokens: Vec::new() };
+    match reader::read_to_sink(stream, &descriptor, config, &mut writer) {
+        Ok(formdata) => Ok(stored(formdata, &mut writer.tokens)),
+        Err(e) => {
+            for token in writer.tokens.drain(..).flatten() {
+                let _ = block_on(sink.abandon(token));
+            }
+            Err(e)
+        },
+    }
+}
+
+// `formdata` with each file written to a sink replaced by its token, from `tokens`.
+fn stored<T>(formdata: FormData, tokens: &mut [Option<T>]) -> StoredFormData<T> {
+    let files = formdata.files.into_iter()
+        .filter_map(|(name, file)| {
+            let token = file.stored.and_then(|id| tokens[id].take())?;
+            let size = file.size.unwrap_or(0);
+            Some((name, StoredFile { headers: file.headers, token, size }))
+        })
+        .collect();
+    StoredFormData {
+        fields: formdata.fields,
+        files,
+        large_fields: formdata.large_fields,
+        #[cfg(feature = "serde_json")]
+        json_parts: formdata.json_parts,
+        groups: formdata.groups.into_iter()
+            .map(|(name, group)| (name, stored(group, tokens)))
+            .collect(),
+    }
+}
+
+// A file being written by `SinkWriter`: its upload, and content not yet passed to it.
+type Pending<U> = (U, Vec<u8>);
+
+// Passes the files of a body being parsed to a `StorageSink` in chunks, waiting for each
+// step, and keeps the tokens of those stored, by their ids.
+struct SinkWriter<'k, K: StorageSink + 'k> {
+    sink: &'k K,
+    uploads: Vec<Option<Pending<K::Upload>>>,
+    tokens: Vec<Option<K::Token>>,
+}
+
+impl<'k, K: StorageSink> FileSink for SinkWriter<'k, K> {
+    fn begin(&mut self, name: &str, headers: &Headers) -> Result<usize, Error> {
+        let upload = block_on(self.sink.begin(name, headers))?;
+        self.uploads.push(Some((upload, Vec::new())));
+        self.tokens.push(None);
+        Ok(self.uploads.len() - 1)
+    }
+
+    fn write(&mut self, id: usize, data: &[u8]) -> Result<(), Error> {
+        if let Some((upload, mut chunk)) = self.uploads[id].take() {
+            chunk.extend_from_slice(data);
+            self.uploads[id] = Some(if chunk.len() < CHUNK_SIZE {
+                (upload, chunk)
+            } else {
+                (block_on(upload.write(mem::take(&mut chunk)))?, chunk)
+            });
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, id: usize) -> Result<(), Error> {
+        if let Some((mut upload, chunk)) = self.uploads[id].take() {
+            if !chunk.is_empty() {
+                upload = block_on(upload.write(chunk))?;
+            }
+            self.tokens[id] = Some(block_on(upload.finish())?);
+        }
+        Ok(())
+    }
+}
+
+// Where the parser writes file content in place of temporary files: a `StorageSink` with
+// its types hidden.  Each file is begun, given its content, and finished, by the id that
+// `begin()` gives it.
+pub(crate) trait FileSink {
+    fn begin(&mut self, name: &str, headers: &Headers) -> Result<usize, Error>;
+    fn write(&mut self, id: usize, data: &[u8]) -> Result<(), Error>;
+    fn finish(&mut self, id: usize) -> Result<(), Error>;
+}
+
+/// Hand every file of `formdata`, which was parsed already, to `sink`, one after another.
+/// Each temporary file is deleted as soon as the sink has accepted it.
+pub fn store_formdata<K>(formdata: FormData, sink: Arc<K>)
+                         -> BoxFuture<'static, Result<StoredFormData<K::Token>, Error>>
+    where K: StorageSink + Send + Sync + 'static
+{
+    let FormData { fields, files, large_fields, groups, .. } = formdata;
+    #[cfg(feature = "serde_json")]
+    let json_parts = formdata.json_parts;
+    let group_sink = sink.clone();
+    stream::iter(files.into_iter().map(Ok))
+        .and_then(move |(name, file)| store_file(&*sink, name, file))
+        .try_collect()
+        .and_then(move |files| {
+            stream::iter(groups.into_iter().map(Ok))
+                .and_then(move |(name, group)| {
+                    store_formdata(group, group_sink.clone()).map_ok(move |group| (name, group))
+                })
+                .try_collect()
+                .map_ok(move |groups| StoredFormData {
+                    fields,
+                    files,
+                    large_fields,
+                    #[cfg(feature = "serde_json")]
+                    json_parts,
+                    groups,
+                })
+        })
+        .boxed()
+}
+
+type NamedStoredFile<T> = (String, StoredFile<T>);
+
+fn store_file<K>(sink: &K, name: String, file: FilePart)
+                 -> BoxFuture<'static, Result<NamedStoredFile<K::Token>, Error>>
+    where K: StorageSink
+{
+    let reader = match file.open_content() {
+        Ok(f) => f,
+        Err(e) => return futures::future::err(From::from(e)).boxed(),
+    };
+    let headers = file.headers.clone();
+    sink.begin(&name, &headers)
+        .and_then(move |upload| {
+            stream::iter(Chunks(reader))
+                .try_fold((upload, 0), |(upload, size), chunk| {
+                    let len = chunk.len();
+                    upload.write(chunk).map_ok(move |upload| (upload, size + len))
+                })
+        })
+        .and_then(|(upload, size)| upload.finish().map_ok(move |token| (token, size)))
+        .map_ok(move |(token, size)| {
+            drop(file);
+            (name, StoredFile { headers, token, size })
+        })
+        .boxed()
+}
+
+// Reads a file as a series of chunks.
+struct Chunks(Take<File>);
+
+impl Iterator for Chunks {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            match self.0.read(&mut chunk) {
+                Ok(0) => return None,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    return Some(Ok(chunk));
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(From::from(e))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use futures::executor::block_on;
+    use futures::future::{self, BoxFuture, FutureExt};
+    use hyper::header::Headers;
+    use super::{StorageSink, StorageUpload, read_formdata_to_sink, store_formdata};
+    use bench;
+    use config::ParseConfig;
+    use error::Error;
+    use file_part::FilePart;
+    use form_data::FormData;
+
+    #[derive(Default)]
+    struct MemorySink {
+        objects: Arc<Mutex<Vec<Vec<u8>>>>,
+        abandoned: Mutex<Vec<usize>>,
+    }
+
+    struct MemoryUpload {
+        objects: Arc<Mutex<Vec<Vec<u8>>>>,
+        content: Vec<u8>,
+    }
+
+    impl StorageSink for MemorySink {
+        type Token = usize;
+        type Upload = MemoryUpload;
+
+        fn begin(&self, _: &str, _: &Headers) -> BoxFuture<'static, Result<MemoryUpload, Error>> {
+            let upload = MemoryUpload { objects: self.objects.clone(), content: vec![] };
+            future::ok(upload).boxed()
+        }
+
+        fn abandon(&self, token: usize) -> BoxFuture<'static, Result<(), Error>> {
+            self.abandoned.lock().unwrap().push(token);
+            future::ok(()).boxed()
+        }
+    }
+
+    impl StorageUpload for MemoryUpload {
+        type Token = usize;
+
+        fn write(mut self, chunk: Vec<u8>) -> BoxFuture<'static, Result<Self, Error>> {
+            self.content.extend(chunk);
+            future::ok(self).boxed()
+        }
+
+        fn finish(self) -> BoxFuture<'static, Result<usize, Error>> {
+            let mut objects = self.objects.lock().unwrap();
+            objects.push(self.content);
+            future::ok(objects.len() - 1).boxed()
+        }
+    }
+
+    #[test]
+    fn store_files_in_sink() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let tmppath = tmpdir.path().join("testfile");
+        File::create(&tmppath).unwrap().write_all(b"file content").unwrap();
+
+        let formdata = FormData {
+            fields: vec![ ("name".into(), "Mike".into()) ],
+            files: vec![ ("photo".to_owned(), FilePart::new(Headers::new(), &tmppath)) ],
+            ..FormData::new()
+        };
+
+        let sink = Arc::new(MemorySink::default());
+        let stored = block_on(store_formdata(formdata, sink.clone())).unwrap();
+        assert_eq!(stored.fields.len(), 1);
+        assert_eq!(stored.files.len(), 1);
+        assert_eq!(stored.files[0].0, "photo");
+        assert_eq!(stored.files[0].1.size, 12);
+        let objects = sink.objects.lock().unwrap();
+        assert_eq!(objects[stored.files[0].1.token], b"file content");
+    }
+
+    #[test]
+    fn parse_files_into_sink() {
+        let tmpdir = tempdir::TempDir::new("formdata_test").unwrap();
+        let large = "x".repeat(100_000);
+        let body = format!("--abcdefg\r\n\
+                            Content-Disposition: form-data; name=\"name\"\r\n\
+                            \r\n\
+                            Mike\r\n\
+                            --abcdefg\r\n\
+                            Content-Disposition: form-data; name=\"photo\"; filename=\"a.png\"\r\n\
+                            \r\n\
+                            {}\r\n\
+                            --abcdefg\r\n\
+                            Content-Disposition: form-data; name=\"photo\"; filename=\"b.png\"\r\n\
+                            \r\n\
+                            small\r\n\
+                            --abcdefg--", large);
+        let headers = bench::headers("abcdefg");
+        let config = ParseConfig { temp_dir: Some(tmpdir.path().to_owned()),
+                                   ..ParseConfig::default() };
+
+        let sink = MemorySink::default();
+        let stored = read_formdata_to_sink(&mut body.as_bytes(), &headers, &config, &sink)
+            .unwrap();
+        assert_eq!(stored.fields.len(), 1);
+        let files: Vec<(&str, usize)> = stored.files.iter()
+            .map(|f| (&*f.0, f.1.size))
+            .collect();
+        assert_eq!(files, vec![("photo", 100_000), ("photo", 5)]);
+        {
+            let objects = sink.objects.lock().unwrap();
+            assert_eq!(objects[stored.files[0].1.token], large.as_bytes());
+            assert_eq!(objects[stored.files[1].1.token], b"small");
+        }
+        // Nothing was written to disk
+        assert_eq!(::std::fs::read_dir(tmpdir.path()).unwrap().count(), 0);
+
+        // A body that fails leaves nothing stored
+        let config = ParseConfig { max_files: Some(1), abort_on_limit: true, ..config };
+        match read_formdata_to_sink(&mut body.as_bytes(), &headers, &config, &sink) {
+            Err(Error::TooManyFiles(_)) => {},
+            other => panic!("expected TooManyFiles, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(*sink.abandoned.lock().unwrap(), vec![2]);
+    }
+}
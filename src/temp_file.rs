@@ -0,0 +1,87 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! Creation of the temporary files that uploads are written to, readable only by their
+//! owner (see `ParseConfig::file_mode`).  On Linux with the `tmpfile` feature, a file can
+//! instead be created unnamed (`O_TMPFILE`) and linked into its directory once complete.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// Create the directory `dir`, with the permission bits that go with files of `mode`: each
+/// read bit brings the matching execute bit.
+#[cfg(unix)]
+pub(crate) fn create_dir(dir: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new().mode(mode | (mode & 0o444) >> 2).create(dir)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_dir(dir: &Path, _mode: u32) -> io::Result<()> {
+    fs::create_dir(dir)
+}
+
+/// Create the file at `path` for writing, with permission bits `mode`.
+#[cfg(unix)]
+pub(crate) fn create(path: &Path, mode: u32) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create(path: &Path, _mode: u32) -> io::Result<File> {
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)
+}
+
+/// Create an unnamed file in the directory `dir`, with permission bits `mode`.  Returns
+/// `None` if the filesystem does not support unnamed files.
+#[cfg(all(feature = "tmpfile", target_os = "linux"))]
+pub(crate) fn create_unnamed(dir: &Path, mode: u32) -> io::Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let opened = OpenOptions::new().write(true).mode(mode)
+        .custom_flags(::libc::O_TMPFILE).open(dir);
+    match opened {
+        Ok(file) => Ok(Some(file)),
+        Err(e) => match e.raw_os_error() {
+            // Older kernels take O_TMPFILE to be O_DIRECTORY, which cannot be written
+            Some(::libc::EOPNOTSUPP) | Some(::libc::EISDIR) | Some(::libc::EINVAL) => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+#[cfg(not(all(feature = "tmpfile", target_os = "linux")))]
+pub(crate) fn create_unnamed(_dir: &Path, _mode: u32) -> io::Result<Option<File>> {
+    Ok(None)
+}
+
+/// Give `file`, created by `create_unnamed()`, the name `path`.
+#[cfg(all(feature = "tmpfile", target_os = "linux"))]
+pub(crate) fn link(file: &File, path: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+    let to = CString::new(path.as_os_str().as_bytes())?;
+    // Linking the file descriptor itself (with AT_EMPTY_PATH) needs CAP_DAC_READ_SEARCH,
+    // but linking its /proc entry does not.
+    let from = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+    let linked = unsafe {
+        ::libc::linkat(::libc::AT_FDCWD, from.as_ptr(), ::libc::AT_FDCWD, to.as_ptr(),
+                       ::libc::AT_SYMLINK_FOLLOW)
+    };
+    if linked == 0 {
+        return Ok(());
+    }
+    let empty = CString::default();
+    let linked = unsafe {
+        ::libc::linkat(file.as_raw_fd(), empty.as_ptr(), ::libc::AT_FDCWD, to.as_ptr(),
+                       ::libc::AT_EMPTY_PATH)
+    };
+    if linked == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(all(feature = "tmpfile", target_os = "linux")))]
+pub(crate) fn link(_file: &File, _path: &Path) -> io::Result<()> {
+    Ok(())
+}
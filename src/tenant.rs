@@ -0,0 +1,100 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use error::Error;
+use shared::Shared;
+
+/// The tenant a request is parsed on behalf of, for multi-tenant deployments.  Set it in
+/// `ParseConfig::tenant` for each request.  Each tenant's uploads are kept in a
+/// subdirectory of the temporary directory named after its id (see `temp_dir()`), so they
+/// can be cleaned up together, and count against its quota, if it has one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tenant {
+    /// Identifies the tenant.  Bytes other than ASCII letters, digits, `-`, `_` and `.` are
+    /// percent-encoded in the directory name, so that each id has a directory of its own.
+    pub id: String,
+    /// The tenant's quota, shared by all of its requests.  `None` means no limit.
+    pub quota: Option<TenantQuota>,
+}
+
+impl Tenant {
+    pub fn new(id: &str) -> Tenant {
+        Tenant { id: id.to_owned(), quota: None }
+    }
+
+    /// The directory within `base` that holds this tenant's uploads.
+    pub fn temp_dir(&self, base: &Path) -> PathBuf {
+        let mut dir = String::new();
+        for &b in self.id.as_bytes() {
+            if b.is_ascii_alphanumeric() || b"-_.".contains(&b) {
+                dir.push(b as char);
+            } else {
+                dir.push_str(&format!("%{:02X}", b));
+            }
+        }
+        match &*dir {
+            // No other id gives a lone `%`
+            "" => base.join("%"),
+            "." => base.join("%2E"),
+            ".." => base.join("%2E%2E"),
+            _ => base.join(dir),
+        }
+    }
+}
+
+/// A budget of upload bytes shared by all requests of a tenant.  File content (including
+/// fields spilled to disk) counts against it as it is received; a request that would take
+/// it over its limit fails with `Error::QuotaExceeded`.  The bytes of a request that fails
+/// are returned to the budget (unless its partial form is kept, with
+/// `ParseConfig::keep_partial`); otherwise, they are only returned by `release()` or
+/// `reset()`, e.g. once uploads are deleted or at the start of a billing period.
+///
+/// Cloning a `TenantQuota` gives another handle to the same budget.
+#[derive(Clone, PartialEq)]
+pub struct TenantQuota {
+    used: Shared<AtomicU64>,
+    limit: u64,
+}
+
+impl TenantQuota {
+    /// Create a quota of `limit` bytes.
+    pub fn new(limit: u64) -> TenantQuota {
+        TenantQuota { used: Shared(Arc::new(AtomicU64::new(0))), limit }
+    }
+
+    /// The number of bytes used.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Return `bytes` to the budget.
+    pub fn release(&self, bytes: u64) {
+        let _ = self.used.fetch_update(Ordering::SeqCst, Ordering::SeqCst,
+                                       |used| Some(used.saturating_sub(bytes)));
+    }
+
+    /// Return all bytes to the budget.
+    pub fn reset(&self) {
+        self.used.store(0, Ordering::SeqCst);
+    }
+
+    // Take `bytes` from the budget, unless that would exceed the limit.
+    pub(crate) fn consume(&self, bytes: u64) -> Result<(), Error> {
+        self.used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+            used.checked_add(bytes).filter(|&total| total <= self.limit)
+        }).map(|_| ()).map_err(|_| Error::QuotaExceeded)
+    }
+}
+
+impl fmt::Debug for TenantQuota {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TenantQuota")
+            .field("used", &self.used())
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
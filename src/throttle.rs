@@ -0,0 +1,91 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use shared::Shared;
+
+/// A token-bucket bandwidth limiter.  Give one to `ParseConfig::rate_limiter` or
+/// `WriteConfig::rate_limiter` to cap how fast a body is read or written; the calling
+/// thread sleeps as needed.
+///
+/// Cloning a `RateLimiter` gives another handle to the same bucket, so one limiter can cap
+/// the combined bandwidth of several uploads, or each upload can be given its own.
+#[derive(Clone, PartialEq)]
+pub struct RateLimiter {
+    bucket: Shared<Mutex<Bucket>>,
+    bytes_per_sec: u64,
+    burst: u64,
+}
+
+struct Bucket {
+    // May go negative: a large read is allowed through, and later ones wait off the debt.
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `bytes_per_sec` on average, and bursts of up to `burst`
+    /// bytes.
+    pub fn new(bytes_per_sec: u64, burst: u64) -> RateLimiter {
+        let bucket = Bucket { tokens: burst as f64, last: Instant::now() };
+        RateLimiter {
+            bucket: Shared(Arc::new(Mutex::new(bucket))),
+            bytes_per_sec: bytes_per_sec.max(1),
+            burst,
+        }
+    }
+
+    /// Account for `bytes` transferred, sleeping until the rate allows it.
+    pub fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = match self.bucket.lock() {
+                Ok(bucket) => bucket,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last).as_secs_f64() * self.bytes_per_sec as f64;
+            bucket.tokens = (bucket.tokens + refill).min(self.burst as f64) - bytes as f64;
+            bucket.last = now;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec as f64)
+            } else {
+                Duration::from_secs(0)
+            }
+        };
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("bytes_per_sec", &self.bytes_per_sec)
+            .field("burst", &self.burst)
+            .finish()
+    }
+}
+
+// A writer that passes everything written through a `RateLimiter`.
+pub(crate) struct ThrottledWriter<'a, W: Write + 'a> {
+    pub inner: &'a mut W,
+    pub limiter: &'a RateLimiter,
+}
+
+impl<'a, W: Write> Write for ThrottledWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.limiter.consume(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright © 2015 by Michael Dilger (of New Zealand)
+// This code is licensed under the MIT license (see LICENSE-MIT for details)
+
+//! A dedicated thread for writing uploaded files to disk, so that disk writes overlap with
+//! reading the next piece of the body.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+use durability::DurabilityPolicy;
+use error::Error;
+
+enum Job {
+    Open(usize, File),
+    Write(usize, Vec<u8>),
+    // Close the file, first truncating it to the given length if there is one, and making
+    // it as durable as asked.
+    Close(usize, Option<u64>, DurabilityPolicy),
+}
+
+/// The writer thread, fed through a bounded queue.  Errors are reported by `finish()` (or
+/// by the first send after the thread has given up).
+pub(crate) struct WriterThread {
+    tx: Option<SyncSender<Job>>,
+    handle: Option<JoinHandle<io::Result<()>>>,
+    next_id: usize,
+}
+
+impl WriterThread {
+    /// Start a writer thread whose queue holds up to `capacity` pending jobs.
+    pub fn spawn(capacity: usize) -> Result<WriterThread, Error> {
+        let (tx, rx) = sync_channel::<Job>(capacity);
+        let handle = thread::Builder::new()
+            .name("formdata-writer".to_owned())
+            .spawn(move || {
+                let mut files: HashMap<usize, File> = HashMap::new();
+                for job in rx {
+                    match job {
+                        Job::Open(id, file) => { files.insert(id, file); },
+                        Job::Write(id, data) => if let Some(file) = files.get_mut(&id) {
+                            file.write_all(&data)?;
+                        },
+                        Job::Close(id, len, durability) =>
+                            if let Some(mut file) = files.remove(&id) {
+                                file.flush()?;
+                                if let Some(len) = len {
+                                    file.set_len(len)?;
+                                }
+                                durability.sync_file(&mut file)?;
+                            },
+                    }
+                }
+                Ok(())
+            })?;
+        Ok(WriterThread { tx: Some(tx), handle: Some(handle), next_id: 0 })
+    }
+
+    /// Hand `file` to the writer thread, returning the id to write to it with.
+    pub fn open(&mut self, file: File) -> Result<usize, Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(Job::Open(id, file))?;
+        Ok(id)
+    }
+
+    pub fn write(&mut self, id: usize, data: Vec<u8>) -> Result<(), Error> {
+        self.send(Job::Write(id, data))
+    }
+
+    /// Close the file, first truncating it to `len` if given, and making it as durable as
+    /// `durability` asks.
+    pub fn close(&mut self, id: usize, len: Option<u64>, durability: DurabilityPolicy)
+                 -> Result<(), Error>
+    {
+        self.send(Job::Close(id, len, durability))
+    }
+
+    /// Wait for all queued writes to complete.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.tx = None;
+        match self.handle.take() {
+            Some(handle) => match handle.join() {
+                Ok(result) => Ok(result?),
+                Err(_) => Err(Error::Io(io::Error::other("formdata writer thread panicked"))),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn send(&mut self, job: Job) -> Result<(), Error> {
+        let sent = match self.tx {
+            Some(ref tx) => tx.send(job).is_ok(),
+            None => false,
+        };
+        if sent {
+            return Ok(());
+        }
+        // The thread has stopped, which it only does on an error.
+        self.finish()?;
+        Err(Error::Io(io::Error::new(io::ErrorKind::BrokenPipe,
+                                     "formdata writer thread stopped")))
+    }
+}
+
+impl Drop for WriterThread {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}